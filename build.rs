@@ -0,0 +1,53 @@
+//! Makes the initrd that goes in the kernel image: the archive `MTOS_INITRD` names (ustar or newc
+//! cpio), or if that's not set, a ustar archive of the `initrd` directory.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+#[path = "src/initrd/tar_writer.rs"]
+mod tar_writer;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("initrd");
+    println!("cargo:rerun-if-env-changed=MTOS_INITRD");
+    let archive = match env::var_os("MTOS_INITRD") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", Path::new(&path).display());
+            fs::read(&path).unwrap_or_else(|e| panic!("Can't read {}: {}", Path::new(&path).display(), e))
+        }
+        None => {
+            println!("cargo:rerun-if-changed=initrd");
+            let mut archive = Vec::new();
+            if Path::new("initrd").exists() {
+                add_dir(&mut archive, Path::new("initrd"), "").expect("Can't archive initrd");
+            }
+            tar_writer::finish(&mut archive);
+            archive
+        }
+    };
+    fs::write(&out, archive).expect("Can't write the initrd");
+}
+
+/* Add what's in `dir` to `archive`, as `prefix`..., in name order so builds are the same. */
+fn add_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            tar_writer::add(archive, &name, b'2', mode, &[], &target.to_string_lossy());
+        } else if metadata.is_dir() {
+            tar_writer::add(archive, &format!("{}/", name), b'5', mode, &[], "");
+            add_dir(archive, &entry.path(), &format!("{}/", name))?;
+        } else {
+            tar_writer::add(archive, &name, b'0', mode, &fs::read(entry.path())?, "");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! Just enough ACPI to find the CPUs and interrupt controllers: the RSDP, the RSDT/XSDT, and the
+//! MADT. The tables are read in place through the physical memory mapping.
+
+use crate::memory;
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/* Where the BIOS leaves the RSDP: the first KiB of the EBDA, whose segment is in the BDA, or
+ * the BIOS ROM area. */
+const BDA_EBDA_SEGMENT: u64 = 0x40e;
+const BIOS_ROM_START: u64 = 0xe_0000;
+const BIOS_ROM_END: u64 = 0x10_0000;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const MADT_LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    /* ACPI 2.0+ */
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt it handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that's wired to a different GSI, or with non-default polarity/triggering.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// What the MADT says about the machine's interrupt controllers.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// APIC IDs of the usable CPUs, the boot CPU among them.
+    pub cpu_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+fn phys_ptr<T>(addr: u64) -> Option<*const T> {
+    memory::phys_to_virt(PhysAddr::new(addr)).map(|v| v.as_ptr())
+}
+
+fn checksum_ok(addr: u64, len: usize) -> bool {
+    match phys_ptr::<u8>(addr) {
+        Some(p) => unsafe { slice::from_raw_parts(p, len) }
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b))
+            == 0,
+        None => false,
+    }
+}
+
+fn find_rsdp_in(start: u64, end: u64) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|addr| {
+        let rsdp = unsafe { ptr::read_unaligned(phys_ptr::<Rsdp>(addr)?) };
+        if &rsdp.signature == RSDP_SIGNATURE && checksum_ok(addr, 20) {
+            Some(rsdp)
+        } else {
+            None
+        }
+    })
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = unsafe { ptr::read_unaligned(phys_ptr::<u16>(BDA_EBDA_SEGMENT)?) };
+    let ebda = u64::from(ebda) << 4;
+    let in_ebda = if ebda != 0 { find_rsdp_in(ebda, ebda + 1024) } else { None };
+    in_ebda.or_else(|| find_rsdp_in(BIOS_ROM_START, BIOS_ROM_END))
+}
+
+/// The header of the table at `addr`, if its checksum is good.
+fn read_sdt(addr: u64) -> Option<SdtHeader> {
+    let header = unsafe { ptr::read_unaligned(phys_ptr::<SdtHeader>(addr)?) };
+    if checksum_ok(addr, header.length as usize) {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+/// Find a table by signature through the RSDT or XSDT.
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp = find_rsdp()?;
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+    let header = read_sdt(root)?;
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let first = root + mem::size_of::<SdtHeader>() as u64;
+
+    (0..entries).find_map(|i| {
+        let entry = first + (i * entry_size) as u64;
+        let addr = unsafe {
+            if entry_size == 8 {
+                ptr::read_unaligned(phys_ptr::<u64>(entry)?)
+            } else {
+                u64::from(ptr::read_unaligned(phys_ptr::<u32>(entry)?))
+            }
+        };
+        match read_sdt(addr) {
+            Some(h) if &h.signature == signature => Some(addr),
+            _ => None,
+        }
+    })
+}
+
+/// Parse the MADT. None if there's no ACPI, or no MADT, eg on a machine without APICs.
+pub fn madt() -> Option<Madt> {
+    let addr = find_table(MADT_SIGNATURE)?;
+    let header = read_sdt(addr)?;
+    let body = addr + mem::size_of::<SdtHeader>() as u64;
+    let end = addr + u64::from(header.length);
+    let read_u8 = |a: u64| unsafe { ptr::read_unaligned(phys_ptr::<u8>(a).unwrap()) };
+    let read_u16 = |a: u64| unsafe { ptr::read_unaligned(phys_ptr::<u16>(a).unwrap()) };
+    let read_u32 = |a: u64| unsafe { ptr::read_unaligned(phys_ptr::<u32>(a).unwrap()) };
+    let read_u64 = |a: u64| unsafe { ptr::read_unaligned(phys_ptr::<u64>(a).unwrap()) };
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(read_u32(body))),
+        cpu_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    /* Entries: type, length, then type-specific fields. */
+    let mut entry = body + 8;
+    while entry + 2 <= end {
+        let len = u64::from(read_u8(entry + 1));
+        if len < 2 || entry + len > end {
+            break;
+        }
+        match read_u8(entry) {
+            MADT_LOCAL_APIC => {
+                let flags = read_u32(entry + 4);
+                if flags & (MADT_LOCAL_APIC_ENABLED | MADT_LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    madt.cpu_apic_ids.push(read_u8(entry + 3));
+                }
+            }
+            MADT_IO_APIC => madt.io_apics.push(IoApic {
+                id: read_u8(entry + 2),
+                address: PhysAddr::new(u64::from(read_u32(entry + 4))),
+                gsi_base: read_u32(entry + 8),
+            }),
+            MADT_INTERRUPT_OVERRIDE => madt.overrides.push(InterruptOverride {
+                irq: read_u8(entry + 3),
+                gsi: read_u32(entry + 4),
+                flags: read_u16(entry + 8),
+            }),
+            MADT_LOCAL_APIC_ADDRESS => madt.local_apic_address = PhysAddr::new(read_u64(entry + 4)),
+            _ => (),
+        }
+        entry += len;
+    }
+    Some(madt)
+}
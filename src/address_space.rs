@@ -0,0 +1,597 @@
+//! Address spaces for user code, each with page tables of its own.
+//!
+//! An address space's L4 table points at the kernel's own L3 tables for everything outside the
+//! user range, so kernel mappings are shared: a change below L4 shows up everywhere at once. The
+//! kernel only maps things at runtime under L4 entries that exist by the time there's a heap, so
+//! its L4 table itself never changes after the first address space is made. The user range is
+//! each address space's own, down to the frames, which are all freed with it.
+//!
+//! Forking shares pages rather than copying them: writable ones become read-only in both address
+//! spaces, marked copy-on-write, and a write fault on one gives the faulting space a copy of its
+//! own. The frame allocator counts the references, so the last space to let go frees the frame.
+//! Pages mapped with `map_shared()` (shared memory) are the exception: they stay shared, writes
+//! and all.
+//!
+//! When the CPU has PCIDs, each address space gets one (while they last), so its TLB entries stay
+//! cached while other address spaces run.
+
+use crate::sync::IrqSpinLock;
+use crate::user::{MapError, USER_END, USER_START};
+use crate::{ipi, memory, println, smp, tlb};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/* The L4 slots the user range covers. */
+const USER_L4_START: usize = (USER_START >> 39) as usize;
+const USER_L4_END: usize = (USER_END >> 39) as usize;
+
+/* Loading CR3 with this set keeps the new PCID's cached entries. */
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+/* An available bit, set on pages that are read-only only until they're next written. */
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/* Another, set on pages that stay shared when the address space is duplicated. */
+const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+static COW_FAULTS: AtomicU64 = AtomicU64::new(0);
+static COW_COPIES: AtomicU64 = AtomicU64::new(0);
+
+/* PCID 0 is the kernel's, and that of any address space that didn't get one. */
+const PCIDS: u16 = 4096;
+
+lazy_static! {
+    static ref FREE_PCIDS: IrqSpinLock<Vec<u16>> = IrqSpinLock::new((1..PCIDS).rev().collect());
+}
+
+pub struct AddressSpace {
+    l4: PhysFrame,
+    pcid: Option<u16>,
+    /* Held while changing or walking the user page tables. */
+    lock: IrqSpinLock<()>,
+}
+
+impl AddressSpace {
+    /// A new address space with nothing in its user range.
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let frame = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+        let l4 = *frame;
+        let kernel = unsafe { table(memory::kernel_l4_frame()) };
+        let table = unsafe { table(l4) };
+        for (i, entry) in table.iter_mut().enumerate() {
+            if (USER_L4_START..USER_L4_END).contains(&i) {
+                entry.set_unused();
+            } else {
+                *entry = kernel[i].clone();
+            }
+        }
+        let pcid = if tlb::pcid_enabled() { FREE_PCIDS.lock().pop() } else { None };
+        Ok(AddressSpace { l4, pcid, lock: IrqSpinLock::new(()) })
+    }
+
+    /// Map `pages` zeroed pages from `start`, which must be page aligned. If it fails, nothing is
+    /// mapped.
+    pub fn map(&self, start: VirtAddr, pages: u64, writable: bool, executable: bool) -> Result<(), MapError> {
+        let first = Page::<Size4KiB>::from_start_address(start).map_err(|_| MapError::OutOfRange)?;
+        check_range(start, pages)?;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let mut mapped = 0;
+        let (result, undone) = {
+            let _lock = self.lock.lock();
+            if self.any_mapped(first, pages) {
+                return Err(MapError::AlreadyMapped);
+            }
+            let result = Page::range(first, first + pages).try_for_each(|page| {
+                let entry = self.entry(page, true).ok_or(MapError::OutOfMemory)?;
+                let frame = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+                unsafe { zero(*frame) };
+                entry.set_frame(*frame, flags);
+                mapped += 1;
+                Ok(())
+            });
+            let undone = if result.is_err() { self.undo_map(first, mapped) } else { Vec::new() };
+            (result, undone)
+        };
+        if !undone.is_empty() {
+            self.shootdown(start, mapped);
+        }
+        for frame in undone {
+            memory::free_frame(frame);
+        }
+        result
+    }
+
+    /* Whether any of `pages` pages from `first` is mapped. The caller holds the lock. */
+    fn any_mapped(&self, first: Page, pages: u64) -> bool {
+        Page::range(first, first + pages)
+            .any(|page| self.entry(page, false).map_or(false, |entry| !entry.is_unused()))
+    }
+
+    /* Unmap the `pages` pages from `first` that a map that then failed did map, returning their
+     * frames for the caller to free once it's dropped the lock and shot them down. */
+    fn undo_map(&self, first: Page, pages: u64) -> Vec<PhysFrame> {
+        let mut frames = Vec::new();
+        for page in Page::range(first, first + pages) {
+            let entry = self.entry(page, false).unwrap();
+            frames.push(entry.frame().unwrap());
+            entry.set_unused();
+        }
+        frames
+    }
+
+    /// A copy of this address space: the same user mappings, to the same frames, with writable
+    /// pages made copy-on-write in both (except shared ones).
+    pub fn duplicate(&self) -> Result<AddressSpace, MapError> {
+        let copy = AddressSpace::new()?;
+        let shared = {
+            let _lock = self.lock.lock();
+            self.for_each_page(|page, entry| {
+                let to = copy.entry(page, true).ok_or(MapError::OutOfMemory)?;
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                let frame = entry.frame().unwrap();
+                if !memory::share_frame(frame) {
+                    return Err(MapError::OutOfMemory);
+                }
+                to.set_frame(frame, flags);
+                Ok(())
+            })
+        };
+        /* Even if it failed part way, some pages may have just become read-only here. */
+        self.shootdown(VirtAddr::new(USER_START), (USER_END - USER_START) / 4096);
+        shared.map(|_| copy)
+    }
+
+    /// Handle a write fault at `addr`: if it's on a copy-on-write page, give this address space a
+    /// copy of its own to write to (or just make it writable, if nothing else shares it any more).
+    /// False if the fault is a real one, or there's no memory for the copy.
+    pub fn copy_on_write(&self, addr: VirtAddr) -> bool {
+        if addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+            return false;
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let mut old = Vec::new();
+        {
+            let _lock = self.lock.lock();
+            let entry = match self.entry(page, false) {
+                Some(entry) if entry.flags().contains(PageTableFlags::PRESENT) => entry,
+                _ => return false,
+            };
+            if entry.flags().contains(PageTableFlags::WRITABLE) {
+                /* Already made writable (by `write()`, say), but this CPU still had the old entry. */
+                tlb::flush_local(page.start_address(), 1);
+                return true;
+            }
+            if !entry.flags().contains(COPY_ON_WRITE) || !self.break_cow(entry, &mut old) {
+                return false;
+            }
+        }
+        COW_FAULTS.fetch_add(1, Ordering::Relaxed);
+        /* Other threads in this address space may be running on other CPUs, with the read-only
+         * entry cached, and it has to go before the old frame can. */
+        self.shootdown(page.start_address(), 1);
+        for frame in old {
+            memory::free_frame(frame);
+        }
+        true
+    }
+
+    /* Make a copy-on-write page writable, copying it first if it's still shared. False if there's
+     * no memory for that. The caller holds the lock, shoots down the old entry, and only then
+     * drops this address space's reference to the frame it pointed at, which goes on `old`. */
+    fn break_cow(&self, entry: &mut PageTableEntry, old: &mut Vec<PhysFrame>) -> bool {
+        let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let frame = entry.frame().unwrap();
+        if memory::frame_references(frame) == 1 {
+            entry.set_flags(flags);
+            return true;
+        }
+        let copy = match memory::allocate_frame() {
+            Some(copy) => *copy,
+            None => return false,
+        };
+        unsafe {
+            let src = memory::phys_to_virt(frame.start_address()).unwrap().as_ptr::<u8>();
+            let dest = memory::phys_to_virt(copy.start_address()).unwrap().as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(src, dest, 4096);
+        }
+        entry.set_frame(copy, flags);
+        old.push(frame);
+        COW_COPIES.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Unmap and free `pages` pages from `start`, skipping any that aren't mapped.
+    pub fn unmap(&self, start: VirtAddr, pages: u64) -> Result<(), MapError> {
+        let first = Page::<Size4KiB>::from_start_address(start).map_err(|_| MapError::OutOfRange)?;
+        check_range(start, pages)?;
+        let mut freed = Vec::new();
+        {
+            let _lock = self.lock.lock();
+            for page in Page::range(first, first + pages) {
+                if let Some(entry) = self.entry(page, false) {
+                    if let Ok(frame) = entry.frame() {
+                        freed.push(frame);
+                    }
+                    entry.set_unused();
+                }
+            }
+        }
+        self.shootdown(start, pages);
+        for frame in freed {
+            memory::free_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Map `frames` from `start`, which must be page aligned, taking over the caller's reference
+    /// to each. Unlike other pages, they stay shared when the address space is duplicated. If it
+    /// fails, nothing is mapped and the references are dropped.
+    pub fn map_shared(&self, start: VirtAddr, frames: Vec<PhysFrame>, writable: bool, executable: bool) -> Result<(), MapError> {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | SHARED;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let pages = frames.len() as u64;
+        let mut mapped = 0;
+        let result = Page::<Size4KiB>::from_start_address(start).map_err(|_| MapError::OutOfRange).and_then(|first| {
+            check_range(start, pages)?;
+            let _lock = self.lock.lock();
+            if self.any_mapped(first, pages) {
+                return Err(MapError::AlreadyMapped);
+            }
+            let result = Page::range(first, first + pages).zip(&frames).try_for_each(|(page, &frame)| {
+                let entry = self.entry(page, true).ok_or(MapError::OutOfMemory)?;
+                entry.set_frame(frame, flags);
+                mapped += 1;
+                Ok(())
+            });
+            if result.is_err() {
+                self.undo_map(first, mapped);
+            }
+            result
+        });
+        if result.is_err() {
+            if mapped > 0 {
+                self.shootdown(start, mapped);
+            }
+            for &frame in &frames {
+                memory::free_frame(frame);
+            }
+        }
+        result
+    }
+
+    /// Unmap the page at `addr`, which must be page aligned and mapped, and hand over this address
+    /// space's reference to its frame rather than dropping it.
+    pub fn take_page(&self, addr: VirtAddr) -> Result<PhysFrame, MapError> {
+        let page = Page::<Size4KiB>::from_start_address(addr).map_err(|_| MapError::OutOfRange)?;
+        check_range(addr, 1)?;
+        let frame = {
+            let _lock = self.lock.lock();
+            let entry = self.entry(page, false).ok_or(MapError::OutOfRange)?;
+            let frame = entry.frame().map_err(|_| MapError::OutOfRange)?;
+            entry.set_unused();
+            frame
+        };
+        self.shootdown(addr, 1);
+        Ok(frame)
+    }
+
+    /// Map `frame` at `addr`, which must be page aligned and not mapped, taking over the caller's
+    /// reference to it. It's writable, but copy-on-write while anything else has it mapped too.
+    pub fn give_page(&self, addr: VirtAddr, frame: PhysFrame) -> Result<(), MapError> {
+        let page = Page::<Size4KiB>::from_start_address(addr).map_err(|_| MapError::OutOfRange)?;
+        check_range(addr, 1)?;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        if memory::frame_references(frame) == 1 {
+            flags |= PageTableFlags::WRITABLE;
+        } else {
+            flags |= COPY_ON_WRITE;
+        }
+        let _lock = self.lock.lock();
+        let entry = self.entry(page, true).ok_or(MapError::OutOfMemory)?;
+        if !entry.is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        entry.set_frame(frame, flags);
+        Ok(())
+    }
+
+    /// Copy `data` to `addr`, which must be mapped (writable or not).
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        self.unshare(addr, data.len())?;
+        self.copy(addr, data.len(), |virt, at, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[at..].as_ptr(), virt.as_mut_ptr(), len)
+        })
+    }
+
+    /// Copy from `addr`, which must be mapped, to `buf`.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), MapError> {
+        self.copy(addr, buf.len(), |virt, at, len| unsafe {
+            core::ptr::copy_nonoverlapping(virt.as_ptr(), buf[at..].as_mut_ptr(), len)
+        })
+    }
+
+    /* Break copy-on-write on the pages `len` bytes from `addr` cover, so the kernel can write to
+     * them without writing to other address spaces too. */
+    fn unshare(&self, addr: VirtAddr, len: usize) -> Result<(), MapError> {
+        let start = addr.as_u64();
+        if len == 0 || start < USER_START || start >= USER_END {
+            return Ok(()); // copy() checks the range
+        }
+        let end = start.saturating_add(len as u64).min(USER_END);
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let pages = (end - first.start_address().as_u64() + 4095) / 4096;
+        let mut result = Ok(());
+        let mut broken = false;
+        let mut old = Vec::new();
+        {
+            let _lock = self.lock.lock();
+            for page in Page::range(first, first + pages) {
+                match self.entry(page, false) {
+                    Some(entry) if entry.flags().contains(COPY_ON_WRITE) => {
+                        if !self.break_cow(entry, &mut old) {
+                            result = Err(MapError::OutOfMemory);
+                            break;
+                        }
+                        broken = true;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if broken {
+            self.shootdown(first.start_address(), pages);
+        }
+        for frame in old {
+            memory::free_frame(frame);
+        }
+        result
+    }
+
+    /* Call `f` for each page's worth of `len` bytes from `addr`, with where that is in the
+     * physical memory mapping and how far along it is. */
+    fn copy<F: FnMut(VirtAddr, usize, usize)>(&self, addr: VirtAddr, len: usize, mut f: F) -> Result<(), MapError> {
+        check_range(addr, 0)?;
+        let _lock = self.lock.lock();
+        let mut done = 0;
+        while done < len {
+            let at = addr + done;
+            let page = Page::<Size4KiB>::containing_address(at);
+            if page.start_address().as_u64() >= USER_END {
+                return Err(MapError::OutOfRange);
+            }
+            let frame = self.entry(page, false).and_then(|e| e.frame().ok()).ok_or(MapError::OutOfRange)?;
+            let offset = (at - page.start_address()) as usize;
+            let chunk = (4096 - offset).min(len - done);
+            f(memory::phys_to_virt(frame.start_address()).unwrap() + offset, done, chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Whether user mode could access `addr` (and write to it, if `write`): every level of the
+    /// page tables has to allow it.
+    pub fn can_access(&self, addr: VirtAddr, write: bool) -> bool {
+        let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            needed |= PageTableFlags::WRITABLE;
+        }
+        let addr = addr.as_u64();
+        if addr < USER_START || addr >= USER_END {
+            return false;
+        }
+        let _lock = self.lock.lock();
+        let mut table = unsafe { table(self.l4) };
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+        for (level, &index) in indices.iter().enumerate() {
+            let entry = &table[index];
+            if !entry.flags().contains(needed) {
+                return false;
+            }
+            if level + 1 < indices.len() {
+                table = unsafe { self::table(entry.frame().unwrap()) };
+            }
+        }
+        true
+    }
+
+    /// Switch this CPU to this address space, if it isn't already on it.
+    pub fn activate(&self) {
+        if Cr3::read().0 != self.l4 {
+            unsafe { load_cr3(self.l4, self.pcid) };
+        }
+    }
+
+    /* The L1 entry for `page`, creating the tables above it if `create`. None if they're missing
+     * (or can't be allocated). The caller holds the lock. */
+    fn entry(&self, page: Page, create: bool) -> Option<&mut PageTableEntry> {
+        let mut table = unsafe { table(self.l4) };
+        for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+            let entry = &mut table[index];
+            if entry.is_unused() {
+                if !create {
+                    return None;
+                }
+                let frame = *memory::allocate_frame()?;
+                unsafe { zero(frame) };
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+                entry.set_frame(frame, flags);
+            }
+            table = unsafe { self::table(entry.frame().ok()?) };
+        }
+        Some(&mut table[page.p1_index()])
+    }
+
+    /* Call `f` with each mapped user page and its L1 entry, stopping at the first error. The
+     * caller holds the lock. */
+    fn for_each_page<F>(&self, mut f: F) -> Result<(), MapError>
+    where
+        F: FnMut(Page, &mut PageTableEntry) -> Result<(), MapError>,
+    {
+        let index = |i: usize| PageTableIndex::new(i as u16);
+        let l4 = unsafe { table(self.l4) };
+        for i4 in USER_L4_START..USER_L4_END {
+            let l3 = match l4[i4].frame() {
+                Ok(frame) => unsafe { table(frame) },
+                Err(_) => continue,
+            };
+            for i3 in 0..512 {
+                let l2 = match l3[i3].frame() {
+                    Ok(frame) => unsafe { table(frame) },
+                    Err(_) => continue,
+                };
+                for i2 in 0..512 {
+                    let l1 = match l2[i2].frame() {
+                        Ok(frame) => unsafe { table(frame) },
+                        Err(_) => continue,
+                    };
+                    for i1 in 0..512 {
+                        if l1[i1].frame().is_ok() {
+                            let page = Page::from_page_table_indices(index(i4), index(i3), index(i2), index(i1));
+                            f(page, &mut l1[i1])?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* Drop cached entries for `pages` pages from `start` on every CPU. Ones on a PCID can be
+     * dropped anywhere; otherwise only CPUs running this address space have any, as loading CR3
+     * without a PCID flushes. */
+    fn shootdown(&self, start: VirtAddr, pages: u64) {
+        let (l4, pcid) = (self.l4, self.pcid);
+        let flush = move || match pcid {
+            Some(pcid) => tlb::flush_pcid(pcid, start, pages),
+            None if Cr3::read().0 == l4 => tlb::flush_local(start, pages),
+            None => (),
+        };
+        if smp::online_count() > 1 {
+            ipi::call(ipi::Target::All, flush);
+        } else {
+            flush();
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        /* Nothing's running on it by now, so it's in no CPU's CR3. */
+        let l4 = unsafe { table(self.l4) };
+        for entry in &l4[USER_L4_START..USER_L4_END] {
+            if let Ok(l3) = entry.frame() {
+                unsafe { free_table(l3, 3) };
+            }
+        }
+        memory::free_frame(self.l4);
+
+        /* Its entries may still be cached under its PCID, so flush them before it's reused. */
+        if let Some(pcid) = self.pcid {
+            if smp::online_count() > 1 {
+                ipi::call(ipi::Target::All, || tlb::flush_context(pcid));
+            } else {
+                tlb::flush_context(pcid);
+            }
+            FREE_PCIDS.lock().push(pcid);
+        }
+    }
+}
+
+/// Copy-on-write activity since boot.
+#[derive(Debug, Clone, Copy)]
+pub struct CowStats {
+    /// Write faults resolved by copying or unsharing a page.
+    pub faults: u64,
+    /// Pages copied (by faults, or by the kernel writing to user memory).
+    pub copies: u64,
+    /// Frames shared rather than copied, right now.
+    pub pages_saved: usize,
+}
+
+pub fn cow_stats() -> CowStats {
+    CowStats {
+        faults: COW_FAULTS.load(Ordering::Relaxed),
+        copies: COW_COPIES.load(Ordering::Relaxed),
+        pages_saved: memory::frames_saved(),
+    }
+}
+
+/// Print the copy-on-write counters.
+pub fn dump_stats() {
+    let stats = cow_stats();
+    println!(
+        "copy-on-write: {} faults, {} pages copied, {} pages saved",
+        stats.faults, stats.copies, stats.pages_saved
+    );
+}
+
+/// Switch this CPU back to the kernel's page tables, for threads with no address space.
+pub fn activate_kernel() {
+    let kernel = memory::kernel_l4_frame();
+    if Cr3::read().0 != kernel {
+        /* Flushing, as address spaces without a PCID share the kernel's. */
+        unsafe { load_cr3(kernel, None) };
+    }
+}
+
+unsafe fn load_cr3(l4: PhysFrame, pcid: Option<u16>) {
+    let mut value = l4.start_address().as_u64();
+    if let Some(pcid) = pcid {
+        value |= CR3_NOFLUSH | u64::from(pcid);
+    }
+    asm!("mov cr3, {}", in(reg) value, options(nostack));
+}
+
+fn check_range(start: VirtAddr, pages: u64) -> Result<(), MapError> {
+    let end = pages.checked_mul(4096).and_then(|len| start.as_u64().checked_add(len));
+    match end {
+        Some(end) if start.as_u64() >= USER_START && end <= USER_END => Ok(()),
+        _ => Err(MapError::OutOfRange),
+    }
+}
+
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).unwrap().as_mut_ptr()
+}
+
+unsafe fn zero(frame: PhysFrame) {
+    memory::phys_to_virt(frame.start_address()).unwrap().as_mut_ptr::<u8>().write_bytes(0, 4096);
+}
+
+/* Free a page table at `level` (1 being the one with the pages in), everything under it, and
+ * the frames it maps. */
+unsafe fn free_table(frame: PhysFrame, level: usize) {
+    for entry in table(frame).iter() {
+        if let Ok(next) = entry.frame() {
+            if level > 1 {
+                free_table(next, level - 1);
+            } else {
+                memory::free_frame(next);
+            }
+        }
+    }
+    memory::free_frame(frame);
+}
@@ -1,5 +1,7 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::vec::Vec;
 use core::ptr::null_mut;
+use linked_list_allocator::LockedHeap;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -22,6 +24,34 @@ unsafe impl GlobalAlloc for DummyAllocator {
     }
 }
 
+/// The heap, with interrupts held off while its lock is held. The scheduler allocates from the timer
+/// interrupt path, which would deadlock against a thread preempted while holding the heap lock.
+pub struct IrqSafeHeap {
+    heap: LockedHeap,
+}
+
+impl IrqSafeHeap {
+    pub const fn empty() -> Self {
+        IrqSafeHeap {
+            heap: LockedHeap::empty(),
+        }
+    }
+
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        x86_64::instructions::interrupts::without_interrupts(|| self.heap.lock().init(start, size));
+    }
+}
+
+unsafe impl GlobalAlloc for IrqSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| self.heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| self.heap.dealloc(ptr, layout))
+    }
+}
+
 pub fn init(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -42,8 +72,23 @@ pub fn init(
     }
 
     unsafe {
-        super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        super::ALLOCATOR.init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
+
+/// An empty Vec with room for `capacity` bytes, or None if the heap hasn't got that much, rather
+/// than the allocation error a Vec's own allocating would be. For buffers whose size comes from
+/// outside the kernel.
+pub fn try_vec(capacity: usize) -> Option<Vec<u8>> {
+    if capacity == 0 {
+        return Some(Vec::new());
+    }
+    let layout = Layout::array::<u8>(capacity).ok()?;
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    if ptr.is_null() {
+        return None;
+    }
+    Some(unsafe { Vec::from_raw_parts(ptr, 0, capacity) })
+}
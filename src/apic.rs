@@ -0,0 +1,144 @@
+//! The Local APIC: each CPU's own interrupt controller, used here for inter-processor interrupts
+//! (see `ipi` for what they're used for), and for the APs' scheduler ticks. Device interrupts, and
+//! the PIT's ticks, still come through the PICs to the boot CPU.
+
+use crate::{memory, time};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+
+/// Where unwanted interrupts from the APIC land; needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// The APIC timer's interrupt.
+pub const TIMER_VECTOR: u8 = 0xef;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_16: u32 = 0b0011;
+const TIMER_CALIBRATION_MS: u64 = 10;
+
+/* Virtual address of the registers; the same physical page on every CPU, each seeing its own. */
+static BASE: AtomicU64 = AtomicU64::new(0);
+/* Timer counts (at the bus clock / 16, which every CPU shares) in one tick; 0 until calibrated. */
+static TIMER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Map the registers. Once, on the boot CPU, before any other function here is used.
+pub fn init(phys: PhysAddr) {
+    let virt = memory::map_mmio(phys, 4096).expect("No room to map the local APIC");
+    BASE.store(virt.as_u64(), Ordering::Relaxed);
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+fn read(reg: usize) -> u32 {
+    unsafe { ((BASE.load(Ordering::Relaxed) as usize + reg) as *const u32).read_volatile() }
+}
+
+fn write(reg: usize, value: u32) {
+    unsafe { ((BASE.load(Ordering::Relaxed) as usize + reg) as *mut u32).write_volatile(value) }
+}
+
+/// Software-enable this CPU's APIC so it accepts interrupts. Each CPU calls this for itself.
+pub fn enable() {
+    write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+/// This CPU's APIC ID.
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+/// Measure the timer against the TSC, so `start_timer()` knows what to count to. Once, on the boot
+/// CPU, after the TSC's calibrated.
+pub fn calibrate_timer() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+    write(REG_TIMER_INITIAL, u32::MAX);
+    time::spin_delay(Duration::from_millis(TIMER_CALIBRATION_MS));
+    let counted = u64::from(u32::MAX - read(REG_TIMER_CURRENT));
+    write(REG_TIMER_INITIAL, 0);
+    let count = counted * 1000 / TIMER_CALIBRATION_MS / u64::from(time::TIMER_HZ);
+    TIMER_COUNT.store(count.max(1).min(u64::from(u32::MAX)), Ordering::Relaxed);
+}
+
+/// Have this CPU's timer interrupt TIMER_HZ times a second, at `TIMER_VECTOR`. For the APs, which
+/// don't get the PIT's.
+pub fn start_timer() {
+    let count = TIMER_COUNT.load(Ordering::Relaxed);
+    assert!(count != 0, "APIC timer not calibrated");
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+    write(REG_TIMER_INITIAL, count as u32);
+}
+
+/// Where to send an IPI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// The CPU with this APIC ID.
+    Apic(u8),
+    All,
+    AllButSelf,
+}
+
+fn send_command(apic_id: u8, command: u32) {
+    /* The ICR is per-CPU, but an interrupt handler here could send one in between the writes. */
+    interrupts::without_interrupts(|| {
+        write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+}
+
+/// Raise interrupt `vector` on the destination CPUs. CPUs that never started ignore it.
+pub fn send_ipi(dest: Destination, vector: u8) {
+    let vector = u32::from(vector) | ICR_LEVEL_ASSERT;
+    match dest {
+        Destination::Apic(id) => send_command(id, vector),
+        Destination::All => send_command(0, vector | ICR_SHORTHAND_ALL),
+        Destination::AllButSelf => send_command(0, vector | ICR_SHORTHAND_ALL_BUT_SELF),
+    }
+}
+
+/// Reset a CPU and have it start in real mode at `vector << 12`, with the INIT-SIPI-SIPI dance
+/// (and delays) from the Intel MP spec.
+pub fn start_cpu(apic_id: u8, vector: u8) {
+    send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    time::spin_delay(Duration::from_millis(10));
+    for _ in 0..2 {
+        send_command(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(vector));
+        time::spin_delay(Duration::from_micros(200));
+    }
+}
+
+/// Reset a CPU back to waiting for a startup IPI, eg one that was too slow to start: it runs
+/// nothing until it's sent another.
+pub fn stop_cpu(apic_id: u8) {
+    send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    time::spin_delay(Duration::from_millis(10));
+}
@@ -34,14 +34,7 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("failed");
-    serial_println!("{}", info);
-
-    unsafe {
-        exit_qemu();
-    }
-
-    loop {}
+    mtos::testing::fail(info)
 }
 
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
@@ -67,10 +60,5 @@ extern "x86-interrupt" fn double_fault_handler(
     _stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("ok");
-
-    unsafe {
-        exit_qemu();
-    }
-    loop {}
+    mtos::testing::pass()
 }
@@ -0,0 +1,69 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mtos::process::fd::FileOps;
+use mtos::vfs::{self, ramfs::RamFs, FileType};
+use mtos::*;
+
+entry_point!(test_main);
+
+/* What's in the initrd, if it's made from the initrd directory as it is by default. */
+const MOTD: &[u8] = include_bytes!("../../initrd/etc/motd");
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let fs = RamFs::new(256, allocator::HEAP_SIZE / 4).expect("Can't make a ramfs");
+    vfs::mount("/", fs).expect("Can't mount the ramfs");
+
+    /* The built-in initrd, at the root and again somewhere that doesn't exist yet. */
+    let entries = initrd::unpack(initrd::INITRD, "/").expect("Can't unpack the initrd");
+    if entries == 0 {
+        panic!("the initrd is empty");
+    }
+    initrd::unpack(initrd::INITRD, "/copy/of").expect("Can't unpack the initrd again");
+    for path in &["/etc/motd", "/copy/of/etc/motd"] {
+        let stat = vfs::stat(path).unwrap_or_else(|e| panic!("Can't stat {}: {:?}", path, e));
+        if stat.kind != FileType::Regular || stat.size != MOTD.len() as u64 {
+            panic!("{} is {:?}", path, stat);
+        }
+        let file = vfs::open(path, vfs::O_RDONLY, 0).unwrap();
+        let mut buf = [0; 256];
+        let len = file.read(&mut buf).unwrap();
+        if &buf[..len] != MOTD {
+            panic!("{} has {:?}", path, &buf[..len]);
+        }
+    }
+    let etc = vfs::stat("/copy/of/etc").expect("Can't stat /copy/of/etc");
+    if etc.kind != FileType::Directory {
+        panic!("/copy/of/etc is {:?}", etc);
+    }
+
+    /* Unpacking over what's there already replaces files, and keeps directories. */
+    initrd::unpack(initrd::INITRD, "/").expect("Can't unpack the initrd over itself");
+    check(vfs::stat("/etc/motd").map(|s| s.size as usize).ok(), MOTD.len(), "size after unpacking again");
+
+    if initrd::unpack(b"not an archive, but long enough to check for being one", "/").is_ok() {
+        panic!("unpacked something that isn't an archive");
+    }
+
+    testing::pass()
+}
+
+fn check(got: Option<usize>, expected: usize, what: &str) {
+    if got != Some(expected) {
+        panic!("{}: got {:?}, expected {}", what, got, expected);
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,343 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::AddressSpace;
+use mtos::process::ExitStatus;
+use mtos::syscall::Error;
+use mtos::user::USER_START;
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* A server and a forked client. The server makes a port (handle 1 in r13) and a send-only handle
+ * for it (2, in r14), then forks, and answers messages until the client sends its results: calls
+ * to add 1 to a number, a page, and a capability. The client closes its receiving handle, tries
+ * receiving on both, makes a call, sends the page and the capability, then times 10000 calls
+ * against 10000 getpid()s with rdtsc. It sends all that to the server, which closes the port, and
+ * checks a call then fails. Results go in the page at rdi (r12 from then on); messages are built
+ * at r12 + 512 and r12 + 1024. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_ipc, "a"
+.global test_ipc_main
+test_ipc_main:
+    mov r12, rdi
+    mov eax, 19                     // port_create()
+    syscall
+    mov [r12], rax
+    mov r13, rax
+    mov eax, 20                     // cap_restrict(port, RIGHT_SEND)
+    mov rdi, r13
+    mov esi, 1
+    syscall
+    mov [r12 + 8], rax
+    mov r14, rax
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_ipc_client
+    mov rbx, rax
+    lea rbp, [r12 + 0xf000]         // where a page sent goes
+
+test_ipc_serve:
+    mov eax, 23                     // receive(port, msg, page)
+    mov rdi, r13
+    lea rsi, [r12 + 512]
+    mov rdx, rbp
+    syscall
+    test rax, rax
+    js test_ipc_server_failed
+    mov r15, rax
+    mov rax, [r12 + 512]
+    cmp rax, 1
+    je test_ipc_echo
+    cmp rax, 2
+    je test_ipc_page
+    cmp rax, 3
+    je test_ipc_cap
+    cmp rax, 4
+    je test_ipc_results
+    jmp test_ipc_server_failed
+
+test_ipc_echo:
+    inc qword ptr [r12 + 520]
+    mov eax, 25                     // reply(reply handle, msg)
+    mov rdi, r15
+    lea rsi, [r12 + 512]
+    syscall
+    test rax, rax
+    jnz test_ipc_server_failed
+    jmp test_ipc_serve
+
+test_ipc_page:
+    mov [r12 + 16], r15
+    mov rax, [r12 + 560]
+    mov [r12 + 24], rax
+    test rax, rax
+    jz test_ipc_serve
+    mov rax, [rax]
+    mov [r12 + 32], rax
+    xor ebp, ebp
+    jmp test_ipc_serve
+
+test_ipc_cap:
+    mov rax, [r12 + 568]
+    mov [r12 + 40], rax
+    mov eax, 20                     // cap_restrict(its handle, RIGHT_RECEIVE)
+    mov rdi, [r12 + 568]
+    mov esi, 2
+    syscall
+    mov [r12 + 48], rax
+    mov eax, 21                     // cap_close(its handle)
+    mov rdi, [r12 + 568]
+    syscall
+    mov [r12 + 56], rax
+    jmp test_ipc_serve
+
+test_ipc_results:
+    mov rax, [r12 + 520]
+    mov [r12 + 64], rax
+    mov rax, [r12 + 528]
+    mov [r12 + 72], rax
+    mov rax, [r12 + 536]
+    mov [r12 + 80], rax
+    mov rax, [r12 + 544]
+    mov [r12 + 88], rax
+    mov rax, [r12 + 552]
+    mov [r12 + 96], rax
+    mov eax, 21                     // cap_close(port): it closes
+    mov rdi, r13
+    syscall
+    mov eax, 21                     // cap_close(send-only handle)
+    mov rdi, r14
+    syscall
+    mov eax, 8                      // waitpid(child, &status, 0)
+    mov rdi, rbx
+    lea rsi, [r12 + 104]
+    xor edx, edx
+    syscall
+    mov qword ptr [r12 + 112], 1
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_ipc_server_failed:
+    mov [r12 + 120], rax
+    mov eax, 0                      // exit(1)
+    mov edi, 1
+    syscall
+
+test_ipc_client:
+    mov eax, 21                     // cap_close(port)
+    mov rdi, r13
+    syscall
+    mov eax, 23                     // receive(port, msg, NULL): closed
+    mov rdi, r13
+    lea rsi, [r12 + 512]
+    xor edx, edx
+    syscall
+    mov [r12 + 1048], rax
+    mov eax, 23                     // receive(send-only handle, msg, NULL)
+    mov rdi, r14
+    lea rsi, [r12 + 512]
+    xor edx, edx
+    syscall
+    mov [r12 + 1056], rax
+
+    mov qword ptr [r12 + 512], 1    // call(send-only handle, {1, 41})
+    mov qword ptr [r12 + 520], 41
+    mov eax, 24
+    mov rdi, r14
+    lea rsi, [r12 + 512]
+    syscall
+    mov rax, [r12 + 520]
+    mov [r12 + 1064], rax
+
+    lea r15, [r12 + 0x1000]         // send(send-only handle, {2, page = spare page})
+    mov qword ptr [r15], 0x5eed
+    mov qword ptr [r12 + 512], 2
+    mov [r12 + 560], r15
+    mov eax, 22
+    mov rdi, r14
+    lea rsi, [r12 + 512]
+    syscall
+    mov qword ptr [r12 + 560], 0
+
+    mov qword ptr [r12 + 512], 3    // send(send-only handle, {3, cap = send-only handle})
+    mov [r12 + 568], r14
+    mov eax, 22
+    mov rdi, r14
+    lea rsi, [r12 + 512]
+    syscall
+    mov qword ptr [r12 + 568], 0
+
+    mov qword ptr [r12 + 512], 1    // 10000 x call(send-only handle, {1, n})
+    rdtsc
+    shl rdx, 32
+    or rax, rdx
+    mov r15, rax
+    mov ebx, 10000
+test_ipc_time_calls:
+    mov eax, 24
+    mov rdi, r14
+    lea rsi, [r12 + 512]
+    syscall
+    dec ebx
+    jnz test_ipc_time_calls
+    rdtsc
+    shl rdx, 32
+    or rax, rdx
+    sub rax, r15
+    mov [r12 + 1032], rax
+
+    rdtsc                           // 10000 x getpid()
+    shl rdx, 32
+    or rax, rdx
+    mov r15, rax
+    mov ebx, 10000
+test_ipc_time_syscalls:
+    mov eax, 9
+    syscall
+    dec ebx
+    jnz test_ipc_time_syscalls
+    rdtsc
+    shl rdx, 32
+    or rax, rdx
+    sub rax, r15
+    mov [r12 + 1040], rax
+
+    mov qword ptr [r12 + 1024], 4   // send(send-only handle, {4, results})
+    mov qword ptr [r12 + 1072], 0
+    mov qword ptr [r12 + 1080], 0
+    mov eax, 22
+    mov rdi, r14
+    lea rsi, [r12 + 1024]
+    syscall
+
+    mov qword ptr [r12 + 512], 1    // call(send-only handle, {1, n}): the port's closed
+    mov eax, 24
+    mov rdi, r14
+    lea rsi, [r12 + 512]
+    syscall
+    cmp rax, -32
+    jne test_ipc_client_failed
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_ipc_client_failed:
+    mov eax, 0                      // exit(1)
+    mov edi, 1
+    syscall
+.global test_ipc_main_end
+test_ipc_main_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_ipc_main: u8;
+    static test_ipc_main_end: u8;
+}
+
+/* Offsets in the results page. */
+const PORT: u64 = 0;
+const SEND_ONLY: u64 = 8;
+const PAGE_REPLY: u64 = 16;
+const PAGE_ADDR: u64 = 24;
+const PAGE_CONTENTS: u64 = 32;
+const CAP: u64 = 40;
+const CAP_RESTRICT: u64 = 48;
+const CAP_CLOSE: u64 = 56;
+const CALL_CYCLES: u64 = 64;
+const SYSCALL_CYCLES: u64 = 72;
+const RECEIVE_CLOSED: u64 = 80;
+const RECEIVE_SEND_ONLY: u64 = 88;
+const ECHO: u64 = 96;
+const CLIENT_STATUS: u64 = 104;
+const DONE: u64 = 112;
+const SERVER_ERROR: u64 = 120;
+
+const ROUND_TRIPS: u64 = 10000;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let code = unsafe { slice(&test_ipc_main, &test_ipc_main_end) };
+    let base = VirtAddr::new(USER_START);
+    let results = base + 0x1000u64;
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(results, 3, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    process::start("ipc", space.clone(), base, base + 0x4000u64, results.as_u64());
+
+    let deadline = time::uptime() + TIMEOUT;
+    while !process::list().is_empty() {
+        if time::uptime() > deadline {
+            process::dump();
+            panic!("processes didn't finish");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let peek = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    let errno = |e: Error| (e as u64).wrapping_neg();
+    check(peek(SERVER_ERROR), 0, "server's error");
+    check(peek(DONE), 1, "server finished");
+    check(peek(PORT), 1, "port's handle");
+    check(peek(SEND_ONLY), 2, "send-only handle");
+
+    check(peek(RECEIVE_CLOSED), errno(Error::BadFd), "receive on a closed handle");
+    check(peek(RECEIVE_SEND_ONLY), errno(Error::NotPermitted), "receive without the right");
+    check(peek(ECHO), 42, "reply to a call");
+
+    check(peek(PAGE_REPLY), 0, "reply handle for a send");
+    check(peek(PAGE_ADDR), USER_START + 0x10000, "where the page went");
+    check(peek(PAGE_CONTENTS), 0x5eed, "what was in the page");
+
+    check(peek(CAP), 3, "handle for the capability sent");
+    check(peek(CAP_RESTRICT), errno(Error::NotPermitted), "adding a right to it");
+    check(peek(CAP_CLOSE), 0, "closing it");
+
+    let status = peek(CLIENT_STATUS) as u32 as u64;
+    check(status, ExitStatus::Exited(0).wait_status(), "client's status (a call to a closed port)");
+
+    let call = time::tsc_to_ns(peek(CALL_CYCLES)) / ROUND_TRIPS;
+    let syscall = time::tsc_to_ns(peek(SYSCALL_CYCLES)) / ROUND_TRIPS;
+    serial_println!("ipc round trip: {} ns, syscall: {} ns", call, syscall);
+
+    testing::pass()
+}
+
+fn check(got: u64, expected: u64, what: &str) {
+    if got != expected {
+        panic!("{}: got {:#x}, expected {:#x}", what, got, expected);
+    }
+}
+
+unsafe fn slice(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
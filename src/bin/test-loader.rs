@@ -0,0 +1,168 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::loader::{self, elf};
+use mtos::testing::{dynamic, rela, ElfBuilder};
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* A program, made into a static PIE below: its text, then a page of data holding what it found
+ * on its stack and a pointer that has to be relocated to point at that. It waits for the go flag
+ * before exiting, so we can look. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_loader, "a"
+.balign 4096
+.global test_loader_text
+test_loader_text:
+    lea r12, [rip + test_loader_results]
+    mov rax, [rsp]
+    mov [r12], rax
+    mov rax, [rsp + 16]
+    mov rax, [rax]
+    mov [r12 + 8], rax
+    mov rax, [rsp + 32]
+    mov rax, [rax]
+    mov [r12 + 16], rax
+    mov rax, [rip + test_loader_pointer]
+    mov [r12 + 24], rax
+    mov [r12 + 32], rsp
+    mov qword ptr [r12 + 40], 1
+test_loader_wait:
+    mov eax, 5
+    syscall
+    cmp qword ptr [r12 + 48], 0
+    je test_loader_wait
+    xor edi, edi
+    xor eax, eax
+    syscall
+.balign 4096
+.global test_loader_data
+test_loader_data:
+test_loader_results:
+    .zero 64
+test_loader_pointer:
+    .quad 0
+.global test_loader_end
+test_loader_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_loader_text: u8;
+    static test_loader_data: u8;
+    static test_loader_end: u8;
+}
+
+/* Offsets in the results. */
+const ARGC: u64 = 0;
+const ARG1: u64 = 8;
+const ENV0: u64 = 16;
+const POINTER: u64 = 24;
+const RSP: u64 = 32;
+const DONE: u64 = 40;
+const GO: u64 = 48;
+/* ...and of the pointer to relocate, in the data. */
+const POINTER_OFFSET: u64 = 64;
+
+const TEXT_VADDR: u64 = 0x1000;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let text = unsafe { slice(&test_loader_text, &test_loader_data) };
+    let data = unsafe { slice(&test_loader_data, &test_loader_end) };
+    let data_vaddr = TEXT_VADDR + text.len() as u64;
+    let image = pie(text, data, data_vaddr);
+
+    match loader::load(&image[..16], &[], &[]) {
+        Err(loader::LoadError::Elf(elf::ElfError::TooShort)) => (),
+        Err(e) => panic!("Truncated image gave {:?}", e),
+        Ok(_) => panic!("Loaded a truncated image"),
+    }
+
+    /* The data segment with a bss bigger than a program can have. */
+    let mut huge = image.clone();
+    let memsz = 64 + 56 + 40;
+    huge[memsz..memsz + 8].copy_from_slice(&((loader::MAX_IMAGE_PAGES + 1) * 4096).to_le_bytes());
+    match loader::load(&huge, &[], &[]) {
+        Err(loader::LoadError::TooBig) => (),
+        Err(e) => panic!("Huge bss gave {:?}", e),
+        Ok(_) => panic!("Loaded a huge bss"),
+    }
+
+    let program = loader::load(&image, &["test", "argument"], &["KEY=value"]).expect("Can't load program");
+    let results = VirtAddr::new(program.base + data_vaddr);
+    let space = Arc::new(program.space);
+    let id = thread::spawn_user("loaded", space.clone(), program.entry, program.stack, 0);
+
+    let read = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_le_bytes(bytes)
+    };
+    let deadline = time::uptime() + TIMEOUT;
+    while read(DONE) == 0 {
+        if !thread::list().iter().any(|t| t.id == id) {
+            panic!("Program died before it finished");
+        }
+        if time::uptime() > deadline {
+            panic!("Program didn't run");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(read(ARGC), 2);
+    assert_eq!(&read(ARG1).to_le_bytes(), b"argument");
+    assert_eq!(&read(ENV0).to_le_bytes(), b"KEY=valu");
+    assert_eq!(read(POINTER), results.as_u64(), "Relocation not applied");
+    assert_eq!(read(RSP) % 16, 0, "Stack misaligned");
+    space.write(results + GO, &1u64.to_le_bytes()).unwrap();
+
+    let deadline = time::uptime() + TIMEOUT;
+    while thread::list().iter().any(|t| t.id == id) {
+        if time::uptime() > deadline {
+            panic!("Program didn't exit");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    testing::pass()
+}
+
+unsafe fn slice(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+/// An ET_DYN image with `text` at `TEXT_VADDR` and `data` right after it, followed by the
+/// relocation for the pointer in it, and a dynamic section saying where that is.
+fn pie(text: &[u8], data: &[u8], data_vaddr: u64) -> Vec<u8> {
+    let mut data = data.to_vec();
+    let rela_vaddr = data_vaddr + data.len() as u64;
+    data.extend_from_slice(&rela(data_vaddr + POINTER_OFFSET, elf::R_X86_64_RELATIVE, data_vaddr as i64));
+    let dynamic = dynamic(&[(elf::DT_RELA, rela_vaddr), (elf::DT_RELASZ, 24), (elf::DT_RELAENT, 24)]);
+    ElfBuilder::new(elf::ET_DYN, TEXT_VADDR)
+        .segment(elf::PT_LOAD, elf::PF_R | elf::PF_X, TEXT_VADDR, text, text.len() as u64)
+        .segment(elf::PT_LOAD, elf::PF_R | elf::PF_W, data_vaddr, &data, data.len() as u64)
+        .segment(elf::PT_DYNAMIC, elf::PF_R, 0, &dynamic, dynamic.len() as u64)
+        .build()
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,42 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mtos::sync::IrqSpinLock;
+use mtos::*;
+
+entry_point!(test_main);
+
+static A: IrqSpinLock<()> = IrqSpinLock::new(());
+static B: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/* Takes A then B, and later B then A. That never deadlocks on one CPU with interrupts off, but
+ * it's the kind of ordering lockdep should catch before it does. */
+#[cfg(not(test))]
+fn test_main(_boot_info: &'static BootInfo) -> ! {
+    gdt::init();
+    interrupts::init();
+
+    {
+        let _a = A.lock();
+        let _b = B.lock();
+    }
+    {
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+
+    if !sync::lockdep::reported() {
+        panic!("lockdep missed an A -> B -> A inversion");
+    }
+
+    testing::pass()
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
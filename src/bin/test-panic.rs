@@ -13,10 +13,5 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("ok");
-
-    unsafe {
-        exit_qemu();
-    }
-    loop {}
+    testing::pass()
 }
@@ -0,0 +1,324 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::AddressSpace;
+use mtos::process::pipe;
+use mtos::process::signal::SIGPIPE;
+use mtos::process::ExitStatus;
+use mtos::syscall::Error;
+use mtos::user::USER_START;
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* Three pipes, each with a forked child writing to it. The first child writes through the pipe
+ * as its standard output, having moved it there with dup2(); the second writes more than a pipe
+ * holds, so has to wait for the parent to read; the third writes with the reading end closed, and
+ * gets SIGPIPE. Then the parent ignores SIGPIPE and tries that itself, and tries some bad dup2()s.
+ * Results go in the page at rdi (r12 from then on); r13 and r14 hold each pipe's ends. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_pipe, "a"
+.global test_pipe_main
+test_pipe_main:
+    mov r12, rdi
+    mov eax, 17                     // pipe(fds)
+    lea rdi, [r12 + 512]
+    syscall
+    mov [r12], rax
+    mov r13d, [r12 + 512]
+    mov r14d, [r12 + 516]
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_pipe_hello_writer
+    mov rbx, rax
+    mov eax, 12                     // close(write end)
+    mov rdi, r14
+    syscall
+    xor r15d, r15d
+test_pipe_read_hello:
+    mov eax, 11                     // read(read end, buf + total, 64), to the end
+    mov rdi, r13
+    lea rsi, [r12 + r15 + 1024]
+    mov edx, 64
+    syscall
+    test rax, rax
+    jle test_pipe_read_hello_done
+    add r15, rax
+    jmp test_pipe_read_hello
+test_pipe_read_hello_done:
+    mov [r12 + 8], r15
+    mov [r12 + 64], rax
+    mov eax, 12                     // close(read end)
+    mov rdi, r13
+    syscall
+    mov eax, 8                      // waitpid(child, &status, 0)
+    mov rdi, rbx
+    lea rsi, [r12 + 16]
+    xor edx, edx
+    syscall
+
+    mov eax, 17                     // pipe(fds)
+    lea rdi, [r12 + 512]
+    syscall
+    mov r13d, [r12 + 512]
+    mov r14d, [r12 + 516]
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_pipe_big_writer
+    mov rbx, rax
+    mov eax, 12                     // close(write end)
+    mov rdi, r14
+    syscall
+    xor r15d, r15d
+test_pipe_read_big:
+    mov eax, 11                     // read(read end, buf, 512), to the end
+    mov rdi, r13
+    lea rsi, [r12 + 2048]
+    mov edx, 512
+    syscall
+    test rax, rax
+    jle test_pipe_read_big_done
+    add r15, rax
+    jmp test_pipe_read_big
+test_pipe_read_big_done:
+    mov [r12 + 40], r15
+    mov eax, 12                     // close(read end)
+    mov rdi, r13
+    syscall
+    mov eax, 8                      // waitpid(child, &status, 0)
+    mov rdi, rbx
+    lea rsi, [r12 + 48]
+    xor edx, edx
+    syscall
+
+    mov eax, 17                     // pipe(fds)
+    lea rdi, [r12 + 512]
+    syscall
+    mov r13d, [r12 + 512]
+    mov r14d, [r12 + 516]
+    mov eax, 12                     // close(read end)
+    mov rdi, r13
+    syscall
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_pipe_broken_writer
+    mov rdi, rax                    // waitpid(child, &status, 0)
+    mov eax, 8
+    lea rsi, [r12 + 32]
+    xor edx, edx
+    syscall
+    mov qword ptr [r12 + 520], 1    // sigaction(SIGPIPE, {SIG_IGN}, NULL)
+    mov eax, 13
+    mov edi, 13
+    lea rsi, [r12 + 520]
+    xor edx, edx
+    syscall
+    mov eax, 1                      // write(write end, buf, 1)
+    mov rdi, r14
+    mov rsi, r12
+    mov edx, 1
+    syscall
+    mov [r12 + 24], rax
+    mov eax, 12                     // close(write end)
+    mov rdi, r14
+    syscall
+
+    mov eax, 18                     // dup2(99, 1)
+    mov edi, 99
+    mov esi, 1
+    syscall
+    mov [r12 + 56], rax
+    mov eax, 18                     // dup2(1, 64)
+    mov edi, 1
+    mov esi, 64
+    syscall
+    mov [r12 + 72], rax
+    mov eax, 18                     // dup2(1, 1)
+    mov edi, 1
+    mov esi, 1
+    syscall
+    mov [r12 + 80], rax
+
+    mov qword ptr [r12 + 88], 1
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_pipe_hello_writer:
+    mov eax, 12                     // close(read end)
+    mov rdi, r13
+    syscall
+    mov eax, 18                     // dup2(write end, 1)
+    mov rdi, r14
+    mov esi, 1
+    syscall
+    mov rbx, rax
+    mov eax, 12                     // close(write end)
+    mov rdi, r14
+    syscall
+    mov eax, 1                      // write(1, "hello", 5)
+    mov edi, 1
+    lea rsi, [rip + test_pipe_hello]
+    mov edx, 5
+    syscall
+    cmp rbx, 1
+    jne test_pipe_fail
+    cmp rax, 5
+    jne test_pipe_fail
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_pipe_big_writer:
+    mov eax, 12                     // close(read end)
+    mov rdi, r13
+    syscall
+    mov eax, 1                      // write(write end, code page onwards, 10000)
+    mov rdi, r14
+    lea rsi, [r12 - 4096]
+    mov edx, 10000
+    syscall
+    cmp rax, 10000
+    jne test_pipe_fail
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_pipe_broken_writer:
+    mov eax, 1                      // write(write end, buf, 1), which should kill it
+    mov rdi, r14
+    mov rsi, r12
+    mov edx, 1
+    syscall
+
+test_pipe_fail:
+    mov eax, 0                      // exit(1)
+    mov edi, 1
+    syscall
+
+test_pipe_hello:
+    .ascii "hello"
+.global test_pipe_main_end
+test_pipe_main_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_pipe_main: u8;
+    static test_pipe_main_end: u8;
+}
+
+/* Offsets in the results page. */
+const PIPE: u64 = 0;
+const HELLO_LEN: u64 = 8;
+const HELLO_STATUS: u64 = 16;
+const IGNORED_WRITE: u64 = 24;
+const BROKEN_STATUS: u64 = 32;
+const BIG_LEN: u64 = 40;
+const BIG_STATUS: u64 = 48;
+const DUP2_BAD_OLD: u64 = 56;
+const HELLO_EOF: u64 = 64;
+const DUP2_BAD_NEW: u64 = 72;
+const DUP2_SAME: u64 = 80;
+const DONE: u64 = 88;
+const HELLO: u64 = 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let code = unsafe { slice(&test_pipe_main, &test_pipe_main_end) };
+    let base = VirtAddr::new(USER_START);
+    let results = base + 0x1000u64;
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(results, 2, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    process::start("pipes", space.clone(), base, base + 0x3000u64, results.as_u64());
+
+    let deadline = time::uptime() + TIMEOUT;
+    while !process::list().is_empty() {
+        if time::uptime() > deadline {
+            process::dump();
+            panic!("processes didn't finish");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let peek = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    let status = |offset: u64| peek(offset) as u32 as u64;
+    let errno = |e: Error| (e as u64).wrapping_neg();
+    check(peek(DONE), 1, "parent finished");
+    check(peek(PIPE), 0, "pipe");
+
+    let mut hello = [0; 5];
+    space.read(results + HELLO, &mut hello).unwrap();
+    if &hello != b"hello" {
+        panic!("read {:?} through the pipe", hello);
+    }
+    check(peek(HELLO_LEN), 5, "bytes through stdout");
+    check(peek(HELLO_EOF), 0, "read at the end");
+    check(status(HELLO_STATUS), ExitStatus::Exited(0).wait_status(), "stdout writer's status");
+
+    check(peek(BIG_LEN), 10000, "bytes through a full pipe");
+    check(status(BIG_STATUS), ExitStatus::Exited(0).wait_status(), "big writer's status");
+
+    check(status(BROKEN_STATUS), ExitStatus::Killed(SIGPIPE).wait_status(), "broken writer's status");
+    check(peek(IGNORED_WRITE), errno(Error::BrokenPipe), "write with SIGPIPE ignored");
+
+    check(peek(DUP2_BAD_OLD), errno(Error::BadFd), "dup2 from a closed descriptor");
+    check(peek(DUP2_BAD_NEW), errno(Error::BadFd), "dup2 past the last descriptor");
+    check(peek(DUP2_SAME), 1, "dup2 to itself");
+
+    /* The processes' pipes have all gone, frames and all, so there can be MAX_PIPES more, but no
+     * more than that. */
+    let frames = memory::free_frames();
+    let mut pipes = Vec::new();
+    for _ in 0..pipe::MAX_PIPES {
+        pipes.push(pipe::pipe().expect("Can't make a pipe"));
+    }
+    if pipe::pipe().err() != Some(Error::FileTableFull) {
+        panic!("made more than MAX_PIPES pipes");
+    }
+    drop(pipes);
+    check(memory::free_frames() as u64, frames as u64, "free frames after closing the pipes");
+
+    testing::pass()
+}
+
+fn check(got: u64, expected: u64, what: &str) {
+    if got != expected {
+        panic!("{}: got {:#x}, expected {:#x}", what, got, expected);
+    }
+}
+
+unsafe fn slice(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
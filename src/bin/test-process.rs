@@ -0,0 +1,313 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::{self, AddressSpace};
+use mtos::loader::elf;
+use mtos::process::fd::FileOps;
+use mtos::process::signal::SIGSEGV;
+use mtos::process::ExitStatus;
+use mtos::syscall::Error;
+use mtos::testing::ElfBuilder;
+use mtos::user::USER_START;
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* The parent forks four children, one at a time, recording what it sees in the page at rdi (r12
+ * from then on). The first checks it's a copy and exits 42; the second execs the program below;
+ * the third sleeps so the parent can try waiting without blocking; the fourth is killed. The
+ * children can only report through their exit statuses, as their memory is their own. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_process, "a"
+.global test_process_parent
+test_process_parent:
+    mov r12, rdi
+    mov eax, 9                      // getpid()
+    syscall
+    mov [r12], rax
+    mov r13, rax
+    mov qword ptr [r12 + 32], 7
+
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_process_child1
+    mov [r12 + 8], rax
+    mov eax, 8                      // waitpid(-1, &status, 0)
+    mov rdi, -1
+    lea rsi, [r12 + 16]
+    xor edx, edx
+    syscall
+    mov [r12 + 24], rax
+
+    lea rax, [rip + test_process_arg0]
+    mov [r12 + 256], rax
+    lea rax, [rip + test_process_arg1]
+    mov [r12 + 264], rax
+    lea rax, [rip + test_process_arg2]
+    mov [r12 + 272], rax
+    lea rax, [rip + test_process_env0]
+    mov [r12 + 288], rax
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_process_child2
+    mov [r12 + 120], rax
+    mov rdi, rax                    // waitpid(child, &status, 0)
+    mov eax, 8
+    lea rsi, [r12 + 48]
+    xor edx, edx
+    syscall
+    mov [r12 + 56], rax
+
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_process_child3
+    mov [r12 + 88], rax
+    mov rbx, rax
+    mov rdi, rbx                    // waitpid(child, &status, WNOHANG)
+    mov eax, 8
+    lea rsi, [r12 + 72]
+    mov edx, 1
+    syscall
+    mov [r12 + 64], rax
+    mov rdi, rbx                    // waitpid(child, &status, 0)
+    mov eax, 8
+    lea rsi, [r12 + 72]
+    xor edx, edx
+    syscall
+    mov [r12 + 80], rax
+
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_process_child4
+    mov [r12 + 128], rax
+    mov eax, 8                      // waitpid(-1, &status, 0)
+    mov rdi, -1
+    lea rsi, [r12 + 96]
+    xor edx, edx
+    syscall
+    mov [r12 + 104], rax
+
+    mov eax, 8                      // waitpid(-1, NULL, 0), with no children left
+    mov rdi, -1
+    xor esi, esi
+    xor edx, edx
+    syscall
+    mov [r12 + 40], rax
+
+    mov qword ptr [r12 + 112], 1
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_process_child1:
+    mov eax, 10                     // getppid()
+    syscall
+    cmp rax, r13
+    jne test_process_fail
+    cmp qword ptr [r12 + 32], 7
+    jne test_process_fail
+    mov qword ptr [r12 + 32], 99
+    mov eax, 0                      // exit(42)
+    mov edi, 42
+    syscall
+
+test_process_child2:
+    mov eax, 7                      // exec("/nope", argv, envp)
+    lea rdi, [rip + test_process_nope]
+    lea rsi, [r12 + 256]
+    lea rdx, [r12 + 288]
+    syscall
+    cmp rax, -2
+    jne test_process_fail
+    mov eax, 7                      // exec("/bin/args", argv, envp)
+    lea rdi, [rip + test_process_path]
+    lea rsi, [r12 + 256]
+    lea rdx, [r12 + 288]
+    syscall
+    jmp test_process_fail
+
+test_process_child3:
+    mov eax, 3                      // sleep(50)
+    mov edi, 50
+    syscall
+    mov eax, 0                      // exit(5)
+    mov edi, 5
+    syscall
+
+test_process_child4:
+    hlt
+
+test_process_fail:
+    mov eax, 0                      // exit(1)
+    mov edi, 1
+    syscall
+
+test_process_nope:
+    .asciz "/nope"
+test_process_path:
+    .asciz "/bin/args"
+test_process_arg0:
+    .asciz "args"
+test_process_arg1:
+    .asciz "a"
+test_process_arg2:
+    .asciz "b"
+test_process_env0:
+    .asciz "K=v"
+.global test_process_parent_end
+test_process_parent_end:
+
+.global test_process_args
+test_process_args:
+    mov rdi, [rsp]                  // exit(argc, plus 100 if envp[0] starts with K)
+    mov rax, [rsp + 8 * rdi + 16]
+    cmp byte ptr [rax], 'K'
+    jne test_process_args_exit
+    add rdi, 100
+test_process_args_exit:
+    mov eax, 0
+    syscall
+.global test_process_args_end
+test_process_args_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_process_parent: u8;
+    static test_process_parent_end: u8;
+    static test_process_args: u8;
+    static test_process_args_end: u8;
+}
+
+/* Offsets in the results page. */
+const PID: u64 = 0;
+const CHILD1: u64 = 8;
+const STATUS1: u64 = 16;
+const WAIT1: u64 = 24;
+const MEMORY: u64 = 32;
+const NO_CHILD: u64 = 40;
+const STATUS2: u64 = 48;
+const WAIT2: u64 = 56;
+const NO_HANG: u64 = 64;
+const STATUS3: u64 = 72;
+const WAIT3: u64 = 80;
+const CHILD3: u64 = 88;
+const STATUS4: u64 = 96;
+const WAIT4: u64 = 104;
+const DONE: u64 = 112;
+const CHILD2: u64 = 120;
+const CHILD4: u64 = 128;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    /* exec() reads the program from the filesystem. */
+    let root = vfs::ramfs::RamFs::new(64, 8192).expect("Can't make a ramfs");
+    vfs::mount("/", root).expect("Can't mount the ramfs");
+    vfs::mkdir("/bin", 0o755).unwrap();
+    let args = unsafe { slice(&test_process_args, &test_process_args_end) };
+    let image = elf_image(args);
+    let file = vfs::open("/bin/args", vfs::O_CREAT | vfs::O_WRONLY, 0o755).unwrap();
+    if file.write(&image) != Ok(image.len()) {
+        panic!("can't write /bin/args");
+    }
+    drop((file, image));
+
+    let code = unsafe { slice(&test_process_parent, &test_process_parent_end) };
+    let base = VirtAddr::new(USER_START);
+    let results = base + 0x1000u64;
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(results, 2, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    let pid = process::start("parent", space.clone(), base, base + 0x3000u64, results.as_u64());
+
+    /* It has no parent, so it's reaped when it exits, and its children with it. */
+    let deadline = time::uptime() + TIMEOUT;
+    while !process::list().is_empty() {
+        if time::uptime() > deadline {
+            process::dump();
+            panic!("processes didn't finish");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let peek = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    let status = |offset: u64| peek(offset) as u32 as u64;
+    check(peek(DONE), 1, "parent finished");
+    check(peek(PID), pid.as_u64(), "getpid");
+    let children = [peek(CHILD1), peek(CHILD2), peek(CHILD3), peek(CHILD4)];
+    for (i, &child) in children.iter().enumerate() {
+        if child == 0 || child == pid.as_u64() || children[..i].contains(&child) {
+            panic!("fork gave pids {:?}, parent {}", children, pid);
+        }
+    }
+
+    check(peek(WAIT1), peek(CHILD1), "waitpid for any child");
+    check(status(STATUS1), ExitStatus::Exited(42).wait_status(), "copied child's status");
+    check(peek(MEMORY), 7, "parent memory after child wrote its copy");
+    check(peek(WAIT2), peek(CHILD2), "waitpid for exec'd child");
+    check(status(STATUS2), ExitStatus::Exited(103).wait_status(), "exec'd child's status");
+    check(peek(NO_HANG), 0, "waitpid with WNOHANG");
+    check(peek(WAIT3), peek(CHILD3), "waitpid for sleeping child");
+    check(status(STATUS3), ExitStatus::Exited(5).wait_status(), "sleeping child's status");
+    check(peek(WAIT4), peek(CHILD4), "waitpid for killed child");
+    check(status(STATUS4), ExitStatus::Killed(SIGSEGV).wait_status(), "killed child's status");
+    check(peek(NO_CHILD), (Error::NoChild as u64).wrapping_neg(), "waitpid with no children");
+
+    /* Forking shared the pages, and writing to them after made copies; with the children gone,
+     * the parent's are its own again. */
+    let cow = address_space::cow_stats();
+    if cow.faults == 0 || cow.copies == 0 {
+        panic!("no copy-on-write: {:?}", cow);
+    }
+    check(cow.pages_saved as u64, 0, "pages still shared");
+
+    testing::pass()
+}
+
+fn check(got: u64, expected: u64, what: &str) {
+    if got != expected {
+        panic!("{}: got {:#x}, expected {:#x}", what, got, expected);
+    }
+}
+
+unsafe fn slice(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+/// A PIE with just `text`, at 0x1000, which is where it starts.
+fn elf_image(text: &[u8]) -> Vec<u8> {
+    ElfBuilder::new(elf::ET_DYN, 0x1000)
+        .segment(elf::PT_LOAD, elf::PF_R | elf::PF_X, 0x1000, text, text.len() as u64)
+        .build()
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
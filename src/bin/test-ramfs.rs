@@ -0,0 +1,131 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mtos::process::fd::FileOps;
+use mtos::syscall::Error;
+use mtos::vfs::{self, ramfs::RamFs, FileType};
+use mtos::*;
+
+entry_point!(test_main);
+
+const MAX_PAGES: u64 = 4;
+const MAX_HEAP: usize = 4096;
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let fs = RamFs::new(MAX_PAGES, MAX_HEAP).expect("Can't make a ramfs");
+    vfs::mount("/", fs.clone()).expect("Can't mount the ramfs");
+    let empty = fs.usage();
+
+    /* A sparse file: a page at the start, and one three pages on. */
+    let file = vfs::open("/file", vfs::O_CREAT | vfs::O_RDWR, 0o644).expect("Can't create /file");
+    check(file.write(b"hello"), 5, "write");
+    check(file.seek(3 * 4096 + 10, vfs::SEEK_SET).map(|o| o as usize), 3 * 4096 + 10, "seek past the end");
+    check(file.write(b"x"), 1, "write past the end");
+    check(vfs::stat("/file").map(|s| s.size as usize), 3 * 4096 + 11, "size");
+    check(Ok(fs.usage().pages as usize), 2, "pages of a sparse file");
+    let mut buf = [0xff; 16];
+    check(file.seek(4096 - 8, vfs::SEEK_SET).map(|o| o as usize), 4096 - 8, "seek to the gap");
+    check(file.read(&mut buf), 16, "read across the gap");
+    if buf.iter().any(|&b| b != 0) {
+        panic!("gap read as {:?}", buf);
+    }
+    check(file.seek(3 * 4096 + 8, vfs::SEEK_SET).map(|o| o as usize), 3 * 4096 + 8, "seek to the end");
+    check(file.read(&mut buf), 3, "read up to the end");
+    if &buf[..3] != b"\0\0x" {
+        panic!("read {:?} at the end", &buf[..3]);
+    }
+
+    /* Shrinking frees pages, and what was cut off reads as zeroes if it grows again. */
+    check(file.truncate(3).map(|_| 0), 0, "shrink");
+    check(Ok(fs.usage().pages as usize), 1, "pages after shrinking");
+    check(file.truncate(8).map(|_| 0), 0, "grow");
+    check(file.seek(0, vfs::SEEK_SET).map(|o| o as usize), 0, "seek to the start");
+    check(file.read(&mut buf), 8, "read after growing");
+    if &buf[..8] != b"hel\0\0\0\0\0" {
+        panic!("read {:?} after growing", &buf[..8]);
+    }
+
+    /* Running out of pages: as much of a write as fits, then none. */
+    let big = [0x55; 4 * 4096];
+    check(file.seek(4096, vfs::SEEK_SET).map(|o| o as usize), 4096, "seek to the second page");
+    check(file.write(&big), 3 * 4096, "write that fills the filesystem");
+    check_error(file.write(b"x").map(|_| ()), Error::NoSpace, "write to a full filesystem");
+    check(Ok(fs.usage().pages as usize), MAX_PAGES as usize, "pages when full");
+
+    /* Directories and symlinks. */
+    vfs::mkdir("/dir", 0o755).expect("Can't mkdir /dir");
+    vfs::symlink("../file", "/dir/link").expect("Can't make /dir/link");
+    let linked = vfs::stat("/dir/link").expect("Can't stat through /dir/link");
+    if linked != vfs::stat("/file").unwrap() {
+        panic!("/dir/link is {:?}", linked);
+    }
+    let dir = vfs::stat("/dir").unwrap();
+    if dir.kind != FileType::Directory || dir.nlink != 2 || dir.size != 1 {
+        panic!("/dir is {:?}", dir);
+    }
+    check(vfs::stat("/").map(|s| s.nlink as usize), 3, "links to / with a subdirectory");
+    check_error(vfs::mkdir("/dir", 0o755), Error::Exists, "mkdir over a directory");
+    check_error(vfs::rmdir("/dir"), Error::NotEmpty, "rmdir of a directory with something in it");
+    check_error(vfs::open("/dir/link/x", vfs::O_CREAT, 0).map(|_| ()), Error::NotDirectory, "create in a file");
+    vfs::unlink("/dir/link").expect("Can't unlink /dir/link");
+    vfs::rmdir("/dir").expect("Can't rmdir /dir");
+    check_error(vfs::stat("/dir").map(|_| ()), Error::NoEntry, "stat after rmdir");
+
+    /* An unlinked file keeps its pages until it's closed. */
+    vfs::unlink("/file").expect("Can't unlink /file");
+    check_error(vfs::stat("/file").map(|_| ()), Error::NoEntry, "stat after unlink");
+    check(Ok(fs.usage().pages as usize), MAX_PAGES as usize, "pages of an unlinked, open file");
+    drop(file);
+    check(Ok(fs.usage().pages as usize), 0, "pages after closing an unlinked file");
+
+    /* Running out of heap. */
+    let mut made = 0;
+    loop {
+        match vfs::open(&format!("/{:032}", made), vfs::O_CREAT, 0o644) {
+            Ok(_) => made += 1,
+            Err(Error::NoSpace) => break,
+            Err(e) => panic!("creating file {}: {:?}", made, e),
+        }
+        if made > MAX_HEAP / 32 {
+            panic!("made {} files without running out of heap", made);
+        }
+    }
+    if made == 0 {
+        panic!("no room for any files");
+    }
+    for i in 0..made {
+        vfs::unlink(&format!("/{:032}", i)).expect("Can't unlink");
+    }
+    if fs.usage() != empty {
+        panic!("usage is {:?} with everything removed, {:?} to start with", fs.usage(), empty);
+    }
+
+    testing::pass()
+}
+
+fn check(got: Result<usize, Error>, expected: usize, what: &str) {
+    if got != Ok(expected) {
+        panic!("{}: got {:?}, expected {}", what, got, expected);
+    }
+}
+
+fn check_error(got: Result<(), Error>, expected: Error, what: &str) {
+    if got != Err(expected) {
+        panic!("{}: got {:?}, expected {:?}", what, got, expected);
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,325 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::AddressSpace;
+use mtos::process::signal::SIGSEGV;
+use mtos::process::ExitStatus;
+use mtos::syscall::Error;
+use mtos::user::{MapError, USER_START};
+use mtos::*;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* A two-page shared memory object (descriptor in r14), mapped read-write at USER_START + 0x10000
+ * (r13), then a fork. The child checks it sees what the parent wrote before, writes through the
+ * mapping it inherited, maps the second page again read-only at USER_START + 0x20000 (r15), and
+ * reads back through that what it wrote through the first. A second child writes to a read-only
+ * mapping, and should get SIGSEGV. Then the parent tries some bad mmap()s, grows the object and
+ * maps the new page, closes the descriptor and unmaps. Results go in the page at rdi (r12 from
+ * then on). */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_shm, "a"
+.global test_shm_main
+test_shm_main:
+    mov r12, rdi
+    lea r13, [r12 + 0xf000]
+    lea r15, [r12 + 0x1f000]
+    mov eax, 26                     // shm_create(8192)
+    mov edi, 8192
+    syscall
+    mov [r12], rax
+    mov r14, rax
+    mov eax, 28                     // mmap(r13, 8192, PROT_READ | PROT_WRITE, fd, 0)
+    mov rdi, r13
+    mov esi, 8192
+    mov edx, 3
+    mov r10, r14
+    xor r8d, r8d
+    syscall
+    mov [r12 + 8], rax
+    mov qword ptr [r13 + 8], 0xabc
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_shm_child
+    mov rdi, rax                    // waitpid(child, &status, 0)
+    mov eax, 8
+    lea rsi, [r12 + 16]
+    xor edx, edx
+    syscall
+    mov rax, [r13]
+    mov [r12 + 24], rax
+    mov rax, [r13 + 16]
+    mov [r12 + 32], rax
+
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_shm_read_only_child
+    mov rdi, rax                    // waitpid(child, &status, 0)
+    mov eax, 8
+    lea rsi, [r12 + 128]
+    xor edx, edx
+    syscall
+
+    mov eax, 28                     // mmap(r13 + 1, 4096, PROT_READ | PROT_WRITE, fd, 0)
+    lea rdi, [r13 + 1]
+    mov esi, 4096
+    mov edx, 3
+    mov r10, r14
+    xor r8d, r8d
+    syscall
+    mov [r12 + 40], rax
+    mov eax, 28                     // mmap(r15, 4096, PROT_READ | PROT_WRITE, fd, 8192)
+    mov rdi, r15
+    mov esi, 4096
+    mov edx, 3
+    mov r10, r14
+    mov r8d, 8192
+    syscall
+    mov [r12 + 48], rax
+    mov eax, 28                     // mmap(r13, 4096, PROT_READ | PROT_WRITE, fd, 0)
+    mov rdi, r13
+    mov esi, 4096
+    mov edx, 3
+    mov r10, r14
+    xor r8d, r8d
+    syscall
+    mov [r12 + 56], rax
+    mov eax, 28                     // mmap(r15, 4096, PROT_READ | PROT_WRITE, 1, 0)
+    mov rdi, r15
+    mov esi, 4096
+    mov edx, 3
+    mov r10d, 1
+    xor r8d, r8d
+    syscall
+    mov [r12 + 64], rax
+
+    mov eax, 27                     // ftruncate(fd, 12288)
+    mov rdi, r14
+    mov esi, 12288
+    syscall
+    mov [r12 + 72], rax
+    mov eax, 28                     // mmap(r13 + 0x20000, 4096, PROT_READ | PROT_WRITE, fd, 8192)
+    lea rdi, [r13 + 0x20000]
+    mov esi, 4096
+    mov edx, 3
+    mov r10, r14
+    mov r8d, 8192
+    syscall
+    mov [r12 + 80], rax
+    mov rax, [r13 + 0x20000]
+    mov [r12 + 88], rax
+    mov qword ptr [r13 + 0x20000], 0x3333
+
+    mov eax, 12                     // close(fd)
+    mov rdi, r14
+    syscall
+    mov [r12 + 96], rax
+    mov qword ptr [r13 + 4096 + 8], 0x4444
+    mov rax, [r13 + 4096 + 8]
+    mov [r12 + 136], rax
+    mov eax, 29                     // munmap(r13, 8192)
+    mov rdi, r13
+    mov esi, 8192
+    syscall
+    mov [r12 + 104], rax
+    mov eax, 28                     // mmap(r13, 4096, PROT_READ | PROT_WRITE, fd, 0)
+    mov rdi, r13
+    mov esi, 4096
+    mov edx, 3
+    mov r10, r14
+    xor r8d, r8d
+    syscall
+    mov [r12 + 112], rax
+
+    mov qword ptr [r12 + 120], 1
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_shm_child:
+    cmp qword ptr [r13 + 8], 0xabc
+    jne test_shm_fail
+    mov qword ptr [r13], 0x1111
+    mov eax, 28                     // mmap(r15, 4096, PROT_READ, fd, 4096)
+    mov rdi, r15
+    mov esi, 4096
+    mov edx, 1
+    mov r10, r14
+    mov r8d, 4096
+    syscall
+    cmp rax, r15
+    jne test_shm_fail
+    mov qword ptr [r13 + 4096], 0x2222
+    mov rax, [r15]
+    mov [r13 + 16], rax
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_shm_read_only_child:
+    mov eax, 28                     // mmap(r15, 4096, PROT_READ, fd, 0)
+    mov rdi, r15
+    mov esi, 4096
+    mov edx, 1
+    mov r10, r14
+    xor r8d, r8d
+    syscall
+    cmp rax, r15
+    jne test_shm_fail
+    mov qword ptr [r15], 1          // which should kill it
+
+test_shm_fail:
+    mov eax, 0                      // exit(1)
+    mov edi, 1
+    syscall
+.global test_shm_main_end
+test_shm_main_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_shm_main: u8;
+    static test_shm_main_end: u8;
+}
+
+/* Offsets in the results page. */
+const FD: u64 = 0;
+const MMAP: u64 = 8;
+const CHILD_STATUS: u64 = 16;
+const CHILD_WRITE: u64 = 24;
+const CHILD_READ: u64 = 32;
+const MMAP_MISALIGNED: u64 = 40;
+const MMAP_PAST_END: u64 = 48;
+const MMAP_MAPPED: u64 = 56;
+const MMAP_CONSOLE: u64 = 64;
+const FTRUNCATE: u64 = 72;
+const MMAP_GROWN: u64 = 80;
+const GROWN_PAGE: u64 = 88;
+const CLOSE: u64 = 96;
+const MUNMAP: u64 = 104;
+const MMAP_CLOSED: u64 = 112;
+const DONE: u64 = 120;
+const READ_ONLY_STATUS: u64 = 128;
+const AFTER_CLOSE: u64 = 136;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let code = unsafe { slice(&test_shm_main, &test_shm_main_end) };
+    let base = VirtAddr::new(USER_START);
+    let results = base + 0x1000u64;
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(results, 2, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    let saved = memory::frames_saved();
+    process::start("shm", space.clone(), base, base + 0x3000u64, results.as_u64());
+
+    let deadline = time::uptime() + TIMEOUT;
+    while !process::list().is_empty() {
+        if time::uptime() > deadline {
+            process::dump();
+            panic!("processes didn't finish");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let peek = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    let status = |offset: u64| peek(offset) as u32 as u64;
+    let errno = |e: Error| (e as u64).wrapping_neg();
+    let shared = USER_START + 0x10000;
+    check(peek(DONE), 1, "parent finished");
+    check(peek(FD), 3, "shared memory descriptor");
+    check(peek(MMAP), shared, "mmap");
+
+    check(status(CHILD_STATUS), ExitStatus::Exited(0).wait_status(), "child's status");
+    check(peek(CHILD_WRITE), 0x1111, "child's write, seen by the parent");
+    check(peek(CHILD_READ), 0x2222, "child's write, seen through its read-only mapping");
+    let read_only = status(READ_ONLY_STATUS);
+    check(read_only, ExitStatus::Killed(SIGSEGV).wait_status(), "status after writing to a read-only mapping");
+
+    check(peek(MMAP_MISALIGNED), errno(Error::Invalid), "mmap at a misaligned address");
+    check(peek(MMAP_PAST_END), errno(Error::Invalid), "mmap past the end");
+    check(peek(MMAP_MAPPED), errno(Error::Exists), "mmap over a mapping");
+    check(peek(MMAP_CONSOLE), errno(Error::NoDevice), "mmap of the console");
+
+    check(peek(FTRUNCATE), 0, "ftruncate");
+    check(peek(MMAP_GROWN), shared + 0x20000, "mmap of a new page");
+    check(peek(GROWN_PAGE), 0, "new page's contents");
+
+    check(peek(CLOSE), 0, "close");
+    check(peek(AFTER_CLOSE), 0x4444, "write after closing");
+    check(peek(MUNMAP), 0, "munmap");
+    check(peek(MMAP_CLOSED), errno(Error::BadFd), "mmap of a closed descriptor");
+
+    /* The parent's address space still has the grown page, and is all that does now. */
+    let mut bytes = [0; 8];
+    space.read(VirtAddr::new(shared + 0x20000), &mut bytes).unwrap();
+    check(u64::from_ne_bytes(bytes), 0x3333, "grown page, from the kernel");
+    if space.can_access(VirtAddr::new(shared), false) {
+        panic!("shared memory still mapped after munmap");
+    }
+    check(memory::frames_saved() as u64, saved as u64, "frames shared, after everything's exited");
+
+    check_overlap();
+
+    testing::pass()
+}
+
+/* Mapping over the end of a mapping fails without mapping any of the rest, or keeping the frames. */
+#[cfg(not(test))]
+fn check_overlap() {
+    let space = AddressSpace::new().expect("Can't make address space");
+    let base = VirtAddr::new(USER_START);
+    space.map(base + 0x1000u64, 1, true, false).expect("Can't map a page");
+    let free = memory::free_frames();
+    let frames: Vec<PhysFrame> = (0..2).map(|_| *memory::allocate_frame().expect("Out of memory")).collect();
+    if space.map_shared(base, frames, true, false) != Err(MapError::AlreadyMapped) {
+        panic!("map_shared over a mapping didn't fail");
+    }
+    if space.map(base, 2, true, false) != Err(MapError::AlreadyMapped) {
+        panic!("map over a mapping didn't fail");
+    }
+    if space.can_access(base, false) {
+        panic!("part of a failed mapping was left mapped");
+    }
+    check(memory::free_frames() as u64, free as u64, "free frames after failed mappings");
+}
+
+fn check(got: u64, expected: u64, what: &str) {
+    if got != expected {
+        panic!("{}: got {:#x}, expected {:#x}", what, got, expected);
+    }
+}
+
+unsafe fn slice(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,306 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::AddressSpace;
+use mtos::process::signal::{self, SIGTERM, SIGUSR1};
+use mtos::process::ExitStatus;
+use mtos::syscall::Error;
+use mtos::user::USER_START;
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* The program installs handlers, then: signals itself and checks the handler ran with the signal
+ * blocked and that sigreturn() put its registers back; faults, with the SIGSEGV handler moving it
+ * past the faulting instruction; signals itself with the signal blocked, then unblocks it; kills a
+ * child that's spinning in user mode; and stops a child, checks it doesn't exit, then continues
+ * it. Results go in the page at rdi (r12 from then on), and SIGCHLD is counted there too. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_signal, "a"
+.global test_signal_main
+test_signal_main:
+    mov r12, rdi
+    mov edi, 10                     // SIGUSR1
+    lea rsi, [rip + test_signal_usr1]
+    call test_signal_set_handler
+    mov [r12], rax
+    mov edi, 11                     // SIGSEGV
+    lea rsi, [rip + test_signal_segv]
+    call test_signal_set_handler
+    mov edi, 12                     // SIGUSR2
+    lea rsi, [rip + test_signal_usr2]
+    call test_signal_set_handler
+    mov edi, 17                     // SIGCHLD
+    lea rsi, [rip + test_signal_chld]
+    call test_signal_set_handler
+    mov edi, 9                      // SIGKILL, which can't be handled
+    lea rsi, [rip + test_signal_usr1]
+    call test_signal_set_handler
+    mov [r12 + 120], rax
+
+    mov eax, 9                      // getpid()
+    syscall
+    mov r13, rax
+    mov rbx, 0x1234
+    mov eax, 16                     // kill(self, SIGUSR1)
+    mov rdi, r13
+    mov esi, 10
+    syscall
+    mov [r12 + 32], rax
+    mov [r12 + 40], rbx
+
+    lea rax, [rip + test_signal_after_fault]
+    mov [r12 + 504], rax
+    mov qword ptr [0], 1
+test_signal_after_fault:
+    mov qword ptr [r12 + 56], 1
+
+    mov qword ptr [r12 + 512], 0x800
+    mov eax, 14                     // sigprocmask(SIG_BLOCK, {SIGUSR2}, NULL)
+    xor edi, edi
+    lea rsi, [r12 + 512]
+    xor edx, edx
+    syscall
+    mov eax, 16                     // kill(self, SIGUSR2)
+    mov rdi, r13
+    mov esi, 12
+    syscall
+    mov rax, [r12 + 64]
+    mov [r12 + 72], rax
+    mov eax, 14                     // sigprocmask(SIG_UNBLOCK, {SIGUSR2}, NULL)
+    mov edi, 1
+    lea rsi, [r12 + 512]
+    xor edx, edx
+    syscall
+    mov rax, [r12 + 64]
+    mov [r12 + 80], rax
+
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_signal_spin
+    mov rbx, rax
+    mov [r12 + 136], rbx
+    mov eax, 16                     // kill(child, SIGTERM)
+    mov rdi, rbx
+    mov esi, 15
+    syscall
+test_signal_wait_killed:
+    mov eax, 8                      // waitpid(child, &status, 0), again if interrupted
+    mov rdi, rbx
+    lea rsi, [r12 + 88]
+    xor edx, edx
+    syscall
+    cmp rax, -4
+    je test_signal_wait_killed
+    mov [r12 + 128], rax
+
+    mov eax, 6                      // fork()
+    syscall
+    test rax, rax
+    jz test_signal_nap
+    mov rbx, rax
+    mov [r12 + 144], rbx
+    mov eax, 16                     // kill(child, SIGSTOP)
+    mov rdi, rbx
+    mov esi, 19
+    syscall
+    mov eax, 3                      // sleep(100)
+    mov edi, 100
+    syscall
+    mov eax, 8                      // waitpid(child, &status, WNOHANG)
+    mov rdi, rbx
+    lea rsi, [r12 + 96]
+    mov edx, 1
+    syscall
+    mov [r12 + 152], rax
+    mov eax, 16                     // kill(child, SIGCONT)
+    mov rdi, rbx
+    mov esi, 18
+    syscall
+test_signal_wait_stopped:
+    mov eax, 8                      // waitpid(child, &status, 0), again if interrupted
+    mov rdi, rbx
+    lea rsi, [r12 + 104]
+    xor edx, edx
+    syscall
+    cmp rax, -4
+    je test_signal_wait_stopped
+    mov [r12 + 160], rax
+
+    mov qword ptr [r12 + 168], 1
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+
+test_signal_spin:
+    jmp test_signal_spin
+
+test_signal_nap:
+    mov eax, 3                      // sleep(30)
+    mov edi, 30
+    syscall
+    mov eax, 0                      // exit(7)
+    mov edi, 7
+    syscall
+
+test_signal_set_handler:            // sigaction(rdi, {rsi, 0, restorer, 0}, NULL)
+    mov [r12 + 520], rsi
+    mov qword ptr [r12 + 528], 0
+    lea rax, [rip + test_signal_restorer]
+    mov [r12 + 536], rax
+    mov qword ptr [r12 + 544], 0
+    lea rsi, [r12 + 520]
+    xor edx, edx
+    mov eax, 13
+    syscall
+    ret
+
+test_signal_usr1:
+    mov [r12 + 8], rdi
+    add qword ptr [r12 + 16], 1
+    xor ebx, ebx
+    mov eax, 14                     // sigprocmask(SIG_BLOCK, NULL, &old)
+    xor edi, edi
+    xor esi, esi
+    lea rdx, [r12 + 24]
+    syscall
+    ret
+
+test_signal_segv:                   // resume at the address in [r12 + 504]
+    mov [r12 + 48], rdi
+    mov rax, [r12 + 504]
+    mov [rdx + 120], rax
+    ret
+
+test_signal_usr2:
+    add qword ptr [r12 + 64], 1
+    ret
+
+test_signal_chld:
+    add qword ptr [r12 + 112], 1
+    ret
+
+test_signal_restorer:
+    mov eax, 15                     // sigreturn()
+    syscall
+.global test_signal_main_end
+test_signal_main_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_signal_main: u8;
+    static test_signal_main_end: u8;
+}
+
+/* Offsets in the results page. */
+const SIGACTION: u64 = 0;
+const USR1_SIGNAL: u64 = 8;
+const USR1_COUNT: u64 = 16;
+const USR1_BLOCKED: u64 = 24;
+const KILL_RESULT: u64 = 32;
+const RBX: u64 = 40;
+const SEGV_SIGNAL: u64 = 48;
+const AFTER_FAULT: u64 = 56;
+const USR2_WHILE_BLOCKED: u64 = 72;
+const USR2_UNBLOCKED: u64 = 80;
+const KILLED_STATUS: u64 = 88;
+const STOPPED_STATUS: u64 = 104;
+const CHLD_COUNT: u64 = 112;
+const SIGACTION_KILL: u64 = 120;
+const KILLED_WAIT: u64 = 128;
+const KILLED_CHILD: u64 = 136;
+const STOPPED_CHILD: u64 = 144;
+const STOPPED_NO_HANG: u64 = 152;
+const STOPPED_WAIT: u64 = 160;
+const DONE: u64 = 168;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let code = unsafe { slice(&test_signal_main, &test_signal_main_end) };
+    let base = VirtAddr::new(USER_START);
+    let results = base + 0x1000u64;
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(results, 2, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    process::start("signals", space.clone(), base, base + 0x3000u64, results.as_u64());
+
+    let deadline = time::uptime() + TIMEOUT;
+    while !process::list().is_empty() {
+        if time::uptime() > deadline {
+            process::dump();
+            panic!("processes didn't finish");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let peek = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    let status = |offset: u64| peek(offset) as u32 as u64;
+    let errno = |e: Error| (e as u64).wrapping_neg();
+    check(peek(DONE), 1, "program finished");
+    check(peek(SIGACTION), 0, "sigaction");
+    check(peek(SIGACTION_KILL), errno(Error::Invalid), "sigaction for SIGKILL");
+
+    check(peek(USR1_COUNT), 1, "handler calls");
+    check(peek(USR1_SIGNAL), u64::from(SIGUSR1), "handler's argument");
+    check(peek(USR1_BLOCKED) & signal::bit(SIGUSR1), signal::bit(SIGUSR1), "signal blocked in its handler");
+    check(peek(KILL_RESULT), 0, "kill's result after the handler");
+    check(peek(RBX), 0x1234, "rbx after the handler");
+
+    check(peek(SEGV_SIGNAL), u64::from(signal::SIGSEGV), "fault handler's argument");
+    check(peek(AFTER_FAULT), 1, "carried on after the fault");
+
+    check(peek(USR2_WHILE_BLOCKED), 0, "handler calls while blocked");
+    check(peek(USR2_UNBLOCKED), 1, "handler calls once unblocked");
+
+    check(peek(KILLED_WAIT), peek(KILLED_CHILD), "waitpid for killed child");
+    check(status(KILLED_STATUS), ExitStatus::Killed(SIGTERM).wait_status(), "killed child's status");
+
+    check(peek(STOPPED_NO_HANG), 0, "waitpid for stopped child");
+    check(peek(STOPPED_WAIT), peek(STOPPED_CHILD), "waitpid for continued child");
+    check(status(STOPPED_STATUS), ExitStatus::Exited(7).wait_status(), "continued child's status");
+
+    if peek(CHLD_COUNT) == 0 {
+        panic!("no SIGCHLD");
+    }
+
+    testing::pass()
+}
+
+fn check(got: u64, expected: u64, what: &str) {
+    if got != expected {
+        panic!("{}: got {:#x}, expected {:#x}", what, got, expected);
+    }
+}
+
+unsafe fn slice(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
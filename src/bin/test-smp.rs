@@ -0,0 +1,212 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use alloc::{sync::Arc, vec::Vec};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use mtos::*;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* Run with `make test-smp` for several CPUs; on one it checks the MADT parsing still copes. */
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    smp::init();
+    let found = smp::cpu_count();
+    let online = smp::online_count();
+    serial_println!("{} CPUs found, {} online", found, online);
+    if online != found {
+        panic!("only {} of {} CPUs came up", online, found);
+    }
+    if smp::current_cpu() != 0 {
+        panic!("boot CPU isn't CPU 0");
+    }
+    /* Each CPU should have set up its own per-CPU block, and be running its own idle thread. */
+    let mut idle_threads = Vec::new();
+    for cpu in 0..online {
+        let block = percpu::of(cpu).unwrap_or_else(|| panic!("CPU {} has no per-CPU block", cpu));
+        if block.cpu.load(Ordering::Relaxed) != cpu {
+            panic!("CPU {}'s block says it's CPU {}", cpu, block.cpu.load(Ordering::Relaxed));
+        }
+        let running = block.current_thread.load(Ordering::Relaxed);
+        if cpu > 0 {
+            if idle_threads.contains(&running) {
+                panic!("CPU {} is running another CPU's thread", cpu);
+            }
+            idle_threads.push(running);
+        }
+    }
+
+    let calls = AtomicUsize::new(0);
+    ipi::call(ipi::Target::Others, || {
+        calls.fetch_add(1, Ordering::SeqCst);
+    });
+    if calls.load(Ordering::SeqCst) != online - 1 {
+        panic!("call ran on {} of {} other CPUs", calls.load(Ordering::SeqCst), online - 1);
+    }
+
+    check_shootdown();
+
+    /* An idle CPU needs the reschedule IPI to notice a thread queued for it. */
+    if online > 1 {
+        let cpu = online - 1;
+        let ran_on = Arc::new(AtomicUsize::new(usize::MAX));
+        let r = ran_on.clone();
+        thread::spawn_on(cpu, "remote", move || r.store(smp::current_cpu(), Ordering::SeqCst))
+            .unwrap_or_else(|| panic!("CPU {} has no run queue", cpu));
+        let deadline = time::uptime() + Duration::from_secs(1);
+        while ran_on.load(Ordering::SeqCst) == usize::MAX {
+            if time::uptime() > deadline {
+                panic!("thread queued on CPU {} never ran", cpu);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        if ran_on.load(Ordering::SeqCst) != cpu {
+            panic!("thread for CPU {} ran on CPU {}", cpu, ran_on.load(Ordering::SeqCst));
+        }
+
+        check_ap_preemption(cpu);
+        check_stealing();
+        check_yield_storm(online);
+    }
+
+    testing::pass()
+}
+
+/* Two threads that never yield, both kept on an AP: the second only runs if the AP's own timer
+ * preempts the first. */
+#[cfg(not(test))]
+fn check_ap_preemption(cpu: usize) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let spins = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+    for i in 0..2 {
+        let (stop, spins) = (stop.clone(), spins.clone());
+        thread::spawn_on(cpu, "spin", move || {
+            while !stop.load(Ordering::SeqCst) {
+                spins[i].fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .unwrap_or_else(|| panic!("CPU {} has no run queue", cpu));
+    }
+    let deadline = time::uptime() + Duration::from_secs(1);
+    while spins.iter().any(|s| s.load(Ordering::SeqCst) == 0) {
+        if time::uptime() > deadline {
+            stop.store(true, Ordering::SeqCst);
+            panic!("a thread on CPU {} was never preempted", cpu);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    stop.store(true, Ordering::SeqCst);
+}
+
+/* Two busy threads started here should spread to the idle CPUs, once they've run here. */
+#[cfg(not(test))]
+fn check_stealing() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let moved = Arc::new(AtomicBool::new(false));
+    for _ in 0..2 {
+        let (stop, moved) = (stop.clone(), moved.clone());
+        thread::spawn("busy", move || {
+            while !stop.load(Ordering::SeqCst) {
+                if smp::current_cpu() != 0 {
+                    moved.store(true, Ordering::SeqCst);
+                }
+            }
+        });
+    }
+    let deadline = time::uptime() + Duration::from_secs(1);
+    while !moved.load(Ordering::SeqCst) {
+        if time::uptime() > deadline {
+            stop.store(true, Ordering::SeqCst);
+            panic!("no idle CPU took a thread from CPU 0");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    stop.store(true, Ordering::SeqCst);
+}
+
+/* Threads yielding as fast as they can, with the idle CPUs taking them from CPU 0: each must only
+ * ever run on one CPU at a time, and come back to its own stack as it left it. */
+#[cfg(not(test))]
+fn check_yield_storm(online: usize) {
+    const YIELDS: usize = 10_000;
+    let threads = 2 * online;
+    let done = Arc::new(AtomicUsize::new(0));
+    for i in 0..threads {
+        let done = done.clone();
+        thread::spawn("yield", move || {
+            let running = AtomicBool::new(false);
+            let canary = [i; 16];
+            for _ in 0..YIELDS {
+                thread::yield_now();
+                /* Both CPUs would come back here, on the one stack. */
+                if running.swap(true, Ordering::SeqCst) {
+                    panic!("yield thread {} is running on two CPUs", i);
+                }
+                if unsafe { core::ptr::read_volatile(&canary) }.iter().any(|&c| c != i) {
+                    panic!("yield thread {}'s stack changed under it", i);
+                }
+                running.store(false, Ordering::SeqCst);
+            }
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    let deadline = time::uptime() + Duration::from_secs(10);
+    while done.load(Ordering::SeqCst) < threads {
+        if time::uptime() > deadline {
+            panic!("only {} of {} yield threads finished", done.load(Ordering::SeqCst), threads);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/* Somewhere unused in the kernel's part of the address space. */
+#[cfg(not(test))]
+const TEST_PAGE: u64 = 0x_4444_a000_0000;
+
+/* Every CPU reads a page, then it's remapped to a different frame: they must all see the new one. */
+#[cfg(not(test))]
+fn check_shootdown() {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGE));
+    let map = |value: u64| {
+        let frame = *memory::allocate_frame().expect("Out of memory");
+        unsafe { memory::phys_to_virt(frame.start_address()).unwrap().as_mut_ptr::<u64>().write(value) };
+        memory::with_mapper(|mapper, frames| memory::create_mapping(page, frame, mapper, frames));
+    };
+    let read = || unsafe { (TEST_PAGE as *const u64).read_volatile() };
+
+    map(1);
+    ipi::call(ipi::Target::All, || {
+        read();
+    });
+    /* Kept until the end, so the new mapping can't get the same frame back. */
+    let old = memory::unmap_page(page).unwrap();
+    map(2);
+    let stale = AtomicUsize::new(0);
+    ipi::call(ipi::Target::All, || {
+        if read() != 2 {
+            stale.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    if stale.load(Ordering::SeqCst) > 0 {
+        panic!("{} CPUs still saw the old mapping", stale.load(Ordering::SeqCst));
+    }
+
+    memory::free_frame(memory::unmap_page(page).unwrap());
+    memory::free_frame(old);
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,106 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mtos::sync::{Condvar, Mutex, Semaphore};
+use mtos::task::{executor::Executor, Task};
+use mtos::*;
+
+entry_point!(test_main);
+
+const WORKERS: usize = 4;
+const ROUNDS: usize = 1000;
+const TASKS: usize = 4;
+
+static COUNT: Mutex<usize> = Mutex::new(0);
+static DONE: Mutex<usize> = Mutex::new(0);
+static DONE_CV: Condvar = Condvar::new();
+static SLOTS: Semaphore = Semaphore::new(2);
+static IN_SLOTS: Mutex<usize> = Mutex::new(0);
+/* Taken by both the workers and the tasks. */
+static MIXED: Mutex<usize> = Mutex::new(0);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    /* Tasks taking MIXED asynchronously, alongside the workers blocking on it: a task that finds
+     * it free mustn't leave a stale wait behind to swallow the unlock meant for a worker. */
+    thread::spawn("executor", || {
+        let mut executor = Executor::new();
+        for _ in 0..TASKS {
+            executor.spawn(Task::new(async {
+                for i in 0..ROUNDS {
+                    let mut mixed = MIXED.lock_async().await;
+                    let seen = *mixed;
+                    if i % 10 == 0 {
+                        task::yield_now().await;
+                    }
+                    *mixed = seen + 1;
+                }
+                *DONE.lock_async().await += 1;
+                DONE_CV.notify_all();
+            }));
+        }
+        executor.run();
+    });
+
+    for _ in 0..WORKERS {
+        thread::spawn("worker", || {
+            for i in 0..ROUNDS {
+                /* Read-modify-write with a yield in the middle: lost updates unless it excludes. */
+                let mut count = COUNT.lock();
+                let seen = *count;
+                if i % 100 == 0 {
+                    thread::yield_now();
+                }
+                *count = seen + 1;
+            }
+            for i in 0..ROUNDS {
+                let mut mixed = MIXED.lock();
+                let seen = *mixed;
+                if i % 10 == 0 {
+                    thread::yield_now();
+                }
+                *mixed = seen + 1;
+            }
+
+            SLOTS.acquire();
+            {
+                let mut in_slots = IN_SLOTS.lock();
+                *in_slots += 1;
+                if *in_slots > 2 {
+                    panic!("{} threads past a semaphore of 2", *in_slots);
+                }
+            }
+            thread::sleep(core::time::Duration::from_millis(20));
+            *IN_SLOTS.lock() -= 1;
+            SLOTS.release();
+
+            *DONE.lock() += 1;
+            DONE_CV.notify_all();
+        });
+    }
+
+    let done = DONE_CV.wait_while(DONE.lock(), |done| *done < WORKERS + TASKS);
+    drop(done);
+
+    let count = *COUNT.lock();
+    if count != WORKERS * ROUNDS {
+        panic!("count {}, expected {}", count, WORKERS * ROUNDS);
+    }
+    let mixed = *MIXED.lock();
+    if mixed != (WORKERS + TASKS) * ROUNDS {
+        panic!("mixed count {}, expected {}", mixed, (WORKERS + TASKS) * ROUNDS);
+    }
+
+    testing::pass()
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,153 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::AddressSpace;
+use mtos::syscall::Error;
+use mtos::user::USER_START;
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* Makes some system calls, recording the results in the page at rdi (r12 from then on, which
+ * they should preserve), then yields until told to exit. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_syscall, "a"
+.global test_syscall_prog
+test_syscall_prog:
+    mov r12, rdi
+
+    mov eax, 1                      // write(1, msg, len)
+    mov edi, 1
+    lea rsi, [rip + test_syscall_msg]
+    mov edx, test_syscall_msg_end - test_syscall_msg
+    syscall
+    mov [r12], rax
+
+    mov eax, 1                      // write(1, kernel address, 8)
+    mov edi, 1
+    mov rsi, [r12 + 48]
+    mov edx, 8
+    syscall
+    mov [r12 + 8], rax
+
+    mov eax, 3                      // sleep(20)
+    mov edi, 20
+    syscall
+    mov eax, 4                      // time()
+    syscall
+    mov [r12 + 16], rax
+
+    mov eax, 99                     // no such call
+    syscall
+    mov [r12 + 24], rax
+
+    mov qword ptr [r12 + 32], 1
+test_syscall_wait:
+    mov eax, 5                      // yield()
+    syscall
+    cmp qword ptr [r12 + 40], 0
+    je test_syscall_wait
+
+    mov eax, 0                      // exit(0)
+    xor edi, edi
+    syscall
+test_syscall_stuck:
+    jmp test_syscall_stuck
+
+test_syscall_msg:
+    .ascii "hello from user mode\n"
+test_syscall_msg_end:
+.global test_syscall_prog_end
+test_syscall_prog_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_syscall_prog: u8;
+    static test_syscall_prog_end: u8;
+}
+
+/* Offsets in the results page. */
+const WRITE_OK: u64 = 0;
+const WRITE_BAD: u64 = 8;
+const TIME: u64 = 16;
+const NO_SYS: u64 = 24;
+const DONE: u64 = 32;
+const GO: u64 = 40;
+const KERNEL_ADDR: u64 = 48;
+
+const MSG_LEN: u64 = 21;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    let code = unsafe {
+        let start = &test_syscall_prog as *const u8;
+        core::slice::from_raw_parts(start, &test_syscall_prog_end as *const u8 as usize - start as usize)
+    };
+    let base = VirtAddr::new(USER_START);
+    let results = base + 0x1000u64;
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(results, 2, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    let kernel_addr = &TIMEOUT as *const Duration as u64;
+    space.write(results + KERNEL_ADDR, &kernel_addr.to_ne_bytes()).unwrap();
+    let pid = process::start("user", space.clone(), base, base + 0x3000u64, results.as_u64());
+
+    let peek = |offset: u64| {
+        let mut bytes = [0; 8];
+        space.read(results + offset, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    let deadline = time::uptime() + TIMEOUT;
+    let mut alive = true;
+    let mut checked = false;
+    while alive {
+        alive = process::list().iter().any(|p| p.pid == pid);
+        if alive && !checked && peek(DONE) == 1 {
+            check(peek(WRITE_OK), MSG_LEN, "write");
+            check(peek(WRITE_BAD), (Error::Fault as u64).wrapping_neg(), "write of kernel memory");
+            check(peek(NO_SYS), (Error::NoSys as u64).wrapping_neg(), "unknown call");
+            if peek(TIME) < 20_000_000 {
+                panic!("time {}ns, after sleeping 20ms", peek(TIME));
+            }
+            space.write(results + GO, &1u64.to_ne_bytes()).unwrap();
+            checked = true;
+        }
+        if time::uptime() > deadline {
+            panic!("user process didn't finish (checked {})", checked);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    if !checked {
+        panic!("user process died before making its calls");
+    }
+
+    testing::pass()
+}
+
+fn check(got: u64, expected: u64, what: &str) {
+    if got != expected {
+        panic!("{}: got {:#x}, expected {:#x}", what, got, expected);
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,50 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use mtos::*;
+
+entry_point!(test_main);
+
+static SPINNER_RAN: AtomicBool = AtomicBool::new(false);
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    /* Never yields, so main only gets the CPU back if preemption works. */
+    thread::spawn("spinner", || {
+        SPINNER_RAN.store(true, Ordering::SeqCst);
+        loop {}
+    });
+    let workers: usize = 4;
+    for _ in 0..workers {
+        thread::spawn("worker", || {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+            thread::yield_now();
+        });
+    }
+
+    while COUNTER.load(Ordering::SeqCst) < workers || !SPINNER_RAN.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    /* The workers should have exited & been reaped; leaving main, idle, and the spinner. */
+    thread::sleep(core::time::Duration::from_millis(100));
+    let live = thread::list().len();
+    if live != 3 {
+        panic!("{} threads alive, expected 3", live);
+    }
+
+    testing::pass()
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
@@ -0,0 +1,126 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+#![feature(global_asm)]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use mtos::address_space::AddressSpace;
+use mtos::user::USER_START;
+use mtos::*;
+use x86_64::VirtAddr;
+
+entry_point!(test_main);
+
+/* User programs, copied into user pages to run. Both end up killed: counting by a #GP from hlt,
+ * which is privileged, and peeking by a page fault. Counting first loads a user data selector
+ * into GS, which zeroes its base, and spins long enough to be preempted with it like that. */
+global_asm!(
+    r#"
+.section .rodata.mtos_test_user, "a"
+.global test_user_count
+test_user_count:
+    mov ax, 0x1b
+    mov gs, ax
+test_user_count_loop:
+    inc qword ptr [rdi]
+    cmp qword ptr [rdi], 0x2000000
+    jb test_user_count_loop
+    hlt
+test_user_count_stuck:
+    jmp test_user_count_stuck
+.global test_user_count_end
+test_user_count_end:
+
+.global test_user_peek
+test_user_peek:
+    mov rax, [rdi]
+test_user_peek_stuck:
+    jmp test_user_peek_stuck
+.global test_user_peek_end
+test_user_peek_end:
+.text
+"#
+);
+
+extern "C" {
+    static test_user_count: u8;
+    static test_user_count_end: u8;
+    static test_user_peek: u8;
+    static test_user_peek_end: u8;
+}
+
+static KERNEL_SECRET: u64 = 0x5ec2e7;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    /* Two at the same address, each in its own address space, so with their own counters. */
+    let code = unsafe { program(&test_user_count, &test_user_count_end) };
+    let base = VirtAddr::new(USER_START);
+    let counter = base + 0x1000u64;
+    let (first, first_space) = spawn(code, base, counter.as_u64());
+    let (second, second_space) = spawn(code, base, counter.as_u64());
+
+    let peek = |space: &AddressSpace| {
+        let mut bytes = [0; 8];
+        space.read(counter, &mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    };
+    wait_for_exit(first);
+    wait_for_exit(second);
+    for space in &[first_space, second_space] {
+        /* Each counts up to this, then gets killed for hlt. */
+        if peek(space) != 0x2000000 {
+            panic!("user counter at {:#x}", peek(space));
+        }
+    }
+
+    let code = unsafe { program(&test_user_peek, &test_user_peek_end) };
+    let (id, _) = spawn(code, base, &KERNEL_SECRET as *const u64 as u64);
+    wait_for_exit(id);
+
+    testing::pass()
+}
+
+unsafe fn program(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+/// Run `code` at `base` in user mode in a new address space, with a data page after it and a
+/// stack page after that.
+fn spawn(code: &[u8], base: VirtAddr, arg: u64) -> (thread::ThreadId, Arc<AddressSpace>) {
+    let space = Arc::new(AddressSpace::new().expect("Can't make address space"));
+    space.map(base, 1, false, true).expect("Can't map user code");
+    space.map(base + 0x1000u64, 2, true, false).expect("Can't map user data & stack");
+    space.write(base, code).unwrap();
+    let id = thread::spawn_user("user", space.clone(), base, base + 0x3000u64, arg);
+    (id, space)
+}
+
+fn wait_for_exit(id: thread::ThreadId) {
+    let deadline = time::uptime() + TIMEOUT;
+    loop {
+        if !thread::list().iter().any(|t| t.id == id) {
+            return;
+        }
+        if time::uptime() > deadline {
+            panic!("user thread {} wasn't killed", id);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
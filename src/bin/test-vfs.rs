@@ -0,0 +1,242 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cmp;
+use core::convert::TryInto;
+use core::panic::PanicInfo;
+use mtos::process::fd::FileOps;
+use mtos::syscall::Error;
+use mtos::vfs::{self, DirEntry, FileSystem, FileType, Inode, Stat};
+use mtos::*;
+
+entry_point!(test_main);
+
+/* A read-only filesystem that's always the same tree:
+ *
+ *     hello           "hello, world\n"
+ *     loop -> loop
+ *     mnt/
+ *     sub/
+ *         link -> ../hello
+ */
+struct Toy {
+    root: Arc<dyn Inode>,
+}
+
+enum Node {
+    File(&'static [u8]),
+    Dir(Vec<(&'static str, Arc<ToyInode>)>),
+    Link(&'static str),
+}
+
+struct ToyInode {
+    ino: u64,
+    node: Node,
+}
+
+impl Toy {
+    fn new() -> Arc<dyn FileSystem> {
+        let inode = |ino, node| Arc::new(ToyInode { ino, node });
+        let sub = inode(5, Node::Dir(vec![("link", inode(6, Node::Link("../hello")))]));
+        let root = inode(
+            1,
+            Node::Dir(vec![
+                ("hello", inode(2, Node::File(b"hello, world\n"))),
+                ("loop", inode(3, Node::Link("loop"))),
+                ("mnt", inode(4, Node::Dir(Vec::new()))),
+                ("sub", sub),
+            ]),
+        );
+        Arc::new(Toy { root })
+    }
+}
+
+impl FileSystem for Toy {
+    fn name(&self) -> &'static str {
+        "toy"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl ToyInode {
+    fn kind(&self) -> FileType {
+        match self.node {
+            Node::File(_) => FileType::Regular,
+            Node::Dir(_) => FileType::Directory,
+            Node::Link(_) => FileType::Symlink,
+        }
+    }
+}
+
+impl Inode for ToyInode {
+    fn stat(&self) -> Stat {
+        let size = match self.node {
+            Node::File(data) => data.len(),
+            Node::Dir(ref entries) => entries.len(),
+            Node::Link(target) => target.len(),
+        };
+        Stat { dev: 0, ino: self.ino, kind: self.kind(), mode: 0o755, nlink: 1, size: size as u64 }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.node {
+            Node::File(data) => {
+                let start = cmp::min(offset as usize, data.len());
+                let len = cmp::min(buf.len(), data.len() - start);
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                Ok(len)
+            }
+            _ => Err(Error::IsDirectory),
+        }
+    }
+
+    fn readlink(&self) -> Result<String, Error> {
+        match self.node {
+            Node::Link(target) => Ok(String::from(target)),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        match self.node {
+            Node::Dir(ref entries) => match entries.iter().find(|(n, _)| *n == name) {
+                Some((_, inode)) => Ok(inode.clone()),
+                None => Err(Error::NoEntry),
+            },
+            _ => Err(Error::NotDirectory),
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        match self.node {
+            Node::Dir(ref entries) => Ok(entries.get(index).map(|(name, inode)| DirEntry {
+                name: String::from(*name),
+                ino: inode.ino,
+                kind: inode.kind(),
+            })),
+            _ => Err(Error::NotDirectory),
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    testing::init(boot_info);
+
+    check_error(vfs::stat("/hello").map(|_| ()), Error::NoEntry, "stat with nothing mounted");
+    check_error(vfs::mount("/mnt", Toy::new()), Error::NoEntry, "first mount somewhere other than /");
+    vfs::mount("/", Toy::new()).expect("Can't mount the root");
+
+    /* Reading, and seeking. */
+    let file = vfs::open("/hello", vfs::O_RDONLY, 0).expect("Can't open /hello");
+    let mut buf = [0; 64];
+    check(file.read(&mut buf[..5]), 5, "first read");
+    check(file.read(&mut buf[5..]), 8, "second read");
+    check(file.read(&mut buf), 0, "read at the end");
+    if &buf[..13] != b"hello, world\n" {
+        panic!("read {:?}", &buf[..13]);
+    }
+    check(file.seek(7, vfs::SEEK_SET).map(|o| o as usize), 7, "seek from the start");
+    check(file.read(&mut buf[..5]), 5, "read after seeking");
+    if &buf[..5] != b"world" {
+        panic!("read {:?} after seeking", &buf[..5]);
+    }
+    check(file.seek(-1, vfs::SEEK_END).map(|o| o as usize), 12, "seek from the end");
+    check(file.seek(-2, vfs::SEEK_CUR).map(|o| o as usize), 10, "seek from the current offset");
+    check_error(file.seek(-11, vfs::SEEK_CUR).map(|_| ()), Error::Invalid, "seek before the start");
+    check_error(file.write(b"x").map(|_| ()), Error::BadFd, "write to a read-only file");
+
+    /* Paths. */
+    let hello = vfs::stat("/hello").unwrap();
+    let link = vfs::stat("/sub/link").expect("Can't stat through a symlink");
+    if link != hello || hello.kind != FileType::Regular || hello.size != 13 {
+        panic!("/sub/link is {:?}, /hello {:?}", link, hello);
+    }
+    for path in &["hello", "//hello", "/sub/../hello", "/./sub/./link", "/../hello", "/sub/link/"] {
+        check(vfs::stat(path).map(|s| s.ino as usize), 2, path);
+    }
+    check_error(vfs::stat("/loop").map(|_| ()), Error::Loop, "stat through a symlink loop");
+    check_error(vfs::stat("/hello/x").map(|_| ()), Error::NotDirectory, "path through a file");
+    check_error(vfs::stat("/nothing").map(|_| ()), Error::NoEntry, "stat of nothing");
+    let long: String = core::iter::repeat('x').take(vfs::NAME_MAX + 1).collect();
+    check_error(vfs::stat(&long).map(|_| ()), Error::NameTooLong, "stat of a long name");
+
+    /* Mounts. */
+    let root = vfs::stat("/").unwrap();
+    vfs::mount("/mnt", Toy::new()).expect("Can't mount at /mnt");
+    let mounted = vfs::stat("/mnt").unwrap();
+    if mounted.dev == root.dev || mounted.ino != root.ino {
+        panic!("/mnt is {:?} after mounting, / is {:?}", mounted, root);
+    }
+    check(vfs::stat("/mnt/hello").map(|s| s.dev as usize), mounted.dev as usize, "file in a mount");
+    check(vfs::stat("/mnt/sub/link").map(|s| s.dev as usize), mounted.dev as usize, "symlink in a mount");
+    check(vfs::stat("/mnt/..").map(|s| s.dev as usize), root.dev as usize, ".. out of a mount");
+    check_error(vfs::mount("/mnt", Toy::new()), Error::Busy, "mount on a mount point");
+    check_error(vfs::mount("/hello", Toy::new()), Error::NotDirectory, "mount on a file");
+    check_error(vfs::rmdir("/mnt"), Error::Busy, "rmdir of a mount point");
+    check_error(vfs::unlink("/sub"), Error::IsDirectory, "unlink of a directory");
+    check_error(vfs::rmdir("/hello"), Error::NotDirectory, "rmdir of a file");
+
+    /* Opening. */
+    let open = |path, flags| vfs::open(path, flags, 0).map(|_| ());
+    check_error(open("/", vfs::O_WRONLY), Error::IsDirectory, "open a directory to write");
+    check_error(open("/hello", vfs::O_DIRECTORY), Error::NotDirectory, "open a file as a directory");
+    check_error(open("/hello", vfs::O_CREAT | vfs::O_EXCL), Error::Exists, "exclusive create");
+
+    /* Directory entries, a few at a time. */
+    let dir = vfs::open("/", vfs::O_RDONLY | vfs::O_DIRECTORY, 0).expect("Can't open /");
+    let mut names = Vec::new();
+    let mut buf = [0; 56];
+    loop {
+        let len = dir.getdents(&mut buf).expect("getdents failed");
+        if len == 0 {
+            break;
+        }
+        let mut at = 0;
+        while at < len {
+            let size = u16::from_ne_bytes(buf[at + 16..at + 18].try_into().unwrap()) as usize;
+            let name = &buf[at + 19..at + size];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+            names.push((String::from(core::str::from_utf8(name).unwrap()), buf[at + 18]));
+            at += size;
+        }
+    }
+    let expected = [("hello", 8), ("loop", 10), ("mnt", 4), ("sub", 4)];
+    if names.len() != expected.len() || names.iter().zip(&expected).any(|((n, t), (en, et))| n != en || t != et) {
+        panic!("getdents gave {:?}", names);
+    }
+    check(dir.seek(0, vfs::SEEK_SET).map(|o| o as usize), 0, "seek back to the first entry");
+    check_error(dir.getdents(&mut buf[..8]).map(|_| ()), Error::Invalid, "getdents into too small a buffer");
+    check_error(file.getdents(&mut buf).map(|_| ()), Error::NotDirectory, "getdents on a file");
+
+    testing::pass()
+}
+
+fn check(got: Result<usize, Error>, expected: usize, what: &str) {
+    if got != Ok(expected) {
+        panic!("{}: got {:?}, expected {}", what, got, expected);
+    }
+}
+
+fn check_error(got: Result<(), Error>, expected: Error, what: &str) {
+    if got != Err(expected) {
+        panic!("{}: got {:?}, expected {:?}", what, got, expected);
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
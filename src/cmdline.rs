@@ -0,0 +1,25 @@
+/* The bootloader doesn't pass us a command line, so it's baked in at build time instead, eg
+ *   MTOS_CMDLINE="ttyS0=115200n8 ttyS1=9600e71 log=ttyS0 shell=ttyS1 debug=ttyS2" make image
+ * It's a whitespace-separated list of `key=value` and bare `flag` words. */
+pub fn cmdline() -> &'static str {
+    option_env!("MTOS_CMDLINE").unwrap_or("")
+}
+
+/// Value of the last `key=value` word for `key`, so later words override earlier ones.
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline()
+        .split_whitespace()
+        .filter_map(|w| {
+            let mut kv = w.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None,
+            }
+        })
+        .last()
+}
+
+/// Whether `flag` appears as a bare word.
+pub fn has(flag: &str) -> bool {
+    cmdline().split_whitespace().any(|w| w == flag)
+}
@@ -1,52 +1,99 @@
-use lazy_static::lazy_static;
+use crate::memory::KernelStack;
+use crate::percpu;
+use alloc::boxed::Box;
+use core::sync::atomic::Ordering;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-struct SegmentSelectors {
-    code_segment_selector: SegmentSelector,
-    tss_segment_selector: SegmentSelector,
-}
+/// Each application processor's double fault stack, which gets a guard page unlike the boot CPU's.
+const AP_DOUBLE_FAULT_STACK_PAGES: u64 = 2;
+
+/* Every CPU's GDT is laid out the same, so the selectors are constants. SYSRET needs user data
+ * straight before user code. */
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, SegmentSelectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_segment_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_segment_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            SegmentSelectors {
-                code_segment_selector,
-                tss_segment_selector,
-            },
-        )
-    };
+/* Present, writable, ring 0 data; the rest is ignored in long mode. */
+const KERNEL_DATA_SEGMENT: u64 = 0x0000_9200_0000_0000;
+
+/// Every CPU has its own GDT and TSS, since the TSS holds its interrupt stacks and is marked busy
+/// in the GDT once loaded.
+fn build_gdt(tss: &'static TaskStateSegment) -> GlobalDescriptorTable {
+    let mut gdt = GlobalDescriptorTable::new();
+    let selectors = [
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT)),
+        gdt.add_entry(Descriptor::user_data_segment()),
+        gdt.add_entry(Descriptor::user_code_segment()),
+        gdt.add_entry(Descriptor::tss_segment(tss)),
+    ];
+    let expected = [
+        KERNEL_CODE_SELECTOR,
+        KERNEL_DATA_SELECTOR,
+        USER_DATA_SELECTOR,
+        USER_CODE_SELECTOR,
+        TSS_SELECTOR,
+    ];
+    for (got, want) in selectors.iter().zip(expected.iter()) {
+        assert_eq!(got.index(), want.index());
+    }
+    gdt
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-             // no proper allocation now, so use this wherever it ends up
-             // must be mut otherwise the compiler will put it in an RO page
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // NB: no guard page
-            stack_end
-        };
-        tss
-    };
+/* The boot CPU's, which are needed before there's a heap. Only init() touches them directly;
+ * afterwards the TSS is reached through the per-CPU block like everyone else's. */
+static mut BSP_TSS: Option<TaskStateSegment> = None;
+static mut BSP_GDT: Option<GlobalDescriptorTable> = None;
+static mut BSP_DOUBLE_FAULT_STACK: [u8; 4096] = [0; 4096]; // NB: no guard page
+
+fn load(gdt: &'static GlobalDescriptorTable) {
+    gdt.load();
+    unsafe {
+        x86_64::instructions::segmentation::set_cs(KERNEL_CODE_SELECTOR);
+        x86_64::instructions::tables::load_tss(TSS_SELECTOR);
+    }
 }
 
+/// Set up the boot CPU: its per-CPU block, GDT and TSS.
 pub fn init() {
-    GDT.0.load();
+    percpu::init_bsp();
     unsafe {
-        x86_64::instructions::segmentation::set_cs(GDT.1.code_segment_selector);
-        x86_64::instructions::tables::load_tss(GDT.1.tss_segment_selector);
+        let mut tss = TaskStateSegment::new();
+        let stack_start = VirtAddr::from_ptr(&BSP_DOUBLE_FAULT_STACK);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_start + BSP_DOUBLE_FAULT_STACK.len();
+        BSP_TSS = Some(tss);
+        let tss = BSP_TSS.as_mut().unwrap();
+        percpu::this().tss.store(tss as *mut _, Ordering::Relaxed);
+
+        BSP_GDT = Some(build_gdt(BSP_TSS.as_ref().unwrap()));
+        load(BSP_GDT.as_ref().unwrap());
     }
 }
+
+/// Set up an application processor's per-CPU block, GDT and TSS. They, and the IST stacks, live
+/// as long as the CPU does, ie forever. Needs the heap.
+pub fn init_ap(cpu: usize) {
+    percpu::init_ap(cpu);
+    let stack = KernelStack::new(AP_DOUBLE_FAULT_STACK_PAGES).expect("Out of memory for IST stack");
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.top();
+    core::mem::forget(stack);
+
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(tss));
+    percpu::this().tss.store(tss as *mut _, Ordering::Relaxed);
+    load(Box::leak(Box::new(build_gdt(tss))));
+}
+
+/// Set the stack this CPU switches to when an interrupt or system call arrives from user mode:
+/// the running thread's kernel stack. Interrupts must be off.
+pub fn set_kernel_stack(top: VirtAddr) {
+    let tss = percpu!(tss).load(Ordering::Relaxed);
+    unsafe { (*tss).privilege_stack_table[0] = top };
+    percpu!(syscall_stack).store(top.as_u64(), Ordering::Relaxed);
+}
@@ -0,0 +1,166 @@
+//! Reading newc cpio archives, as Linux's initramfs has them: a 110-byte header of hex fields,
+//! the name, then the data, each padded to 4 bytes, until an entry called "TRAILER!!!".
+//!
+//! Hard links (entries sharing an inode, with the data only on the last) come out as separate
+//! files, the others empty.
+
+use super::{ArchiveError, Contents, Entry};
+use alloc::vec::Vec;
+use core::str;
+
+const HEADER: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Whether `data` looks like a newc cpio archive (with or without checksums).
+pub fn is_cpio(data: &[u8]) -> bool {
+    matches!(data.get(..6), Some(b"070701") | Some(b"070702"))
+}
+
+pub fn parse(data: &[u8]) -> Result<Vec<Entry<'_>>, ArchiveError> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    loop {
+        let header = data.get(at..at + HEADER).ok_or(ArchiveError::Truncated)?;
+        if !is_cpio(header) {
+            return Err(ArchiveError::BadMagic);
+        }
+        let mode = hex(&header[14..22])?;
+        let size = hex(&header[54..62])? as usize;
+        let name_size = hex(&header[94..102])? as usize;
+        let name_end = (at + HEADER).checked_add(name_size).ok_or(ArchiveError::Truncated)?;
+        let name = data.get(at + HEADER..name_end).ok_or(ArchiveError::Truncated)?;
+        let name = match name.split_last() {
+            Some((&0, name)) => str::from_utf8(name).map_err(|_| ArchiveError::BadName)?,
+            _ => return Err(ArchiveError::BadName),
+        };
+        if name == TRAILER {
+            return Ok(entries);
+        }
+        let start = align(name_end);
+        let end = start.checked_add(size).ok_or(ArchiveError::Truncated)?;
+        let body = data.get(start..end).ok_or(ArchiveError::Truncated)?;
+        let contents = match mode & S_IFMT {
+            S_IFREG => Contents::File(body),
+            S_IFDIR => Contents::Directory,
+            S_IFLNK => Contents::Symlink(str::from_utf8(body).map_err(|_| ArchiveError::BadName)?),
+            _ => Contents::Other,
+        };
+        entries.push(Entry::new("", name, mode & 0o7777, contents)?);
+        at = align(end);
+    }
+}
+
+fn align(at: usize) -> usize {
+    (at + 3) & !3
+}
+
+/* A header field: 8 hex digits. */
+fn hex(field: &[u8]) -> Result<u32, ArchiveError> {
+    let digits = str::from_utf8(field).map_err(|_| ArchiveError::BadNumber)?;
+    u32::from_str_radix(digits, 16).map_err(|_| ArchiveError::BadNumber)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds newc archives, an entry at a time.
+    struct Builder {
+        out: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder { out: Vec::new() }
+        }
+
+        fn entry(mut self, name: &str, mode: u32, data: &[u8]) -> Self {
+            let fields = [1, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+            self.out.extend_from_slice(b"070701");
+            for field in &fields {
+                self.out.extend_from_slice(format!("{:08X}", field).as_bytes());
+            }
+            self.out.extend_from_slice(name.as_bytes());
+            self.out.push(0);
+            self.out.resize(align(self.out.len()), 0);
+            self.out.extend_from_slice(data);
+            self.out.resize(align(self.out.len()), 0);
+            self
+        }
+
+        fn build(self) -> Vec<u8> {
+            self.entry(TRAILER, 0, b"").out
+        }
+    }
+
+    #[test]
+    fn files_directories_and_symlinks() {
+        let data = Builder::new()
+            .entry(".", S_IFDIR | 0o755, b"")
+            .entry("bin", S_IFDIR | 0o755, b"")
+            .entry("bin/init", S_IFREG | 0o755, b"\x7fELF")
+            .entry("bin/sh", S_IFLNK | 0o777, b"init")
+            .build();
+        assert!(is_cpio(&data));
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].path, "");
+        assert_eq!(entries[1].path, "bin");
+        assert_eq!(entries[1].mode, 0o755);
+        assert_eq!(entries[1].contents, Contents::Directory);
+        assert_eq!(entries[2].path, "bin/init");
+        assert_eq!(entries[2].contents, Contents::File(b"\x7fELF"));
+        assert_eq!(entries[3].path, "bin/sh");
+        assert_eq!(entries[3].mode, 0o777);
+        assert_eq!(entries[3].contents, Contents::Symlink("init"));
+    }
+
+    #[test]
+    fn names_and_data_are_padded() {
+        /* Names of each length mod 4, and data of each length. */
+        let mut builder = Builder::new();
+        for i in 1..=4 {
+            builder = builder.entry(&"abcd"[..i], S_IFREG | 0o644, &b"wxyz"[..i]);
+        }
+        let data = builder.build();
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries.len(), 4);
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.path, &"abcd"[..i + 1]);
+            assert_eq!(entry.contents, Contents::File(&b"wxyz"[..i + 1]));
+        }
+    }
+
+    #[test]
+    fn other_kinds_are_passed_over() {
+        let data = Builder::new().entry("dev/null", 0o020666, b"").build();
+        assert_eq!(parse(&data).unwrap()[0].contents, Contents::Other);
+    }
+
+    #[test]
+    fn dot_dot_is_rejected() {
+        let data = Builder::new().entry("bin/../../escape", S_IFREG | 0o644, b"").build();
+        assert_eq!(parse(&data), Err(ArchiveError::BadPath));
+    }
+
+    #[test]
+    fn bad_archives() {
+        let data = Builder::new().entry("a", S_IFREG | 0o644, b"hello").build();
+        assert_eq!(parse(&data[..data.len() - 4]), Err(ArchiveError::Truncated));
+        assert_eq!(parse(&data[..120]), Err(ArchiveError::Truncated));
+        let mut bad = data.clone();
+        bad[5] = b'7';
+        assert_eq!(parse(&bad), Err(ArchiveError::BadMagic));
+        let mut bad = data.clone();
+        bad[14] = b'g';
+        assert_eq!(parse(&bad), Err(ArchiveError::BadNumber));
+        let mut bad = data;
+        bad[HEADER + 1] = b'x';
+        assert_eq!(parse(&bad), Err(ArchiveError::BadName));
+    }
+}
@@ -0,0 +1,132 @@
+//! The initial ramdisk: an archive of files the build puts in the kernel image, that's unpacked
+//! into the root filesystem at boot. It can be a ustar or newc cpio archive; see build.rs for where
+//! it comes from.
+//!
+//! The parsers only read the archive, with bounds checks, and don't touch the rest of the kernel,
+//! so they're unit tested on the host.
+
+use crate::process::fd::FileOps;
+use crate::syscall::Error;
+use crate::vfs;
+use alloc::{format, string::String, vec::Vec};
+
+pub mod cpio;
+pub mod tar;
+#[cfg(test)]
+mod tar_writer;
+
+/// The archive the build made.
+pub static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// Neither a ustar nor a newc cpio archive, or an entry partway through that isn't.
+    BadMagic,
+    /// A header or an entry's data runs off the end.
+    Truncated,
+    BadNumber,
+    BadChecksum,
+    /// A name (or symlink target) that isn't UTF-8, or a cpio name without its NUL.
+    BadName,
+    /// A path with a ".." in it, which could reach outside wherever the archive's unpacked.
+    BadPath,
+}
+
+/// An entry in an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Relative to where the archive's unpacked, without "./" or a trailing "/"; empty for the
+    /// top directory itself.
+    pub path: String,
+    /// Permission bits.
+    pub mode: u32,
+    pub contents: Contents<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Contents<'a> {
+    File(&'a [u8]),
+    Directory,
+    Symlink(&'a str),
+    /// Devices, FIFOs, hard links in a tar and so on, which are passed over.
+    Other,
+}
+
+impl<'a> Entry<'a> {
+    fn new(prefix: &str, name: &str, mode: u32, contents: Contents<'a>) -> Result<Entry<'a>, ArchiveError> {
+        let parts = prefix
+            .split('/')
+            .chain(name.split('/'))
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<_>>();
+        if parts.contains(&"..") {
+            return Err(ArchiveError::BadPath);
+        }
+        Ok(Entry { path: parts.join("/"), mode: mode & 0o7777, contents })
+    }
+}
+
+/// The entries in `data`, whichever kind of archive it is.
+pub fn parse(data: &[u8]) -> Result<Vec<Entry<'_>>, ArchiveError> {
+    if cpio::is_cpio(data) {
+        cpio::parse(data)
+    } else if tar::is_tar(data) || data.iter().all(|&b| b == 0) {
+        tar::parse(data)
+    } else {
+        Err(ArchiveError::BadMagic)
+    }
+}
+
+/// Unpack `data` into the directory at `to`, returning how many entries there were. What's
+/// already there is kept, but files in the archive replace files of the same name. An entry that
+/// can't be made is logged and passed over, as are kinds of entry that aren't supported.
+pub fn unpack(data: &[u8], to: &str) -> Result<usize, ArchiveError> {
+    let entries = parse(data)?;
+    for entry in &entries {
+        if entry.path.is_empty() {
+            continue;
+        }
+        let path = format!("{}/{}", to.trim_end_matches('/'), entry.path);
+        if let Err(e) = make(&path, entry) {
+            log::warn!("initrd: can't make {}: {:?}", path, e);
+        }
+    }
+    Ok(entries.len())
+}
+
+/* Make `entry` at `path`, and any directories it's in that the archive didn't have first. */
+fn make(path: &str, entry: &Entry) -> Result<(), Error> {
+    for (i, _) in path.match_indices('/').skip(1) {
+        match vfs::mkdir(&path[..i], 0o755) {
+            Ok(()) | Err(Error::Exists) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    match entry.contents {
+        Contents::File(data) => {
+            let file = vfs::open(path, vfs::O_CREAT | vfs::O_WRONLY | vfs::O_TRUNC, entry.mode)?;
+            let mut written = 0;
+            while written < data.len() {
+                written += file.write(&data[written..])?;
+            }
+            Ok(())
+        }
+        Contents::Directory => match vfs::mkdir(path, entry.mode) {
+            Ok(()) | Err(Error::Exists) => Ok(()),
+            Err(e) => Err(e),
+        },
+        Contents::Symlink(target) => vfs::symlink(target, path),
+        Contents::Other => {
+            log::info!("initrd: passing over {}, which isn't a file, directory or symlink", path);
+            Ok(())
+        }
+    }
+}
+
+/// Unpack the initrd into the root filesystem.
+pub fn init() {
+    match unpack(INITRD, "/") {
+        Ok(entries) => log::info!("Unpacked {} entries from the initrd", entries),
+        Err(e) => log::error!("Can't unpack the initrd: {:?}", e),
+    }
+}
@@ -0,0 +1,187 @@
+//! Reading ustar archives: 512-byte headers, each followed by the entry's data padded to a whole
+//! block, and two zeroed blocks at the end. GNU tar's headers are the same but for the magic.
+
+use super::{ArchiveError, Contents, Entry};
+use alloc::vec::Vec;
+use core::str;
+
+const BLOCK: usize = 512;
+
+/// Whether `data` looks like a ustar archive.
+pub fn is_tar(data: &[u8]) -> bool {
+    data.get(257..262) == Some(&b"ustar"[..])
+}
+
+pub fn parse(data: &[u8]) -> Result<Vec<Entry<'_>>, ArchiveError> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let header = data.get(at..at + BLOCK).ok_or(ArchiveError::Truncated)?;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !is_tar(header) {
+            return Err(ArchiveError::BadMagic);
+        }
+        if octal(&header[148..156])? != checksum(header) {
+            return Err(ArchiveError::BadChecksum);
+        }
+        let mode = octal(&header[100..108])? as u32;
+        let size = octal(&header[124..136])? as usize;
+        let start = at + BLOCK;
+        let end = start.checked_add(size).ok_or(ArchiveError::Truncated)?;
+        let body = data.get(start..end).ok_or(ArchiveError::Truncated)?;
+        let contents = match header[156] {
+            b'0' | 0 => Contents::File(body),
+            b'5' => Contents::Directory,
+            b'2' => Contents::Symlink(string(&header[157..257])?),
+            _ => Contents::Other,
+        };
+        let name = string(&header[0..100])?;
+        let prefix = string(&header[345..500])?;
+        entries.push(Entry::new(prefix, name, mode, contents)?);
+        at = end + (BLOCK - size % BLOCK) % BLOCK;
+    }
+    Ok(entries)
+}
+
+/* A number in a header field: octal digits, maybe with spaces around them, ending in a NUL or a
+ * space. (GNU tar's base-256 numbers, for files of 8GiB and up, aren't read.) */
+fn octal(field: &[u8]) -> Result<u64, ArchiveError> {
+    let digits = field.split(|&b| b == 0).next().unwrap();
+    let digits = str::from_utf8(digits).map_err(|_| ArchiveError::BadNumber)?.trim();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| ArchiveError::BadNumber)
+}
+
+/* The sum of a header's bytes, with the checksum field itself taken as spaces. */
+fn checksum(header: &[u8]) -> u64 {
+    let sum: u64 = header.iter().map(|&b| b as u64).sum();
+    sum - header[148..156].iter().map(|&b| b as u64).sum::<u64>() + 8 * b' ' as u64
+}
+
+/* A NUL-terminated (or field-long) string. */
+fn string(field: &[u8]) -> Result<&str, ArchiveError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| ArchiveError::BadName)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initrd::tar_writer::{self, set_checksum};
+
+    /// Builds ustar archives, an entry at a time, with the same writer as build.rs.
+    struct Builder {
+        out: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder { out: Vec::new() }
+        }
+
+        fn entry(mut self, name: &str, kind: u8, mode: u32, data: &[u8], link: &str) -> Self {
+            tar_writer::add(&mut self.out, name, kind, mode, data, link);
+            self
+        }
+
+        fn build(mut self) -> Vec<u8> {
+            tar_writer::finish(&mut self.out);
+            self.out
+        }
+    }
+
+    #[test]
+    fn files_directories_and_symlinks() {
+        let data = Builder::new()
+            .entry("./etc/", b'5', 0o755, b"", "")
+            .entry("./etc/motd", b'0', 0o644, b"hello\n", "")
+            .entry("./etc/link", b'2', 0o777, b"", "motd")
+            .build();
+        assert!(is_tar(&data));
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, "etc");
+        assert_eq!(entries[0].mode, 0o755);
+        assert_eq!(entries[0].contents, Contents::Directory);
+        assert_eq!(entries[1].path, "etc/motd");
+        assert_eq!(entries[1].mode, 0o644);
+        assert_eq!(entries[1].contents, Contents::File(b"hello\n"));
+        assert_eq!(entries[2].path, "etc/link");
+        assert_eq!(entries[2].contents, Contents::Symlink("motd"));
+    }
+
+    #[test]
+    fn data_is_padded_to_blocks() {
+        let big = [7u8; 600];
+        let data = Builder::new().entry("a", b'0', 0o600, &big, "").entry("b", 0, 0o600, b"x", "").build();
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries[0].contents, Contents::File(&big[..]));
+        assert_eq!(entries[1].path, "b");
+        assert_eq!(entries[1].contents, Contents::File(b"x"));
+    }
+
+    #[test]
+    fn prefix_goes_before_the_name() {
+        let mut data = Builder::new().entry("name", b'0', 0o644, b"", "").build();
+        data[345..351].copy_from_slice(b"prefix");
+        set_checksum(&mut data);
+        assert_eq!(parse(&data).unwrap()[0].path, "prefix/name");
+    }
+
+    #[test]
+    fn long_paths_are_split() {
+        let dir = "d".repeat(120);
+        let path = format!("{}/{}", dir, "f".repeat(90));
+        let data = Builder::new().entry(&path, b'0', 0o644, b"", "").build();
+        assert_eq!(&data[345..345 + dir.len()], dir.as_bytes());
+        assert_eq!(parse(&data).unwrap()[0].path, path);
+    }
+
+    #[test]
+    fn other_kinds_are_passed_over() {
+        let data = Builder::new().entry("fifo", b'6', 0o644, b"", "").build();
+        assert_eq!(parse(&data).unwrap()[0].contents, Contents::Other);
+    }
+
+    #[test]
+    fn bad_archives() {
+        let data = Builder::new().entry("a", b'0', 0o644, b"hello", "").build();
+        let mut bad = data.clone();
+        bad[0] = b'b';
+        assert_eq!(parse(&bad), Err(ArchiveError::BadChecksum));
+        assert_eq!(parse(&data[..BLOCK]), Err(ArchiveError::Truncated));
+        assert_eq!(parse(&data[..100]), Err(ArchiveError::Truncated));
+        let mut bad = data.clone();
+        bad[257] = b'x';
+        assert_eq!(parse(&bad), Err(ArchiveError::BadMagic));
+        let mut bad = data;
+        bad[124] = b'9';
+        set_checksum(&mut bad);
+        assert_eq!(parse(&bad), Err(ArchiveError::BadNumber));
+    }
+
+    #[test]
+    fn dot_dot_is_rejected() {
+        for name in &["../escape", "etc/../../escape", ".."] {
+            let data = Builder::new().entry(name, b'0', 0o644, b"", "").build();
+            assert_eq!(parse(&data), Err(ArchiveError::BadPath));
+        }
+        let mut data = Builder::new().entry("escape", b'0', 0o644, b"", "").build();
+        data[345..347].copy_from_slice(b"..");
+        set_checksum(&mut data);
+        assert_eq!(parse(&data), Err(ArchiveError::BadPath));
+        /* Just in a name is fine. */
+        let data = Builder::new().entry("a..b", b'0', 0o644, b"", "").build();
+        assert_eq!(parse(&data).unwrap()[0].path, "a..b");
+    }
+
+    #[test]
+    fn empty_archive() {
+        assert_eq!(parse(&Builder::new().build()), Ok(Vec::new()));
+        assert_eq!(parse(&[]), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,54 @@
+//! Writing ustar archives, the way `tar` reads them. Not part of the kernel: build.rs includes it
+//! (with `#[path]`) to make the initrd, and `tar`'s tests use it to make archives to read. So it
+//! only uses what's in the prelude of both std and this crate's tests.
+
+/// Header and data are in blocks of this many bytes.
+pub const BLOCK: usize = 512;
+
+/// Add an entry of type `kind` (`b'0'` for a file, `b'5'` a directory, `b'2'` a symlink to
+/// `link`) with `data`, padded to a whole block. Owners and times are all 0. Panics if the name
+/// or link is too long for ustar.
+pub fn add(archive: &mut Vec<u8>, name: &str, kind: u8, mode: u32, data: &[u8], link: &str) {
+    let mut header = [0u8; BLOCK];
+    let (prefix, name) = split_name(name);
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = kind;
+    assert!(link.len() <= 100, "symlink target too long for ustar: {}", link);
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    set_checksum(&mut header);
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + BLOCK - 1) / BLOCK * BLOCK, 0);
+}
+
+/// End the archive, with its two zeroed blocks.
+pub fn finish(archive: &mut Vec<u8>) {
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+}
+
+/// Work out the checksum of the header at the start of `header`, and fill it in.
+pub fn set_checksum(header: &mut [u8]) {
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header[..BLOCK].iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+}
+
+/* A path as ustar has it: a prefix of up to 155 bytes, then a "/", then up to 100 of name. */
+fn split_name(path: &str) -> (&str, &str) {
+    if path.len() <= 100 {
+        return ("", path);
+    }
+    let split = path[..path.len() - 1]
+        .match_indices('/')
+        .map(|(i, _)| i)
+        .find(|&i| i <= 155 && path.len() - i - 1 <= 100)
+        .unwrap_or_else(|| panic!("path too long for ustar: {}", path));
+    (&path[..split], &path[split + 1..])
+}
@@ -1,19 +1,31 @@
-use crate::gdt;
-use crate::{print, println};
+use crate::process::signal::{self, SIGFPE, SIGILL, SIGSEGV};
+use crate::{apic, gdt, ipi, thread};
+use crate::println;
+use crate::sync::IrqSpinLock;
+use crate::user::Registers;
+use alloc::{format, string::String};
+use core::sync::atomic::Ordering;
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
-use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{HandlerFunc, HandlerFuncWithErrCode, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, PageFaultHandlerFunc};
+use x86_64::VirtAddr;
 
 const PIC_0_OFFSET: u8 = 32;
 const PIC_1_OFFSET: u8 = PIC_0_OFFSET + 8;
 const TIMER_INTERRUPT_ID: u8 = PIC_0_OFFSET + 0;
 const KEYBOARD_INTERRUPT_ID: u8 = PIC_0_OFFSET + 1;
+const COM2_INTERRUPT_ID: u8 = PIC_0_OFFSET + IRQ_COM2;
+const COM1_INTERRUPT_ID: u8 = PIC_0_OFFSET + IRQ_COM1;
+
+pub const IRQ_COM2: u8 = 3;
+pub const IRQ_COM1: u8 = 4;
 
 const PORT_PS2_DATA: u16 = 0x60;
+const PORT_PIC_0_DATA: u16 = 0x21;
+const PORT_PIC_1_DATA: u16 = 0xA1;
 
-static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_0_OFFSET, PIC_1_OFFSET) });
+static PICS: IrqSpinLock<ChainedPics> =
+    IrqSpinLock::new(unsafe { ChainedPics::new(PIC_0_OFFSET, PIC_1_OFFSET) });
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -24,9 +36,24 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        /* Exceptions user mode can cause go through the stubs below, which pass kernel ones on to
+         * the handlers here. The stubs are the handlers as far as the CPU's concerned. */
+        unsafe {
+            idt.divide_error.set_handler_fn(core::mem::transmute::<_, HandlerFunc>(mtos_divide_error_entry as unsafe extern "C" fn()));
+            idt.invalid_opcode.set_handler_fn(core::mem::transmute::<_, HandlerFunc>(mtos_invalid_opcode_entry as unsafe extern "C" fn()));
+            idt.stack_segment_fault.set_handler_fn(core::mem::transmute::<_, HandlerFuncWithErrCode>(mtos_stack_segment_fault_entry as unsafe extern "C" fn()));
+            idt.general_protection_fault.set_handler_fn(core::mem::transmute::<_, HandlerFuncWithErrCode>(mtos_general_protection_fault_entry as unsafe extern "C" fn()));
+            idt.page_fault.set_handler_fn(core::mem::transmute::<_, PageFaultHandlerFunc>(mtos_page_fault_entry as unsafe extern "C" fn()));
+        }
         idt[usize::from(TIMER_INTERRUPT_ID)].set_handler_fn(timer_handler);
         idt[usize::from(KEYBOARD_INTERRUPT_ID)].set_handler_fn(keyboard_handler);
+        idt[usize::from(COM2_INTERRUPT_ID)].set_handler_fn(com2_handler);
+        idt[usize::from(COM1_INTERRUPT_ID)].set_handler_fn(com1_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
+        idt[usize::from(apic::TIMER_VECTOR)].set_handler_fn(apic_timer_handler);
+        idt[usize::from(ipi::RESCHEDULE_VECTOR)].set_handler_fn(reschedule_handler);
+        idt[usize::from(ipi::CALL_FUNCTION_VECTOR)].set_handler_fn(call_function_handler);
+        idt[usize::from(ipi::HALT_VECTOR)].set_handler_fn(halt_handler);
         idt
     };
 }
@@ -34,6 +61,7 @@ lazy_static! {
 pub fn init() {
     init_idt();
     unsafe { PICS.lock().initialize() };
+    crate::time::init();
 
     x86_64::instructions::interrupts::enable();
 }
@@ -42,7 +70,255 @@ fn init_idt() {
     IDT.load();
 }
 
+/// Interrupt setup for an application processor: just the shared IDT, as the PICs and PIT are the
+/// boot CPU's. Its scheduler ticks come from its APIC timer, once `smp` starts it.
+pub fn init_ap() {
+    init_idt();
+}
+
+/// Whether this CPU is running an interrupt handler.
+pub fn in_irq() -> bool {
+    irq_depth() > 0
+}
+
+/* How many interrupt handlers deep this CPU is. Saved & restored by each thread across a switch,
+ * as a thread preempted in the timer handler resumes inside it. */
+pub(crate) fn irq_depth() -> usize {
+    crate::percpu!(irq_depth).load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_irq_depth(depth: usize) {
+    crate::percpu!(irq_depth).store(depth, Ordering::Relaxed);
+}
+
+/// Switches to the kernel's GS base, and so its per-CPU data, if the interrupt arrived in user
+/// mode, and back when dropped. Must come before anything in a handler that uses `percpu!`. A
+/// handler that never returns to user mode (having killed the thread) just stays on the kernel's.
+struct KernelGs {
+    from_user: bool,
+}
+
+impl KernelGs {
+    fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = from_user(stack_frame);
+        if from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGs { from_user }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Marks the extent of a hardware interrupt handler.
+struct IrqContext {
+    _gs: KernelGs,
+}
+
+impl IrqContext {
+    fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let gs = KernelGs::enter(stack_frame);
+        crate::percpu!(irq_depth).fetch_add(1, Ordering::Relaxed);
+        IrqContext { _gs: gs }
+    }
+}
+
+impl Drop for IrqContext {
+    fn drop(&mut self) {
+        crate::percpu!(irq_depth).fetch_sub(1, Ordering::Relaxed);
+        // then _gs swaps back
+    }
+}
+
+/// Let an IRQ line through the PICs. The BIOS leaves some lines (eg the UARTs') masked, and
+/// `ChainedPics::initialize()` preserves whatever it finds.
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock(); // serialise the read-modify-write
+    let (mut port, bit) = if irq < 8 {
+        (Port::<u8>::new(PORT_PIC_0_DATA), irq)
+    } else {
+        (Port::<u8>::new(PORT_PIC_1_DATA), irq - 8)
+    };
+    unsafe {
+        let mask = port.read();
+        port.write(mask & !(1 << bit));
+    }
+}
+
+/* Entry stubs for exceptions user mode can cause. One from kernel mode (CS's RPL, in the frame
+ * above any error code, is 0) goes straight to its x86-interrupt handler. One from user mode gets
+ * the kernel's GS, and has its vector pushed along with all the registers, so it can be turned into
+ * a signal with the state to resume from after the handler: that's a UserFaultFrame. Nothing else
+ * is on the thread's kernel stack, so rsp stays 16-byte aligned for the call. */
+macro_rules! user_fault_stub {
+    ($name:literal, $vector:literal, $cs:literal, $push_error:literal) => {
+        concat!(
+            ".global mtos_", $name, "_entry\n",
+            "mtos_", $name, "_entry:\n",
+            "    test qword ptr [rsp + ", $cs, "], 3\n",
+            "    jz mtos_", $name, "_handler\n",
+            $push_error,
+            "    push ", $vector, "\n",
+            "    jmp mtos_user_fault_entry\n",
+        )
+    };
+}
+
+global_asm!(concat!(
+    user_fault_stub!("divide_error", "0", "8", "    push 0\n"),
+    user_fault_stub!("invalid_opcode", "6", "8", "    push 0\n"),
+    user_fault_stub!("stack_segment_fault", "12", "16", ""),
+    user_fault_stub!("general_protection_fault", "13", "16", ""),
+    user_fault_stub!("page_fault", "14", "16", ""),
+    r#"
+mtos_user_fault_entry:
+    swapgs
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+    mov rdi, rsp
+    call mtos_user_fault
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    add rsp, 16
+    swapgs
+    iretq
+"#
+));
+
+extern "C" {
+    fn mtos_divide_error_entry();
+    fn mtos_invalid_opcode_entry();
+    fn mtos_stack_segment_fault_entry();
+    fn mtos_general_protection_fault_entry();
+    fn mtos_page_fault_entry();
+}
+
+/// What the user fault stub pushes over the CPU's interrupt frame, lowest address first.
+#[repr(C)]
+struct UserFaultFrame {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    vector: u64,
+    /* 0 for exceptions without one. */
+    error_code: u64,
+    rip: u64,
+    _cs: u64,
+    rflags: u64,
+    rsp: u64,
+    _ss: u64,
+}
+
+impl UserFaultFrame {
+    fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rsp: self.rsp,
+            rflags: self.rflags,
+        }
+    }
+}
+
+/// An exception in user mode, with the kernel's GS base in place and interrupts off. A write to a
+/// copy-on-write page is dealt with, and returns to retry the instruction; anything else raises
+/// a signal in the process, so doesn't return.
+#[no_mangle]
+extern "C" fn mtos_user_fault(frame: &mut UserFaultFrame) {
+    let (signal, what) = match frame.vector {
+        0 => (SIGFPE, String::from("divide error")),
+        6 => (SIGILL, String::from("invalid opcode")),
+        12 => (SIGSEGV, format!("stack segment fault ({:#x})", frame.error_code)),
+        13 => (SIGSEGV, format!("general protection fault ({:#x})", frame.error_code)),
+        _ => {
+            let addr = x86_64::registers::control::Cr2::read();
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            let write_to_read_only = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+            if error_code.contains(write_to_read_only)
+                && thread::address_space().map_or(false, |space| space.copy_on_write(addr))
+            {
+                return;
+            }
+            (SIGSEGV, format!("page fault at {:?} ({:?})", addr, error_code))
+        }
+    };
+    log::debug!(
+        "Thread {}: {} at {:?}, raising signal {}",
+        thread::current(),
+        what,
+        VirtAddr::new(frame.rip),
+        signal
+    );
+    drop(what); // force() doesn't return
+    signal::force(signal, &frame.registers());
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
     println!("CPU EXCEPTION: BREAKPOINT.\n{:#?}", stack_frame);
 }
 
@@ -50,50 +326,114 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    let _gs = KernelGs::enter(stack_frame);
     /* error code always 0 */
     println!("CPU EXCEPTION: DOUBLE FAULT.\n{:#?}", stack_frame);
     crate::sleep_loop(); // stay here as we can't recover (function returns bottom).
 }
 
-extern "x86-interrupt" fn page_fault_handler(
+#[no_mangle]
+extern "x86-interrupt" fn mtos_divide_error_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    println!("CPU EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+    crate::sleep_loop();
+}
+
+#[no_mangle]
+extern "x86-interrupt" fn mtos_invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    println!("CPU EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+    crate::sleep_loop();
+}
+
+#[no_mangle]
+extern "x86-interrupt" fn mtos_stack_segment_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(stack_frame);
+    println!("CPU EXCEPTION: STACK SEGMENT FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+    crate::sleep_loop();
+}
+
+#[no_mangle]
+extern "x86-interrupt" fn mtos_general_protection_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(stack_frame);
+    println!("CPU EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+    crate::sleep_loop();
+}
+
+#[no_mangle]
+extern "x86-interrupt" fn mtos_page_fault_handler(
     stack_frame: &mut InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: PageFaultErrorCode,
 ) {
+    let _gs = KernelGs::enter(stack_frame);
+    let addr = x86_64::registers::control::Cr2::read();
     println!("CPU EXCEPTION: PAGE FAULT");
-    println!(
-        "Attempted access to virutal address: {:?}",
-        x86_64::registers::control::Cr2::read()
-    );
+    println!("Attempted access to virutal address: {:?}", addr);
     println!("{:#?}", stack_frame);
     crate::sleep_loop();
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn timer_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
+    crate::time::tick();
     unsafe { PICS.lock().notify_end_of_interrupt(TIMER_INTERRUPT_ID) }
+    /* May switch to another thread; we come back here when this one is next scheduled. */
+    crate::thread::timer_tick();
+    if from_user(stack_frame) {
+        signal::handle_preempted();
+    }
 }
 
-extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+/* An AP's tick. Only the boot CPU's (the PIT's) count time; this is just for scheduling. */
+extern "x86-interrupt" fn apic_timer_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
+    apic::end_of_interrupt();
+    crate::thread::timer_tick();
+    if from_user(stack_frame) {
+        signal::handle_preempted();
     }
+}
 
-    let mut keyboard = KEYBOARD.lock();
+extern "x86-interrupt" fn keyboard_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
     let mut port = x86_64::instructions::port::Port::new(PORT_PS2_DATA);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(c) => print!("{}", c),
-                DecodedKey::RawKey(k) => print!("{:?}", k),
-            }
-        }
-    }
+    crate::keyboard::add_scancode(scancode);
 
     unsafe { PICS.lock().notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID) }
 }
+
+extern "x86-interrupt" fn com2_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
+    crate::serial::handle_irq(IRQ_COM2);
+    unsafe { PICS.lock().notify_end_of_interrupt(COM2_INTERRUPT_ID) }
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    // nothing to do (not even with GS), and no EOI
+}
+
+extern "x86-interrupt" fn com1_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
+    crate::serial::handle_irq(IRQ_COM1);
+    unsafe { PICS.lock().notify_end_of_interrupt(COM1_INTERRUPT_ID) }
+}
+
+extern "x86-interrupt" fn reschedule_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
+    apic::end_of_interrupt();
+    crate::thread::reschedule();
+}
+
+extern "x86-interrupt" fn call_function_handler(stack_frame: &mut InterruptStackFrame) {
+    let _irq = IrqContext::enter(stack_frame);
+    ipi::run_calls();
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn halt_handler(_stack_frame: &mut InterruptStackFrame) {
+    x86_64::instructions::interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
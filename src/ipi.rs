@@ -0,0 +1,127 @@
+//! Inter-processor interrupts: asking other CPUs to reschedule, to run a function, or to stop.
+//! Before the APIC is up there are no other CPUs to ask, and sending does nothing.
+
+use crate::apic::{self, Destination};
+use crate::{percpu, smp};
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+pub const RESCHEDULE_VECTOR: u8 = 0xf0;
+pub const CALL_FUNCTION_VECTOR: u8 = 0xf1;
+pub const HALT_VECTOR: u8 = 0xf2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// Something was queued on the CPU's run queue; pick again.
+    Reschedule,
+    /// Run the functions waiting in the CPU's call queue. Use `call()` rather than sending this.
+    CallFunction,
+    /// Stop for good, with interrupts off.
+    Halt,
+}
+
+impl Message {
+    fn vector(self) -> u8 {
+        match self {
+            Message::Reschedule => RESCHEDULE_VECTOR,
+            Message::CallFunction => CALL_FUNCTION_VECTOR,
+            Message::Halt => HALT_VECTOR,
+        }
+    }
+}
+
+/// Which CPUs, by CPU number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Cpu(usize),
+    All,
+    Others,
+}
+
+impl Target {
+    fn includes(self, cpu: usize, this_cpu: usize) -> bool {
+        match self {
+            Target::Cpu(c) => c == cpu,
+            Target::All => true,
+            Target::Others => cpu != this_cpu,
+        }
+    }
+}
+
+pub fn send(target: Target, msg: Message) {
+    if !apic::is_initialized() {
+        return;
+    }
+    let dest = match target {
+        Target::Cpu(cpu) => Destination::Apic(smp::apic_id(cpu)),
+        Target::All => Destination::All,
+        Target::Others => Destination::AllButSelf,
+    };
+    apic::send_ipi(dest, msg.vector());
+}
+
+/* A function queued on another CPU by call(). The references really live on the caller's stack;
+ * it doesn't return until every CPU has run the function and dropped its copy. */
+#[derive(Clone, Copy)]
+pub(crate) struct CallRequest {
+    func: &'static (dyn Fn() + Sync),
+    pending: &'static AtomicUsize,
+}
+
+unsafe impl Send for CallRequest {}
+
+/// Run `f` on each target CPU, from an interrupt handler on the others, and wait for all of them
+/// to finish. Offline CPUs are skipped.
+///
+/// Mustn't be called with a spinlock held: a target spinning on it with interrupts off would
+/// never take the IPI. Two CPUs calling each other at once is fine, as waiting runs calls made
+/// to this CPU.
+pub fn call<F: Fn() + Sync>(target: Target, f: F) {
+    interrupts::without_interrupts(|| {
+        let this_cpu = percpu::cpu_id();
+        let pending = AtomicUsize::new(0);
+        let req = unsafe {
+            CallRequest {
+                func: mem::transmute::<&(dyn Fn() + Sync), &'static (dyn Fn() + Sync)>(&f),
+                pending: &*(&pending as *const AtomicUsize),
+            }
+        };
+
+        let mut remote = 0;
+        for cpu in 0..smp::cpu_count() {
+            if cpu != this_cpu && target.includes(cpu, this_cpu) && smp::is_online(cpu) {
+                pending.fetch_add(1, Ordering::SeqCst);
+                percpu::of(cpu).unwrap().calls.lock().push(req);
+                remote += 1;
+            }
+        }
+        if remote > 0 {
+            let target = if target == Target::All { Target::Others } else { target };
+            send(target, Message::CallFunction);
+        }
+
+        if target.includes(this_cpu, this_cpu) {
+            f();
+        }
+        while pending.load(Ordering::Acquire) > 0 {
+            run_calls();
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+}
+
+/// Run whatever other CPUs have queued for this one. Interrupts must be off.
+pub(crate) fn run_calls() {
+    let calls = mem::replace(&mut *percpu!(calls).lock(), Vec::new());
+    for req in calls {
+        (req.func)();
+        req.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Stop every other CPU, eg on panic.
+pub fn halt_others() {
+    send(Target::Others, Message::Halt);
+}
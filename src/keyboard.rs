@@ -0,0 +1,157 @@
+use crate::sync::{Mutex, WaitQueue};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use spin::Once;
+
+pub use pc_keyboard::{DecodedKey, KeyCode};
+
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+/* Set 1 scancodes the interrupt handler looks at itself, for Ctrl-C. Right Ctrl is left Ctrl's
+ * after an 0xe0 prefix. */
+const SCANCODE_CTRL: u8 = 0x1d;
+const SCANCODE_CTRL_RELEASE: u8 = 0x9d;
+const SCANCODE_C: u8 = 0x2e;
+
+/* Filled by the interrupt handler, so it must never block: a lock-free queue, and a Once rather
+ * than a lazy_static so the handler can't end up being the one to allocate it. */
+static SCANCODE_QUEUE: Once<ArrayQueue<u8>> = Once::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/* Threads blocked in read_key(). */
+static READERS: WaitQueue = WaitQueue::new();
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/* Whether Ctrl is down, as far as the interrupt handler's concerned. */
+static CTRL_DOWN: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /* Decoding is stateful (shift, caps lock, multi-byte scancodes), so all readers share one
+     * decoder. It's only used outside interrupt context. */
+    static ref DECODER: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+}
+
+/// Allocate the scancode queue; keypresses before this are dropped. Needs the heap.
+pub fn init() {
+    SCANCODE_QUEUE.call_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE));
+}
+
+/// Called from the keyboard interrupt handler. Ctrl-C interrupts the foreground process rather
+/// than being queued.
+pub(crate) fn add_scancode(scancode: u8) {
+    match scancode {
+        SCANCODE_CTRL => CTRL_DOWN.store(true, Ordering::Relaxed),
+        SCANCODE_CTRL_RELEASE => CTRL_DOWN.store(false, Ordering::Relaxed),
+        SCANCODE_C if CTRL_DOWN.load(Ordering::Relaxed) => {
+            crate::process::signal::interrupt_foreground();
+            return;
+        }
+        _ => (),
+    }
+    match SCANCODE_QUEUE.r#try() {
+        Some(queue) => {
+            if queue.push(scancode).is_ok() {
+                WAKER.wake();
+                READERS.wake_all();
+            } else {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Scancodes lost because the queue was full (or not yet allocated).
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Decode queued scancodes until one completes a keypress, or the queue runs dry.
+pub fn try_read_key() -> Option<DecodedKey> {
+    let queue = SCANCODE_QUEUE.r#try()?;
+    let mut decoder = DECODER.lock();
+
+    while let Ok(scancode) = queue.pop() {
+        if let Ok(Some(event)) = decoder.add_byte(scancode) {
+            if let Some(key) = decoder.process_keyevent(event) {
+                return Some(key);
+            }
+        }
+    }
+    None
+}
+
+/// Block until a key is pressed.
+pub fn read_key() -> DecodedKey {
+    read_key_unless(|| false).unwrap()
+}
+
+/// Block until a key is pressed, or give up with None once `interrupted` returns true. It's
+/// checked before each wait, and whenever the waiting thread is woken.
+pub fn read_key_unless<F: FnMut() -> bool>(mut interrupted: F) -> Option<DecodedKey> {
+    loop {
+        if let Some(key) = try_read_key() {
+            return Some(key);
+        }
+        let mut gave_up = false;
+        READERS.wait_until(|| {
+            gave_up = interrupted();
+            gave_up || SCANCODE_QUEUE.r#try().map_or(false, |q| !q.is_empty())
+        });
+        if gave_up {
+            return None;
+        }
+    }
+}
+
+/// Keypresses as an async stream. Any number can exist, but they share one queue, so each
+/// keypress goes to only one of them.
+pub struct KeyStream {
+    _private: (),
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        KeyStream { _private: () }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        if let Some(key) = try_read_key() {
+            return Poll::Ready(Some(key));
+        }
+
+        WAKER.register(&cx.waker());
+        /* A scancode could have arrived before the waker was registered; look again. */
+        match try_read_key() {
+            Some(key) => {
+                WAKER.take();
+                Poll::Ready(Some(key))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Consumer that echoes keypresses to the screen, as the interrupt handler used to.
+pub async fn print_keypresses() {
+    use futures_util::stream::StreamExt;
+
+    let mut keys = KeyStream::new();
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode(c) => crate::print!("{}", c),
+            DecodedKey::RawKey(k) => crate::print!("{:?}", k),
+        }
+    }
+}
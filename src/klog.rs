@@ -0,0 +1,330 @@
+use crate::{cmdline, serial, time, vga};
+use core::fmt::{self, Write};
+use log::{LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+
+/* Sinks & the dmesg buffer are all static so that we can log before the heap is up. */
+const MAX_SINKS: usize = 8;
+const MAX_FILTERS: usize = 8;
+const DMESG_SIZE: usize = 16 * 1024;
+
+/// Somewhere log lines go. Called with interrupts off, so must not block.
+pub trait Sink: Sync {
+    fn name(&self) -> &'static str;
+    fn write_line(&self, line: fmt::Arguments);
+}
+
+#[derive(Clone, Copy)]
+struct SinkEntry {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+static SINKS: Mutex<[Option<SinkEntry>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+static FILTERS: Mutex<Filters> = Mutex::new(Filters::new());
+
+/// Per-module levels: records from a target under one of the prefixes are dropped if they're
+/// above its level, whatever the sinks would take. The longest matching prefix wins.
+struct Filters {
+    entries: [Option<(&'static str, LevelFilter)>; MAX_FILTERS],
+}
+
+impl Filters {
+    const fn new() -> Self {
+        Filters { entries: [None; MAX_FILTERS] }
+    }
+
+    /// Set the level for targets under `prefix`; false if there's no room for another.
+    fn set(&mut self, prefix: &'static str, level: LevelFilter) -> bool {
+        let slot = match self.entries.iter().position(|e| e.map_or(false, |(p, _)| p == prefix)) {
+            Some(i) => i,
+            None => match self.entries.iter().position(Option::is_none) {
+                Some(i) => i,
+                None => return false,
+            },
+        };
+        self.entries[slot] = Some((prefix, level));
+        true
+    }
+
+    /// Take a comma-separated list of `<prefix>=<level>`, eg `mtos::smp=debug,mtos::thread=off`,
+    /// skipping anything that doesn't parse.
+    fn parse(&mut self, list: &'static str) {
+        for item in list.split(',') {
+            let mut kv = item.rsplitn(2, '=');
+            match (kv.next().map(str::parse), kv.next()) {
+                (Some(Ok(level)), Some(prefix)) if !prefix.is_empty() => {
+                    self.set(prefix, level);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn level(&self, target: &str) -> Option<LevelFilter> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| {
+                /* Whole path segments only: `mtos::smp` isn't under `mtos::sm`. */
+                target.starts_with(prefix)
+                    && (target.len() == prefix.len() || target[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    }
+
+    fn allows(&self, metadata: &Metadata) -> bool {
+        self.level(metadata.target()).map_or(true, |level| level >= metadata.level())
+    }
+}
+
+static LOGGER: KernelLogger = KernelLogger;
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        max_sink_level() >= metadata.level()
+            && x86_64::instructions::interrupts::without_interrupts(|| FILTERS.lock().allows(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        /* The log macros only check the max level, not enabled(). */
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let ts = time::uptime();
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            /* Sinks print, so interrupt handlers logging would deadlock with this code. */
+            for entry in SINKS.lock().iter().flatten() {
+                if entry.level >= record.level() {
+                    entry.sink.write_line(format_args!(
+                        "[{:5}.{:03}] {:5} {}: {}\n",
+                        ts.as_secs(),
+                        ts.subsec_millis(),
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    ));
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {
+        serial::flush();
+    }
+}
+
+fn max_sink_level() -> LevelFilter {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SINKS
+            .lock()
+            .iter()
+            .flatten()
+            .map(|e| e.level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    })
+}
+
+/// Install the logger with the VGA, serial, and dmesg sinks. Each sink's level defaults as below
+/// and can be overridden on the command line with eg `loglevel.vga=debug`; `loglevel=` sets them all.
+/// Modules can be quietened with eg `logfilter=mtos::smp=warn,mtos::thread=off`.
+pub fn init() {
+    add_sink(&VGA_SINK, default_level("loglevel.vga", LevelFilter::Warn));
+    add_sink(&SERIAL_SINK, default_level("loglevel.serial", LevelFilter::Info));
+    add_sink(&DMESG, default_level("loglevel.dmesg", LevelFilter::Debug));
+    if let Some(list) = cmdline::get("logfilter") {
+        x86_64::instructions::interrupts::without_interrupts(|| FILTERS.lock().parse(list));
+    }
+
+    log::set_logger(&LOGGER).expect("Logger already installed");
+    log::set_max_level(max_sink_level());
+}
+
+fn default_level(key: &str, level: LevelFilter) -> LevelFilter {
+    cmdline::get(key)
+        .or_else(|| cmdline::get("loglevel"))
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(level)
+}
+
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|s| s.is_none())
+            .expect("Too many log sinks");
+        *slot = Some(SinkEntry { sink, level });
+    });
+    log::set_max_level(max_sink_level());
+}
+
+/// Change a sink's level filter at runtime. Returns false if there's no sink by that name.
+pub fn set_level(name: &str, level: LevelFilter) -> bool {
+    let found = x86_64::instructions::interrupts::without_interrupts(|| {
+        match SINKS.lock().iter_mut().flatten().find(|e| e.sink.name() == name) {
+            Some(entry) => {
+                entry.level = level;
+                true
+            }
+            None => false,
+        }
+    });
+    log::set_max_level(max_sink_level());
+    found
+}
+
+/// Drop records from targets under `prefix` (a module path, eg `mtos::smp`) above `level`, at
+/// runtime. Returns false if there are too many filters already.
+pub fn set_filter(prefix: &'static str, level: LevelFilter) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| FILTERS.lock().set(prefix, level))
+}
+
+pub struct VgaSink;
+pub static VGA_SINK: VgaSink = VgaSink;
+
+impl Sink for VgaSink {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write_line(&self, line: fmt::Arguments) {
+        vga::_print(line);
+    }
+}
+
+pub struct SerialSink;
+pub static SERIAL_SINK: SerialSink = SerialSink;
+
+impl Sink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_line(&self, line: fmt::Arguments) {
+        serial::write_to(serial::port(serial::Role::Log), line);
+    }
+}
+
+/// In-memory ring of the most recent log text. When it wraps, the oldest lines are overwritten.
+pub struct RingSink {
+    ring: Mutex<Ring>,
+}
+
+struct Ring {
+    buf: [u8; DMESG_SIZE],
+    next: usize,
+    wrapped: bool,
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.buf[self.next] = b;
+            self.next += 1;
+            if self.next == DMESG_SIZE {
+                self.next = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub static DMESG: RingSink = RingSink {
+    ring: Mutex::new(Ring {
+        buf: [0; DMESG_SIZE],
+        next: 0,
+        wrapped: false,
+    }),
+};
+
+impl Sink for RingSink {
+    fn name(&self) -> &'static str {
+        "dmesg"
+    }
+
+    fn write_line(&self, line: fmt::Arguments) {
+        self.ring.lock().write_fmt(line).unwrap();
+    }
+}
+
+impl RingSink {
+    /// Replay the buffer, oldest first. If it has wrapped, the first (partial) line is skipped.
+    pub fn replay(&self, out: &mut dyn Write) -> fmt::Result {
+        let ring = x86_64::instructions::interrupts::without_interrupts(|| {
+            /* Copy it out so we don't hold the lock (with interrupts off) while `out` prints. */
+            let r = self.ring.lock();
+            let mut copy = alloc::vec::Vec::with_capacity(DMESG_SIZE);
+            if r.wrapped {
+                copy.extend_from_slice(&r.buf[r.next..]);
+            }
+            copy.extend_from_slice(&r.buf[..r.next]);
+            (copy, r.wrapped)
+        });
+
+        let (bytes, wrapped) = ring;
+        let start = if wrapped {
+            bytes.iter().position(|&b| b == b'\n').map_or(bytes.len(), |i| i + 1)
+        } else {
+            0
+        };
+        for chunk in bytes[start..].split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            out.write_str(core::str::from_utf8(chunk).unwrap_or("<garbled>"))?;
+            out.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the kernel log buffer to the screen.
+pub fn dmesg() {
+    struct Screen;
+    impl Write for Screen {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            vga::_print(format_args!("{}", s));
+            Ok(())
+        }
+    }
+
+    DMESG.replay(&mut Screen).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, MetadataBuilder};
+
+    fn allows(filters: &Filters, target: &str, level: Level) -> bool {
+        filters.allows(&MetadataBuilder::new().target(target).level(level).build())
+    }
+
+    #[test]
+    fn filtered_modules_are_suppressed() {
+        let mut filters = Filters::new();
+        filters.parse("mtos::smp=off,mtos::thread=warn,mtos::thread::scheduler=debug");
+        assert!(!allows(&filters, "mtos::smp", Level::Error));
+        assert!(!allows(&filters, "mtos::smp::inner", Level::Error));
+        assert!(allows(&filters, "mtos::smpx", Level::Info));
+        assert!(allows(&filters, "mtos::thread", Level::Warn));
+        assert!(!allows(&filters, "mtos::thread", Level::Info));
+        /* The longer prefix wins. */
+        assert!(allows(&filters, "mtos::thread::scheduler", Level::Debug));
+        assert!(allows(&filters, "mtos::serial", Level::Trace));
+    }
+
+    #[test]
+    fn bad_filters_are_skipped() {
+        let mut filters = Filters::new();
+        filters.parse("mtos::smp=loud,,=,mtos::vfs");
+        assert!(filters.entries.iter().all(Option::is_none));
+        filters.parse("mtos::vfs=error");
+        filters.parse("mtos::vfs=off");
+        assert!(!allows(&filters, "mtos::vfs", Level::Error));
+        assert_eq!(filters.entries.iter().flatten().count(), 1);
+    }
+}
@@ -1,22 +1,48 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
+#![feature(wake_trait)]
+#![feature(global_asm)]
+#![feature(track_caller)]
+#![cfg_attr(feature = "lockdep", feature(const_caller_location))]
 #![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
 // re-export these
+pub mod acpi;
+pub mod address_space;
 pub mod allocator;
+pub mod apic;
+pub mod cmdline;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
+pub mod ipi;
+pub mod keyboard;
+pub mod klog;
+pub mod loader;
 pub mod memory;
+pub mod percpu;
+pub mod process;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod testing;
+pub mod thread;
+pub mod time;
+pub mod tlb;
+pub mod user;
+pub mod vfs;
 pub mod vga;
 
-use linked_list_allocator::LockedHeap;
-
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+/* Host unit tests use std's allocator. */
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: allocator::IrqSafeHeap = allocator::IrqSafeHeap::empty();
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("Allocation error for: {:?}", layout)
@@ -30,6 +56,7 @@ pub fn sleep_loop() -> ! {
 
 // Return type should really be bottom, but idk how to tell rustc that port.write() won't return.
 pub unsafe fn exit_qemu() -> () {
+    serial::flush();
     let mut port = x86_64::instructions::port::Port::<u32>::new(0xf4);
     port.write(0);
 }
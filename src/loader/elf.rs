@@ -0,0 +1,388 @@
+//! Parsing ELF64 executables, as much as loading them needs. Everything is read from the byte
+//! slice with bounds checks, as images come from untrusted places; nothing here touches the rest
+//! of the kernel, so it's unit tested on the host.
+
+use core::convert::TryInto;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const DT_NULL: u64 = 0;
+pub const DT_RELA: u64 = 7;
+pub const DT_RELASZ: u64 = 8;
+pub const DT_RELAENT: u64 = 9;
+pub const DT_REL: u64 = 17;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    /// Not 64-bit, little-endian, version 1.
+    WrongFormat,
+    NotX86_64,
+    /// Not an executable or position-independent executable.
+    NotExecutable,
+    BadProgramHeaders,
+    /// A segment that lies outside the file, or is bigger in the file than in memory.
+    BadSegment,
+    BadDynamic,
+    /// A relocation type the loader can't do; static PIEs only need `R_X86_64_RELATIVE`.
+    UnsupportedRelocation(u32),
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at.checked_add(2)?)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at.checked_add(4)?)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at.checked_add(8)?)?.try_into().ok()?))
+}
+
+/// A program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl Segment {
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    /// Whether `vaddr` is backed by the file in this segment.
+    fn file_contains(&self, vaddr: u64) -> bool {
+        vaddr >= self.vaddr && vaddr - self.vaddr < self.file_size
+    }
+}
+
+/// A RELA relocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rela {
+    pub offset: u64,
+    pub kind: u32,
+    pub symbol: u32,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    kind: u16,
+    entry: u64,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the header and program headers.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if &data[0..4] != b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        /* class 64-bit, little-endian, version 1 */
+        if data[4] != 2 || data[5] != 1 || data[6] != 1 {
+            return Err(ElfError::WrongFormat);
+        }
+        let kind = read_u16(data, 16).unwrap();
+        if read_u16(data, 18).unwrap() != EM_X86_64 {
+            return Err(ElfError::NotX86_64);
+        }
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let elf = Elf {
+            data,
+            kind,
+            entry: read_u64(data, 24).unwrap(),
+            phoff: read_u64(data, 32).unwrap() as usize,
+            phentsize: usize::from(read_u16(data, 54).unwrap()),
+            phnum: usize::from(read_u16(data, 56).unwrap()),
+        };
+        let table_len = elf.phentsize.checked_mul(elf.phnum).ok_or(ElfError::BadProgramHeaders)?;
+        let table_end = elf.phoff.checked_add(table_len).ok_or(ElfError::BadProgramHeaders)?;
+        if elf.phentsize < PHDR_SIZE || table_end > data.len() {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        for seg in elf.segments() {
+            let file_end = seg.offset.checked_add(seg.file_size).ok_or(ElfError::BadSegment)?;
+            let in_file = file_end <= data.len() as u64;
+            let fits = seg.vaddr.checked_add(seg.mem_size).is_some();
+            if seg.kind == PT_LOAD && (!in_file || !fits || seg.file_size > seg.mem_size) {
+                return Err(ElfError::BadSegment);
+            }
+            if seg.kind == PT_DYNAMIC && !in_file {
+                return Err(ElfError::BadDynamic);
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Position independent, ie can be loaded anywhere (with relocation).
+    pub fn is_pie(&self) -> bool {
+        self.kind == ET_DYN
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn phnum(&self) -> usize {
+        self.phnum
+    }
+
+    pub fn phentsize(&self) -> usize {
+        self.phentsize
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        let (data, phoff, phentsize) = (self.data, self.phoff, self.phentsize);
+        (0..self.phnum).map(move |i| {
+            let at = phoff + i * phentsize;
+            Segment {
+                kind: read_u32(data, at).unwrap(),
+                flags: read_u32(data, at + 4).unwrap(),
+                offset: read_u64(data, at + 8).unwrap(),
+                vaddr: read_u64(data, at + 16).unwrap(),
+                file_size: read_u64(data, at + 32).unwrap(),
+                mem_size: read_u64(data, at + 40).unwrap(),
+            }
+        })
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        self.segments().filter(|s| s.kind == PT_LOAD)
+    }
+
+    /// The part of a segment that comes from the file. `parse()` checked it's all there.
+    pub fn segment_data(&self, seg: &Segment) -> &'a [u8] {
+        &self.data[seg.offset as usize..(seg.offset + seg.file_size) as usize]
+    }
+
+    /// Where the program headers end up in memory (before relocation), for the auxv.
+    pub fn phdr_vaddr(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments().find(|s| s.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        let phoff = self.phoff as u64;
+        self.load_segments()
+            .find(|s| phoff >= s.offset && phoff - s.offset < s.file_size)
+            .map(|s| s.vaddr + (phoff - s.offset))
+    }
+
+    /// File offset of `len` bytes at `vaddr`, if the file has them all in one segment.
+    fn vaddr_to_offset(&self, vaddr: u64, len: u64) -> Option<usize> {
+        let seg = self.load_segments().find(|s| s.file_contains(vaddr))?;
+        if len > 0 && !seg.file_contains(vaddr.checked_add(len - 1)?) {
+            return None;
+        }
+        Some((seg.offset + (vaddr - seg.vaddr)) as usize)
+    }
+
+    /// The relocations to apply when loading, from the dynamic section. None for a static
+    /// executable.
+    pub fn relocations(&self) -> Result<impl Iterator<Item = Rela> + 'a, ElfError> {
+        let (mut rela, mut rela_size, mut rela_ent) = (None, 0, RELA_SIZE as u64);
+        if let Some(dynamic) = self.segments().find(|s| s.kind == PT_DYNAMIC) {
+            let entries = self.segment_data(&dynamic).chunks_exact(DYN_SIZE);
+            for entry in entries {
+                let tag = read_u64(entry, 0).unwrap();
+                let value = read_u64(entry, 8).unwrap();
+                match tag {
+                    DT_NULL => break,
+                    DT_RELA => rela = Some(value),
+                    DT_RELASZ => rela_size = value,
+                    DT_RELAENT => rela_ent = value,
+                    DT_REL => return Err(ElfError::BadDynamic), // x86_64 only uses RELA
+                    _ => (),
+                }
+            }
+        }
+        if rela_ent != RELA_SIZE as u64 {
+            return Err(ElfError::BadDynamic);
+        }
+
+        let table = match rela {
+            Some(addr) if rela_size > 0 => {
+                let at = self.vaddr_to_offset(addr, rela_size).ok_or(ElfError::BadDynamic)?;
+                &self.data[at..at + rela_size as usize]
+            }
+            _ => &[][..],
+        };
+        Ok(table.chunks_exact(RELA_SIZE).map(|entry| {
+            let info = read_u64(entry, 8).unwrap();
+            Rela {
+                offset: read_u64(entry, 0).unwrap(),
+                kind: info as u32,
+                symbol: (info >> 32) as u32,
+                addend: read_u64(entry, 16).unwrap() as i64,
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dynamic, rela, ElfBuilder};
+
+    #[test]
+    fn parses_static_executable() {
+        let image = ElfBuilder::new(ET_EXEC, 0x40_1000)
+            .segment(PT_LOAD, PF_R | PF_X, 0x40_1000, &[0x90, 0xc3], 2)
+            .segment(PT_LOAD, PF_R | PF_W, 0x40_2000, &[1, 2, 3, 4], 0x2000)
+            .build();
+        let elf = Elf::parse(&image).unwrap();
+        assert!(!elf.is_pie());
+        assert_eq!(elf.entry(), 0x40_1000);
+
+        let segs: Vec<Segment> = elf.load_segments().collect();
+        assert_eq!(segs.len(), 2);
+        assert!(segs[0].executable() && !segs[0].writable());
+        assert!(segs[1].writable() && !segs[1].executable());
+        assert_eq!(segs[1].mem_size, 0x2000);
+        assert_eq!(elf.segment_data(&segs[0]), &[0x90, 0xc3]);
+        assert_eq!(elf.segment_data(&segs[1]), &[1, 2, 3, 4]);
+        assert_eq!(elf.relocations().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn finds_program_headers_in_memory() {
+        /* The first segment starts at file offset 0 in real executables, covering the headers. */
+        let mut image = ElfBuilder::new(ET_EXEC, 0x40_0000).segment(PT_LOAD, PF_R, 0x40_0000, &[], 0).build();
+        let len = image.len() as u64;
+        image[EHDR_SIZE + 8..EHDR_SIZE + 16].copy_from_slice(&0u64.to_le_bytes());
+        image[EHDR_SIZE + 32..EHDR_SIZE + 40].copy_from_slice(&len.to_le_bytes());
+        image[EHDR_SIZE + 40..EHDR_SIZE + 48].copy_from_slice(&len.to_le_bytes());
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.phdr_vaddr(), Some(0x40_0000 + EHDR_SIZE as u64));
+
+        let image = ElfBuilder::new(ET_EXEC, 0).segment(PT_PHDR, PF_R, 0x40_0040, &[], 0).build();
+        assert_eq!(Elf::parse(&image).unwrap().phdr_vaddr(), Some(0x40_0040));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let good = ElfBuilder::new(ET_EXEC, 0).segment(PT_LOAD, PF_R, 0, &[0; 16], 16).build();
+        assert!(Elf::parse(&good).is_ok());
+
+        assert_eq!(Elf::parse(&good[..40]).unwrap_err(), ElfError::TooShort);
+
+        let mut bad = good.clone();
+        bad[1] = b'X';
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::BadMagic);
+
+        let mut bad = good.clone();
+        bad[4] = 1; // 32-bit
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::WrongFormat);
+
+        let mut bad = good.clone();
+        bad[18] = 3; // i386
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::NotX86_64);
+
+        let mut bad = good.clone();
+        bad[16] = 1; // relocatable object
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::NotExecutable);
+
+        let mut bad = good.clone();
+        bad[56] = 200; // more program headers than there's room for
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::BadProgramHeaders);
+    }
+
+    #[test]
+    fn rejects_bad_segments() {
+        /* Bigger in the file than in memory */
+        let image = ElfBuilder::new(ET_EXEC, 0).segment(PT_LOAD, PF_R, 0x1000, &[0; 16], 8).build();
+        assert_eq!(Elf::parse(&image).unwrap_err(), ElfError::BadSegment);
+
+        /* Past the end of the file */
+        let image = ElfBuilder::new(ET_EXEC, 0).segment(PT_LOAD, PF_R, 0x1000, &[0; 16], 16).build();
+        assert_eq!(Elf::parse(&image[..image.len() - 1]).unwrap_err(), ElfError::BadSegment);
+
+        /* Wrapping round the address space */
+        let image = ElfBuilder::new(ET_EXEC, 0).segment(PT_LOAD, PF_R, u64::max_value(), &[], 16).build();
+        assert_eq!(Elf::parse(&image).unwrap_err(), ElfError::BadSegment);
+    }
+
+    /// A PIE with its RELA table at 0x2000 and the dynamic section after it.
+    fn pie(relocs: &[Vec<u8>], dyn_entries: &[(u64, u64)]) -> Vec<u8> {
+        let table: Vec<u8> = relocs.concat();
+        ElfBuilder::new(ET_DYN, 0x1000)
+            .segment(PT_LOAD, PF_R | PF_X, 0x1000, &[0xc3], 1)
+            .segment(PT_LOAD, PF_R | PF_W, 0x2000, &table, table.len() as u64 + 0x100)
+            .segment(PT_DYNAMIC, PF_R | PF_W, 0x3000, &dynamic(dyn_entries), 0)
+            .build()
+    }
+
+    #[test]
+    fn parses_pie_relocations() {
+        let relocs = [rela(0x2100, R_X86_64_RELATIVE, 0x1000), rela(0x2108, R_X86_64_RELATIVE, -8)];
+        let image = pie(&relocs, &[(DT_RELA, 0x2000), (DT_RELASZ, 48), (DT_RELAENT, 24)]);
+        let elf = Elf::parse(&image).unwrap();
+        assert!(elf.is_pie());
+        let got: Vec<Rela> = elf.relocations().unwrap().collect();
+        assert_eq!(
+            got,
+            [
+                Rela { offset: 0x2100, kind: R_X86_64_RELATIVE, symbol: 0, addend: 0x1000 },
+                Rela { offset: 0x2108, kind: R_X86_64_RELATIVE, symbol: 0, addend: -8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_relocation_tables() {
+        let relocs = [rela(0x2100, R_X86_64_RELATIVE, 0)];
+
+        /* Table runs past what the file has */
+        let image = pie(&relocs, &[(DT_RELA, 0x2000), (DT_RELASZ, 48)]);
+        assert_eq!(Elf::parse(&image).unwrap().relocations().err(), Some(ElfError::BadDynamic));
+
+        /* Not in any segment */
+        let image = pie(&relocs, &[(DT_RELA, 0x9000), (DT_RELASZ, 24)]);
+        assert_eq!(Elf::parse(&image).unwrap().relocations().err(), Some(ElfError::BadDynamic));
+
+        /* Odd entry size */
+        let image = pie(&relocs, &[(DT_RELA, 0x2000), (DT_RELASZ, 24), (DT_RELAENT, 16)]);
+        assert_eq!(Elf::parse(&image).unwrap().relocations().err(), Some(ElfError::BadDynamic));
+
+        /* REL rather than RELA */
+        let image = pie(&relocs, &[(DT_REL, 0x2000)]);
+        assert_eq!(Elf::parse(&image).unwrap().relocations().err(), Some(ElfError::BadDynamic));
+    }
+}
@@ -0,0 +1,164 @@
+//! Loading ELF64 programs into user memory and starting them in ring 3.
+//!
+//! Programs are static: fixed-address executables, or static PIEs relocated to the bottom of
+//! the user range. Each gets an address space of its own, with the stack at the top of the user
+//! range. A fixed-address program has to be linked inside the user range.
+
+use crate::address_space::AddressSpace;
+use crate::memory;
+use crate::process::{self, Pid};
+use crate::time;
+use crate::user::{MapError, USER_END, USER_START};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use x86_64::VirtAddr;
+
+pub mod elf;
+mod stack;
+
+pub use elf::{Elf, ElfError, Rela, Segment};
+
+/// Where PIEs are loaded.
+pub const PIE_BASE: u64 = USER_START;
+const STACK_PAGES: u64 = 16;
+const STACK_TOP: u64 = USER_END;
+
+/// The most memory a program's segments can take between them, in pages (64MiB), and the most
+/// segments it can have.
+pub const MAX_IMAGE_PAGES: u64 = 16384;
+pub const MAX_SEGMENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    Map(MapError),
+    /// The arguments and environment don't fit on the stack.
+    ArgumentsTooLong,
+    /// The segments need more memory than a program can have, or than there is, or there are too
+    /// many of them.
+    TooBig,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        LoadError::Elf(e)
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(e: MapError) -> Self {
+        LoadError::Map(e)
+    }
+}
+
+/// A program ready to run: its address space, and where to start it.
+pub struct Program {
+    pub space: AddressSpace,
+    /// Where the image was loaded: what's added to a PIE's addresses, or 0.
+    pub base: u64,
+    pub entry: VirtAddr,
+    pub stack: VirtAddr,
+}
+
+/// Map `image`'s segments, apply its relocations, and set up a stack holding `argv` and `envp`.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, LoadError> {
+    let elf = Elf::parse(image)?;
+    let base = if elf.is_pie() { PIE_BASE } else { 0 };
+    let stack_bottom = STACK_TOP - STACK_PAGES * 4096;
+    let space = AddressSpace::new()?;
+
+    /* A segment's first and last pages can be shared with its neighbours, and then get both's
+     * permissions; the pages between are its own, and are mapped straight away. */
+    let mut edges = BTreeMap::new();
+    let mut total = 0;
+    if elf.load_segments().count() > MAX_SEGMENTS {
+        return Err(LoadError::TooBig);
+    }
+    for seg in elf.load_segments().filter(|seg| seg.mem_size > 0) {
+        let start = user_addr(base, seg.vaddr)?.as_u64() & !0xfff;
+        let end = user_addr(base, seg.vaddr.checked_add(seg.mem_size).ok_or(ElfError::BadSegment)?)?.as_u64();
+        if end > stack_bottom {
+            return Err(MapError::OutOfRange.into());
+        }
+        let last = (end - 1) & !0xfff;
+        total += (last - start) / 4096 + 1;
+        if total > MAX_IMAGE_PAGES || total > memory::free_frames() as u64 {
+            return Err(LoadError::TooBig);
+        }
+        if last > start + 4096 {
+            space.map(VirtAddr::new(start + 4096), (last - start) / 4096 - 1, seg.writable(), seg.executable())?;
+        }
+        for &page in &[start, last] {
+            let perms = edges.entry(page).or_insert((false, false));
+            perms.0 |= seg.writable();
+            perms.1 |= seg.executable();
+        }
+    }
+    for (&page, &(writable, executable)) in &edges {
+        space.map(VirtAddr::new(page), 1, writable, executable)?;
+    }
+    for seg in elf.load_segments() {
+        space.write(VirtAddr::new(base + seg.vaddr), elf.segment_data(&seg))?;
+    }
+
+    for rela in elf.relocations()? {
+        match rela.kind {
+            elf::R_X86_64_NONE => {}
+            elf::R_X86_64_RELATIVE => {
+                let value = base.wrapping_add(rela.addend as u64);
+                space.write(user_addr(base, rela.offset)?, &value.to_le_bytes())?;
+            }
+            kind => return Err(ElfError::UnsupportedRelocation(kind).into()),
+        }
+    }
+
+    let entry = user_addr(base, elf.entry())?;
+    space.map(VirtAddr::new(stack_bottom), STACK_PAGES, true, false)?;
+    let mut auxv = vec![
+        (stack::AT_PHENT, elf.phentsize() as u64),
+        (stack::AT_PHNUM, elf.phnum() as u64),
+        (stack::AT_PAGESZ, 4096),
+        (stack::AT_BASE, 0),
+        (stack::AT_ENTRY, entry.as_u64()),
+    ];
+    if let Some(phdr) = elf.phdr_vaddr() {
+        auxv.push((stack::AT_PHDR, base + phdr));
+    }
+    let (contents, rsp) = stack::build(STACK_TOP, argv, envp, &auxv, random_bytes());
+    /* Leave most of the stack for the program. */
+    if contents.len() as u64 > STACK_PAGES * 4096 / 4 {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+    space.write(VirtAddr::new(rsp), &contents)?;
+
+    Ok(Program { space, base, entry, stack: VirtAddr::new(rsp) })
+}
+
+/// Load `image` and start it as a new process.
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, LoadError> {
+    Ok(process::spawn(name, load(image, argv, envp)?))
+}
+
+/// `base + offset`, if that's in the user range.
+fn user_addr(base: u64, offset: u64) -> Result<VirtAddr, LoadError> {
+    match base.checked_add(offset) {
+        Some(addr) if addr >= USER_START && addr <= USER_END => Ok(VirtAddr::new(addr)),
+        _ => Err(MapError::OutOfRange.into()),
+    }
+}
+
+/* For AT_RANDOM, which C libraries seed their stack canaries from. Not cryptographic, but
+ * different every time. */
+fn random_bytes() -> [u8; 16] {
+    let mut state = time::rdtsc();
+    let mut bytes = [0; 16];
+    for chunk in bytes.chunks_mut(8) {
+        /* splitmix64 */
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        chunk.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+    }
+    bytes
+}
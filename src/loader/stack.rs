@@ -0,0 +1,108 @@
+//! A new program's initial stack, as the SysV x86_64 ABI lays it out. From the stack pointer up:
+//! argc, the argv pointers and a NULL, the envp pointers and a NULL, then the auxiliary vector's
+//! (type, value) pairs ending with AT_NULL. The strings they point to go above, at the top.
+
+use alloc::vec::Vec;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// Lay out a stack ending at `top`, with `auxv` plus an AT_RANDOM entry for `random`. Returns its
+/// contents, which go from the returned stack pointer (16-byte aligned) up to `top`.
+pub fn build(top: u64, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)], random: [u8; 16]) -> (Vec<u8>, u64) {
+    let mut strings = Vec::new();
+    let mut offsets = Vec::with_capacity(argv.len() + envp.len());
+    for s in argv.iter().chain(envp) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let random_offset = strings.len() as u64;
+    strings.extend_from_slice(&random);
+    let strings_start = (top - strings.len() as u64) & !0xf;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(offsets[..argv.len()].iter().map(|off| strings_start + off));
+    words.push(0);
+    words.extend(offsets[argv.len()..].iter().map(|off| strings_start + off));
+    words.push(0);
+    for &(kind, value) in auxv.iter().chain(&[(AT_RANDOM, strings_start + random_offset), (AT_NULL, 0)]) {
+        words.push(kind);
+        words.push(value);
+    }
+    let rsp = (strings_start - words.len() as u64 * 8) & !0xf;
+
+    let mut image = vec![0u8; (top - rsp) as usize];
+    for (i, word) in words.iter().enumerate() {
+        image[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    let at = (strings_start - rsp) as usize;
+    image[at..at + strings.len()].copy_from_slice(&strings);
+    (image, rsp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryInto;
+
+    const TOP: u64 = 0x7000_0000;
+
+    struct Stack {
+        image: Vec<u8>,
+        rsp: u64,
+    }
+
+    impl Stack {
+        fn word(&self, i: usize) -> u64 {
+            u64::from_le_bytes(self.image[i * 8..i * 8 + 8].try_into().unwrap())
+        }
+
+        fn string(&self, addr: u64) -> &str {
+            let at = (addr - self.rsp) as usize;
+            let len = self.image[at..].iter().position(|&b| b == 0).unwrap();
+            core::str::from_utf8(&self.image[at..at + len]).unwrap()
+        }
+    }
+
+    #[test]
+    fn lays_out_arguments_and_auxv() {
+        let random = [7u8; 16];
+        let (image, rsp) = build(TOP, &["prog", "-v"], &["HOME=/"], &[(AT_PAGESZ, 4096)], random);
+        let stack = Stack { image, rsp };
+        assert_eq!(rsp % 16, 0);
+        assert_eq!(rsp + stack.image.len() as u64, TOP);
+
+        assert_eq!(stack.word(0), 2);
+        assert_eq!(stack.string(stack.word(1)), "prog");
+        assert_eq!(stack.string(stack.word(2)), "-v");
+        assert_eq!(stack.word(3), 0);
+        assert_eq!(stack.string(stack.word(4)), "HOME=/");
+        assert_eq!(stack.word(5), 0);
+
+        assert_eq!((stack.word(6), stack.word(7)), (AT_PAGESZ, 4096));
+        assert_eq!(stack.word(8), AT_RANDOM);
+        let at = (stack.word(9) - rsp) as usize;
+        assert_eq!(stack.image[at..at + 16], random);
+        assert_eq!((stack.word(10), stack.word(11)), (AT_NULL, 0));
+    }
+
+    #[test]
+    fn copes_with_no_arguments() {
+        let (image, rsp) = build(TOP, &[], &[], &[], [0; 16]);
+        let stack = Stack { image, rsp };
+        assert_eq!(rsp % 16, 0);
+        assert_eq!(stack.word(0), 0);
+        assert_eq!(stack.word(1), 0);
+        assert_eq!(stack.word(2), 0);
+        assert_eq!(stack.word(3), AT_RANDOM);
+        assert_eq!((stack.word(5), stack.word(6)), (AT_NULL, 0));
+    }
+}
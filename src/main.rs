@@ -7,33 +7,55 @@ extern crate raw_cpuid;
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use core::time::Duration;
 use raw_cpuid::{CpuId,CacheType};
 
 use mtos::*;
+use mtos::task::{executor::Executor, Task};
 
 entry_point!(kernel_main);
 
+/* The root filesystem's limits: 2MiB of files, and a third of the heap (about 33KiB) for
+ * everything. The files' page maps can take 16KiB of that, at 32 bytes a page, which leaves the
+ * rest for inodes and names. */
+const ROOT_MAX_PAGES: u64 = 512;
+const ROOT_MAX_HEAP: usize = allocator::HEAP_SIZE / 3;
+
 #[cfg(not(test))]
 pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     gdt::init();
+    syscall::init();
+    tlb::init();
     interrupts::init();
-    let mut mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
-    let mut frame_allocator = memory::BootInfoFrameAllocator_new(&boot_info.memory_map);
-    allocator::init(&mut mapper, &mut frame_allocator).expect("Heap initialisation failed");
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset), &boot_info.memory_map) };
+    memory::with_mapper(|mapper, frame_allocator| allocator::init(mapper, frame_allocator))
+        .expect("Heap initialisation failed");
+    keyboard::init();
+    thread::init();
 
     use x86_64::structures::paging::{Page, PhysFrame};
     use x86_64::{PhysAddr, VirtAddr};
     // Map VGA buffer to 0x1000
-    memory::create_mapping(
-        Page::containing_address(VirtAddr::new(0x1000)),
-        PhysFrame::containing_address(PhysAddr::new(0xb8000)),
-        &mut mapper,
-        &mut frame_allocator,
-    );
+    memory::with_mapper(|mapper, frame_allocator| {
+        memory::create_mapping(
+            Page::containing_address(VirtAddr::new(0x1000)),
+            PhysFrame::containing_address(PhysAddr::new(0xb8000)),
+            mapper,
+            frame_allocator,
+        )
+    });
+    klog::init(); // the VGA sink needs the mapping above
+    serial::init();
+    smp::init();
+
+    let root = vfs::ramfs::RamFs::new(ROOT_MAX_PAGES, ROOT_MAX_HEAP).expect("Can't make the root filesystem");
+    vfs::mount("/", root).expect("Can't mount the root filesystem");
+    initrd::init();
 
     serial_banner();
     console_banner();
     cpu_info();
+    serial::dump_ports();
 
     let x = Box::new(42);
     println!("value on the heap: {} at {:p}", x, x);
@@ -51,19 +73,75 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("current ref count is {}", Rc::strong_count(&clone));
 
     //unsafe { exit_qemu() };
-    mtos::sleep_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(host_console()));
+    executor.spawn(Task::new(heartbeat()));
+    executor.run();
+}
+
+/// Lines typed on the host's end of the shell port (eg `make run-background`) get echoed to the
+/// screen, bar a couple of commands.
+async fn host_console() {
+    loop {
+        let line = serial::read_line_async().await;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["dmesg"] => klog::dmesg(),
+            ["ports"] => serial::dump_ports(),
+            ["threads"] => thread::dump(),
+            ["processes"] => process::dump(),
+            ["cow"] => address_space::dump_stats(),
+            ["kill", pid, sig] => match (pid.parse(), sig.parse()) {
+                (Ok(pid), Ok(sig)) => {
+                    if let Err(e) = process::signal::send(process::Pid::from_u64(pid), sig) {
+                        println!("kill: {:?}", e);
+                    }
+                }
+                _ => println!("usage: kill <pid> <signal>"),
+            },
+            ["cpus"] => {
+                for (i, cpu) in smp::cpus().iter().enumerate() {
+                    println!("cpu{}: apic {} {}", i, cpu.apic_id, if cpu.online { "online" } else { "offline" });
+                }
+            }
+            ["sched", policy] => {
+                if !thread::set_policy(policy) {
+                    println!("unknown policy {}", policy);
+                }
+            }
+            ["prio", id, prio] => match (id.parse(), prio.parse()) {
+                (Ok(id), Ok(prio)) if thread::set_priority(thread::ThreadId::from_u64(id), prio) => (),
+                _ => println!("usage: prio <thread> <0-31>"),
+            },
+            ["nice", id, nice] => match (id.parse(), nice.parse()) {
+                (Ok(id), Ok(nice)) if thread::set_nice(thread::ThreadId::from_u64(id), nice) => (),
+                _ => println!("usage: nice <thread> <-20-19>"),
+            },
+            _ => println!("host> {}", line),
+        }
+    }
+}
+
+async fn heartbeat() {
+    loop {
+        time::sleep(Duration::from_secs(10)).await;
+        log::debug!("uptime {:?}", time::uptime());
+    }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    mtos::ipi::halt_others();
     println!("{}", info);
     mtos::sleep_loop();
 }
 
 fn serial_banner() {
-    serial_println!("mtOS");
-    serial_println!("Hello Host!");
+    log::info!("mtOS");
+    log::info!("Hello Host!");
 }
 fn console_banner() {
     println!("mtOS");
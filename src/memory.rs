@@ -1,21 +1,167 @@
-use crate::println;
+use crate::{println, tlb};
 
+use alloc::vec::Vec;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqSpinLock;
+use lazy_static::lazy_static;
 use x86_64::structures::paging::{
-    FrameAllocator, MappedPageTable, Mapper, MapperAllSizes, Page, PageTable, PageTableIndex, PhysFrame, UnusedPhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PageTableIndex, PhysFrame, UnusedPhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
 const X86_64_PAGE_TABLE_DEPTH: usize = 4;
 type PageTableOffsets = [PageTableIndex; X86_64_PAGE_TABLE_DEPTH];
 
-pub unsafe fn init(phys_mem_offset: VirtAddr) -> impl MapperAllSizes {
-    let l4_table = active_l4_table(phys_mem_offset);
-    let c = move |f: PhysFrame| -> *mut PageTable { _frame_to_page_table(phys_mem_offset, f) };
+/* Where the bootloader mapped all of physical memory; 0 until init() has run. */
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
 
-    MappedPageTable::new(l4_table, c)
+/* The kernel's L4 table, the one in CR3 at boot. Address spaces share its kernel entries. */
+static KERNEL_L4: AtomicU64 = AtomicU64::new(0);
+
+/* The kernel's page tables and the physical frame pool. Always locked in this order, and with
+ * interrupts off, since the scheduler frees thread stacks from the timer interrupt path. */
+static MAPPER: IrqSpinLock<Option<OffsetPageTable<'static>>> = IrqSpinLock::new(None);
+static FRAME_ALLOCATOR: IrqSpinLock<Option<BootInfoFrameAllocator>> = IrqSpinLock::new(None);
+
+/* Kernel thread stacks live in fixed-size slots here, each with an unmapped guard page below. */
+const KERNEL_STACKS_START: u64 = 0x_4444_8000_0000;
+const KERNEL_STACK_SLOT_SIZE: u64 = 64 * 1024;
+const KERNEL_STACK_SLOTS: u64 = 1024;
+
+/* Device registers get mapped uncached here, bump-allocated as drivers ask for them. */
+const MMIO_START: u64 = 0x_4444_c000_0000;
+const MMIO_SIZE: u64 = 0x_4000_0000;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Frames below this are never handed out: real-mode code (the AP trampoline) has to live there,
+/// and the BIOS data we read is scattered around it.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+pub unsafe fn init(phys_mem_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    PHYS_MEM_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
+    let (l4_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_L4.store(l4_frame.start_address().as_u64(), Ordering::Relaxed);
+    let l4_table = _frame_to_page_table(phys_mem_offset, l4_frame);
+
+    *MAPPER.lock() = Some(OffsetPageTable::new(l4_table, phys_mem_offset));
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::new(memory_map));
+}
+
+/// Run `f` with the kernel's page tables and frame allocator.
+pub fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+{
+    let mut mapper = MAPPER.lock();
+    let mut frames = FRAME_ALLOCATOR.lock();
+    f(
+        mapper.as_mut().expect("memory::init() not called"),
+        frames.as_mut().expect("memory::init() not called"),
+    )
+}
+
+pub fn allocate_frame() -> Option<UnusedPhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+}
+
+/// Drop a reference to a frame, freeing it if that was the last.
+pub fn free_frame(frame: PhysFrame) {
+    let mut frames = FRAME_ALLOCATOR.lock();
+    let frames = frames.as_mut().expect("memory::init() not called");
+    frames.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+}
+
+/// Add a reference to an allocated frame, for mapping it somewhere else too. False if there's no
+/// memory to count it in.
+#[must_use]
+pub fn share_frame(frame: PhysFrame) -> bool {
+    FRAME_ALLOCATOR.lock().as_mut().expect("memory::init() not called").share(frame)
+}
+
+/// How many references there are to an allocated frame.
+pub fn frame_references(frame: PhysFrame) -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().expect("memory::init() not called").references(frame)
+}
+
+/// How many frames there are left to allocate, fresh or freed.
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().map_or(0, |frames| frames.available())
+}
+
+/// How many frames sharing is saving right now: the references to frames beyond the first.
+pub fn frames_saved() -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().map_or(0, |frames| frames.saved())
+}
+
+/// Map `size` bytes of device registers at `phys`, uncached. Mappings are never taken down.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Option<VirtAddr> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let pages = (last.start_address() - first.start_address()) / 4096 + 1;
+
+    let start = MMIO_NEXT.fetch_add(pages * 4096, Ordering::Relaxed);
+    if start + pages * 4096 > MMIO_START + MMIO_SIZE {
+        return None;
+    }
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    with_mapper(|mapper, frames| {
+        for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+            let page = Page::containing_address(VirtAddr::new(start + i as u64 * 4096));
+            let frame = unsafe { UnusedPhysFrame::new(frame) };
+            mapper.map_to(page, frame, flags, frames).ok()?.flush();
+        }
+        Some(())
+    })?;
+    Some(VirtAddr::new(start) + (phys - first.start_address()))
 }
 
+/// Unmap a page on every CPU, returning the frame it mapped, for the caller to free (or not).
+pub fn unmap_page(page: Page) -> Option<PhysFrame> {
+    let frame = with_mapper(|mapper, _| {
+        let (frame, flush) = mapper.unmap(page).ok()?;
+        flush.ignore();
+        Some(frame)
+    })?;
+    tlb::shootdown(page.start_address(), 1);
+    Some(frame)
+}
+
+/// Change a mapped page's flags, on every CPU. False if it isn't mapped.
+pub fn protect_page(page: Page, flags: PageTableFlags) -> bool {
+    let updated = with_mapper(|mapper, _| match unsafe { mapper.update_flags(page, flags) } {
+        Ok(flush) => {
+            flush.ignore();
+            true
+        }
+        Err(_) => false,
+    });
+    if updated {
+        tlb::shootdown(page.start_address(), 1);
+    }
+    updated
+}
+
+/// The virtual address through which a physical address can be accessed, once `init()` has run.
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    match PHYS_MEM_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset + addr.as_u64())),
+    }
+}
+
+/// The kernel's L4 table, which threads without an address space of their own run on.
+pub fn kernel_l4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_L4.load(Ordering::Relaxed)))
+}
+
+/// The L4 table in CR3, which is the kernel's or the running thread's address space's.
 pub fn active_l4_table(phys_mem_offset: VirtAddr) -> &'static mut PageTable {
     let (l4_table_phys, _) = x86_64::registers::control::Cr3::read();
     unsafe { _frame_to_page_table(phys_mem_offset, l4_table_phys) }
@@ -154,6 +300,8 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
+/// Map `page` to `frame`, writable. As the page wasn't mapped before, no other CPU can have it
+/// cached, so flushing this CPU's TLB is enough.
 pub fn create_mapping(
     page: Page,
     frame: PhysFrame,
@@ -176,51 +324,253 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-pub struct BootInfoFrameAllocator<I>
-where
-    I: Iterator<Item = UnusedPhysFrame>,
-{
-    frames: I,
+/// Hands out the usable frames from the bootloader's memory map in order, then reuses freed
+/// ones. Freed frames form a list threaded through the frames themselves, so no heap is needed.
+///
+/// A frame can be shared (for copy-on-write), in which case it takes a deallocation per reference
+/// to free it. The counts are kept in frames of the allocator's own, taken the first time a frame
+/// in their range is shared and kept after, so sharing doesn't need the heap but can still fail.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    region: usize,
+    next: u64,
+    free_list: Option<PhysFrame>,
+    free_count: usize,
+    /* A frame of the physical addresses of the count frames, each holding the counts of
+     * COUNTS_PER_FRAME frames in a row (0 for a range with none yet). A count is of the
+     * references beyond the first, so a frame that isn't shared has 0. */
+    counts: Option<PhysFrame>,
+    saved: usize,
 }
 
-impl<I> BootInfoFrameAllocator<I>
-where
-    I: Iterator<Item = UnusedPhysFrame>,
-{
-    pub fn new(
-        memory_map: &'static MemoryMap,
-    ) -> BootInfoFrameAllocator<impl Iterator<Item = UnusedPhysFrame>> {
-        let frames = memory_map
+const COUNTS_PER_FRAME: u64 = 4096 / 2;
+
+impl BootInfoFrameAllocator {
+    pub fn new(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            region: 0,
+            next: 0,
+            free_list: None,
+            free_count: 0,
+            counts: None,
+            saved: 0,
+        }
+    }
+
+    fn next_fresh(&mut self) -> Option<PhysFrame> {
+        while let Some(r) = self.memory_map.iter().nth(self.region) {
+            if r.region_type == MemoryRegionType::Usable {
+                let addr = self.next.max(r.range.start_addr()).max(LOW_MEMORY_END);
+                if addr < r.range.end_addr() {
+                    self.next = addr + 4096;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+
+    /// Whether the memory map says `frame` is free RAM (whether or not it's been handed out).
+    pub fn is_usable(&self, frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        self.memory_map.iter().any(|r| {
+            r.region_type == MemoryRegionType::Usable
+                && r.range.start_addr() <= addr
+                && addr + 4096 <= r.range.end_addr()
+        })
+    }
+
+    /// Frames that have been freed and are waiting for reuse.
+    pub fn free_count(&self) -> usize {
+        self.free_count
+    }
+
+    /// Frames that can still be allocated: the freed ones, and those never handed out.
+    pub fn available(&self) -> usize {
+        let fresh: u64 = self
+            .memory_map
             .iter()
+            .skip(self.region)
             .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-            .map(|f| unsafe { UnusedPhysFrame::new(f) });
+            .map(|r| {
+                let start = self.next.max(r.range.start_addr()).max(LOW_MEMORY_END);
+                r.range.end_addr().saturating_sub(start) / 4096
+            })
+            .sum();
+        self.free_count + fresh as usize
+    }
+
+    /// Add a reference to an allocated frame. False if there's no frame for its count, or it's
+    /// past the memory counts can be kept for (the first 4GiB), or it has all it can have.
+    #[must_use]
+    pub fn share(&mut self, frame: PhysFrame) -> bool {
+        match self.make_count(frame) {
+            Some(count) if *count < u16::MAX => {
+                *count += 1;
+                self.saved += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How many references there are to an allocated frame.
+    pub fn references(&self, frame: PhysFrame) -> usize {
+        1 + self.counts.and_then(|counts| count_in(counts, frame)).map_or(0, |count| *count as usize)
+    }
 
-        BootInfoFrameAllocator { frames }
+    /// The references to shared frames beyond the first.
+    pub fn saved(&self) -> usize {
+        self.saved
+    }
+
+    /* Where `frame`'s count is, taking frames to keep it in first if they're not there yet. */
+    fn make_count(&mut self, frame: PhysFrame) -> Option<&'static mut u16> {
+        if self.counts.is_none() {
+            self.counts = Some(self.zeroed_frame()?);
+        }
+        let counts = self.counts.unwrap();
+        let index = (frame_number(frame) / COUNTS_PER_FRAME) as usize;
+        if *count_table(counts).get(index)? == 0 {
+            count_table(counts)[index] = self.zeroed_frame()?.start_address().as_u64();
+        }
+        count_in(counts, frame)
+    }
+
+    fn zeroed_frame(&mut self) -> Option<PhysFrame> {
+        let frame = *self.allocate_frame()?;
+        let virt = phys_to_virt(frame.start_address()).unwrap();
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096) };
+        Some(frame)
     }
 }
 
-pub fn BootInfoFrameAllocator_new(
-    memory_map: &'static MemoryMap,
-) -> BootInfoFrameAllocator<impl Iterator<Item = UnusedPhysFrame>> {
-    let frames = memory_map
-        .iter()
-        .filter(|r| r.region_type == MemoryRegionType::Usable)
-        .map(|r| r.range.start_addr()..r.range.end_addr())
-        .flat_map(|r| r.step_by(4096))
-        .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-        .map(|f| unsafe { UnusedPhysFrame::new(f) });
+fn frame_number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / 4096
+}
 
-    BootInfoFrameAllocator { frames }
+/* The table of count frames in `counts`. Only the frame allocator has it, under its lock. */
+fn count_table(counts: PhysFrame) -> &'static mut [u64; 512] {
+    unsafe { &mut *phys_to_virt(counts.start_address()).unwrap().as_mut_ptr() }
 }
 
-unsafe impl<I> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<I>
-where
-    I: Iterator<Item = UnusedPhysFrame>,
-{
+/* `frame`'s count, if there's a count frame for its range. */
+fn count_in(counts: PhysFrame, frame: PhysFrame) -> Option<&'static mut u16> {
+    let page = *count_table(counts).get((frame_number(frame) / COUNTS_PER_FRAME) as usize)?;
+    if page == 0 {
+        return None;
+    }
+    let page = phys_to_virt(PhysAddr::new(page)).unwrap().as_mut_ptr::<[u16; COUNTS_PER_FRAME as usize]>();
+    Some(unsafe { &mut (*page)[(frame_number(frame) % COUNTS_PER_FRAME) as usize] })
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        self.frames.next()
+        let frame = match self.free_list {
+            Some(head) => {
+                let link = phys_to_virt(head.start_address()).unwrap().as_ptr::<u64>();
+                let next = unsafe { link.read() };
+                self.free_list = if next == 0 {
+                    None
+                } else {
+                    Some(PhysFrame::containing_address(PhysAddr::new(next)))
+                };
+                self.free_count -= 1;
+                head
+            }
+            None => self.next_fresh()?,
+        };
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        let frame = *frame;
+        let count = self.counts.and_then(|counts| count_in(counts, frame));
+        if let Some(count) = count.filter(|count| **count > 0) {
+            *count -= 1;
+            self.saved -= 1;
+            return;
+        }
+        let link = phys_to_virt(frame.start_address()).unwrap().as_mut_ptr::<u64>();
+        let next = self.free_list.map_or(0, |f| f.start_address().as_u64());
+        unsafe { link.write(next) };
+        self.free_list = Some(frame);
+        self.free_count += 1;
+    }
+}
+
+/// A kernel stack in its own slot of the stacks region, with an unmapped guard page below it so
+/// that overflowing it faults rather than trampling whatever is next door.
+pub struct KernelStack {
+    slot: u64,
+    pages: u64,
+}
+
+lazy_static! {
+    static ref FREE_STACK_SLOTS: IrqSpinLock<Vec<u64>> = IrqSpinLock::new((0..KERNEL_STACK_SLOTS).rev().collect());
+}
+
+impl KernelStack {
+    /// `pages` must leave room for the guard page in the slot.
+    pub fn new(pages: u64) -> Option<KernelStack> {
+        assert!((pages + 1) * 4096 <= KERNEL_STACK_SLOT_SIZE);
+        let slot = FREE_STACK_SLOTS.lock().pop()?;
+        let stack = KernelStack { slot, pages };
+
+        let mapped = with_mapper(|mapper, frames| {
+            for page in stack.page_range() {
+                let frame = frames.allocate_frame()?;
+                let phys = *frame;
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                match mapper.map_to(page, frame, flags, frames) {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => {
+                        /* Not mapped, so dropping `stack` wouldn't find it. */
+                        frames.deallocate_frame(unsafe { UnusedPhysFrame::new(phys) });
+                        return None;
+                    }
+                }
+            }
+            Some(())
+        });
+        // on failure, dropping `stack` unmaps whatever did get mapped
+        mapped.map(|_| stack)
+    }
+
+    fn page_range(&self) -> impl Iterator<Item = Page> {
+        let top = Page::containing_address(self.top() - 1u64);
+        Page::range_inclusive(top - (self.pages - 1), top)
+    }
+
+    /// Initial stack pointer: one past the highest byte.
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACKS_START + (self.slot + 1) * KERNEL_STACK_SLOT_SIZE)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages * 4096
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let unmapped: Vec<PhysFrame> = with_mapper(|mapper, _| {
+            self.page_range()
+                .filter_map(|page| mapper.unmap(page).ok())
+                .map(|(frame, flush)| {
+                    flush.ignore();
+                    frame
+                })
+                .collect()
+        });
+        tlb::shootdown(self.bottom(), self.pages);
+        for frame in unmapped {
+            free_frame(frame);
+        }
+        FREE_STACK_SLOTS.lock().push(self.slot);
     }
 }
@@ -0,0 +1,163 @@
+//! Per-CPU data. Each CPU has a `PerCpu` block, and its GS base points at it, so finding this
+//! CPU's block is a single GS-relative load with no locking. Fields are atomics, as other CPUs may
+//! peek at them, but only their own CPU changes most of them.
+//!
+//! User mode has a GS base of its own; see `user_gs_base()`.
+//!
+//! A thread only stays on one CPU while it can't be preempted, so use `percpu!` with interrupts
+//! off or inside a `PreemptGuard`.
+
+use crate::ipi::CallRequest;
+use crate::smp::MAX_CPUS;
+use crate::sync::IrqSpinLock;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::tss::TaskStateSegment;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+#[repr(C)]
+pub struct PerCpu {
+    /* Must come first: this() finds the block by reading it through gs:0. */
+    self_addr: AtomicU64,
+    /* These two are used by the syscall entry stub, at fixed offsets: the running thread's kernel
+     * stack, and somewhere to put the user stack pointer while switching to it. */
+    pub(crate) syscall_stack: AtomicU64,
+    pub(crate) user_rsp: AtomicU64,
+    /// CPU number, 0 being the boot CPU.
+    pub cpu: AtomicUsize,
+    pub apic_id: AtomicU8,
+    /// How many interrupt handlers deep this CPU is.
+    pub irq_depth: AtomicUsize,
+    /// Preemption is off while non-zero.
+    pub preempt_count: AtomicUsize,
+    pub tss: AtomicPtr<TaskStateSegment>,
+    /// ID of the thread running here.
+    pub current_thread: AtomicU64,
+    /* Functions other CPUs have asked this one to run; see ipi::call(). */
+    pub(crate) calls: IrqSpinLock<Vec<CallRequest>>,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            self_addr: AtomicU64::new(0),
+            syscall_stack: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            cpu: AtomicUsize::new(0),
+            apic_id: AtomicU8::new(0),
+            irq_depth: AtomicUsize::new(0),
+            preempt_count: AtomicUsize::new(0),
+            tss: AtomicPtr::new(ptr::null_mut()),
+            current_thread: AtomicU64::new(0),
+            calls: IrqSpinLock::new(Vec::new()),
+        }
+    }
+}
+
+/* The boot CPU's block is static, as it's needed before there's a heap. */
+static BSP_PERCPU: PerCpu = PerCpu::new();
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+];
+
+/// This CPU's block, eg `percpu!(irq_depth)`.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::percpu::this().$field
+    };
+}
+
+/// Offsets of `syscall_stack` and `user_rsp`, as hard-coded in the syscall entry stub.
+pub(crate) const SYSCALL_STACK_OFFSET: u64 = 8;
+pub(crate) const USER_RSP_OFFSET: u64 = 16;
+
+fn install(block: &'static PerCpu, cpu: usize) {
+    let addr = block as *const PerCpu as u64;
+    assert_eq!(&block.syscall_stack as *const _ as u64 - addr, SYSCALL_STACK_OFFSET);
+    assert_eq!(&block.user_rsp as *const _ as u64 - addr, USER_RSP_OFFSET);
+    block.self_addr.store(addr, Ordering::Relaxed);
+    block.cpu.store(cpu, Ordering::Relaxed);
+    CPUS[cpu].store(block as *const PerCpu as *mut PerCpu, Ordering::Release);
+    unsafe {
+        Msr::new(IA32_GS_BASE).write(addr);
+        Msr::new(IA32_KERNEL_GS_BASE).write(0);
+    }
+}
+
+/* User mode gets its own GS base: swapgs on the way into and out of the kernel exchanges it with
+ * ours, and while in the kernel it waits in IA32_KERNEL_GS_BASE. It belongs to the thread, so
+ * it's saved and restored across switches. */
+
+/// The GS base the running thread will have when it returns to user mode.
+pub fn user_gs_base() -> u64 {
+    unsafe { Msr::new(IA32_KERNEL_GS_BASE).read() }
+}
+
+pub fn set_user_gs_base(base: u64) {
+    unsafe { Msr::new(IA32_KERNEL_GS_BASE).write(base) };
+}
+
+/// Set up the boot CPU's block. Must come before anything uses `percpu!`.
+pub fn init_bsp() {
+    install(&BSP_PERCPU, 0);
+}
+
+/// Set up an application processor's block. Needs the heap.
+pub fn init_ap(cpu: usize) {
+    install(Box::leak(Box::new(PerCpu::new())), cpu);
+}
+
+/// This CPU's block.
+#[inline]
+pub fn this() -> &'static PerCpu {
+    let addr: u64;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) addr, options(nostack, readonly, preserves_flags)) };
+    unsafe { &*(addr as *const PerCpu) }
+}
+
+/// Another CPU's block, if it's been set up.
+pub fn of(cpu: usize) -> Option<&'static PerCpu> {
+    let ptr = CPUS.get(cpu)?.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// This CPU's number.
+pub fn cpu_id() -> usize {
+    this().cpu.load(Ordering::Relaxed)
+}
+
+/// Keeps the current thread on this CPU while it's held, without turning interrupts off.
+pub struct PreemptGuard {
+    _private: (),
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        this().preempt_count.fetch_add(1, Ordering::Relaxed);
+        PreemptGuard { _private: () }
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        this().preempt_count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn preemptible() -> bool {
+    this().preempt_count.load(Ordering::Relaxed) == 0
+}
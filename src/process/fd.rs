@@ -0,0 +1,146 @@
+//! File descriptors: each process has a table of them, each referring to an open file of some
+//! kind. A file can be in several tables at once (after a fork), and goes away when the last
+//! descriptor for it is closed.
+
+use crate::syscall::Error;
+use crate::{keyboard, vga};
+use alloc::sync::Arc;
+use alloc::{vec, vec::Vec};
+use x86_64::structures::paging::PhysFrame;
+
+/// The most descriptors a process can have open.
+pub const MAX_FDS: usize = 64;
+
+/// What an open file can do. Calls may block.
+pub trait FileOps: Send + Sync {
+    /// Read into `buf`, returning how many bytes were read; 0 is the end of the file.
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::Invalid)
+    }
+
+    /// Write from `buf`, returning how many bytes were written.
+    fn write(&self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::Invalid)
+    }
+
+    /// Make the file `len` bytes long.
+    fn truncate(&self, _len: u64) -> Result<(), Error> {
+        Err(Error::Invalid)
+    }
+
+    /// Move the offset reads and writes happen at to `offset` from where `whence` says, and
+    /// return where that is.
+    fn seek(&self, _offset: i64, _whence: u64) -> Result<u64, Error> {
+        Err(Error::IllegalSeek)
+    }
+
+    /// Read a directory's entries into `buf`, as `linux_dirent64`s, returning how many bytes
+    /// that took; 0 at the end.
+    fn getdents(&self, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// The frames holding `pages` pages of the file from page `first`, for mapping, with a
+    /// reference added to each for the mapping to hold. Only files that live in memory have any.
+    fn frames(&self, _first: u64, _pages: u64) -> Result<Vec<PhysFrame>, Error> {
+        Err(Error::NoDevice)
+    }
+}
+
+/// The screen and keyboard.
+pub struct Console;
+
+impl FileOps for Console {
+    /// Wait for a key with a character, and return that, UTF-8 encoded. Other keys are ignored.
+    /// A signal stops the wait.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let key = keyboard::read_key_unless(super::signal::interrupted).ok_or(Error::Interrupted)?;
+            if let keyboard::DecodedKey::Unicode(c) = key {
+                let mut encoded = [0; 4];
+                let bytes = c.encode_utf8(&mut encoded).as_bytes();
+                if bytes.len() > buf.len() {
+                    return Err(Error::Invalid);
+                }
+                buf[..bytes.len()].copy_from_slice(bytes);
+                return Ok(bytes.len());
+            }
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        vga::write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+/// A process's open files, by descriptor.
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<Arc<dyn FileOps>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// Standard input, output and error (0, 1 and 2) on the console.
+    pub fn with_console() -> Self {
+        let console: Arc<dyn FileOps> = Arc::new(Console);
+        FdTable {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<dyn FileOps>, Error> {
+        self.files.get(fd as usize).cloned().flatten().ok_or(Error::BadFd)
+    }
+
+    /// Add `file` at the lowest free descriptor, and return that.
+    pub fn insert(&mut self, file: Arc<dyn FileOps>) -> Result<u64, Error> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(Error::TooManyFiles),
+        };
+        self.files[fd] = Some(file);
+        Ok(fd as u64)
+    }
+
+    /// How many descriptors are open.
+    pub fn open_count(&self) -> usize {
+        self.files.iter().filter(|f| f.is_some()).count()
+    }
+
+    /// Remove a descriptor, returning its file so the caller can drop it (outside any locks, as
+    /// closing some files wakes things up).
+    pub fn close(&mut self, fd: u64) -> Result<Arc<dyn FileOps>, Error> {
+        self.files.get_mut(fd as usize).and_then(Option::take).ok_or(Error::BadFd)
+    }
+
+    /// Make `new` refer to the same file as `old`, closing what it referred to before. Returns
+    /// that file, if any, for the caller to drop, as with `close()`.
+    pub fn dup2(&mut self, old: u64, new: u64) -> Result<Option<Arc<dyn FileOps>>, Error> {
+        let file = self.get(old)?;
+        let new = new as usize;
+        if new >= MAX_FDS {
+            return Err(Error::BadFd);
+        }
+        if self.files.len() <= new {
+            self.files.resize(new + 1, None);
+        }
+        Ok(self.files[new].replace(file))
+    }
+
+    /// Remove every descriptor, returning the files.
+    pub fn close_all(&mut self) -> Vec<Arc<dyn FileOps>> {
+        self.files.drain(..).flatten().collect()
+    }
+}
@@ -0,0 +1,484 @@
+//! Message passing through ports, for servers (drivers, say) in user space.
+//!
+//! A port is a kernel queue of messages that processes reach through capabilities: handles in a
+//! per-process table, each naming a port and the rights it gives there, to send and to receive.
+//! The process that makes a port gets both; it can pass on handles with fewer, in a message or by
+//! forking. A port closes once no capability can receive from it, and anything still queued
+//! bounces.
+//!
+//! Everything's synchronous. `send()` waits until a receiver has the message; `call()` waits
+//! for the reply too, and the receiver gets a one-shot reply capability to answer it with. A
+//! message is a few words of data, plus optionally a page, moved out of the sender's memory and
+//! into the receiver's, and a capability, copied from the sender's table to the receiver's.
+
+use super::{install_cap, signal, with_current};
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall::Error;
+use crate::user::{USER_END, USER_START};
+use crate::{memory, thread};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::convert::TryInto;
+use core::mem;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+/// Words of data in a message.
+pub const MESSAGE_WORDS: usize = 6;
+
+/// The most capabilities a process can hold.
+pub const MAX_CAPS: usize = 64;
+
+/// A handle that's never valid: "no capability".
+pub const NO_CAP: u64 = 0;
+
+/// Rights a capability gives at its port.
+pub const RIGHT_SEND: u64 = 1;
+pub const RIGHT_RECEIVE: u64 = 2;
+pub const RIGHTS_ALL: u64 = RIGHT_SEND | RIGHT_RECEIVE;
+
+/// A message as user mode sees it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Message {
+    /// Whatever the sender likes; the kernel doesn't look.
+    pub data: [u64; MESSAGE_WORDS],
+    /// Sending, a page-aligned address of a page to move to the receiver, or 0. Received, where
+    /// that page now is, or 0.
+    pub page: u64,
+    /// Sending, a handle for a capability to copy to the receiver, or `NO_CAP`. Received, the
+    /// receiver's handle for it.
+    pub cap: u64,
+}
+
+impl Message {
+    pub const SIZE: usize = 64;
+
+    pub fn from_bytes(bytes: &[u8; Message::SIZE]) -> Message {
+        let word = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        let mut data = [0; MESSAGE_WORDS];
+        for (i, d) in data.iter_mut().enumerate() {
+            *d = word(i);
+        }
+        Message { data, page: word(MESSAGE_WORDS), cap: word(MESSAGE_WORDS + 1) }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Message::SIZE] {
+        let mut bytes = [0; Message::SIZE];
+        let words = self.data.iter().chain(&[self.page, self.cap]);
+        for (i, word) in words.enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&word.to_ne_bytes());
+        }
+        bytes
+    }
+}
+
+/// A queue of messages.
+pub struct Port {
+    state: IrqSpinLock<PortState>,
+    /* Receivers waiting for a message. */
+    receivers: WaitQueue,
+}
+
+struct PortState {
+    queue: VecDeque<Arc<Transfer>>,
+    closed: bool,
+}
+
+/* Held by every capability that can receive from a port; when the last goes, the port closes. */
+pub(super) struct Receiver(Arc<Port>);
+
+/* A one-shot right to answer a call. Dropping it unanswered lets the caller go. */
+pub(super) struct ReplyRight(Arc<Transfer>);
+
+/// What a handle refers to.
+#[derive(Clone)]
+pub(super) enum Capability {
+    Port {
+        port: Arc<Port>,
+        rights: u64,
+        receiver: Option<Arc<Receiver>>,
+    },
+    Reply(Arc<ReplyRight>),
+}
+
+/* A message on its way, and how far it's got. The sender waits on `done`. */
+struct Transfer {
+    state: IrqSpinLock<TransferState>,
+    done: WaitQueue,
+    call: bool,
+}
+
+enum TransferState {
+    Queued(Payload),
+    /* Taken by a receiver; for a call, waiting for the reply. */
+    Received,
+    Replied(Payload),
+    /* Back to the sender, as the port closed. */
+    Bounced(Payload),
+    /* The sender gave up, or the reply right was dropped; or it's all over. */
+    Finished,
+}
+
+/* A message in the kernel's hands. */
+struct Payload {
+    data: [u64; MESSAGE_WORDS],
+    page: Option<Frame>,
+    cap: Option<Capability>,
+}
+
+/* A reference to a frame that's in no address space just now. */
+struct Frame(PhysFrame);
+
+impl Frame {
+    fn into_inner(self) -> PhysFrame {
+        let frame = self.0;
+        mem::forget(self);
+        frame
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        memory::free_frame(self.0);
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let bounced: Vec<Arc<Transfer>> = {
+            let mut state = self.0.state.lock();
+            state.closed = true;
+            state.queue.drain(..).collect()
+        };
+        for transfer in bounced {
+            {
+                let mut state = transfer.state.lock();
+                if let TransferState::Queued(_) = *state {
+                    if let TransferState::Queued(payload) = mem::replace(&mut *state, TransferState::Finished) {
+                        *state = TransferState::Bounced(payload);
+                    }
+                }
+            }
+            transfer.done.wake_all();
+        }
+    }
+}
+
+impl Drop for ReplyRight {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        if let TransferState::Received = *state {
+            *state = TransferState::Finished;
+            drop(state);
+            self.0.done.wake_all();
+        }
+    }
+}
+
+/// A process's capabilities, by handle.
+#[derive(Clone, Default)]
+pub(super) struct CapTable {
+    caps: Vec<Option<Capability>>,
+}
+
+impl CapTable {
+    pub fn new() -> Self {
+        CapTable { caps: Vec::new() }
+    }
+
+    pub fn get(&self, handle: u64) -> Result<Capability, Error> {
+        let index = handle.checked_sub(1).ok_or(Error::BadFd)?;
+        self.caps.get(index as usize).cloned().flatten().ok_or(Error::BadFd)
+    }
+
+    /// Add `cap` at the lowest free handle, and return that.
+    pub fn insert(&mut self, cap: Capability) -> Result<u64, Error> {
+        let index = match self.caps.iter().position(Option::is_none) {
+            Some(index) => index,
+            None if self.caps.len() < MAX_CAPS => {
+                self.caps.push(None);
+                self.caps.len() - 1
+            }
+            None => return Err(Error::TooManyFiles),
+        };
+        self.caps[index] = Some(cap);
+        Ok(index as u64 + 1)
+    }
+
+    /// How many handles are in use.
+    pub fn count(&self) -> usize {
+        self.caps.iter().filter(|c| c.is_some()).count()
+    }
+
+    /// Remove a handle, returning its capability for the caller to drop outside any locks, as
+    /// the last of some wakes things up.
+    pub fn remove(&mut self, handle: u64) -> Result<Capability, Error> {
+        let index = handle.checked_sub(1).ok_or(Error::BadFd)?;
+        self.caps.get_mut(index as usize).and_then(Option::take).ok_or(Error::BadFd)
+    }
+
+    /// Remove every handle, returning the capabilities.
+    pub fn remove_all(&mut self) -> Vec<Capability> {
+        self.caps.drain(..).flatten().collect()
+    }
+}
+
+/* The port a handle of the calling process's refers to, if it gives `rights` there. */
+fn port(handle: u64, rights: u64) -> Result<Arc<Port>, Error> {
+    match with_current(|p| p.caps.get(handle))?? {
+        Capability::Port { port, rights: held, .. } if held & rights == rights => Ok(port),
+        Capability::Port { .. } => Err(Error::NotPermitted),
+        Capability::Reply(_) => Err(Error::Invalid),
+    }
+}
+
+/// Make a port, and give the calling process a capability with every right to it. Returns the
+/// handle.
+pub fn create_port() -> Result<u64, Error> {
+    let port = Arc::new(Port {
+        state: IrqSpinLock::new(PortState { queue: VecDeque::new(), closed: false }),
+        receivers: WaitQueue::new(),
+    });
+    let receiver = Some(Arc::new(Receiver(port.clone())));
+    install_cap(Capability::Port { port, rights: RIGHTS_ALL, receiver })
+}
+
+/// Give the calling process another handle for the same port as `handle`, with only `rights`
+/// (which it must have). Returns the new handle.
+pub fn restrict(handle: u64, rights: u64) -> Result<u64, Error> {
+    if rights & !RIGHTS_ALL != 0 {
+        return Err(Error::Invalid);
+    }
+    let cap = match with_current(|p| p.caps.get(handle))?? {
+        Capability::Port { port, rights: held, receiver } if held & rights == rights => Capability::Port {
+            port,
+            rights,
+            receiver: if rights & RIGHT_RECEIVE != 0 { receiver } else { None },
+        },
+        Capability::Port { .. } => return Err(Error::NotPermitted),
+        Capability::Reply(_) => return Err(Error::Invalid),
+    };
+    install_cap(cap)
+}
+
+/// Drop one of the calling process's handles.
+pub fn close(handle: u64) -> Result<(), Error> {
+    let cap = with_current(|p| p.caps.remove(handle))??;
+    drop(cap);
+    Ok(())
+}
+
+/// Send `msg` through the port `handle` refers to, and wait until it's received; with `call`,
+/// wait for the reply too, and return that. A signal stops the wait, unless the message has
+/// already been received by a plain send.
+pub fn send(handle: u64, msg: &Message, call: bool) -> Result<Option<Message>, Error> {
+    let port = port(handle, RIGHT_SEND)?;
+    let cap = match msg.cap {
+        NO_CAP => None,
+        cap => match with_current(|p| p.caps.get(cap))?? {
+            Capability::Reply(_) => return Err(Error::Invalid),
+            cap => Some(cap),
+        },
+    };
+    let page = match msg.page {
+        0 => None,
+        page => Some(Frame(take_page(page)?)),
+    };
+    let transfer = Arc::new(Transfer {
+        state: IrqSpinLock::new(TransferState::Queued(Payload { data: msg.data, page, cap })),
+        done: WaitQueue::new(),
+        call,
+    });
+    {
+        let mut state = port.state.lock();
+        if state.closed {
+            drop(state);
+            return Err(bounce(&transfer, msg.page, Error::BrokenPipe));
+        }
+        state.queue.push_back(transfer.clone());
+    }
+    port.receivers.wake_one();
+
+    let mut result = Ok(None);
+    transfer.done.wait_until(|| {
+        let mut state = transfer.state.lock();
+        match mem::replace(&mut *state, TransferState::Finished) {
+            TransferState::Replied(reply) => {
+                result = Ok(Some(reply));
+                true
+            }
+            TransferState::Bounced(payload) => {
+                *state = TransferState::Bounced(payload);
+                result = Err(Error::BrokenPipe);
+                true
+            }
+            TransferState::Finished => {
+                /* Received by a send, or the reply right was dropped. */
+                if call {
+                    result = Err(Error::BrokenPipe);
+                }
+                true
+            }
+            waiting => {
+                *state = waiting;
+                drop(state);
+                if signal::interrupted() {
+                    result = Err(Error::Interrupted);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    });
+    match result {
+        Ok(Some(reply)) => Ok(Some(deliver(reply, 0))),
+        Ok(None) => Ok(None),
+        Err(e) => Err(bounce(&transfer, msg.page, e)),
+    }
+}
+
+/* The sender's given up on `transfer`: if the message hasn't gone, take it back, and put the
+ * page back at `page` where it came from. Returns `error`. */
+fn bounce(transfer: &Transfer, page: u64, error: Error) -> Error {
+    let payload = {
+        let mut state = transfer.state.lock();
+        match mem::replace(&mut *state, TransferState::Finished) {
+            TransferState::Queued(payload) | TransferState::Bounced(payload) => Some(payload),
+            _ => None,
+        }
+    };
+    if let Some(Payload { page: Some(frame), .. }) = payload {
+        if let Some(space) = thread::address_space() {
+            let frame = frame.into_inner();
+            if space.give_page(VirtAddr::new(page), frame).is_err() {
+                memory::free_frame(frame);
+            }
+        }
+    }
+    error
+}
+
+/// Wait for a message on the port `handle` refers to. A page that comes with it is put at
+/// `page`, if that's not 0: it must be page aligned, and not mapped. Returns the message, and for
+/// a call, a handle for the reply right (`NO_CAP` otherwise). A signal stops the wait.
+pub fn receive(handle: u64, page: u64) -> Result<(Message, u64), Error> {
+    let port = port(handle, RIGHT_RECEIVE)?;
+    if page != 0 {
+        let space = thread::address_space().ok_or(Error::Fault)?;
+        if page & 0xfff != 0 || page < USER_START || page >= USER_END {
+            return Err(Error::Invalid);
+        }
+        if space.can_access(VirtAddr::new(page), false) {
+            return Err(Error::Invalid);
+        }
+    }
+    let mut received = Err(Error::Interrupted);
+    port.receivers.wait_until(|| loop {
+        let next = port.state.lock().queue.pop_front();
+        let transfer = match next {
+            Some(transfer) => transfer,
+            None => {
+                if signal::interrupted() {
+                    received = Err(Error::Interrupted);
+                    return true;
+                }
+                return false;
+            }
+        };
+        let mut state = transfer.state.lock();
+        let next = if transfer.call { TransferState::Received } else { TransferState::Finished };
+        match mem::replace(&mut *state, next) {
+            TransferState::Queued(payload) => {
+                drop(state);
+                received = Ok((transfer.clone(), payload));
+                return true;
+            }
+            /* The sender gave up on it after it was queued. */
+            other => *state = other,
+        }
+    });
+    let (transfer, payload) = received?;
+    if !transfer.call {
+        transfer.done.wake_all();
+    }
+    let msg = deliver(payload, page);
+    let reply = if transfer.call {
+        let right = Capability::Reply(Arc::new(ReplyRight(transfer)));
+        install_cap(right).unwrap_or(NO_CAP)
+    } else {
+        NO_CAP
+    };
+    Ok((msg, reply))
+}
+
+/// Answer a call with `msg`, through the reply right `handle` refers to, which is used up. A
+/// reply can carry a capability, but not a page.
+pub fn reply(handle: u64, msg: &Message) -> Result<(), Error> {
+    if msg.page != 0 {
+        return Err(Error::Invalid);
+    }
+    let cap = match msg.cap {
+        NO_CAP => None,
+        cap => match with_current(|p| p.caps.get(cap))?? {
+            Capability::Reply(_) => return Err(Error::Invalid),
+            cap => Some(cap),
+        },
+    };
+    let right = match with_current(|p| match p.caps.get(handle)? {
+        Capability::Reply(_) => p.caps.remove(handle),
+        Capability::Port { .. } => Err(Error::Invalid),
+    })?? {
+        Capability::Reply(right) => right,
+        Capability::Port { .. } => return Err(Error::Invalid),
+    };
+    let transfer = right.0.clone();
+    let replied = {
+        let mut state = transfer.state.lock();
+        match *state {
+            TransferState::Received => {
+                *state = TransferState::Replied(Payload { data: msg.data, page: None, cap });
+                true
+            }
+            _ => false,
+        }
+    };
+    drop(right);
+    if !replied {
+        /* The caller gave up waiting. */
+        return Err(Error::BrokenPipe);
+    }
+    transfer.done.wake_all();
+    Ok(())
+}
+
+/* Turn a payload into a message for the calling process, mapping its page at `page` (or
+ * dropping it, if that's 0 or can't be done) and adding its capability to the table. */
+fn deliver(payload: Payload, page: u64) -> Message {
+    let mut msg = Message { data: payload.data, page: 0, cap: NO_CAP };
+    if let Some(frame) = payload.page {
+        if let (Some(space), true) = (thread::address_space(), page != 0) {
+            let frame = frame.into_inner();
+            match space.give_page(VirtAddr::new(page), frame) {
+                Ok(()) => msg.page = page,
+                Err(_) => memory::free_frame(frame),
+            }
+        }
+    }
+    if let Some(cap) = payload.cap {
+        msg.cap = install_cap(cap).unwrap_or(NO_CAP);
+    }
+    msg
+}
+
+/* Take the page at `addr` out of the calling process's memory. */
+fn take_page(addr: u64) -> Result<PhysFrame, Error> {
+    if addr & 0xfff != 0 {
+        return Err(Error::Invalid);
+    }
+    if addr < USER_START || addr >= USER_END {
+        return Err(Error::Fault);
+    }
+    let space = thread::address_space().ok_or(Error::Fault)?;
+    space.take_page(VirtAddr::new(addr)).map_err(|_| Error::Fault)
+}
@@ -0,0 +1,438 @@
+//! Processes: a user program's address space, open files and capabilities, and its place in the
+//! family tree, around the thread that runs it (one per process, for now).
+//!
+//! A process that exits stays in the table as a zombie, holding its exit status, until its parent
+//! collects that with `waitpid()`. Nothing adopts orphans: a process without a parent (the ones
+//! the kernel starts, and any whose parent has gone) is reaped as soon as it exits. Its parent, if
+//! it has one, is sent `SIGCHLD`.
+
+use crate::address_space::AddressSpace;
+use crate::loader::{self, LoadError};
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall::{Error, SyscallFrame};
+use crate::thread::{self, ThreadId};
+use crate::user::{self, MapError, Registers};
+use crate::vfs::{self, FileType};
+use crate::{allocator, percpu, println};
+use alloc::borrow::Cow;
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+pub mod fd;
+pub mod ipc;
+pub mod pipe;
+pub mod shm;
+pub mod signal;
+
+use fd::{FdTable, FileOps};
+use ipc::{CapTable, Capability};
+use signal::Signals;
+
+/// `waitpid()` option: don't wait if no child has exited yet.
+pub const WNOHANG: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub const fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// It called exit() with this code.
+    Exited(u8),
+    /// It was killed by this signal.
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// Encoded as Linux's wait() does, which is what C libraries' `WIFEXITED()` etc expect.
+    pub fn wait_status(self) -> u64 {
+        match self {
+            ExitStatus::Exited(code) => u64::from(code) << 8,
+            ExitStatus::Killed(signal) => u64::from(signal),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// Stopped by a signal, until it's sent `SIGCONT`.
+    Stopped,
+    /// Exited, and waiting for its parent to collect the status.
+    Zombie(ExitStatus),
+}
+
+struct Process {
+    name: String,
+    /// None once the parent has exited, or if the kernel started it.
+    parent: Option<Pid>,
+    state: ProcessState,
+    thread: ThreadId,
+    /// Also held by the thread. None once it's exited.
+    space: Option<Arc<AddressSpace>>,
+    files: FdTable,
+    signals: Signals,
+    caps: CapTable,
+}
+
+impl Process {
+    fn is_zombie(&self) -> bool {
+        match self.state {
+            ProcessState::Zombie(_) => true,
+            ProcessState::Running | ProcessState::Stopped => false,
+        }
+    }
+}
+
+struct Processes {
+    procs: BTreeMap<Pid, Process>,
+    /* Running processes, by the thread running them. */
+    by_thread: BTreeMap<ThreadId, Pid>,
+}
+
+lazy_static! {
+    static ref PROCESSES: IrqSpinLock<Processes> = IrqSpinLock::new(Processes {
+        procs: BTreeMap::new(),
+        by_thread: BTreeMap::new(),
+    });
+    /* Programs registered for tests to exec(), by path. */
+    static ref IMAGES: IrqSpinLock<BTreeMap<String, &'static [u8]>> = IrqSpinLock::new(BTreeMap::new());
+}
+
+/* Woken whenever a process becomes a zombie, for parents in waitpid(). */
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+/// A snapshot of a process, for introspection.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    pub thread: ThreadId,
+    pub open_files: usize,
+    pub capabilities: usize,
+}
+
+/// Start a process running a loaded program, with the console as its standard input, output and
+/// error. It has no parent.
+pub fn spawn(name: &str, program: loader::Program) -> Pid {
+    start(name, Arc::new(program.space), program.entry, program.stack, 0)
+}
+
+/// Start a process that drops to user mode at `entry` with `stack` as its stack, both in `space`,
+/// and `arg` in rdi. Like `spawn()`, it has the console open and no parent. It becomes the
+/// foreground process, which Ctrl-C interrupts.
+pub fn start(name: &str, space: Arc<AddressSpace>, entry: VirtAddr, stack: VirtAddr, arg: u64) -> Pid {
+    let files = FdTable::with_console();
+    let pid = create(name, None, space, files, Signals::new(), CapTable::new(), move || unsafe {
+        user::enter(entry, stack, arg)
+    });
+    signal::set_foreground(Some(pid));
+    pid
+}
+
+fn create<F>(
+    name: &str,
+    parent: Option<Pid>,
+    space: Arc<AddressSpace>,
+    files: FdTable,
+    signals: Signals,
+    caps: CapTable,
+    f: F,
+) -> Pid
+where
+    F: FnOnce() + Send + 'static,
+{
+    let pid = Pid::new();
+    /* The thread is queued on this CPU, and isn't moved to another before it's run, so with
+     * interrupts off it can't run (and make system calls) before it's in the table. */
+    interrupts::without_interrupts(|| {
+        let thread = thread::spawn_in(name, space.clone(), f);
+        let mut procs = PROCESSES.lock();
+        procs.by_thread.insert(thread, pid);
+        procs.procs.insert(
+            pid,
+            Process {
+                name: String::from(name),
+                parent,
+                state: ProcessState::Running,
+                thread,
+                space: Some(space),
+                files,
+                signals,
+                caps,
+            },
+        );
+    });
+    pid
+}
+
+/// Make `image` available to `exec()` as `path`, ahead of whatever's in the filesystem there. For
+/// tests, which have programs to run but no filesystem to put them in.
+pub fn register_image(path: &str, image: &'static [u8]) {
+    IMAGES.lock().insert(String::from(path), image);
+}
+
+/// The calling thread's process, if it's in one.
+pub fn current() -> Option<Pid> {
+    PROCESSES.lock().by_thread.get(&thread::current()).copied()
+}
+
+fn with_current<F, R>(f: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut Process) -> R,
+{
+    let mut procs = PROCESSES.lock();
+    let pid = *procs.by_thread.get(&thread::current()).ok_or(Error::NoProcess)?;
+    Ok(f(procs.procs.get_mut(&pid).unwrap()))
+}
+
+/// The calling process's parent, if it has one.
+pub fn parent() -> Result<Option<Pid>, Error> {
+    with_current(|p| p.parent)
+}
+
+/// Run `f` on the calling process's file descriptors. Files it removes should be dropped after
+/// this returns, not in `f`.
+pub fn with_files<F, R>(f: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut FdTable) -> R,
+{
+    with_current(|p| f(&mut p.files))
+}
+
+/// Give the calling process a descriptor for `file`, and return it.
+///
+/// A clone goes in the table, so if it won't fit, the `file` that's dropped isn't the last
+/// reference with the process table locked: dropping the last of some files wakes things up, or
+/// frees their memory. `install_cap()` is the same for capabilities.
+pub fn install_file(file: Arc<dyn FileOps>) -> Result<u64, Error> {
+    with_files(|files| files.insert(file.clone()))?
+}
+
+/* Give the calling process a handle for `cap`, and return it; as `install_file()`. */
+fn install_cap(cap: Capability) -> Result<u64, Error> {
+    with_current(|p| p.caps.insert(cap.clone()))?
+}
+
+/// Copy the calling process, which is in a system call that will return to user mode with
+/// `regs`. The child is a full copy of its memory, shares its open files, has the same signal
+/// actions and mask and the same capabilities, and carries on from the same place with 0 in rax.
+/// Returns the child's pid.
+pub fn fork(regs: &Registers) -> Result<Pid, Error> {
+    let (pid, name, space, files, signals, caps) = {
+        let mut procs = PROCESSES.lock();
+        let pid = *procs.by_thread.get(&thread::current()).ok_or(Error::NoProcess)?;
+        let p = procs.procs.get_mut(&pid).unwrap();
+        (pid, p.name.clone(), p.space.clone().unwrap(), p.files.clone(), p.signals.fork(), p.caps.clone())
+    };
+    let space = space.duplicate().map_err(|_| Error::NoMemory)?;
+    let regs = Registers { rax: 0, ..*regs };
+    let gs_base = percpu::user_gs_base();
+    let child = create(&name, Some(pid), Arc::new(space), files, signals, caps, move || unsafe {
+        percpu::set_user_gs_base(gs_base);
+        user::resume(&regs)
+    });
+    log::debug!("Process {} forked {}", pid, child);
+    Ok(child)
+}
+
+/* The program at `path`: one registered for tests, or else the file, read onto the heap for the
+ * loader. */
+fn read_image(path: &str) -> Result<Cow<'static, [u8]>, Error> {
+    if let Some(&image) = IMAGES.lock().get(path) {
+        return Ok(Cow::Borrowed(image));
+    }
+    let file = vfs::open(path, vfs::O_RDONLY, 0)?;
+    let stat = file.stat();
+    if stat.kind == FileType::Directory {
+        return Err(Error::IsDirectory);
+    }
+    let size = usize::try_from(stat.size).map_err(|_| Error::NoMemory)?;
+    let mut image = allocator::try_vec(size).ok_or(Error::NoMemory)?;
+    image.resize(size, 0);
+    let mut done = 0;
+    while done < size {
+        match file.read(&mut image[done..])? {
+            0 => break, // truncated while we read it
+            len => done += len,
+        }
+    }
+    image.truncate(done);
+    Ok(Cow::Owned(image))
+}
+
+/// Replace the calling process's program with the one at `path`, started with `argv` and `envp`
+/// when the system call making `frame` returns. The process keeps its pid, parent, open files,
+/// capabilities, blocked signals and ignored ones; signals it handled go back to their default
+/// actions.
+pub fn exec(frame: &mut SyscallFrame, path: &str, argv: &[&str], envp: &[&str]) -> Result<(), Error> {
+    let image = read_image(path)?;
+    let program = loader::load(&image, argv, envp).map_err(|e| match e {
+        LoadError::Map(MapError::OutOfMemory) => Error::NoMemory,
+        LoadError::ArgumentsTooLong => Error::TooBig,
+        LoadError::TooBig => Error::NoMemory,
+        LoadError::Elf(_) | LoadError::Map(_) => Error::NoExec,
+    })?;
+    drop(image);
+    let space = Arc::new(program.space);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let old = with_current(|p| {
+        p.name = String::from(name);
+        p.signals.exec();
+        p.space.replace(space.clone())
+    })?;
+    let old_thread = thread::set_address_space(Some(space));
+    drop((old, old_thread)); // now that it's off this CPU
+    percpu::set_user_gs_base(0);
+    frame.restart(program.entry, program.stack);
+    Ok(())
+}
+
+/// End the calling process with `status`: close its files, drop its capabilities, free its memory
+/// and exit its thread.
+/// It stays a zombie until its parent collects the status. Threads outside any process just exit.
+pub fn exit(status: ExitStatus) -> ! {
+    let mut files = Vec::new();
+    let mut caps = Vec::new();
+    let mut space = None;
+    let mut parent = None;
+    {
+        let mut procs = PROCESSES.lock();
+        if let Some(pid) = procs.by_thread.remove(&thread::current()) {
+            let procs = &mut procs.procs;
+            let children: Vec<Pid> = procs.iter().filter(|(_, c)| c.parent == Some(pid)).map(|(&c, _)| c).collect();
+            for child in children {
+                if procs[&child].is_zombie() {
+                    procs.remove(&child);
+                } else {
+                    procs.get_mut(&child).unwrap().parent = None;
+                }
+            }
+
+            let p = procs.get_mut(&pid).unwrap();
+            files = p.files.close_all();
+            caps = p.caps.remove_all();
+            space = p.space.take();
+            parent = p.parent;
+            if parent.is_some() {
+                p.state = ProcessState::Zombie(status);
+            } else {
+                procs.remove(&pid);
+            }
+            log::debug!("Process {} exited: {:?}", pid, status);
+        }
+    }
+    /* Outside the lock. The thread still holds the address space until it's gone. */
+    drop((files, caps, space));
+    CHILD_EXITED.wake_all();
+    if let Some(parent) = parent {
+        let _ = signal::send(parent, signal::SIGCHLD);
+    }
+    thread::exit();
+}
+
+/// Wait for a child of the calling process to exit (`pid`, or any child if None), reap it, and
+/// return its pid and status. With `WNOHANG`, None if there's no such zombie yet. Waiting gives up
+/// with `Error::Interrupted` if a signal arrives.
+pub fn waitpid(pid: Option<Pid>, options: u64) -> Result<Option<(Pid, ExitStatus)>, Error> {
+    let me = current().ok_or(Error::NoProcess)?;
+    let mut result = Ok(None);
+    CHILD_EXITED.wait_until(|| {
+        let mut procs = PROCESSES.lock();
+        let mut any = false;
+        let mut exited = None;
+        for (&child, p) in procs.procs.iter().filter(|(_, p)| p.parent == Some(me)) {
+            if pid.map_or(true, |pid| pid == child) {
+                any = true;
+                if let ProcessState::Zombie(status) = p.state {
+                    exited = Some((child, status));
+                    break;
+                }
+            }
+        }
+        match exited {
+            Some((child, status)) => {
+                procs.procs.remove(&child);
+                result = Ok(Some((child, status)));
+                true
+            }
+            None if !any => {
+                result = Err(Error::NoChild);
+                true
+            }
+            None if options & WNOHANG != 0 => true,
+            None if procs.procs[&me].signals.interrupting() => {
+                result = Err(Error::Interrupted);
+                true
+            }
+            None => false,
+        }
+    });
+    result
+}
+
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESSES
+        .lock()
+        .procs
+        .iter()
+        .map(|(&pid, p)| ProcessInfo {
+            pid,
+            parent: p.parent,
+            name: p.name.clone(),
+            state: p.state,
+            thread: p.thread,
+            open_files: p.files.open_count(),
+            capabilities: p.caps.count(),
+        })
+        .collect()
+}
+
+/// Print the process table.
+pub fn dump() {
+    println!("{:>4} {:>4} {:<12} {:<14} {:>6} {:>3} {:>4}", "PID", "PPID", "NAME", "STATE", "THREAD", "FDS", "CAPS");
+    for p in list() {
+        let parent = match p.parent {
+            Some(parent) => parent.as_u64(),
+            None => 0,
+        };
+        let state = match p.state {
+            ProcessState::Running => String::from("Running"),
+            ProcessState::Stopped => String::from("Stopped"),
+            ProcessState::Zombie(ExitStatus::Exited(code)) => format!("Exited({})", code),
+            ProcessState::Zombie(ExitStatus::Killed(signal)) => format!("Killed({})", signal),
+        };
+        println!(
+            "{:>4} {:>4} {:<12} {:<14} {:>6} {:>3} {:>4}",
+            p.pid, parent, p.name, state, p.thread, p.open_files, p.capabilities
+        );
+    }
+}
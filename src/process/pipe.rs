@@ -0,0 +1,209 @@
+//! Pipes: a bounded buffer with a reading end and a writing end, each an open file. Reading
+//! waits for something to read, and gives the end of the file once the writing end is closed;
+//! writing waits for room, and fails with `Error::BrokenPipe` (and `SIGPIPE`) once the reading
+//! end is closed. An end is closed when the last descriptor for it is, in whichever process.
+//!
+//! A pipe's buffer is a frame of its own rather than heap, and there are only so many pipes.
+
+use super::fd::FileOps;
+use super::signal;
+use crate::memory;
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall::Error;
+use alloc::sync::Arc;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::paging::PhysFrame;
+
+/// How many bytes a pipe holds before writers have to wait: a page.
+pub const PIPE_SIZE: usize = 4096;
+
+/// How many pipes there can be, in all processes together.
+pub const MAX_PIPES: usize = 128;
+
+static PIPES: AtomicUsize = AtomicUsize::new(0);
+
+/* A ring of PIPE_SIZE bytes in `frame`, `len` of them from `start` waiting to be read. */
+struct Buffer {
+    frame: PhysFrame,
+    start: usize,
+    len: usize,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+struct Pipe {
+    buffer: IrqSpinLock<Buffer>,
+    /* Readers waiting for something to read, and writers for room. */
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+/// The reading end of a pipe.
+pub struct PipeReader(Arc<Pipe>);
+
+/// The writing end of a pipe.
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Make a pipe, returning its two ends. `Error::FileTableFull` if there are `MAX_PIPES` already.
+pub fn pipe() -> Result<(PipeReader, PipeWriter), Error> {
+    if PIPES.fetch_add(1, Ordering::Relaxed) >= MAX_PIPES {
+        PIPES.fetch_sub(1, Ordering::Relaxed);
+        return Err(Error::FileTableFull);
+    }
+    let frame = match memory::allocate_frame() {
+        Some(frame) => *frame,
+        None => {
+            PIPES.fetch_sub(1, Ordering::Relaxed);
+            return Err(Error::NoMemory);
+        }
+    };
+    let pipe = Arc::new(Pipe {
+        buffer: IrqSpinLock::new(Buffer { frame, start: 0, len: 0, reader_open: true, writer_open: true }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    Ok((PipeReader(pipe.clone()), PipeWriter(pipe)))
+}
+
+impl Buffer {
+    fn bytes(&mut self) -> &mut [u8] {
+        let virt = memory::phys_to_virt(self.frame.start_address()).unwrap();
+        unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), PIPE_SIZE) }
+    }
+
+    /* Take as much as fits in `buf`, returning how much. */
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let len = cmp::min(buf.len(), self.len);
+        let (start, first) = (self.start, cmp::min(len, PIPE_SIZE - self.start));
+        let bytes = self.bytes();
+        buf[..first].copy_from_slice(&bytes[start..start + first]);
+        buf[first..len].copy_from_slice(&bytes[..len - first]);
+        self.start = (start + len) % PIPE_SIZE;
+        self.len -= len;
+        len
+    }
+
+    /* Add as much of `buf` as there's room for, returning how much. */
+    fn put(&mut self, buf: &[u8]) -> usize {
+        let len = cmp::min(buf.len(), PIPE_SIZE - self.len);
+        let end = (self.start + self.len) % PIPE_SIZE;
+        let first = cmp::min(len, PIPE_SIZE - end);
+        let bytes = self.bytes();
+        bytes[end..end + first].copy_from_slice(&buf[..first]);
+        bytes[..len - first].copy_from_slice(&buf[first..len]);
+        self.len += len;
+        len
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        memory::free_frame(self.buffer.lock().frame);
+        PIPES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl FileOps for PipeReader {
+    /// Wait until there's something to read, and read as much of it as fits. A signal stops the
+    /// wait.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut result = Ok(0);
+        pipe.readable.wait_until(|| {
+            let mut buffer = pipe.buffer.lock();
+            if buffer.len > 0 {
+                result = Ok(buffer.take(buf));
+                return true;
+            }
+            if !buffer.writer_open {
+                return true;
+            }
+            drop(buffer);
+            if signal::interrupted() {
+                result = Err(Error::Interrupted);
+                return true;
+            }
+            false
+        });
+        if let Ok(len) = result {
+            if len > 0 {
+                pipe.writable.wake_all();
+            }
+        }
+        result
+    }
+}
+
+/* How far a writer got before it had to stop waiting. */
+enum Progress {
+    Wrote,
+    Broken,
+    Interrupted,
+}
+
+impl FileOps for PipeWriter {
+    /// Write all of `buf`, waiting for room as needed. If a signal stops the wait, or the reading
+    /// end is closed, returns how much was written, or the error if that's nothing.
+    fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buf.len() {
+            let mut progress = Progress::Wrote;
+            pipe.writable.wait_until(|| {
+                let mut buffer = pipe.buffer.lock();
+                if !buffer.reader_open {
+                    progress = Progress::Broken;
+                    return true;
+                }
+                let len = buffer.put(&buf[written..]);
+                if len > 0 {
+                    written += len;
+                    progress = Progress::Wrote;
+                    return true;
+                }
+                drop(buffer);
+                if signal::interrupted() {
+                    progress = Progress::Interrupted;
+                    return true;
+                }
+                false
+            });
+            match progress {
+                Progress::Wrote => {
+                    pipe.readable.wake_all();
+                }
+                Progress::Broken if written == 0 => {
+                    if let Some(pid) = super::current() {
+                        let _ = signal::send(pid, signal::SIGPIPE);
+                    }
+                    return Err(Error::BrokenPipe);
+                }
+                Progress::Interrupted if written == 0 => return Err(Error::Interrupted),
+                Progress::Broken | Progress::Interrupted => break,
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        {
+            let mut buffer = self.0.buffer.lock();
+            buffer.reader_open = false;
+            buffer.len = 0;
+        }
+        self.0.writable.wake_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.buffer.lock().writer_open = false;
+        self.0.readable.wake_all();
+    }
+}
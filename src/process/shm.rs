@@ -0,0 +1,99 @@
+//! Shared memory: a run of zeroed pages that processes map into their address spaces, through a
+//! file descriptor for it, and see each other's writes in. Each mapping of a page holds a reference
+//! to its frame, as does the object while it has the page, so a frame is freed once the last of
+//! them lets go, whether that's the last descriptor closing or the last mapping going. Shrinking
+//! the object doesn't take pages away from mappings that have them.
+
+use super::fd::FileOps;
+use crate::memory;
+use crate::sync::IrqSpinLock;
+use crate::syscall::Error;
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::structures::paging::PhysFrame;
+
+/// The most pages a shared memory object can have.
+pub const MAX_SHM_PAGES: u64 = 1024;
+
+pub struct SharedMemory {
+    frames: IrqSpinLock<Vec<PhysFrame>>,
+}
+
+impl SharedMemory {
+    /// A shared memory object of `size` bytes, rounded up to whole pages.
+    pub fn new(size: u64) -> Result<SharedMemory, Error> {
+        let shm = SharedMemory { frames: IrqSpinLock::new(Vec::new()) };
+        shm.truncate(size)?;
+        Ok(shm)
+    }
+
+    /// How big it is, in bytes.
+    pub fn size(&self) -> u64 {
+        self.frames.lock().len() as u64 * 4096
+    }
+}
+
+impl FileOps for SharedMemory {
+    /// Grow with zeroed pages, or shrink, to `len` bytes rounded up to whole pages.
+    fn truncate(&self, len: u64) -> Result<(), Error> {
+        let pages = match len.checked_add(4095) {
+            Some(end) if end / 4096 <= MAX_SHM_PAGES => (end / 4096) as usize,
+            _ => return Err(Error::TooBig),
+        };
+        /* New pages are zeroed before taking the lock, which holds interrupts off, and then added;
+         * if it shrank in the meantime, more are got ready. Whatever's left over is freed. */
+        let mut ready = Vec::new();
+        let freed = loop {
+            let have = self.frames.lock().len();
+            while have + ready.len() < pages {
+                match memory::allocate_frame() {
+                    Some(frame) => {
+                        let virt = memory::phys_to_virt(frame.start_address()).unwrap();
+                        unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, 4096) };
+                        ready.push(*frame);
+                    }
+                    None => {
+                        for frame in ready {
+                            memory::free_frame(frame);
+                        }
+                        return Err(Error::NoMemory);
+                    }
+                }
+            }
+            let mut frames = self.frames.lock();
+            if frames.len() + ready.len() >= pages {
+                let more = pages.saturating_sub(frames.len());
+                frames.extend(ready.drain(..more));
+                break frames.split_off(pages);
+            }
+        };
+        for frame in freed.into_iter().chain(ready) {
+            memory::free_frame(frame);
+        }
+        Ok(())
+    }
+
+    fn frames(&self, first: u64, pages: u64) -> Result<Vec<PhysFrame>, Error> {
+        let frames = self.frames.lock();
+        let end = first.checked_add(pages).ok_or(Error::Invalid)?;
+        if pages == 0 || end > frames.len() as u64 {
+            return Err(Error::Invalid);
+        }
+        let wanted = frames[first as usize..end as usize].to_vec();
+        for (i, &frame) in wanted.iter().enumerate() {
+            if !memory::share_frame(frame) {
+                wanted[..i].iter().for_each(|&frame| memory::free_frame(frame));
+                return Err(Error::NoMemory);
+            }
+        }
+        Ok(wanted)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for frame in mem::take(&mut *self.frames.lock()) {
+            memory::free_frame(frame);
+        }
+    }
+}
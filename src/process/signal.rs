@@ -0,0 +1,509 @@
+//! POSIX-style signals. Each process has a set of pending signals, a mask of blocked ones, and an
+//! action for each: the default (terminate, ignore, stop or continue), ignore, or a handler.
+//!
+//! Signals are acted on when the process is on its way back to user mode: after a system call,
+//! or straight away for the ones a CPU exception raises. A handler runs on the user stack, below a
+//! `SignalFrame` saving the interrupted registers, and returns to the action's restorer, which
+//! has to make the `sigreturn()` system call to carry on from where it was. A process that's
+//! preempted in user mode can be terminated or stopped there, but its handlers wait until it
+//! next enters the kernel.
+
+use super::{ExitStatus, Pid, ProcessState, PROCESSES};
+use crate::sync::WaitQueue;
+use crate::syscall::{self, Error};
+use crate::thread;
+use crate::user::{self, Registers, USER_END, USER_START};
+use alloc::{vec, vec::Vec};
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Signal numbers, as Linux's.
+pub const SIGHUP: u8 = 1;
+pub const SIGINT: u8 = 2;
+pub const SIGQUIT: u8 = 3;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGABRT: u8 = 6;
+pub const SIGBUS: u8 = 7;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGUSR1: u8 = 10;
+pub const SIGSEGV: u8 = 11;
+pub const SIGUSR2: u8 = 12;
+pub const SIGPIPE: u8 = 13;
+pub const SIGALRM: u8 = 14;
+pub const SIGTERM: u8 = 15;
+pub const SIGCHLD: u8 = 17;
+pub const SIGCONT: u8 = 18;
+pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+pub const SIGTTIN: u8 = 21;
+pub const SIGTTOU: u8 = 22;
+pub const SIGURG: u8 = 23;
+pub const SIGWINCH: u8 = 28;
+/// One more than the highest signal number.
+pub const NSIG: u8 = 32;
+
+/// `SigAction::handler` values that aren't handlers.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// `SigAction::flags`.
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `sigprocmask()`'s ways of changing the mask.
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/* Below the user stack pointer, which a handler mustn't touch. */
+const RED_ZONE: u64 = 128;
+
+/* Rflags' direction flag, which the ABI has clear on entry to a function. */
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// A set of signals, as a mask: bit `n - 1` for signal `n`.
+pub fn bit(signal: u8) -> u64 {
+    1 << (signal - 1)
+}
+
+/* Signals that can't be caught, blocked or ignored. */
+fn unblockable() -> u64 {
+    bit(SIGKILL) | bit(SIGSTOP)
+}
+
+fn stop_signals() -> u64 {
+    bit(SIGSTOP) | bit(SIGTSTP) | bit(SIGTTIN) | bit(SIGTTOU)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    /// Carry on if stopped; sending the signal does that, so it's otherwise ignored.
+    Continue,
+}
+
+pub fn default_action(signal: u8) -> DefaultAction {
+    match signal {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// What to do with a signal, as set by `sigaction()`, in Linux's layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN`, or the handler's address. It's called with the signal in rdi.
+    pub handler: u64,
+    pub flags: u64,
+    /// Where the handler returns to, to call `sigreturn()`.
+    pub restorer: u64,
+    /// Signals to block while the handler runs, as well as the one it's handling.
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub const SIZE: usize = 32;
+
+    pub fn from_bytes(bytes: &[u8; SigAction::SIZE]) -> SigAction {
+        let word = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        SigAction { handler: word(0), flags: word(1), restorer: word(2), mask: word(3) }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SigAction::SIZE] {
+        let mut bytes = [0; SigAction::SIZE];
+        for (i, word) in [self.handler, self.flags, self.restorer, self.mask].iter().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&word.to_ne_bytes());
+        }
+        bytes
+    }
+}
+
+/// What's put on the user stack for a handler, lowest address first.
+#[repr(C)]
+pub struct SignalFrame {
+    /// The handler's return address.
+    pub restorer: u64,
+    /// Where to carry on from after the handler.
+    pub registers: Registers,
+    /// The blocked signals to go back to.
+    pub blocked: u64,
+    pub signal: u64,
+}
+
+/// A process's signal state.
+#[derive(Clone)]
+pub(super) struct Signals {
+    pending: u64,
+    blocked: u64,
+    actions: [SigAction; NSIG as usize],
+}
+
+impl Signals {
+    pub(super) fn new() -> Self {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::default(); NSIG as usize],
+        }
+    }
+
+    /// For a child: the same actions and mask, but nothing pending.
+    pub(super) fn fork(&self) -> Self {
+        Signals { pending: 0, ..self.clone() }
+    }
+
+    /// Handlers are in the old program, so go back to the default. Ignored signals stay ignored.
+    pub(super) fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+
+    fn ignored(&self, signal: u8) -> bool {
+        match self.actions[signal as usize].handler {
+            SIG_IGN => true,
+            SIG_DFL => match default_action(signal) {
+                DefaultAction::Ignore | DefaultAction::Continue => true,
+                DefaultAction::Terminate | DefaultAction::Stop => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The next pending signal that isn't blocked, SIGKILL first.
+    fn next(&self) -> Option<u8> {
+        let ready = self.pending & !self.blocked;
+        if ready & bit(SIGKILL) != 0 {
+            Some(SIGKILL)
+        } else if ready != 0 {
+            Some(ready.trailing_zeros() as u8 + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a signal that will do something is waiting, so blocking calls should give up.
+    pub(super) fn interrupting(&self) -> bool {
+        (1..NSIG).any(|signal| self.pending & !self.blocked & bit(signal) != 0 && !self.ignored(signal))
+    }
+}
+
+/* What's to be done with a signal being delivered. */
+enum Disposition {
+    Ignore,
+    Terminate,
+    Stop,
+    /* Run the handler, then go back to blocking these. */
+    Handle(SigAction, u64),
+}
+
+/* Woken when a stopped process is continued (or killed). */
+static CONTINUED: WaitQueue = WaitQueue::new();
+
+/* Where Ctrl-C goes; 0 for nowhere. */
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+/// Make `pid` the process that gets SIGINT (along with its descendants) when Ctrl-C is pressed.
+pub fn set_foreground(pid: Option<Pid>) {
+    FOREGROUND.store(pid.map_or(0, Pid::as_u64), Ordering::Relaxed);
+}
+
+/// Called from the keyboard interrupt handler on Ctrl-C.
+pub(crate) fn interrupt_foreground() {
+    let foreground = match FOREGROUND.load(Ordering::Relaxed) {
+        0 => return,
+        pid => Pid::from_u64(pid),
+    };
+    let mut targets = Vec::new();
+    {
+        let procs = PROCESSES.lock();
+        let mut next = vec![foreground];
+        while let Some(pid) = next.pop() {
+            if procs.procs.contains_key(&pid) {
+                targets.push(pid);
+                next.extend(procs.procs.iter().filter(|(_, p)| p.parent == Some(pid)).map(|(&c, _)| c));
+            }
+        }
+    }
+    for pid in targets {
+        let _ = send(pid, SIGINT);
+    }
+}
+
+/// Send `signal` to a process. Signal 0 just checks the process exists.
+pub fn send(pid: Pid, signal: u8) -> Result<(), Error> {
+    if signal >= NSIG {
+        return Err(Error::Invalid);
+    }
+    let (thread, continued) = {
+        let mut procs = PROCESSES.lock();
+        let p = match procs.procs.get_mut(&pid) {
+            Some(p) if !p.is_zombie() => p,
+            _ => return Err(Error::NoProcess),
+        };
+        if signal == 0 {
+            return Ok(());
+        }
+        let s = &mut p.signals;
+        let mut continued = false;
+        if signal == SIGCONT {
+            s.pending &= !stop_signals();
+            if p.state == ProcessState::Stopped {
+                p.state = ProcessState::Running;
+                continued = true;
+            }
+        } else if bit(signal) & stop_signals() != 0 {
+            s.pending &= !bit(SIGCONT);
+        }
+        /* Ignored signals are dropped, unless they're blocked: they might not be ignored by the
+         * time they're unblocked. */
+        if s.blocked & bit(signal) != 0 || !s.ignored(signal) {
+            s.pending |= bit(signal);
+        }
+        (p.thread, continued)
+    };
+    if continued {
+        CONTINUED.wake_all();
+    }
+    /* Interrupt what it's waiting for, if anything; with SIGKILL, that includes being stopped. */
+    thread::wake(thread);
+    if signal == SIGKILL {
+        CONTINUED.wake_all();
+    }
+    Ok(())
+}
+
+/// Whether the calling process has a signal to act on, for blocking calls to give up waiting
+/// (with `Error::Interrupted`).
+pub fn interrupted() -> bool {
+    let procs = PROCESSES.lock();
+    match procs.by_thread.get(&thread::current()) {
+        Some(pid) => procs.procs[pid].signals.interrupting(),
+        None => false,
+    }
+}
+
+/// Set the calling process's action for `signal`, if `action` is Some, and return the old one.
+pub fn set_action(signal: u8, action: Option<SigAction>) -> Result<SigAction, Error> {
+    if signal == 0 || signal >= NSIG {
+        return Err(Error::Invalid);
+    }
+    if let Some(action) = action {
+        if bit(signal) & unblockable() != 0 || !(is_handler(action.handler) && is_handler(action.restorer)) {
+            return Err(Error::Invalid);
+        }
+    }
+    super::with_current(|p| {
+        let s = &mut p.signals;
+        let old = s.actions[signal as usize];
+        if let Some(action) = action {
+            s.actions[signal as usize] = action;
+            if s.ignored(signal) {
+                s.pending &= !bit(signal);
+            }
+        }
+        old
+    })
+}
+
+/* SIG_DFL, SIG_IGN or a user address: anything that's safe to return to user mode at. */
+fn is_handler(addr: u64) -> bool {
+    addr == SIG_DFL || addr == SIG_IGN || (addr >= USER_START && addr < USER_END)
+}
+
+/// Change the calling process's blocked signals (`how` being `SIG_BLOCK`, `SIG_UNBLOCK` or
+/// `SIG_SETMASK`), if `set` is Some, and return the old mask.
+pub fn set_blocked(how: u64, set: Option<u64>) -> Result<u64, Error> {
+    if set.is_some() && how > SIG_SETMASK {
+        return Err(Error::Invalid);
+    }
+    super::with_current(|p| {
+        let s = &mut p.signals;
+        let old = s.blocked;
+        if let Some(set) = set {
+            let blocked = match how {
+                SIG_BLOCK => old | set,
+                SIG_UNBLOCK => old & !set,
+                _ => set,
+            };
+            s.blocked = blocked & !unblockable();
+        }
+        old
+    })
+}
+
+/// Act on the calling process's pending signals, on its way back to user mode with `regs`:
+/// ignore them, stop until continued, terminate, or run a handler, in which case this doesn't
+/// return. Does nothing for threads outside processes.
+pub fn handle_pending(regs: &Registers) {
+    loop {
+        match next_disposition() {
+            None => return,
+            Some((_, Disposition::Ignore)) => (),
+            Some((signal, Disposition::Terminate)) => super::exit(ExitStatus::Killed(signal)),
+            Some((_, Disposition::Stop)) => stop(),
+            Some((signal, Disposition::Handle(action, blocked))) => run_handler(signal, action, blocked, regs),
+        }
+    }
+}
+
+/// From the timer interrupt, when it arrived in user mode: terminate or stop the process if a
+/// signal says to. Handlers have to wait, as the user registers aren't to hand here.
+pub(crate) fn handle_preempted() {
+    let signal = {
+        let mut procs = PROCESSES.lock();
+        let pid = match procs.by_thread.get(&thread::current()) {
+            Some(&pid) => pid,
+            None => return,
+        };
+        let s = &mut procs.procs.get_mut(&pid).unwrap().signals;
+        let ready = (1..NSIG).find(|&signal| {
+            s.pending & !s.blocked & bit(signal) != 0
+                && s.actions[signal as usize].handler == SIG_DFL
+                && !s.ignored(signal)
+        });
+        match ready {
+            Some(signal) => {
+                s.pending &= !bit(signal);
+                signal
+            }
+            None => return,
+        }
+    };
+    match default_action(signal) {
+        DefaultAction::Stop => stop(),
+        _ => super::exit(ExitStatus::Killed(signal)),
+    }
+}
+
+/// A CPU exception in user mode, with the registers when it happened, raised `signal`. The
+/// process's handler runs if it has one; if the signal is ignored or blocked, or has no handler,
+/// the process is terminated.
+pub fn force(signal: u8, regs: &Registers) -> ! {
+    let action = super::with_current(|p| {
+        let s = &mut p.signals;
+        let action = s.actions[signal as usize];
+        if action.handler == SIG_DFL || action.handler == SIG_IGN || s.blocked & bit(signal) != 0 {
+            return None;
+        }
+        let blocked = s.blocked;
+        block_for_handler(s, signal, &action);
+        Some((action, blocked))
+    });
+    match action {
+        Ok(Some((action, blocked))) => run_handler(signal, action, blocked, regs),
+        _ => super::exit(ExitStatus::Killed(signal)),
+    }
+}
+
+/// `sigreturn()`: carry on from before the handler whose frame's registers are at `addr`, just
+/// past the return address the handler popped.
+pub fn sigreturn(addr: u64) -> ! {
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    let frame = match syscall::user_slice(addr.wrapping_sub(8), size) {
+        Ok(bytes) => unsafe { (bytes.as_ptr() as *const SignalFrame).read_unaligned() },
+        Err(_) => super::exit(ExitStatus::Killed(SIGSEGV)),
+    };
+    if frame.registers.rip >= USER_END {
+        super::exit(ExitStatus::Killed(SIGSEGV));
+    }
+    let _ = super::with_current(|p| p.signals.blocked = frame.blocked & !unblockable());
+    handle_pending(&frame.registers);
+    unsafe { user::resume(&frame.registers) }
+}
+
+/* Take the calling process's next signal, and work out what to do with it. */
+fn next_disposition() -> Option<(u8, Disposition)> {
+    let mut procs = PROCESSES.lock();
+    let pid = *procs.by_thread.get(&thread::current())?;
+    let s = &mut procs.procs.get_mut(&pid).unwrap().signals;
+    let signal = s.next()?;
+    s.pending &= !bit(signal);
+    let action = s.actions[signal as usize];
+    let disposition = match action.handler {
+        SIG_IGN => Disposition::Ignore,
+        SIG_DFL => match default_action(signal) {
+            DefaultAction::Terminate => Disposition::Terminate,
+            DefaultAction::Stop => Disposition::Stop,
+            DefaultAction::Ignore | DefaultAction::Continue => Disposition::Ignore,
+        },
+        _ => {
+            let blocked = s.blocked;
+            block_for_handler(s, signal, &action);
+            Disposition::Handle(action, blocked)
+        }
+    };
+    Some((signal, disposition))
+}
+
+/* Block what the action says to while its handler runs, and reset it if it's one-shot. */
+fn block_for_handler(s: &mut Signals, signal: u8, action: &SigAction) {
+    let mut blocked = s.blocked | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        blocked |= bit(signal);
+    }
+    s.blocked = blocked & !unblockable();
+    if action.flags & SA_RESETHAND != 0 {
+        s.actions[signal as usize] = SigAction::default();
+    }
+}
+
+/* Stop the calling process until it's sent SIGCONT (or SIGKILL). */
+fn stop() {
+    let pid = {
+        let mut procs = PROCESSES.lock();
+        let pid = match procs.by_thread.get(&thread::current()) {
+            Some(&pid) => pid,
+            None => return,
+        };
+        procs.procs.get_mut(&pid).unwrap().state = ProcessState::Stopped;
+        pid
+    };
+    log::debug!("Process {} stopped", pid);
+    CONTINUED.wait_until(|| {
+        let mut procs = PROCESSES.lock();
+        let p = procs.procs.get_mut(&pid).unwrap();
+        if p.signals.pending & bit(SIGKILL) != 0 {
+            p.state = ProcessState::Running;
+        }
+        p.state != ProcessState::Stopped
+    });
+}
+
+/* Push a frame for the handler on the user stack and go to it. If the frame can't be written,
+ * the process is terminated with SIGSEGV. */
+fn run_handler(signal: u8, action: SigAction, blocked: u64, regs: &Registers) -> ! {
+    let frame = SignalFrame {
+        restorer: action.restorer,
+        registers: *regs,
+        blocked,
+        signal: u64::from(signal),
+    };
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    /* Aligned as if the handler had been called: 16 bytes, less the return address. */
+    let sp = (regs.rsp.wrapping_sub(RED_ZONE + size) & !15).wrapping_sub(8);
+    match syscall::user_slice_mut(sp, size) {
+        Ok(bytes) => unsafe { (bytes.as_mut_ptr() as *mut SignalFrame).write_unaligned(frame) },
+        Err(_) => super::exit(ExitStatus::Killed(SIGSEGV)),
+    }
+    let handler = Registers {
+        rip: action.handler,
+        rsp: sp,
+        rdi: u64::from(signal),
+        rsi: 0,
+        rdx: sp + 8,
+        rax: 0,
+        rflags: regs.rflags & !RFLAGS_DF,
+        ..*regs
+    };
+    unsafe { user::resume(&handler) }
+}
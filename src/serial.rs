@@ -1,26 +1,725 @@
+use crate::cmdline;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use crate::sync::IrqSpinLock;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::PhysAddr;
+
+pub const MAX_PORTS: usize = 4;
+
+/* The conventional COM1-COM4 bases & IRQs, for when the BIOS Data Area doesn't tell us. */
+const COM_BASES: [u16; MAX_PORTS] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+const COM_IRQS: [u8; MAX_PORTS] = [
+    crate::interrupts::IRQ_COM1,
+    crate::interrupts::IRQ_COM2,
+    crate::interrupts::IRQ_COM1,
+    crate::interrupts::IRQ_COM2,
+];
+
+/* The BIOS lists the ports it found as four u16 bases here, 0 meaning absent. */
+const BDA_COM_PORTS: u64 = 0x400;
+
+const UART_CLOCK: u32 = 115_200;
+
+const RING_SIZE: usize = 256;
+const FIFO_DEPTH: usize = 16;
+
+// Interrupt Enable Register bits
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+
+// Interrupt Identification Register
+const IIR_NO_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b1110;
+const IIR_LINE_STATUS: u8 = 0b0110;
+const IIR_RX_AVAILABLE: u8 = 0b0100;
+const IIR_RX_TIMEOUT: u8 = 0b1100;
+const IIR_TX_EMPTY: u8 = 0b0010;
+
+// Line Status Register bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6; // nothing in the FIFO or being shifted out
+
+// FIFO Control Register bits
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const FCR_RX_14: u8 = 0b11 << 6;
+
+// Line Control Register bits
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_STOP_2: u8 = 1 << 2;
+const LCR_PARITY_EN: u8 = 1 << 3;
+const LCR_PARITY_EVEN: u8 = 1 << 4;
+const LCR_PARITY_STICK: u8 = 1 << 5;
+
+// Modem Control Register bits
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3; // gates the UART's IRQ line on PCs
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const PROBE_BYTE: u8 = 0xAE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+}
+
+impl LineConfig {
+    /// 38400 8n1, what we've always used.
+    pub const DEFAULT: LineConfig = LineConfig {
+        baud: 38400,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+
+    /// Parse Linux console syntax: `<baud>[<parity>[<data bits>[<stop bits>]]]`, eg `115200n8` or
+    /// `9600e71`. Anything unspecified is as `DEFAULT`.
+    pub fn parse(s: &str) -> Option<LineConfig> {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (baud, rest) = s.split_at(digits);
+        let mut cfg = LineConfig {
+            baud: baud.parse().ok()?,
+            ..LineConfig::DEFAULT
+        };
+
+        let mut rest = rest.chars();
+        if let Some(p) = rest.next() {
+            cfg.parity = match p {
+                'n' => Parity::None,
+                'o' => Parity::Odd,
+                'e' => Parity::Even,
+                'm' => Parity::Mark,
+                's' => Parity::Space,
+                _ => return None,
+            };
+        }
+        if let Some(d) = rest.next() {
+            cfg.data_bits = d.to_digit(10)? as u8;
+        }
+        if let Some(st) = rest.next() {
+            cfg.stop_bits = st.to_digit(10)? as u8;
+        }
+        if rest.next().is_some() {
+            return None;
+        }
+
+        if cfg.divisor().is_some() && (5..=8).contains(&cfg.data_bits) && (1..=2).contains(&cfg.stop_bits) {
+            Some(cfg)
+        } else {
+            None
+        }
+    }
+
+    fn divisor(&self) -> Option<u16> {
+        if self.baud == 0 || UART_CLOCK % self.baud != 0 {
+            return None;
+        }
+        let d = UART_CLOCK / self.baud;
+        if d > u32::from(u16::max_value()) {
+            None
+        } else {
+            Some(d as u16)
+        }
+    }
+
+    fn line_ctrl(&self) -> u8 {
+        let mut lcr = self.data_bits - 5;
+        if self.stop_bits == 2 {
+            lcr |= LCR_STOP_2;
+        }
+        lcr |= match self.parity {
+            Parity::None => 0,
+            Parity::Odd => LCR_PARITY_EN,
+            Parity::Even => LCR_PARITY_EN | LCR_PARITY_EVEN,
+            Parity::Mark => LCR_PARITY_EN | LCR_PARITY_STICK,
+            Parity::Space => LCR_PARITY_EN | LCR_PARITY_EVEN | LCR_PARITY_STICK,
+        };
+        lcr
+    }
+}
+
+impl fmt::Display for LineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = match self.parity {
+            Parity::None => 'n',
+            Parity::Odd => 'o',
+            Parity::Even => 'e',
+            Parity::Mark => 'm',
+            Parity::Space => 's',
+        };
+        write!(f, "{}{}{}{}", self.baud, p, self.data_bits, self.stop_bits)
+    }
+}
+
+/// What each port is used for; chosen on the command line with eg `shell=ttyS1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Log,
+    Shell,
+    Debug,
+}
+
+impl Role {
+    const ALL: [Role; 3] = [Role::Log, Role::Shell, Role::Debug];
+
+    fn name(self) -> &'static str {
+        match self {
+            Role::Log => "log",
+            Role::Shell => "shell",
+            Role::Debug => "debug",
+        }
+    }
+}
+
+/* Port index for each Role. Everything starts on COM1. */
+static ROLES: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Fixed-size byte FIFO. Fixed rather than heap-backed because the UARTs are used before the heap
+/// is up.
+pub struct RingBuffer {
+    buf: [u8; RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: [0; RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, b: u8) -> Result<(), u8> {
+        if self.is_full() {
+            return Err(b);
+        }
+        self.buf[(self.head + self.len) % RING_SIZE] = b;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let b = self.buf[self.head];
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(b)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == RING_SIZE
+    }
+}
+
+/// A 16550 UART. Until `enable_interrupts()` is called all output is polled, which is what early
+/// boot and the test binaries rely on.
+pub struct Uart {
+    base: u16,
+    data: Port<u8>,
+    int_en: Port<u8>,
+    fifo_ctrl: Port<u8>,
+    int_id: PortReadOnly<u8>,
+    line_ctrl: Port<u8>,
+    modem_ctrl: Port<u8>,
+    line_sts: PortReadOnly<u8>,
+    modem_sts: PortReadOnly<u8>,
+    rx: RingBuffer,
+    tx: RingBuffer,
+    irq_mode: bool,
+    rx_dropped: usize,
+}
+
+impl Uart {
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart {
+            base,
+            data: Port::new(base),
+            int_en: Port::new(base + 1),
+            fifo_ctrl: Port::new(base + 2),
+            int_id: PortReadOnly::new(base + 2),
+            line_ctrl: Port::new(base + 3),
+            modem_ctrl: Port::new(base + 4),
+            line_sts: PortReadOnly::new(base + 5),
+            modem_sts: PortReadOnly::new(base + 6),
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            irq_mode: false,
+            rx_dropped: 0,
+        }
+    }
+
+    /// Program the line settings, FIFOs on, all UART interrupts off.
+    pub fn init(&mut self, config: &LineConfig) {
+        let divisor = config.divisor().expect("Unrepresentable baud rate");
+        unsafe {
+            self.int_en.write(0x00);
+            self.line_ctrl.write(LCR_DLAB); // data & int_en become the divisor latch
+            self.data.write(divisor as u8);
+            self.int_en.write((divisor >> 8) as u8);
+            self.line_ctrl.write(config.line_ctrl());
+            self.fifo_ctrl.write(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_RX_14);
+            self.modem_ctrl.write(MCR_DTR | MCR_RTS | MCR_OUT2);
+        }
+    }
+
+    /// Whether there's a UART here at all: in loopback mode what we send should come straight back.
+    /// The modem controls are left as they were, but the FIFOs are emptied (after letting what's
+    /// being sent go), so `init()` should follow.
+    pub fn probe(&mut self) -> bool {
+        unsafe {
+            for _ in 0..100_000 {
+                if self.line_status() & LSR_TX_IDLE != 0 {
+                    break;
+                }
+            }
+            let mcr = self.modem_ctrl.read();
+            self.modem_ctrl.write(MCR_LOOPBACK | MCR_RTS);
+            /* So a byte that was waiting isn't taken for the echo. */
+            self.fifo_ctrl.write(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+            self.data.write(PROBE_BYTE);
+            for _ in 0..1000 {
+                if self.line_status() & LSR_DATA_READY != 0 {
+                    break;
+                }
+            }
+            let present = self.line_status() & LSR_DATA_READY != 0 && self.data.read() == PROBE_BYTE;
+            self.fifo_ctrl.write(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+            self.modem_ctrl.write(mcr);
+            present
+        }
+    }
+
+    /// Switch to interrupt-driven operation. The caller must have unmasked the UART's IRQ.
+    pub fn enable_interrupts(&mut self) {
+        self.irq_mode = true;
+        unsafe { self.int_en.write(IER_RX_AVAILABLE) };
+    }
+
+    fn line_status(&mut self) -> u8 {
+        unsafe { self.line_sts.read() }
+    }
+
+    fn send_polled(&mut self, b: u8) {
+        while self.line_status() & LSR_TX_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        unsafe { self.data.write(b) };
+    }
+
+    pub fn send(&mut self, b: u8) {
+        if !self.irq_mode {
+            self.send_polled(b);
+            return;
+        }
+
+        /* We're called with interrupts off, so if the ring is full we can't wait for the TX IRQ to
+         * drain it; push the oldest byte out by hand instead. */
+        if self.tx.is_full() {
+            let oldest = self.tx.pop().unwrap();
+            self.send_polled(oldest);
+        }
+        self.tx.push(b).unwrap();
+        self.kick_tx();
+    }
+
+    /// Fill the transmitter's FIFO from the TX ring if it's empty, arming the TX-empty interrupt
+    /// while there's more to send.
+    fn kick_tx(&mut self) {
+        if self.line_status() & LSR_TX_EMPTY != 0 {
+            for _ in 0..FIFO_DEPTH {
+                match self.tx.pop() {
+                    Some(b) => unsafe { self.data.write(b) },
+                    None => break,
+                }
+            }
+        }
+
+        /* Armed even if the FIFO's still draining, so the TX-empty interrupt gets us back here
+         * once it has: nothing else may come along to send the rest. */
+        let ier = if self.tx.is_empty() {
+            IER_RX_AVAILABLE
+        } else {
+            IER_RX_AVAILABLE | IER_TX_EMPTY
+        };
+        unsafe { self.int_en.write(ier) };
+    }
+
+    /// Drain the TX ring by polling. Used before we stop taking interrupts for good.
+    pub fn flush(&mut self) {
+        while let Some(b) = self.tx.pop() {
+            self.send_polled(b);
+        }
+    }
+
+    pub fn receive(&mut self) -> Option<u8> {
+        if self.irq_mode {
+            return self.rx.pop();
+        }
+        if self.line_status() & LSR_DATA_READY != 0 {
+            Some(unsafe { self.data.read() })
+        } else {
+            None
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Bytes dropped because the RX ring was full.
+    pub fn rx_dropped(&self) -> usize {
+        self.rx_dropped
+    }
+
+    /// Service everything the UART has pending. Returns true if any bytes were received.
+    pub fn handle_interrupt(&mut self) -> bool {
+        let mut received = false;
+
+        loop {
+            let iir = unsafe { self.int_id.read() };
+            if iir & IIR_NO_PENDING != 0 {
+                break;
+            }
+
+            match iir & IIR_ID_MASK {
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT | IIR_LINE_STATUS => {
+                    while self.line_status() & LSR_DATA_READY != 0 {
+                        let b = unsafe { self.data.read() };
+                        if self.rx.push(b).is_err() {
+                            self.rx_dropped += 1;
+                        }
+                        received = true;
+                    }
+                }
+                IIR_TX_EMPTY => self.kick_tx(),
+                _ => {
+                    // modem status change; we don't use those lines, but reading MSR clears it
+                    unsafe { self.modem_sts.read() };
+                }
+            }
+        }
+
+        received
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.send(b);
+        }
+        Ok(())
+    }
+}
 
 lazy_static! {
-    pub static ref UART1: Mutex<SerialPort> = {
-        let mut uart = unsafe { SerialPort::new(0x3F8) };
-        uart.init();
-        Mutex::new(uart)
+    /// COM1-COM4 by index. COM1 is assumed present so early boot can log; the rest appear once
+    /// `init()` has found them. Shared with the UART interrupt handlers.
+    pub static ref PORTS: [IrqSpinLock<Option<Uart>>; MAX_PORTS] = {
+        let mut com1 = unsafe { Uart::new(COM_BASES[0]) };
+        com1.init(&LineConfig::DEFAULT);
+        [IrqSpinLock::new(Some(com1)), IrqSpinLock::new(None), IrqSpinLock::new(None), IrqSpinLock::new(None)]
     };
 }
 
-#[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
+/// Find the ports, configure them from the command line (`ttyS<n>=<config>`), assign roles
+/// (`log=ttyS<n>` etc), and switch them over to interrupt-driven RX & TX.
+pub fn init() {
+    let bases = bios_ports().unwrap_or(COM_BASES);
+    let mut irqs = [false; 16];
+
+    for (i, &base) in bases.iter().enumerate() {
+        if base == 0 {
+            continue;
+        }
+        let mut uart = unsafe { Uart::new(base) };
+        if !uart.probe() {
+            continue;
+        }
+
+        let key = alloc::format!("ttyS{}", i);
+        let config = match cmdline::get(&key) {
+            Some(s) => LineConfig::parse(s).unwrap_or_else(|| {
+                log::warn!("{}: bad line settings {:?}, using {}", key, s, LineConfig::DEFAULT);
+                LineConfig::DEFAULT
+            }),
+            None => LineConfig::DEFAULT,
+        };
+
+        {
+            let mut port = PORTS[i].lock();
+            /* Let COM1 finish what it was saying at the old settings. */
+            if let Some(old) = port.as_mut() {
+                old.flush();
+            }
+            uart.init(&config);
+            uart.enable_interrupts();
+            *port = Some(uart);
+        }
+        irqs[usize::from(COM_IRQS[i])] = true;
+        log::info!("{} at {:#x}, {}", key, base, config);
+    }
+
+    for (irq, _) in irqs.iter().enumerate().filter(|(_, &used)| used) {
+        crate::interrupts::unmask_irq(irq as u8);
+    }
+
+    for &role in Role::ALL.iter() {
+        if let Some(tty) = cmdline::get(role.name()) {
+            match parse_tty(tty).filter(|&i| is_present(i)) {
+                Some(i) => ROLES[role as usize].store(i, Ordering::Relaxed),
+                None => log::warn!("{}: no such port {:?}, staying on ttyS0", role.name(), tty),
+            }
+        }
+    }
+}
+
+/// The port bases the BIOS found, if it left us a list.
+fn bios_ports() -> Option<[u16; MAX_PORTS]> {
+    let bda = crate::memory::phys_to_virt(PhysAddr::new(BDA_COM_PORTS))?;
+    let mut bases = [0u16; MAX_PORTS];
+    for (i, base) in bases.iter_mut().enumerate() {
+        *base = unsafe { core::ptr::read_volatile(bda.as_ptr::<u16>().add(i)) };
+    }
+    if bases.iter().all(|&b| b == 0) {
+        None // some firmware doesn't bother; fall back to probing the usual places
+    } else {
+        Some(bases)
+    }
+}
+
+fn parse_tty(s: &str) -> Option<usize> {
+    let i = s.trim_start_matches("ttyS").parse().ok()?;
+    if i < MAX_PORTS {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+pub fn is_present(port: usize) -> bool {
+    PORTS[port].lock().is_some()
+}
+
+/// Which port index is serving `role`.
+pub fn port(role: Role) -> usize {
+    ROLES[role as usize].load(Ordering::Relaxed)
+}
+
+/// Print a summary of the ports we found.
+pub fn dump_ports() {
+    for i in 0..MAX_PORTS {
+        if let Some(uart) = PORTS[i].lock().as_ref() {
+            let roles = Role::ALL.iter().filter(|&&r| port(r) == i);
+            crate::print!("ttyS{} @ {:#x}", i, uart.base());
+            for r in roles {
+                crate::print!(" {}", r.name());
+            }
+            crate::println!();
+        }
+    }
+}
+
+/// Service the UARTs sharing `irq`.
+pub fn handle_irq(irq: u8) {
+    for i in (0..MAX_PORTS).filter(|&i| COM_IRQS[i] == irq) {
+        let received = PORTS[i].lock().as_mut().map_or(false, |u| u.handle_interrupt());
+        if received {
+            RX_WAKERS[i].wake();
+        }
+    }
+}
+
+/// Push out anything still queued for transmission, eg before exiting QEMU.
+pub fn flush() {
+    for port in PORTS.iter() {
+        if let Some(uart) = port.lock().as_mut() {
+            uart.flush();
+        }
+    }
+}
+
+/// Block until a byte arrives on `port`. Halts waiting for it if interrupts are on, and spins if
+/// they're off, leaving them as they were.
+pub fn read_byte_from(port: usize) -> u8 {
+    use x86_64::instructions::interrupts;
+
+    let enabled = interrupts::are_enabled();
+    loop {
+        /* Check and sleep with interrupts off, so an RX interrupt can't land in between and leave
+         * us halted with a byte waiting. */
+        let received = interrupts::without_interrupts(|| {
+            let b = PORTS[port].lock().as_mut().and_then(|u| u.receive());
+            if b.is_none() && enabled {
+                interrupts::enable_and_hlt();
+            }
+            b
+        });
+        match received {
+            Some(b) => return b,
+            None if !enabled => core::sync::atomic::spin_loop_hint(),
+            None => (),
+        }
+    }
+}
+
+/// Block until a byte arrives on the shell port.
+pub fn read_byte() -> u8 {
+    read_byte_from(port(Role::Shell))
+}
+
+/// Line discipline for the shell port: input is echoed, backspace edits, and the line terminator
+/// (CR or LF) isn't included.
+pub struct LineEditor {
+    port: usize,
+    line: String,
+}
+
+impl LineEditor {
+    pub fn new(port: usize) -> Self {
+        LineEditor {
+            port,
+            line: String::new(),
+        }
+    }
+
+    /// Take one received byte; returns the line once it's been terminated.
+    pub fn feed(&mut self, b: u8) -> Option<String> {
+        match b {
+            b'\r' | b'\n' => {
+                write_to(self.port, format_args!("\r\n"));
+                return Some(core::mem::replace(&mut self.line, String::new()));
+            }
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    write_to(self.port, format_args!("\x08 \x08"));
+                }
+            }
+            b @ 0x20..=0x7e => {
+                self.line.push(b as char);
+                write_to(self.port, format_args!("{}", b as char));
+            }
+            _ => (),
+        }
+        None
+    }
+}
+
+/// Block until a full line arrives on the shell port.
+pub fn read_line() -> String {
+    let shell = port(Role::Shell);
+    let mut editor = LineEditor::new(shell);
+
+    loop {
+        if let Some(line) = editor.feed(read_byte_from(shell)) {
+            return line;
+        }
+    }
+}
+
+/* Tasks waiting for input on each port. */
+static RX_WAKERS: [AtomicWaker; MAX_PORTS] = [
+    AtomicWaker::new(),
+    AtomicWaker::new(),
+    AtomicWaker::new(),
+    AtomicWaker::new(),
+];
+
+/// Bytes received on a port, as an async stream.
+pub struct ByteStream {
+    port: usize,
+}
+
+impl ByteStream {
+    pub fn new(port: usize) -> Self {
+        ByteStream { port }
+    }
+
+    fn try_receive(&self) -> Option<u8> {
+        PORTS[self.port].lock().as_mut().and_then(|u| u.receive())
+    }
+}
+
+impl Stream for ByteStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(b) = self.try_receive() {
+            return Poll::Ready(Some(b));
+        }
 
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        /* Interrupt handlers may want to print, which would cause them to deadlock with this code. */
-        UART1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to UART1 failed");
-    });
+        RX_WAKERS[self.port].register(&cx.waker());
+        /* A byte could have arrived before the waker was registered; look again. */
+        match self.try_receive() {
+            Some(b) => {
+                RX_WAKERS[self.port].take();
+                Poll::Ready(Some(b))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Wait for a full line on the shell port without blocking the CPU.
+pub async fn read_line_async() -> String {
+    use futures_util::stream::StreamExt;
+
+    let shell = port(Role::Shell);
+    let mut bytes = ByteStream::new(shell);
+    let mut editor = LineEditor::new(shell);
+
+    loop {
+        let b = bytes.next().await.unwrap(); // never ends
+        if let Some(line) = editor.feed(b) {
+            return line;
+        }
+    }
+}
+
+/// Write to a specific port; output to absent ports is dropped.
+pub fn write_to(port: usize, args: fmt::Arguments) {
+    if let Some(uart) = PORTS[port].lock().as_mut() {
+        uart.write_fmt(args).unwrap();
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    write_to(port(Role::Log), args);
 }
 
 /// Prints to the host through the serial interface.
@@ -39,3 +738,70 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_is_first_in_first_out() {
+        let mut ring = RingBuffer::new();
+        assert_eq!(ring.pop(), None);
+        /* Round and round, so head wraps a few times. */
+        for i in 0..RING_SIZE * 3 {
+            ring.push(i as u8).unwrap();
+            ring.push(!(i as u8)).unwrap();
+            assert_eq!(ring.pop(), Some(i as u8));
+            assert_eq!(ring.len(), 1);
+            assert_eq!(ring.pop(), Some(!(i as u8)));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn full_ring_refuses_bytes() {
+        let mut ring = RingBuffer::new();
+        ring.push(0).unwrap();
+        ring.pop();
+        for i in 0..RING_SIZE {
+            ring.push(i as u8).unwrap();
+        }
+        assert!(ring.is_full());
+        assert_eq!(ring.push(0xff), Err(0xff));
+        assert_eq!(ring.pop(), Some(0));
+        ring.push(0xff).unwrap();
+        for i in 1..RING_SIZE {
+            assert_eq!(ring.pop(), Some(i as u8));
+        }
+        assert_eq!(ring.pop(), Some(0xff));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn line_config_parses() {
+        let cfg = LineConfig::parse("115200n8").unwrap();
+        assert_eq!(cfg, LineConfig { baud: 115200, ..LineConfig::DEFAULT });
+        let cfg = LineConfig::parse("9600o72").unwrap();
+        assert_eq!(cfg, LineConfig { baud: 9600, data_bits: 7, parity: Parity::Odd, stop_bits: 2 });
+        assert_eq!(cfg.to_string(), "9600o72");
+        assert_eq!(cfg.line_ctrl(), 2 | LCR_STOP_2 | LCR_PARITY_EN);
+        assert_eq!(LineConfig::parse("38400"), Some(LineConfig::DEFAULT));
+        assert_eq!(LineConfig::parse("1200m").map(|cfg| cfg.parity), Some(Parity::Mark));
+        assert_eq!(LineConfig::parse("1200s5").map(|cfg| cfg.parity), Some(Parity::Space));
+    }
+
+    #[test]
+    fn bad_line_configs() {
+        for s in &["", "n8", "abc", "115200x8", "115200nx"] {
+            assert_eq!(LineConfig::parse(s), None, "{:?}", s);
+        }
+        /* Data and stop bits out of range, and too much of them. */
+        for s in &["115200n4", "115200n9", "115200n80", "115200n83", "115200n811"] {
+            assert_eq!(LineConfig::parse(s), None, "{:?}", s);
+        }
+        /* Rates the UART's clock can't be divided down to. */
+        for s in &["0", "1", "7", "1000000"] {
+            assert_eq!(LineConfig::parse(s), None, "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Bringing up the application processors (APs). Each starts in real mode at the trampoline, which
+//! takes it to long mode on the kernel's page tables and into `ap_main()` on its own stack.
+
+use crate::memory::{self, KernelStack};
+use crate::{acpi, apic, gdt, interrupts, percpu, syscall, thread, tlb};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const MAX_CPUS: usize = 16;
+
+/// Where the trampoline is copied to; APs start at `SIPI vector << 12`, so it must be page
+/// aligned and below 1MiB. Its page is identity mapped while APs are starting.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+const AP_STACK_PAGES: u64 = thread::STACK_PAGES;
+const AP_START_TIMEOUT: Duration = Duration::from_millis(100);
+
+/* Real mode -> protected mode -> long mode, using a temporary GDT. Runs at TRAMPOLINE_ADDR, so
+ * addresses are worked out relative to that. The parameters at the end are filled in for each AP
+ * in turn. */
+global_asm!(
+    r#"
+.section .rodata.mtos_ap_trampoline, "a"
+.code16
+.global mtos_ap_trampoline
+mtos_ap_trampoline:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [ap_gdt_ptr - mtos_ap_trampoline + 0x8000]
+    mov eax, cr0
+    or eax, 1                           // PE
+    mov cr0, eax
+    jmp 0x08:(ap_protected - mtos_ap_trampoline + 0x8000)
+
+.code32
+ap_protected:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov eax, cr4
+    or eax, 1 << 5                      // PAE
+    mov cr4, eax
+    mov eax, [ap_cr3 - mtos_ap_trampoline + 0x8000]
+    mov cr3, eax
+    mov ecx, 0xc0000080                 // EFER
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)        // LME, NXE
+    wrmsr
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)       // PG, WP
+    mov cr0, eax
+    jmp 0x18:(ap_long - mtos_ap_trampoline + 0x8000)
+
+.code64
+ap_long:
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, [ap_stack - mtos_ap_trampoline + 0x8000]
+    mov rdi, [ap_cpu - mtos_ap_trampoline + 0x8000]
+    mov rax, [ap_entry - mtos_ap_trampoline + 0x8000]
+    call rax
+    ud2
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff            // 0x08: 32-bit code
+    .quad 0x00cf92000000ffff            // 0x10: data
+    .quad 0x00af9a000000ffff            // 0x18: 64-bit code
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long ap_gdt - mtos_ap_trampoline + 0x8000
+
+.balign 8
+.global mtos_ap_trampoline_params
+mtos_ap_trampoline_params:
+ap_cr3:
+    .quad 0
+ap_stack:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_cpu:
+    .quad 0
+.global mtos_ap_trampoline_end
+mtos_ap_trampoline_end:
+.text
+"#
+);
+
+extern "C" {
+    static mtos_ap_trampoline: u8;
+    static mtos_ap_trampoline_params: u8;
+    static mtos_ap_trampoline_end: u8;
+}
+
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+/// A CPU, numbered from 0 (the boot CPU) in MADT order.
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+    pub apic_id: u8,
+    pub online: bool,
+}
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(1);
+/* How far the AP being started has got, so it and the boot CPU agree on whether it's too late. */
+static AP_STATE: AtomicUsize = AtomicUsize::new(AP_WAITING);
+const AP_WAITING: usize = 0;
+const AP_CLAIMED: usize = 1; // in ap_main(), so it'll finish
+const AP_STARTED: usize = 2;
+const AP_ABANDONED: usize = 3;
+/* APIC ID of each CPU number, filled in by init() before any AP starts. */
+static APIC_IDS: [AtomicUsize; MAX_CPUS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+static ONLINE: [AtomicBool; MAX_CPUS] = [
+    AtomicBool::new(true), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+];
+
+/// CPUs found, whether or not they came up.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn online_count() -> usize {
+    ONLINE_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn cpus() -> Vec<Cpu> {
+    (0..cpu_count())
+        .map(|i| Cpu {
+            apic_id: apic_id(i),
+            online: ONLINE[i].load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    cpu < MAX_CPUS && ONLINE[cpu].load(Ordering::Acquire)
+}
+
+pub fn apic_id(cpu: usize) -> u8 {
+    APIC_IDS[cpu].load(Ordering::Relaxed) as u8
+}
+
+/// The number of the CPU this runs on. Only meaningful with preemption off.
+pub fn current_cpu() -> usize {
+    percpu::cpu_id()
+}
+
+/// Find the other CPUs and start them, one at a time. Needs the heap, threads, and a calibrated
+/// TSC for the startup delays. Without an MADT we carry on with just the boot CPU.
+pub fn init() {
+    let madt = match acpi::madt() {
+        Some(m) => m,
+        None => {
+            log::warn!("No ACPI MADT; running on the boot CPU only");
+            return;
+        }
+    };
+    apic::init(madt.local_apic_address);
+    apic::enable();
+    apic::calibrate_timer();
+
+    let bsp = apic::id();
+    percpu!(apic_id).store(bsp, Ordering::Relaxed);
+    let mut ids = Vec::with_capacity(madt.cpu_apic_ids.len());
+    ids.push(bsp);
+    ids.extend(madt.cpu_apic_ids.iter().copied().filter(|&id| id != bsp));
+    if ids.len() > MAX_CPUS {
+        log::warn!("{} CPUs, only using {}", ids.len(), MAX_CPUS);
+        ids.truncate(MAX_CPUS);
+    }
+    for (i, &id) in ids.iter().enumerate() {
+        APIC_IDS[i].store(usize::from(id), Ordering::Relaxed);
+    }
+    CPU_COUNT.store(ids.len(), Ordering::Relaxed);
+    if ids.len() == 1 {
+        return;
+    }
+
+    if !install_trampoline() {
+        log::warn!("Can't place the AP trampoline at {:#x}; running on the boot CPU only", TRAMPOLINE_ADDR);
+        return;
+    }
+    for (cpu, &id) in ids.iter().enumerate().skip(1) {
+        if start_ap(cpu, id) {
+            log::info!("CPU {} (APIC {}) online", cpu, id);
+        } else {
+            log::warn!("CPU {} (APIC {}) didn't start", cpu, id);
+        }
+    }
+    /* Every AP has checked in or been reset by now, so nothing's left to run the trampoline. */
+    remove_trampoline();
+}
+
+fn trampoline_page() -> (Page, PhysFrame) {
+    let addr = PhysAddr::new(TRAMPOLINE_ADDR);
+    (Page::containing_address(VirtAddr::new(TRAMPOLINE_ADDR)), PhysFrame::containing_address(addr))
+}
+
+/// Copy the trampoline to low memory, and identity map it so it survives turning paging on.
+fn install_trampoline() -> bool {
+    let (page, frame) = trampoline_page();
+    let code = unsafe {
+        let start = &mtos_ap_trampoline as *const u8;
+        let len = &mtos_ap_trampoline_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    assert!(code.len() <= 4096);
+
+    let mapped = memory::with_mapper(|mapper, frames| {
+        if !frames.is_usable(frame) {
+            return false;
+        }
+        let frame = unsafe { UnusedPhysFrame::<Size4KiB>::new(frame) };
+        match mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, frames) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    });
+    if !mapped {
+        return false;
+    }
+
+    let dest = memory::phys_to_virt(frame.start_address()).unwrap().as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), dest, code.len()) };
+    true
+}
+
+/// Unmap the trampoline from every CPU, as the APs ran through it; the frame isn't ours to free.
+fn remove_trampoline() {
+    let (page, _) = trampoline_page();
+    memory::unmap_page(page);
+}
+
+fn trampoline_params() -> *mut TrampolineParams {
+    let offset = unsafe {
+        &mtos_ap_trampoline_params as *const u8 as usize - &mtos_ap_trampoline as *const u8 as usize
+    };
+    (memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).unwrap() + offset).as_mut_ptr()
+}
+
+fn start_ap(cpu: usize, apic_id: u8) -> bool {
+    let l4 = memory::kernel_l4_frame();
+    /* The trampoline loads CR3 from 32-bit code. */
+    assert!(l4.start_address().as_u64() < 1 << 32);
+
+    let stack = KernelStack::new(AP_STACK_PAGES).expect("Out of memory for AP stack");
+    let params = TrampolineParams {
+        cr3: l4.start_address().as_u64(),
+        stack: stack.top().as_u64(),
+        entry: ap_main as usize as u64,
+        cpu: cpu as u64,
+    };
+    unsafe { trampoline_params().write_volatile(params) };
+
+    AP_STATE.store(AP_WAITING, Ordering::SeqCst);
+    apic::start_cpu(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+
+    /* Wait for it to be done with the trampoline before reusing it for the next one. */
+    let start = crate::time::rdtsc();
+    let timeout = crate::time::tsc_hz() * AP_START_TIMEOUT.as_millis() as u64 / 1000;
+    while AP_STATE.load(Ordering::SeqCst) != AP_STARTED {
+        let late = crate::time::rdtsc().wrapping_sub(start) > timeout;
+        /* Once it's claimed its place it's running our code, and will finish. */
+        if late && claim(AP_ABANDONED) {
+            /* It may still be on its way through the trampoline, or turn up later: reset it, so it
+             * can't run whatever's at TRAMPOLINE_ADDR or on its stack once they're reused. */
+            apic::stop_cpu(apic_id);
+            return false;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    /* The AP runs on this stack for as long as it's up, ie forever. */
+    core::mem::forget(stack);
+    true
+}
+
+/* Settle whether the AP being started made it in time, for it (AP_CLAIMED) or the boot CPU
+ * (AP_ABANDONED); true if this was first. */
+fn claim(state: usize) -> bool {
+    AP_STATE
+        .compare_exchange(AP_WAITING, state, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+}
+
+/// Where each AP arrives in long mode.
+extern "C" fn ap_main(cpu: u64) -> ! {
+    if !claim(AP_CLAIMED) {
+        /* Too late: the boot CPU's given up on us, and is about to reset us. */
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    let cpu = cpu as usize;
+    gdt::init_ap(cpu);
+    syscall::init();
+    tlb::init();
+    interrupts::init_ap();
+    apic::enable();
+    /* Interrupts stay off until it's joined the scheduler, so the ticks can start now. */
+    apic::start_timer();
+    percpu!(apic_id).store(apic::id(), Ordering::Relaxed);
+
+    ONLINE[cpu].store(true, Ordering::Release);
+    ONLINE_COUNT.fetch_add(1, Ordering::SeqCst);
+    AP_STATE.store(AP_STARTED, Ordering::SeqCst);
+
+    thread::run_ap()
+}
@@ -0,0 +1,42 @@
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable, paired with a sleeping `Mutex` protecting the condition.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock `guard`'s mutex and park until notified, then relock it. Wakeups may be spurious, so
+    /// callers should loop on their condition, or use `wait_while`.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        /* Queued before the unlock, so a notify from whoever takes the mutex next isn't missed. */
+        self.waiters.wait_after(move || drop(guard));
+        mutex.lock()
+    }
+
+    /// Wait until `cond` returns false for the protected data.
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut cond: F) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while cond(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,97 @@
+use super::lockdep::LockClass;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+/// A spinlock that holds interrupts off for as long as it's held, so it can be shared with
+/// interrupt handlers: one can't interrupt the holder on this CPU and then spin on the lock forever.
+/// Interrupts are restored to how they were when the guard is dropped.
+pub struct IrqSpinLock<T> {
+    lock: spin::Mutex<T>,
+    class: LockClass,
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    // Option so Drop can release the lock before re-enabling interrupts
+    guard: Option<spin::MutexGuard<'a, T>>,
+    irqs_were_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        IrqSpinLock {
+            lock: spin::Mutex::new(data),
+            class: LockClass::new(),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let irqs_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        self.class.acquire(self.addr(), false, Location::caller());
+        IrqSpinLockGuard {
+            lock: self,
+            guard: Some(self.lock.lock()),
+            irqs_were_enabled,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let irqs_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.lock.try_lock() {
+            Some(guard) => {
+                self.class.acquired_try(self.addr(), Location::caller());
+                Some(IrqSpinLockGuard {
+                    lock: self,
+                    guard: Some(guard),
+                    irqs_were_enabled,
+                })
+            }
+            None => {
+                if irqs_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Forcibly unlock, eg to print a panic message. Only for when nothing else will touch it.
+    pub unsafe fn force_unlock(&self) {
+        self.class.release(self.addr());
+        self.lock.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.lock.class.release(self.lock.addr());
+        if self.irqs_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
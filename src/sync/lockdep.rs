@@ -0,0 +1,393 @@
+//! Lock dependency validation, with the `lockdep` feature. Every spinlock belongs to a class: the
+//! place it was constructed, so all the locks made by one line of code (eg in a `new()` of some
+//! struct) are treated as one. We record which classes get taken while others are held, and in
+//! which contexts, and complain the first time the record shows a possible deadlock, rather than
+//! waiting for one to happen:
+//!
+//! * a class taken while holding B in one place, and B taken while holding it (maybe indirectly)
+//!   in another; two CPUs (or an interrupted thread and a handler) doing these at once deadlock.
+//! * a class taken by an interrupt handler, and elsewhere taken with interrupts enabled; the
+//!   handler can interrupt the holder and spin forever.
+//!
+//! Once something's been reported validation turns itself off, as the bookkeeping would then just
+//! repeat itself. Sleeping locks aren't tracked; they can be held across a context switch.
+//!
+//! Without the feature, `LockClass` is empty and all of this compiles away.
+
+use core::panic::Location;
+
+#[cfg(feature = "lockdep")]
+pub use imp::*;
+
+/// Identifies a lock's class for validation; embedded in each lock.
+pub struct LockClass {
+    #[cfg(feature = "lockdep")]
+    site: &'static Location<'static>,
+    #[cfg(feature = "lockdep")]
+    id: core::sync::atomic::AtomicUsize,
+}
+
+#[cfg(not(feature = "lockdep"))]
+impl LockClass {
+    #[track_caller]
+    pub const fn new() -> Self {
+        LockClass {}
+    }
+
+    #[inline(always)]
+    pub fn acquire(&self, _addr: usize, _irqs_enabled: bool, _site: &'static Location<'static>) {}
+
+    #[inline(always)]
+    pub fn acquired_try(&self, _addr: usize, _site: &'static Location<'static>) {}
+
+    #[inline(always)]
+    pub fn release(&self, _addr: usize) {}
+}
+
+#[cfg(not(feature = "lockdep"))]
+#[inline(always)]
+pub fn assert_no_locks_held() {}
+
+#[cfg(feature = "lockdep")]
+mod imp {
+    use super::LockClass;
+    use core::fmt::{self, Write};
+    use core::panic::Location;
+    use crate::percpu;
+    use crate::smp::MAX_CPUS;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    /* Fixed-size, since locks get taken before there's a heap, and by the heap. */
+    const MAX_CLASSES: usize = 64;
+    const MAX_HELD: usize = 16;
+
+    type Site = &'static Location<'static>;
+
+    #[derive(Clone, Copy)]
+    struct ClassInfo {
+        site: Option<Site>,
+        /// Where it was first taken in an interrupt handler.
+        in_irq: Option<Site>,
+        /// Where it was first taken with interrupts enabled.
+        irqs_on: Option<Site>,
+    }
+
+    #[derive(Clone, Copy)]
+    struct Held {
+        class: usize,
+        addr: usize,
+        site: Site,
+    }
+
+    struct State {
+        classes: [ClassInfo; MAX_CLASSES],
+        n_classes: usize,
+        /// Bit b of after[a]: class b has been taken while holding a.
+        after: [u64; MAX_CLASSES],
+        /// Where after[a] bit b was first seen.
+        edge_site: [[Option<Site>; MAX_CLASSES]; MAX_CLASSES],
+        /* Locks each CPU holds, in the order taken. */
+        held: [[Option<Held>; MAX_HELD]; MAX_CPUS],
+        n_held: [usize; MAX_CPUS],
+    }
+
+    const NO_CLASS: ClassInfo = ClassInfo {
+        site: None,
+        in_irq: None,
+        irqs_on: None,
+    };
+
+    /* A plain spinlock: lockdep can't validate itself. Only taken with interrupts off. */
+    static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+        classes: [NO_CLASS; MAX_CLASSES],
+        n_classes: 0,
+        after: [0; MAX_CLASSES],
+        edge_site: [[None; MAX_CLASSES]; MAX_CLASSES],
+        held: [[None; MAX_HELD]; MAX_CPUS],
+        n_held: [0; MAX_CPUS],
+    });
+    static ENABLED: AtomicBool = AtomicBool::new(true);
+
+    impl LockClass {
+        #[track_caller]
+        pub const fn new() -> Self {
+            LockClass {
+                site: Location::caller(),
+                id: AtomicUsize::new(0),
+            }
+        }
+
+        /// Record that the lock at `addr` is about to be taken at `site`. Called before spinning,
+        /// so a report comes out even if this acquisition would hang.
+        pub fn acquire(&self, addr: usize, irqs_enabled: bool, site: Site) {
+            if !ENABLED.load(Ordering::Relaxed) {
+                return;
+            }
+            let in_irq = crate::interrupts::in_irq();
+            interrupts::without_interrupts(|| {
+                let mut state = STATE.lock();
+                let class = match self.class(&mut state) {
+                    Some(c) => c,
+                    None => return disable(state, Report::OutOfClasses),
+                };
+                if let Err(report) = state.check_acquire(class, addr, irqs_enabled, in_irq, site) {
+                    disable(state, report);
+                }
+            });
+        }
+
+        /// Record a successful `try_lock()`. It can't deadlock, so there's nothing to check; but
+        /// what's taken while it's held still needs ordering against it.
+        pub fn acquired_try(&self, addr: usize, site: Site) {
+            if !ENABLED.load(Ordering::Relaxed) {
+                return;
+            }
+            interrupts::without_interrupts(|| {
+                let mut state = STATE.lock();
+                let class = match self.class(&mut state) {
+                    Some(c) => c,
+                    None => return disable(state, Report::OutOfClasses),
+                };
+                let cpu = percpu::cpu_id();
+                if state.n_held[cpu] == MAX_HELD {
+                    return disable(state, Report::TooManyHeld { site });
+                }
+                let n = state.n_held[cpu];
+                state.held[cpu][n] = Some(Held { class, addr, site });
+                state.n_held[cpu] += 1;
+            });
+        }
+
+        pub fn release(&self, addr: usize) {
+            if !ENABLED.load(Ordering::Relaxed) {
+                return;
+            }
+            interrupts::without_interrupts(|| {
+                let mut state = STATE.lock();
+                let cpu = percpu::cpu_id();
+                /* Usually the last one taken, but locks needn't be released in order. */
+                let n = state.n_held[cpu];
+                if let Some(i) = (0..n).rev().find(|&i| state.held[cpu][i].map_or(false, |h| h.addr == addr)) {
+                    for j in i..n - 1 {
+                        state.held[cpu][j] = state.held[cpu][j + 1];
+                    }
+                    state.held[cpu][n - 1] = None;
+                    state.n_held[cpu] -= 1;
+                }
+            });
+        }
+
+        fn class(&self, state: &mut State) -> Option<usize> {
+            let id = self.id.load(Ordering::Relaxed);
+            if id != 0 {
+                return Some(id - 1);
+            }
+            let class = match state.find_class(self.site) {
+                Some(c) => c,
+                None => {
+                    if state.n_classes == MAX_CLASSES {
+                        return None;
+                    }
+                    let c = state.n_classes;
+                    state.classes[c].site = Some(self.site);
+                    state.n_classes += 1;
+                    c
+                }
+            };
+            self.id.store(class + 1, Ordering::Relaxed);
+            Some(class)
+        }
+    }
+
+    /// Whether a problem has been reported (which also turns validation off).
+    pub fn reported() -> bool {
+        !ENABLED.load(Ordering::Relaxed)
+    }
+
+    /// Report a lock that's still held, if any; for places that may switch threads.
+    pub fn assert_no_locks_held() {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            let state = STATE.lock();
+            let cpu = percpu::cpu_id();
+            if let Some(held) = state.held[cpu][..state.n_held[cpu]].iter().flatten().next().copied() {
+                let class = state.classes[held.class].site.unwrap();
+                disable(state, Report::SwitchHolding { class, site: held.site });
+            }
+        });
+    }
+
+    /// A possible deadlock, for printing.
+    enum Report {
+        OutOfClasses,
+        TooManyHeld { site: Site },
+        Recursive { class: Site, site: Site, prev: Site },
+        IrqUnsafe { class: Site, in_irq: Site, irqs_on: Site },
+        Inversion { class: Site, site: Site, held: Site, held_site: Site, next: Site, next_site: Site },
+        SwitchHolding { class: Site, site: Site },
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Report::OutOfClasses => write!(f, "more than {} lock classes", MAX_CLASSES),
+                Report::TooManyHeld { site } => write!(f, "more than {} locks held at {}", MAX_HELD, site),
+                Report::Recursive { class, site, prev } => {
+                    write!(f, "recursive locking of {} at {}, already taken at {}", class, site, prev)
+                }
+                Report::IrqUnsafe { class, in_irq, irqs_on } => write!(
+                    f,
+                    "{} taken in interrupt context at {}, and with interrupts enabled at {}",
+                    class, in_irq, irqs_on
+                ),
+                Report::Inversion { class, site, held, held_site, next, next_site } => write!(
+                    f,
+                    "lock order inversion: {} taken at {} while holding {} (taken at {}), \
+                     but elsewhere {} is taken at {} while holding {}",
+                    class, site, held, held_site, next, next_site, class
+                ),
+                Report::SwitchHolding { class, site } => {
+                    write!(f, "switching threads holding {} (taken at {})", class, site)
+                }
+            }
+        }
+    }
+
+    /// Turn validation off, and print why.
+    fn disable(state: spin::MutexGuard<State>, report: Report) {
+        ENABLED.store(false, Ordering::Relaxed);
+        let cpu = percpu::cpu_id();
+        let n_held = state.n_held[cpu];
+        let held = state.held[cpu];
+        let classes = state.classes;
+        drop(state);
+
+        /* Straight to the UART: the serial & VGA locks might be the ones in trouble. */
+        let mut out = RawSerial;
+        let _ = writeln!(out, "lockdep: {}", report);
+        let _ = writeln!(out, "lockdep: locks held:");
+        for h in held[..n_held].iter().flatten() {
+            let _ = writeln!(out, "lockdep:   {} (taken at {})", classes[h.class].site.unwrap(), h.site);
+        }
+        let _ = writeln!(out, "lockdep: validation disabled");
+    }
+
+    impl State {
+        fn find_class(&self, site: Site) -> Option<usize> {
+            self.classes[..self.n_classes].iter().position(|c| match c.site {
+                Some(s) => s.file() == site.file() && s.line() == site.line() && s.column() == site.column(),
+                None => false,
+            })
+        }
+
+        fn check_acquire(
+            &mut self,
+            class: usize,
+            addr: usize,
+            irqs_enabled: bool,
+            in_irq: bool,
+            site: Site,
+        ) -> Result<(), Report> {
+            let cpu = percpu::cpu_id();
+            let name = self.classes[class].site.unwrap();
+
+            if let Some(held) = self.held[cpu][..self.n_held[cpu]].iter().flatten().find(|h| h.addr == addr) {
+                return Err(Report::Recursive { class: name, site, prev: held.site });
+            }
+
+            /* Interrupt context */
+            if in_irq {
+                if self.classes[class].in_irq.is_none() {
+                    self.classes[class].in_irq = Some(site);
+                }
+            } else if irqs_enabled && self.classes[class].irqs_on.is_none() {
+                self.classes[class].irqs_on = Some(site);
+            }
+            if let (Some(irq_site), Some(on_site)) = (self.classes[class].in_irq, self.classes[class].irqs_on) {
+                return Err(Report::IrqUnsafe { class: name, in_irq: irq_site, irqs_on: on_site });
+            }
+
+            /* Ordering against everything held */
+            for i in 0..self.n_held[cpu] {
+                let held = self.held[cpu][i].unwrap();
+                if held.class == class || self.after[held.class] & (1 << class) != 0 {
+                    continue;
+                }
+                if let Some(first) = self.path_first_hop(class, held.class) {
+                    return Err(Report::Inversion {
+                        class: name,
+                        site,
+                        held: self.classes[held.class].site.unwrap(),
+                        held_site: held.site,
+                        next: self.classes[first].site.unwrap(),
+                        next_site: self.edge_site[class][first].unwrap(),
+                    });
+                }
+                self.after[held.class] |= 1 << class;
+                self.edge_site[held.class][class] = Some(site);
+            }
+
+            if self.n_held[cpu] == MAX_HELD {
+                return Err(Report::TooManyHeld { site });
+            }
+            let n = self.n_held[cpu];
+            self.held[cpu][n] = Some(Held { class, addr, site });
+            self.n_held[cpu] += 1;
+            Ok(())
+        }
+
+        /// If `to` can be reached from `from` through recorded orderings, the first class on the
+        /// way.
+        fn path_first_hop(&self, from: usize, to: usize) -> Option<usize> {
+            let mut seen: u64 = 1 << from;
+            let mut stack = [0usize; MAX_CLASSES];
+            let mut first = [0usize; MAX_CLASSES];
+            let mut n = 0;
+            for b in 0..self.n_classes {
+                if self.after[from] & (1 << b) != 0 {
+                    stack[n] = b;
+                    first[n] = b;
+                    n += 1;
+                    seen |= 1 << b;
+                }
+            }
+            while n > 0 {
+                n -= 1;
+                let (c, hop) = (stack[n], first[n]);
+                if c == to {
+                    return Some(hop);
+                }
+                for b in 0..self.n_classes {
+                    if self.after[c] & (1 << b) != 0 && seen & (1 << b) == 0 {
+                        seen |= 1 << b;
+                        stack[n] = b;
+                        first[n] = hop;
+                        n += 1;
+                    }
+                }
+            }
+            None
+        }
+    }
+
+    /// Polled output on COM1, bypassing the serial driver and its locks.
+    struct RawSerial;
+
+    impl fmt::Write for RawSerial {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let mut data = Port::<u8>::new(0x3f8);
+            let mut line_status = Port::<u8>::new(0x3f8 + 5);
+            for b in s.bytes() {
+                unsafe {
+                    while line_status.read() & 0x20 == 0 {}
+                    data.write(b);
+                }
+            }
+            Ok(())
+        }
+    }
+}
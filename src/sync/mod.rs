@@ -0,0 +1,21 @@
+//! Locks & other synchronisation. `IrqSpinLock` is for state shared with interrupt handlers, and
+//! `SpinLock` for short critical sections that already run with interrupts off; the rest park the
+//! waiting thread or async task instead of spinning, so mustn't be taken in interrupt handlers.
+//! The spinlocks are checked by `lockdep` when that feature's on.
+
+mod condvar;
+mod irq_spin;
+pub mod lockdep;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spin_lock;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use irq_spin::{IrqSpinLock, IrqSpinLockGuard};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spin_lock::{SpinLock, SpinLockGuard};
+pub use wait_queue::{WaitQueue, WaitUntil};
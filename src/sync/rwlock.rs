@@ -0,0 +1,142 @@
+use super::{IrqSpinLock, WaitQueue};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+struct State {
+    readers: usize,
+    writer: bool,
+    /// Writers waiting; new readers hold off while there are any, so writers aren't starved.
+    writers_waiting: usize,
+}
+
+/// A sleeping reader-writer lock, preferring writers.
+pub struct RwLock<T> {
+    state: IrqSpinLock<State>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: IrqSpinLock::new(State {
+                readers: 0,
+                writer: false,
+                writers_waiting: 0,
+            }),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.writers_waiting > 0 {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.waiters.wait_until(|| {
+                let state = self.state.lock();
+                !state.writer && state.writers_waiting == 0
+            });
+        }
+    }
+
+    fn take_write(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return false;
+        }
+        state.writer = true;
+        true
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.take_write() {
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        if self.take_write() {
+            return RwLockWriteGuard { lock: self };
+        }
+        self.state.lock().writers_waiting += 1;
+        loop {
+            self.waiters.wait_until(|| {
+                let state = self.state.lock();
+                !state.writer && state.readers == 0
+            });
+            let mut state = self.state.lock();
+            if !state.writer && state.readers == 0 {
+                state.writer = true;
+                state.writers_waiting -= 1;
+                return RwLockWriteGuard { lock: self };
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        };
+        if last {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.lock().writer = false;
+        /* Both readers and writers may be waiting; let them all recheck. */
+        self.lock.waiters.wake_all();
+    }
+}
@@ -0,0 +1,47 @@
+use super::{IrqSpinLock, WaitQueue};
+
+/// A counting semaphore. `release()` may be called from interrupt handlers; `acquire()` blocks.
+pub struct Semaphore {
+    count: IrqSpinLock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: IrqSpinLock::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count > 0 {
+            *count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters.wait_until(|| *self.count.lock() > 0);
+        }
+    }
+
+    pub async fn acquire_async(&self) {
+        while !self.try_acquire() {
+            self.waiters.wait_until_async(|| *self.count.lock() > 0).await;
+        }
+    }
+
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        *self.count.lock()
+    }
+}
@@ -0,0 +1,71 @@
+use super::lockdep::LockClass;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+/// A plain spinlock, leaving interrupts as they are. Unlike `spin::Mutex` it's checked by
+/// lockdep, which will complain if it's ever taken both by an interrupt handler and with
+/// interrupts enabled.
+pub struct SpinLock<T> {
+    lock: spin::Mutex<T>,
+    class: LockClass,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    guard: Option<spin::MutexGuard<'a, T>>,
+}
+
+impl<T> SpinLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            lock: spin::Mutex::new(data),
+            class: LockClass::new(),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        self.class.acquire(self.addr(), interrupts::are_enabled(), Location::caller());
+        SpinLockGuard {
+            lock: self,
+            guard: Some(self.lock.lock()),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let guard = self.lock.try_lock()?;
+        self.class.acquired_try(self.addr(), Location::caller());
+        Some(SpinLockGuard {
+            lock: self,
+            guard: Some(guard),
+        })
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.lock.class.release(self.lock.addr());
+    }
+}
@@ -0,0 +1,137 @@
+use super::IrqSpinLock;
+use crate::thread::{self, ThreadId};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+enum Waiter {
+    Thread(ThreadId),
+    Task(Waker),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Waiter::Thread(id) => thread::wake(id),
+            Waiter::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+/* Take a waiter's entry back off the queue, eg once it's found its condition true without being
+ * woken through it: left there, it'd soak up a wake_one() meant for someone still waiting. */
+fn remove<P: Fn(&Waiter) -> bool>(waiters: &mut Vec<Waiter>, is_ours: P) {
+    if let Some(i) = waiters.iter().position(is_ours) {
+        waiters.remove(i);
+    }
+}
+
+/// A FIFO of parked threads and tasks, waiting for some condition to become true.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Vec<Waiter>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Park the current thread until `cond` returns true. `cond` is checked with the queue locked,
+    /// so a waker that makes it true and then calls `wake_*()` can't be missed.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut cond: F) {
+        let id = thread::current();
+        interrupts::without_interrupts(|| loop {
+            let mut waiters = self.waiters.lock();
+            if cond() {
+                remove(&mut waiters, |w| match w {
+                    Waiter::Thread(t) => *t == id,
+                    Waiter::Task(_) => false,
+                });
+                return;
+            }
+            waiters.push(Waiter::Thread(id));
+            drop(waiters);
+            /* A wake() between here and block() leaves wakeup_pending set, so isn't lost. */
+            thread::block();
+        })
+    }
+
+    /// Queue the current thread, run `release` (eg to unlock a mutex protecting the condition),
+    /// then park until woken. Wakeups aren't tied to a condition, so callers should recheck it.
+    pub fn wait_after<F: FnOnce()>(&self, release: F) {
+        interrupts::without_interrupts(|| {
+            self.waiters.lock().push(Waiter::Thread(thread::current()));
+            release();
+            thread::block();
+        });
+    }
+
+    /// A future that's ready once `cond` returns true, for waiting from async tasks.
+    pub fn wait_until_async<F: FnMut() -> bool>(&self, cond: F) -> WaitUntil<F> {
+        WaitUntil { queue: self, cond }
+    }
+
+    /// Wake the longest waiting thread or task. Returns false if there were none.
+    pub fn wake_one(&self) -> bool {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                None
+            } else {
+                Some(waiters.remove(0))
+            }
+        };
+        match waiter {
+            Some(w) => {
+                w.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake everything waiting. Returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::replace(&mut *self.waiters.lock(), Vec::new());
+        let n = waiters.len();
+        for w in waiters {
+            w.wake();
+        }
+        n
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+pub struct WaitUntil<'a, F> {
+    queue: &'a WaitQueue,
+    cond: F,
+}
+
+impl<'a, F: FnMut() -> bool + Unpin> Future for WaitUntil<'a, F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        let mut waiters = this.queue.waiters.lock();
+        let is_ours = |w: &Waiter| match w {
+            Waiter::Task(waker) => waker.will_wake(cx.waker()),
+            Waiter::Thread(_) => false,
+        };
+        if (this.cond)() {
+            remove(&mut waiters, is_ours);
+            return Poll::Ready(());
+        }
+        /* Only one entry per task; a spurious wakeup may have left an old one behind. */
+        if !waiters.iter().any(is_ours) {
+            waiters.push(Waiter::Task(cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
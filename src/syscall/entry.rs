@@ -0,0 +1,149 @@
+use crate::gdt;
+use crate::user::{self, Registers};
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+
+const EFER_SCE: u64 = 1 << 0;
+/* RFLAGS bits cleared on entry: TF, IF, DF and AC. */
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/* SYSCALL arrives here with interrupts off, the user rip in rcx and rflags in r11, still on the
+ * user stack and GS. Swap to the kernel's GS, and from there to the thread's kernel stack (the
+ * offsets are percpu::SYSCALL_STACK_OFFSET & USER_RSP_OFFSET), and save the user registers as a
+ * SyscallFrame for the dispatcher. They're restored afterwards, so user mode only loses rcx and
+ * r11, and gets the result in rax. */
+global_asm!(
+    r#"
+.global mtos_syscall_entry
+mtos_syscall_entry:
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+    push qword ptr gs:[16]
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call mtos_syscall_dispatch
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    add rsp, 8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+"#
+);
+
+extern "C" {
+    fn mtos_syscall_entry();
+}
+
+/// What the entry stub pushes, lowest address first.
+#[repr(C)]
+pub struct SyscallFrame {
+    /* Callee-saved, so calls leave them alone, but fork copies them and exec clears them. */
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub number: u64,
+    /// rdi, rsi, rdx, r10, r8, r9.
+    pub args: [u64; 6],
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The registers as user mode will see them when the call returns `result`.
+    pub fn user_registers(&self, result: u64) -> Registers {
+        Registers {
+            rax: result,
+            rbx: self.rbx,
+            rcx: self.rip,
+            rdx: self.args[2],
+            rsi: self.args[1],
+            rdi: self.args[0],
+            rbp: self.rbp,
+            r8: self.args[4],
+            r9: self.args[5],
+            r10: self.args[3],
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rsp: self.rsp,
+            rflags: self.rflags,
+        }
+    }
+
+    /// Return to a fresh start at `entry` with `stack`, and the other registers cleared.
+    pub fn restart(&mut self, entry: VirtAddr, stack: VirtAddr) {
+        *self = SyscallFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            rbp: 0,
+            rbx: 0,
+            number: 0,
+            args: [0; 6],
+            rflags: user::USER_RFLAGS,
+            rip: entry.as_u64(),
+            rsp: stack.as_u64(),
+        };
+    }
+}
+
+/// Enable SYSCALL on this CPU. Each CPU calls this for itself, after loading its GDT.
+pub fn init() {
+    /* SYSCALL loads CS from STAR[47:32] and SS from the next entry; SYSRET loads SS from 8 past
+     * STAR[63:48] and CS from 16 past, which is why the GDT has user data before user code. */
+    let sysret_base = u64::from(gdt::USER_DATA_SELECTOR.index() - 1) << 3;
+    let syscall_base = u64::from(gdt::KERNEL_CODE_SELECTOR.0);
+    assert_eq!(gdt::KERNEL_DATA_SELECTOR.index(), gdt::KERNEL_CODE_SELECTOR.index() + 1);
+    assert_eq!(gdt::USER_CODE_SELECTOR.index(), gdt::USER_DATA_SELECTOR.index() + 1);
+
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SCE);
+        Msr::new(IA32_STAR).write(sysret_base << 48 | syscall_base << 32);
+        Msr::new(IA32_LSTAR).write(mtos_syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(FMASK);
+    }
+}
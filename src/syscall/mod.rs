@@ -0,0 +1,643 @@
+//! System calls from user mode, made with the SYSCALL instruction.
+//!
+//! The ABI follows Linux's: the call number goes in rax and up to six arguments in rdi, rsi, rdx,
+//! r10, r8 and r9. The result comes back in rax, with errors as a negative `Error`. rcx and r11
+//! are clobbered; everything else is preserved. Numbers are stable: new calls go on the end.
+
+use crate::process::fd::FileOps;
+use crate::process::ipc::{self, Message};
+use crate::process::signal::{self, SigAction};
+use crate::process::shm::SharedMemory;
+use crate::process::{self, pipe, ExitStatus, Pid};
+use crate::user::{MapError, USER_END, USER_START};
+use crate::vfs::{self, Stat};
+use crate::{keyboard, thread, time};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::convert::TryInto;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+mod entry;
+
+pub use entry::{init, SyscallFrame};
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_READ_KEY: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_TIME: u64 = 4;
+pub const SYS_YIELD: u64 = 5;
+pub const SYS_FORK: u64 = 6;
+pub const SYS_EXEC: u64 = 7;
+pub const SYS_WAITPID: u64 = 8;
+pub const SYS_GETPID: u64 = 9;
+pub const SYS_GETPPID: u64 = 10;
+pub const SYS_READ: u64 = 11;
+pub const SYS_CLOSE: u64 = 12;
+pub const SYS_SIGACTION: u64 = 13;
+pub const SYS_SIGPROCMASK: u64 = 14;
+pub const SYS_SIGRETURN: u64 = 15;
+pub const SYS_KILL: u64 = 16;
+pub const SYS_PIPE: u64 = 17;
+pub const SYS_DUP2: u64 = 18;
+pub const SYS_PORT_CREATE: u64 = 19;
+pub const SYS_CAP_RESTRICT: u64 = 20;
+pub const SYS_CAP_CLOSE: u64 = 21;
+pub const SYS_SEND: u64 = 22;
+pub const SYS_RECEIVE: u64 = 23;
+pub const SYS_CALL: u64 = 24;
+pub const SYS_REPLY: u64 = 25;
+pub const SYS_SHM_CREATE: u64 = 26;
+pub const SYS_FTRUNCATE: u64 = 27;
+pub const SYS_MMAP: u64 = 28;
+pub const SYS_MUNMAP: u64 = 29;
+pub const SYS_OPEN: u64 = 30;
+pub const SYS_LSEEK: u64 = 31;
+pub const SYS_STAT: u64 = 32;
+pub const SYS_GETDENTS: u64 = 33;
+pub const SYS_MKDIR: u64 = 34;
+pub const SYS_UNLINK: u64 = 35;
+pub const SYS_RMDIR: u64 = 36;
+pub const SYS_SYMLINK: u64 = 37;
+
+/// The descriptors processes start with, all on the console.
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Set in `SYS_READ_KEY`'s result for a key with no character (the low bits are its
+/// `KeyCode`), rather than a Unicode code point.
+pub const RAW_KEY: u64 = 1 << 32;
+
+/// `SYS_MMAP` protections. Mappings can always be read.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Why a system call failed. The values are Linux's errnos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Error {
+    /// A capability without the rights for that.
+    NotPermitted = 1,
+    /// No such file (or program).
+    NoEntry = 2,
+    /// No such process, or the caller isn't in one.
+    NoProcess = 3,
+    /// A signal arrived while the call was waiting.
+    Interrupted = 4,
+    /// Too many arguments, or too long a string.
+    TooBig = 7,
+    /// Not a program that can be run.
+    NoExec = 8,
+    /// No such file descriptor (or capability handle).
+    BadFd = 9,
+    /// No child to wait for.
+    NoChild = 10,
+    NoMemory = 12,
+    /// A pointer argument wasn't to accessible user memory.
+    Fault = 14,
+    /// Something's mounted there.
+    Busy = 16,
+    /// Something's already there.
+    Exists = 17,
+    /// A file that can't be mapped.
+    NoDevice = 19,
+    /// A path went through something that isn't a directory, or that has to be one isn't.
+    NotDirectory = 20,
+    /// Writing to a directory, or removing one as if it were a file.
+    IsDirectory = 21,
+    Invalid = 22,
+    /// The system has as many of something (like pipes) as it can.
+    FileTableFull = 23,
+    /// The process has as many files open (or capabilities) as it can.
+    TooManyFiles = 24,
+    /// A filesystem is full.
+    NoSpace = 28,
+    /// Seeking in a file that has no offset, like a pipe.
+    IllegalSeek = 29,
+    /// Writing to a pipe with its reading end closed, or sending to a closed port.
+    BrokenPipe = 32,
+    /// A name in a path is longer than `vfs::NAME_MAX`.
+    NameTooLong = 36,
+    /// No such system call.
+    NoSys = 38,
+    /// Removing a directory with something in it.
+    NotEmpty = 39,
+    /// A path went through too many symlinks.
+    Loop = 40,
+}
+
+/* The longest string, and the most strings in an array, exec() takes. */
+const MAX_STRING: usize = 4096;
+const MAX_STRINGS: usize = 256;
+
+type Handler = fn(&mut SyscallFrame) -> Result<u64, Error>;
+
+/* Indexed by call number. */
+static TABLE: [Handler; 38] = [
+    sys_exit,
+    sys_write,
+    sys_read_key,
+    sys_sleep,
+    sys_time,
+    sys_yield,
+    sys_fork,
+    sys_exec,
+    sys_waitpid,
+    sys_getpid,
+    sys_getppid,
+    sys_read,
+    sys_close,
+    sys_sigaction,
+    sys_sigprocmask,
+    sys_sigreturn,
+    sys_kill,
+    sys_pipe,
+    sys_dup2,
+    sys_port_create,
+    sys_cap_restrict,
+    sys_cap_close,
+    sys_send,
+    sys_receive,
+    sys_call,
+    sys_reply,
+    sys_shm_create,
+    sys_ftruncate,
+    sys_mmap,
+    sys_munmap,
+    sys_open,
+    sys_lseek,
+    sys_stat,
+    sys_getdents,
+    sys_mkdir,
+    sys_unlink,
+    sys_rmdir,
+    sys_symlink,
+];
+
+/// Called by the entry stub, on the calling thread's kernel stack. System calls run with
+/// interrupts on, so they can block and be preempted like any kernel code. Pending signals are
+/// acted on before going back to user mode.
+#[no_mangle]
+extern "C" fn mtos_syscall_dispatch(frame: &mut SyscallFrame) -> u64 {
+    interrupts::enable();
+    let result = match TABLE.get(frame.number as usize) {
+        Some(handler) => handler(frame),
+        None => Err(Error::NoSys),
+    };
+    let value = match result {
+        Ok(value) => value,
+        Err(e) => (e as u64).wrapping_neg(),
+    };
+    signal::handle_pending(&frame.user_registers(value));
+    interrupts::disable();
+    value
+}
+
+/// Check that `len` bytes at `addr` are user memory that user mode can read (or write, if
+/// `write`), so the kernel touching them on its behalf can't fault or reach kernel data. A
+/// thread's user pages stay mapped while it's running, so the answer holds for the call.
+fn check_user(addr: u64, len: u64, write: bool) -> Result<(), Error> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(Error::Fault)?;
+    if addr < USER_START || end > USER_END {
+        return Err(Error::Fault);
+    }
+    let space = thread::address_space().ok_or(Error::Fault)?;
+    let mut page = addr & !0xfff;
+    while page < end {
+        let page_addr = VirtAddr::new(page);
+        let mut ok = space.can_access(page_addr, write);
+        if !ok && write {
+            /* Copy a copy-on-write page now: only user mode's faults do that. */
+            ok = space.copy_on_write(page_addr) && space.can_access(page_addr, true);
+        }
+        if !ok {
+            return Err(Error::Fault);
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// A user buffer to read from.
+pub(crate) fn user_slice<'a>(addr: u64, len: u64) -> Result<&'a [u8], Error> {
+    check_user(addr, len, false)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+/// A user buffer to write to.
+pub(crate) fn user_slice_mut<'a>(addr: u64, len: u64) -> Result<&'a mut [u8], Error> {
+    check_user(addr, len, true)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+/// A NUL-terminated UTF-8 string in user memory, copied out.
+fn user_string(addr: u64) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    let mut at = addr;
+    loop {
+        /* A page at a time, as the next page may not be there. */
+        let chunk = user_slice(at, 4096 - (at & 0xfff))?;
+        let end = chunk.iter().position(|&b| b == 0);
+        bytes.extend_from_slice(&chunk[..end.unwrap_or(chunk.len())]);
+        if bytes.len() > MAX_STRING {
+            return Err(Error::TooBig);
+        }
+        if end.is_some() {
+            return String::from_utf8(bytes).map_err(|_| Error::Invalid);
+        }
+        at += chunk.len() as u64;
+    }
+}
+
+/// The strings a NULL-terminated array of pointers in user memory points at. A NULL array is
+/// empty.
+fn user_strings(addr: u64) -> Result<Vec<String>, Error> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let at = addr.checked_add(strings.len() as u64 * 8).ok_or(Error::Fault)?;
+        let ptr = u64::from_ne_bytes(user_slice(at, 8)?.try_into().unwrap());
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_STRINGS {
+            return Err(Error::TooBig);
+        }
+        strings.push(user_string(ptr)?);
+    }
+}
+
+/// exit(code): end the calling process (or thread, outside a process).
+fn sys_exit(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    log::debug!("Thread {} exited with {}", thread::current(), frame.args[0] as i64);
+    process::exit(ExitStatus::Exited(frame.args[0] as u8));
+}
+
+/// write(fd, buf, len): returns the bytes written.
+fn sys_write(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (fd, buf, len) = (frame.args[0], frame.args[1], frame.args[2]);
+    let file = process::with_files(|files| files.get(fd))??;
+    Ok(file.write(user_slice(buf, len)?)? as u64)
+}
+
+/// read(fd, buf, len): returns the bytes read, 0 at the end of the file.
+fn sys_read(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (fd, buf, len) = (frame.args[0], frame.args[1], frame.args[2]);
+    let file = process::with_files(|files| files.get(fd))??;
+    Ok(file.read(user_slice_mut(buf, len)?)? as u64)
+}
+
+/// close(fd)
+fn sys_close(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let file = process::with_files(|files| files.close(frame.args[0]))??;
+    drop(file);
+    Ok(0)
+}
+
+/// read_key(): block until a key is pressed. Returns its character, or `RAW_KEY` and its code.
+fn sys_read_key(_frame: &mut SyscallFrame) -> Result<u64, Error> {
+    Ok(match keyboard::read_key() {
+        keyboard::DecodedKey::Unicode(c) => u64::from(u32::from(c)),
+        keyboard::DecodedKey::RawKey(code) => RAW_KEY | code as u64,
+    })
+}
+
+/// sleep(ms)
+fn sys_sleep(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    thread::sleep(Duration::from_millis(frame.args[0]));
+    Ok(0)
+}
+
+/// time(): nanoseconds since boot.
+fn sys_time(_frame: &mut SyscallFrame) -> Result<u64, Error> {
+    Ok(time::uptime().as_nanos() as u64)
+}
+
+/// yield(): let another thread run.
+fn sys_yield(_frame: &mut SyscallFrame) -> Result<u64, Error> {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// fork(): returns the child's pid in the parent, and 0 in the child.
+fn sys_fork(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    Ok(process::fork(&frame.user_registers(0))?.as_u64())
+}
+
+/// exec(path, argv, envp): doesn't return if it works. argv and envp are NULL-terminated arrays
+/// of strings.
+fn sys_exec(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let path = user_string(frame.args[0])?;
+    let argv = user_strings(frame.args[1])?;
+    let envp = user_strings(frame.args[2])?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    process::exec(frame, &path, &argv, &envp)?;
+    Ok(0)
+}
+
+/// waitpid(pid, status, options): wait for child `pid` to exit, or any child if it's -1, and
+/// reap it. Stores its status (Linux-encoded) at `status` unless that's NULL, and returns its pid,
+/// or 0 if `WNOHANG` is set and it hasn't exited.
+fn sys_waitpid(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (pid, status, options) = (frame.args[0] as i64, frame.args[1], frame.args[2]);
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(Error::Invalid),
+    };
+    /* Checked first, so a bad pointer doesn't lose the status. */
+    if status != 0 {
+        check_user(status, 4, true)?;
+    }
+    match process::waitpid(pid, options)? {
+        Some((child, exit_status)) => {
+            if status != 0 {
+                let bytes = (exit_status.wait_status() as u32).to_ne_bytes();
+                user_slice_mut(status, 4)?.copy_from_slice(&bytes);
+            }
+            Ok(child.as_u64())
+        }
+        None => Ok(0),
+    }
+}
+
+/// getpid()
+fn sys_getpid(_frame: &mut SyscallFrame) -> Result<u64, Error> {
+    Ok(process::current().ok_or(Error::NoProcess)?.as_u64())
+}
+
+/// getppid(): 0 if the process has no parent.
+fn sys_getppid(_frame: &mut SyscallFrame) -> Result<u64, Error> {
+    Ok(process::parent()?.map_or(0, Pid::as_u64))
+}
+
+/// sigaction(signal, act, oldact): set the action for `signal` to the `SigAction` at `act`,
+/// unless that's NULL, and store the old one at `oldact`, unless that's NULL.
+fn sys_sigaction(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (sig, act, oldact) = (frame.args[0], frame.args[1], frame.args[2]);
+    let sig: u8 = sig.try_into().map_err(|_| Error::Invalid)?;
+    let size = SigAction::SIZE as u64;
+    let action = match act {
+        0 => None,
+        act => Some(SigAction::from_bytes(user_slice(act, size)?.try_into().unwrap())),
+    };
+    if oldact != 0 {
+        check_user(oldact, size, true)?;
+    }
+    let old = signal::set_action(sig, action)?;
+    if oldact != 0 {
+        user_slice_mut(oldact, size)?.copy_from_slice(&old.to_bytes());
+    }
+    Ok(0)
+}
+
+/// sigprocmask(how, set, oldset): change the blocked signals as `how` says with the mask at
+/// `set`, unless that's NULL, and store the old mask at `oldset`, unless that's NULL.
+fn sys_sigprocmask(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (how, set, oldset) = (frame.args[0], frame.args[1], frame.args[2]);
+    let set = match set {
+        0 => None,
+        set => Some(u64::from_ne_bytes(user_slice(set, 8)?.try_into().unwrap())),
+    };
+    if oldset != 0 {
+        check_user(oldset, 8, true)?;
+    }
+    let old = signal::set_blocked(how, set)?;
+    if oldset != 0 {
+        user_slice_mut(oldset, 8)?.copy_from_slice(&old.to_ne_bytes());
+    }
+    Ok(0)
+}
+
+/// sigreturn(): only for a signal handler's restorer to call, with the stack as the handler left
+/// it. Carries on from where the signal interrupted.
+fn sys_sigreturn(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    signal::sigreturn(frame.rsp);
+}
+
+/// kill(pid, signal): send `signal` to process `pid`. Signal 0 just checks it exists.
+fn sys_kill(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (pid, sig) = (frame.args[0] as i64, frame.args[1]);
+    if pid <= 0 {
+        return Err(Error::Invalid);
+    }
+    let sig: u8 = sig.try_into().map_err(|_| Error::Invalid)?;
+    signal::send(Pid::from_u64(pid as u64), sig)?;
+    Ok(0)
+}
+
+/// pipe(fds): make a pipe, and store descriptors for its reading and writing ends in the two
+/// 32-bit ints at `fds`.
+fn sys_pipe(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let fds = frame.args[0];
+    check_user(fds, 8, true)?;
+    let (reader, writer) = pipe::pipe()?;
+    let reader: Arc<dyn FileOps> = Arc::new(reader);
+    let writer: Arc<dyn FileOps> = Arc::new(writer);
+    /* Both ends go in under the one lock, so if the second won't fit the first can be taken out
+     * again before anyone sees it. Clones go in, as with install_file(). */
+    let (read_fd, write_fd) = process::with_files(|files| {
+        let read_fd = files.insert(reader.clone())?;
+        match files.insert(writer.clone()) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                let _ = files.close(read_fd);
+                Err(e)
+            }
+        }
+    })??;
+    let out = user_slice_mut(fds, 8)?;
+    out[..4].copy_from_slice(&(read_fd as u32).to_ne_bytes());
+    out[4..].copy_from_slice(&(write_fd as u32).to_ne_bytes());
+    Ok(0)
+}
+
+/// dup2(old, new): make descriptor `new` refer to the same file as `old`, closing it first if
+/// it's open. Returns `new`.
+fn sys_dup2(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (old, new) = (frame.args[0], frame.args[1]);
+    let replaced = process::with_files(|files| files.dup2(old, new))??;
+    drop(replaced);
+    Ok(new)
+}
+
+/// port_create(): make a port. Returns a handle with every right to it.
+fn sys_port_create(_frame: &mut SyscallFrame) -> Result<u64, Error> {
+    ipc::create_port()
+}
+
+/// cap_restrict(handle, rights): returns a new handle for the same port as `handle`, with only
+/// `rights`.
+fn sys_cap_restrict(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    ipc::restrict(frame.args[0], frame.args[1])
+}
+
+/// cap_close(handle)
+fn sys_cap_close(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    ipc::close(frame.args[0])?;
+    Ok(0)
+}
+
+/// send(handle, msg): send the `Message` at `msg`, and wait for it to be received.
+fn sys_send(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (handle, msg) = (frame.args[0], frame.args[1]);
+    let msg = Message::from_bytes(user_slice(msg, Message::SIZE as u64)?.try_into().unwrap());
+    ipc::send(handle, &msg, false)?;
+    Ok(0)
+}
+
+/// receive(handle, msg, page): wait for a message, and store it at `msg`; a page that comes with
+/// it goes at `page`, unless that's NULL. Returns a handle to reply with, if it was a call, or 0.
+fn sys_receive(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (handle, msg, page) = (frame.args[0], frame.args[1], frame.args[2]);
+    let size = Message::SIZE as u64;
+    check_user(msg, size, true)?;
+    let (received, reply) = ipc::receive(handle, page)?;
+    user_slice_mut(msg, size)?.copy_from_slice(&received.to_bytes());
+    Ok(reply)
+}
+
+/// call(handle, msg): send the `Message` at `msg`, wait for the reply, and store that at `msg`.
+fn sys_call(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (handle, msg) = (frame.args[0], frame.args[1]);
+    let size = Message::SIZE as u64;
+    check_user(msg, size, true)?;
+    let sent = Message::from_bytes(user_slice(msg, size)?.try_into().unwrap());
+    let reply = ipc::send(handle, &sent, true)?.unwrap_or_default();
+    user_slice_mut(msg, size)?.copy_from_slice(&reply.to_bytes());
+    Ok(0)
+}
+
+/// reply(handle, msg): answer a call with the `Message` at `msg`, through the reply handle
+/// `receive()` gave.
+fn sys_reply(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (handle, msg) = (frame.args[0], frame.args[1]);
+    let msg = Message::from_bytes(user_slice(msg, Message::SIZE as u64)?.try_into().unwrap());
+    ipc::reply(handle, &msg)?;
+    Ok(0)
+}
+
+/// shm_create(size): make a shared memory object of `size` bytes (rounded up to whole pages),
+/// zeroed. Returns a descriptor for it.
+fn sys_shm_create(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    process::install_file(Arc::new(SharedMemory::new(frame.args[0])?))
+}
+
+/// ftruncate(fd, len): make a file `len` bytes long.
+fn sys_ftruncate(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (fd, len) = (frame.args[0], frame.args[1]);
+    let file = process::with_files(|files| files.get(fd))??;
+    file.truncate(len)?;
+    Ok(0)
+}
+
+/// mmap(addr, len, prot, fd, offset): map `len` bytes of the file from `offset`, shared, at
+/// `addr`, which must be free. `addr` and `offset` are page aligned; `len` is rounded up to
+/// whole pages. Returns `addr`.
+fn sys_mmap(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (addr, len, prot, fd, offset) = (frame.args[0], frame.args[1], frame.args[2], frame.args[3], frame.args[4]);
+    if addr % 4096 != 0 || offset % 4096 != 0 || len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Error::Invalid);
+    }
+    if addr < USER_START || addr >= USER_END {
+        return Err(Error::Invalid);
+    }
+    let pages = len.checked_add(4095).ok_or(Error::Invalid)? / 4096;
+    let space = thread::address_space().ok_or(Error::Fault)?;
+    let file = process::with_files(|files| files.get(fd))??;
+    let frames = file.frames(offset / 4096, pages)?;
+    let (writable, executable) = (prot & PROT_WRITE != 0, prot & PROT_EXEC != 0);
+    space.map_shared(VirtAddr::new(addr), frames, writable, executable).map_err(|e| match e {
+        MapError::OutOfRange => Error::Invalid,
+        MapError::OutOfMemory => Error::NoMemory,
+        MapError::AlreadyMapped => Error::Exists,
+    })?;
+    Ok(addr)
+}
+
+/// munmap(addr, len): unmap the pages `len` bytes from `addr` cover, whatever mapped them.
+/// `addr` is page aligned.
+fn sys_munmap(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (addr, len) = (frame.args[0], frame.args[1]);
+    if addr % 4096 != 0 || len == 0 || addr < USER_START || addr >= USER_END {
+        return Err(Error::Invalid);
+    }
+    let pages = len.checked_add(4095).ok_or(Error::Invalid)? / 4096;
+    let space = thread::address_space().ok_or(Error::Fault)?;
+    space.unmap(VirtAddr::new(addr), pages).map_err(|_| Error::Invalid)?;
+    Ok(0)
+}
+
+/// open(path, flags, mode): open the file at `path`, as `vfs::O_RDONLY` etc say. Returns a
+/// descriptor for it.
+fn sys_open(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (path, flags, mode) = (frame.args[0], frame.args[1], frame.args[2]);
+    let path = user_string(path)?;
+    process::install_file(Arc::new(vfs::open(&path, flags, mode as u32 & 0o7777)?))
+}
+
+/// lseek(fd, offset, whence): returns the new offset.
+fn sys_lseek(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (fd, offset, whence) = (frame.args[0], frame.args[1] as i64, frame.args[2]);
+    let file = process::with_files(|files| files.get(fd))??;
+    file.seek(offset, whence)
+}
+
+/// stat(path, buf): store what's at `path` at `buf`, as a `vfs::Stat`.
+fn sys_stat(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (path, buf) = (frame.args[0], frame.args[1]);
+    let path = user_string(path)?;
+    check_user(buf, Stat::SIZE as u64, true)?;
+    let stat = vfs::stat(&path)?;
+    user_slice_mut(buf, Stat::SIZE as u64)?.copy_from_slice(&stat.to_bytes());
+    Ok(0)
+}
+
+/// getdents(fd, buf, len): read directory entries into `buf`, as `linux_dirent64`s. Returns the
+/// bytes used, 0 at the end.
+fn sys_getdents(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (fd, buf, len) = (frame.args[0], frame.args[1], frame.args[2]);
+    let file = process::with_files(|files| files.get(fd))??;
+    Ok(file.getdents(user_slice_mut(buf, len)?)? as u64)
+}
+
+/// mkdir(path, mode)
+fn sys_mkdir(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (path, mode) = (frame.args[0], frame.args[1]);
+    vfs::mkdir(&user_string(path)?, mode as u32 & 0o7777)?;
+    Ok(0)
+}
+
+/// unlink(path): remove a file or symlink.
+fn sys_unlink(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    vfs::unlink(&user_string(frame.args[0])?)?;
+    Ok(0)
+}
+
+/// rmdir(path): remove an empty directory.
+fn sys_rmdir(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    vfs::rmdir(&user_string(frame.args[0])?)?;
+    Ok(0)
+}
+
+/// symlink(target, path): make a symlink at `path`, to `target`.
+fn sys_symlink(frame: &mut SyscallFrame) -> Result<u64, Error> {
+    let (target, path) = (user_string(frame.args[0])?, user_string(frame.args[1])?);
+    vfs::symlink(&target, &path)?;
+    Ok(0)
+}
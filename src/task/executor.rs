@@ -0,0 +1,185 @@
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+const TASK_QUEUE_SIZE: usize = 100;
+
+/// Polls tasks whose wakers have fired, and halts the CPU when there are none.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /* Shared with the wakers, which may be woken from interrupt handlers, hence lock-free. */
+    task_queue: Arc<TaskQueue>,
+    /* Tasks spawned through a Spawner, waiting to be moved into `tasks`. */
+    spawn_queue: Arc<ArrayQueue<Task>>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+/* Tasks to poll. A waker only queues its task once until it's polled, so the queue only fills up
+ * with more than TASK_QUEUE_SIZE tasks waiting; a wakeup that doesn't fit then has every task
+ * polled, rather than being lost (or panicking in an interrupt handler). */
+struct TaskQueue {
+    ids: ArrayQueue<TaskId>,
+    overflowed: AtomicBool,
+}
+
+impl TaskQueue {
+    fn push(&self, id: TaskId) {
+        if self.ids.push(id).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && !self.overflowed.load(Ordering::Acquire)
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(TaskQueue {
+                ids: ArrayQueue::new(TASK_QUEUE_SIZE),
+                overflowed: AtomicBool::new(false),
+            }),
+            spawn_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("Task with same ID already in tasks");
+        }
+        self.task_queue.push(id);
+    }
+
+    /// A handle through which running tasks can spawn more tasks.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            crate::time::run_timers();
+            self.take_spawned();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn take_spawned(&mut self) {
+        while let Ok(task) = self.spawn_queue.pop() {
+            self.spawn(task);
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // destructure to keep the borrow checker happy about the closure below
+        let Self {
+            tasks,
+            task_queue,
+            spawn_queue: _,
+            waker_cache,
+        } = self;
+
+        let everything: Vec<TaskId> = if task_queue.overflowed.swap(false, Ordering::AcqRel) {
+            tasks.keys().copied().collect()
+        } else {
+            Vec::new()
+        };
+        let mut poll = |id: TaskId| {
+            let task = match tasks.get_mut(&id) {
+                Some(task) => task,
+                None => return, // woken after it completed
+            };
+            let task_waker = waker_cache
+                .entry(id)
+                .or_insert_with(|| TaskWaker::new(id, task_queue.clone()));
+            /* Before polling, so a wakeup while it runs queues it again. */
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    tasks.remove(&id);
+                    waker_cache.remove(&id);
+                }
+                Poll::Pending => {}
+            }
+        };
+
+        for id in everything {
+            poll(id);
+        }
+        while let Ok(id) = task_queue.ids.pop() {
+            poll(id);
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts;
+
+        /* Check and halt with interrupts off, otherwise a wakeup from an interrupt between the
+         * check and the hlt would sit unnoticed until the next interrupt. */
+        interrupts::disable();
+        if self.task_queue.is_empty() && self.spawn_queue.is_empty() {
+            /* Give the CPU to any ready kernel threads rather than halting on them. */
+            if crate::thread::has_ready() {
+                interrupts::enable();
+                crate::thread::yield_now();
+            } else {
+                interrupts::enable_and_hlt();
+            }
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) {
+        if self.spawn_queue.push(task).is_err() {
+            panic!("Spawn queue full");
+        }
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    task_queue: Arc<TaskQueue>,
+    /* Queued and not polled since. */
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(id: TaskId, task_queue: Arc<TaskQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker { id, task_queue, queued: AtomicBool::new(false) })
+    }
+
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.task_queue.push(self.id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
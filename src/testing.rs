@@ -0,0 +1,118 @@
+//! What the tests share: bringing the kernel up and reporting back for the test binaries
+//! (src/bin/test-*.rs), and building ELF images, for them and the loader's unit tests alike.
+
+use crate::loader::elf::{DT_NULL, EM_X86_64};
+use crate::{allocator, exit_qemu, gdt, interrupts, memory, serial_println, syscall, thread, tlb};
+use alloc::{vec, vec::Vec};
+use bootloader::BootInfo;
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// Bring up what the tests that run threads or programs need: the GDT, system calls, PCIDs,
+/// interrupts, memory and the heap, then threads.
+pub fn init(boot_info: &'static BootInfo) {
+    gdt::init();
+    syscall::init();
+    tlb::init();
+    interrupts::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset), &boot_info.memory_map) };
+    memory::with_mapper(|mapper, frame_allocator| allocator::init(mapper, frame_allocator))
+        .expect("Heap initialisation failed");
+    thread::init();
+}
+
+/// Tell the test runner the test passed, and exit.
+pub fn pass() -> ! {
+    serial_println!("ok");
+    unsafe {
+        exit_qemu();
+    }
+    loop {}
+}
+
+/// Tell the test runner the test failed, and why, and exit. What a test's panic handler calls.
+pub fn fail(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+    serial_println!("{}", info);
+    unsafe {
+        exit_qemu();
+    }
+    loop {}
+}
+
+/// Builds ELF images: a header, program headers, then each segment's bytes in turn.
+pub struct ElfBuilder {
+    kind: u16,
+    entry: u64,
+    segments: Vec<(u32, u32, u64, Vec<u8>, u64)>,
+}
+
+impl ElfBuilder {
+    /// An image of type `kind` (`ET_EXEC` or `ET_DYN`) starting at `entry`.
+    pub fn new(kind: u16, entry: u64) -> Self {
+        ElfBuilder { kind, entry, segments: Vec::new() }
+    }
+
+    /// Add a segment of `data` at `vaddr`, `mem_size` bytes in memory.
+    pub fn segment(mut self, kind: u32, flags: u32, vaddr: u64, data: &[u8], mem_size: u64) -> Self {
+        self.segments.push((kind, flags, vaddr, data.to_vec(), mem_size));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut out = vec![0u8; EHDR_SIZE];
+        out[0..4].copy_from_slice(b"\x7fELF");
+        out[4] = 2;
+        out[5] = 1;
+        out[6] = 1;
+        out[16..18].copy_from_slice(&self.kind.to_le_bytes());
+        out[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        out[20..24].copy_from_slice(&1u32.to_le_bytes());
+        out[24..32].copy_from_slice(&self.entry.to_le_bytes());
+        out[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        out[52..54].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        out[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        out[56..58].copy_from_slice(&(self.segments.len() as u16).to_le_bytes());
+
+        let mut offset = (EHDR_SIZE + PHDR_SIZE * self.segments.len()) as u64;
+        for (kind, flags, vaddr, data, mem_size) in &self.segments {
+            let mut ph = vec![0u8; PHDR_SIZE];
+            ph[0..4].copy_from_slice(&kind.to_le_bytes());
+            ph[4..8].copy_from_slice(&flags.to_le_bytes());
+            ph[8..16].copy_from_slice(&offset.to_le_bytes());
+            ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            ph[24..32].copy_from_slice(&vaddr.to_le_bytes());
+            ph[32..40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            ph[40..48].copy_from_slice(&mem_size.to_le_bytes());
+            ph[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+            out.extend_from_slice(&ph);
+            offset += data.len() as u64;
+        }
+        for (_, _, _, data, _) in &self.segments {
+            out.extend_from_slice(data);
+        }
+        out
+    }
+}
+
+/// A dynamic section with `entries` (tag, value), and the `DT_NULL` that ends it.
+pub fn dynamic(entries: &[(u64, u64)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (tag, value) in entries.iter().chain(&[(DT_NULL, 0)]) {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out
+}
+
+/// A relocation with an addend.
+pub fn rela(offset: u64, kind: u32, addend: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&u64::from(kind).to_le_bytes());
+    out.extend_from_slice(&addend.to_le_bytes());
+    out
+}
@@ -0,0 +1,58 @@
+use x86_64::VirtAddr;
+
+/* A switched-out thread's stack holds the callee-saved registers and a return address; its saved
+ * rsp points at them. Preemption happens by switching from inside the timer interrupt handler, so
+ * a preempted thread's interrupt frame sits further up its own stack and is unwound when the
+ * thread is switched back to and the handler returns. */
+global_asm!(
+    r#"
+.global mtos_switch_context
+mtos_switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global mtos_thread_trampoline
+mtos_thread_trampoline:
+    mov rdi, r12
+    call mtos_thread_entry
+    ud2
+"#
+);
+
+extern "C" {
+    /// Save the current callee-saved registers & stack pointer to `*old_rsp`, and resume the
+    /// thread whose stack pointer is `new_rsp`. Must be called with interrupts off.
+    pub fn mtos_switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn mtos_thread_trampoline();
+}
+
+const CALLEE_SAVED_REGS: usize = 6;
+
+/// Lay out a fresh stack so that switching to it "returns" into the trampoline, which calls
+/// `mtos_thread_entry(arg)`. Returns the stack pointer to switch to.
+pub unsafe fn init_stack(top: VirtAddr, arg: u64) -> u64 {
+    let top = top.as_u64() & !0xf; // the trampoline's call must see a 16-byte aligned stack
+    let frame = (top as *mut u64).sub(CALLEE_SAVED_REGS + 1);
+
+    // r15, r14, r13, r12 (the entry arg), rbx, rbp, return address
+    for i in 0..CALLEE_SAVED_REGS {
+        frame.add(i).write(0);
+    }
+    frame.add(3).write(arg);
+    frame.add(CALLEE_SAVED_REGS).write(mtos_thread_trampoline as u64);
+
+    frame as u64
+}
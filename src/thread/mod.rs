@@ -0,0 +1,683 @@
+use crate::address_space::{self, AddressSpace};
+use crate::memory::KernelStack;
+use crate::user;
+use crate::smp::MAX_CPUS;
+use crate::sync::{lockdep, SpinLock};
+use crate::{cmdline, gdt, interrupts as irq, ipi, percpu, print, println, time};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+mod context;
+pub mod scheduler;
+
+use scheduler::{Policy, SchedParams};
+
+/// Kernel stack size for spawned threads (plus a guard page).
+pub const STACK_PAGES: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub const fn from_u64(id: u64) -> ThreadId {
+        ThreadId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Parked until someone calls `wake()`.
+    Blocked,
+    /// Parked until the tick count reaches the given value.
+    Sleeping(u64),
+    Exited,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: ThreadState,
+    /// Saved stack pointer while switched out.
+    rsp: u64,
+    /// None for threads adopted from boot code (main, and the APs' idle threads), which run on
+    /// stacks set up elsewhere. Interrupts from user mode arrive at the top of it.
+    stack: Option<KernelStack>,
+    /// What it runs in, if it runs in user mode; otherwise it's on the kernel's page tables.
+    address_space: Option<Arc<AddressSpace>>,
+    /// The CPU whose run queue it's on. An idle CPU can take a ready thread from another's, unless
+    /// it's `pinned`, hasn't run yet, or is still `on_cpu`.
+    cpu: usize,
+    pinned: bool,
+    started: bool,
+    /// Still running on its stack: set when it's switched to, and cleared by the thread switched to
+    /// after it, once its stack pointer's saved. Until then it can be queued but not run elsewhere.
+    on_cpu: bool,
+    /// A `wake()` that arrived while the thread was still running, so its next `block()` returns
+    /// straight away rather than sleeping through it.
+    wakeup_pending: bool,
+    sched: SchedParams,
+    /// CPU time used, in ns.
+    cpu_ns: u64,
+    /// TSC when CPU time was last accounted to this thread, while it's running.
+    accounted_tsc: u64,
+}
+
+impl Thread {
+    fn new(name: &str, state: ThreadState, rsp: u64, stack: Option<KernelStack>) -> Box<Thread> {
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state,
+            rsp,
+            stack,
+            address_space: None,
+            cpu: percpu::cpu_id(),
+            pinned: false,
+            started: state == ThreadState::Running,
+            on_cpu: state == ThreadState::Running,
+            wakeup_pending: false,
+            sched: SchedParams::default(),
+            cpu_ns: 0,
+            accounted_tsc: time::rdtsc(),
+        })
+    }
+}
+
+/// A snapshot of a thread, for introspection.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    pub stack: Option<(VirtAddr, VirtAddr)>,
+    pub cpu: usize,
+    pub priority: u8,
+    pub nice: i8,
+    pub cpu_time: Duration,
+}
+
+/// A CPU's share of the scheduler. Which thread it's running is in its per-CPU block.
+struct RunQueue {
+    policy: Box<dyn Policy>,
+    idle: ThreadId,
+    /* The thread switched away from, for whatever runs next here to mark off the CPU. */
+    prev: Option<ThreadId>,
+    /* Threads that have exited here and switched away; freed by whatever runs next on this CPU. */
+    dead: Vec<Box<Thread>>,
+}
+
+struct Threads {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /* By CPU number; None until that CPU has joined. */
+    run_queues: Vec<Option<RunQueue>>,
+    /* The policy every CPU's run queue has. */
+    policy: &'static str,
+}
+
+/* Only ever locked with interrupts off: the timer interrupt takes it to preempt. */
+static THREADS: SpinLock<Option<Threads>> = SpinLock::new(None);
+
+fn default_policy() -> Box<dyn Policy> {
+    cmdline::get("sched")
+        .and_then(scheduler::from_name)
+        .unwrap_or_else(|| Box::new(scheduler::RoundRobin::new()))
+}
+
+/// Adopt the running code as the boot CPU's "main" thread and start its idle thread. Needs the
+/// heap and per-CPU data.
+pub fn init() {
+    let main = Thread::new("main", ThreadState::Running, 0, None);
+    let main_id = main.id;
+    let idle = new_thread("idle", Box::new(idle_loop));
+    let idle_id = idle.id;
+
+    let mut threads = BTreeMap::new();
+    threads.insert(main_id, main);
+    threads.insert(idle_id, idle);
+    let policy = default_policy();
+    let name = policy.name();
+    let mut run_queues: Vec<Option<RunQueue>> = (0..MAX_CPUS).map(|_| None).collect();
+    run_queues[0] = Some(RunQueue {
+        policy,
+        idle: idle_id,
+        prev: None,
+        dead: Vec::new(),
+    });
+    log::info!("Scheduling policy {}", name);
+
+    interrupts::without_interrupts(|| {
+        percpu!(current_thread).store(main_id.0, Ordering::Relaxed);
+        *THREADS.lock() = Some(Threads { threads, run_queues, policy: name });
+    });
+}
+
+/// Join the scheduler on an application processor, adopting the running code as its idle thread.
+pub fn run_ap() -> ! {
+    interrupts::disable();
+    let idle = Thread::new("idle", ThreadState::Running, 0, None);
+    let idle_id = idle.id;
+    let cpu = percpu::cpu_id();
+
+    percpu!(current_thread).store(idle_id.0, Ordering::Relaxed);
+    with_threads(|t| {
+        t.threads.insert(idle_id, idle);
+        t.run_queues[cpu] = Some(RunQueue {
+            policy: scheduler::from_name(t.policy).unwrap(),
+            idle: idle_id,
+            prev: None,
+            dead: Vec::new(),
+        });
+    });
+
+    idle_loop();
+    unreachable!();
+}
+
+fn idle_loop() {
+    loop {
+        /* Check and halt with interrupts off, so a wakeup from an interrupt can't slip in between;
+         * otherwise it'd wait for the next timer tick. */
+        interrupts::disable();
+        if has_ready() {
+            interrupts::enable();
+            yield_now();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+fn new_thread(name: &str, entry: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+    let stack = KernelStack::new(STACK_PAGES).expect("Out of memory for thread stack");
+    /* A fat Box<dyn FnOnce> doesn't fit in a register, so box it again to get a thin pointer. */
+    let arg = Box::into_raw(Box::new(entry)) as u64;
+    let rsp = unsafe { context::init_stack(stack.top(), arg) };
+
+    Thread::new(name, ThreadState::Ready, rsp, Some(stack))
+}
+
+/// Start a kernel thread running `f`, on this CPU to begin with. It exits when `f` returns.
+pub fn spawn<F>(name: &str, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = new_thread(name, Box::new(f));
+    let id = thread.id;
+
+    with_threads(|t| {
+        t.threads.insert(id, thread);
+        t.make_ready(id);
+    });
+    id
+}
+
+/// Start a thread, on this CPU to begin with, that drops to user mode at `entry` with `stack` as
+/// its stack, both in `space`, and `arg` in rdi. The thread ends if it faults.
+pub fn spawn_user(name: &str, space: Arc<AddressSpace>, entry: VirtAddr, stack: VirtAddr, arg: u64) -> ThreadId {
+    spawn_in(name, space, move || unsafe { user::enter(entry, stack, arg) })
+}
+
+/// Start a thread, on this CPU to begin with, running `f` in `space`, for `f` to go to user mode
+/// from. It won't be moved to another before it's run, so a caller with interrupts off can finish
+/// setting it up first.
+pub fn spawn_in<F>(name: &str, space: Arc<AddressSpace>, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut thread = new_thread(name, Box::new(f));
+    thread.address_space = Some(space);
+    let id = thread.id;
+
+    with_threads(|t| {
+        t.threads.insert(id, thread);
+        t.make_ready(id);
+    });
+    id
+}
+
+/// Start a kernel thread on another CPU, and keep it there. None if that CPU hasn't joined the
+/// scheduler.
+pub fn spawn_on<F>(cpu: usize, name: &str, f: F) -> Option<ThreadId>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut thread = new_thread(name, Box::new(f));
+    thread.cpu = cpu;
+    thread.pinned = true;
+    let id = thread.id;
+
+    let rejected = with_threads(|t| {
+        if t.run_queues.get(cpu).map_or(true, Option::is_none) {
+            return Some(thread);
+        }
+        t.threads.insert(id, thread);
+        t.make_ready(id);
+        None
+    });
+    /* Dropped out here, as freeing its stack takes the memory locks. */
+    match rejected {
+        Some(_) => None,
+        None => Some(id),
+    }
+}
+
+fn with_threads<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Threads) -> R,
+{
+    interrupts::without_interrupts(|| f(THREADS.lock().as_mut().expect("thread::init() not called")))
+}
+
+#[no_mangle]
+extern "C" fn mtos_thread_entry(arg: u64) -> ! {
+    irq::set_irq_depth(0);
+    finish_switch();
+    interrupts::enable();
+
+    let entry = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+    entry();
+    exit();
+}
+
+pub fn current() -> ThreadId {
+    /* Safe to read preemptibly: whichever CPU we end up on, it's running us. */
+    ThreadId(percpu!(current_thread).load(Ordering::Relaxed))
+}
+
+/// The current thread's address space, if it has one.
+pub fn address_space() -> Option<Arc<AddressSpace>> {
+    let id = current();
+    with_threads(|t| t.threads[&id].address_space.clone())
+}
+
+/// Move the current thread to another address space (or the kernel's, if None), returning the one
+/// it was in, which can be dropped now that this CPU has switched away from it.
+pub fn set_address_space(space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+    let id = current();
+    with_threads(|t| {
+        match &space {
+            Some(space) => space.activate(),
+            None => address_space::activate_kernel(),
+        }
+        core::mem::replace(&mut t.threads.get_mut(&id).unwrap().address_space, space)
+    })
+}
+
+/// Whether any thread other than the running one is waiting for this CPU.
+pub fn has_ready() -> bool {
+    with_threads(|t| t.this_rq().policy.has_ready())
+}
+
+/// Let another ready thread run, if there is one.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// End the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let id = current();
+    with_threads(|t| t.threads.get_mut(&id).unwrap().state = ThreadState::Exited);
+    schedule();
+    unreachable!("Exited thread was rescheduled");
+}
+
+/// Put the current thread to sleep for at least `d`, to tick granularity.
+pub fn sleep(d: Duration) {
+    let until = time::ticks().saturating_add(time::ticks_for(d));
+    interrupts::without_interrupts(|| {
+        let id = current();
+        with_threads(|t| t.threads.get_mut(&id).unwrap().state = ThreadState::Sleeping(until));
+        schedule();
+    });
+}
+
+/// Park the current thread until `wake()` is called for it. Callers must have interrupts off and
+/// have published the thread's ID (eg on a wait queue) before calling, so no wakeup is lost.
+pub fn block() {
+    debug_assert!(!interrupts::are_enabled());
+    let id = current();
+    let parked = with_threads(|t| {
+        let thread = t.threads.get_mut(&id).unwrap();
+        if thread.wakeup_pending {
+            thread.wakeup_pending = false;
+            false
+        } else {
+            thread.state = ThreadState::Blocked;
+            true
+        }
+    });
+    if parked {
+        schedule();
+    }
+}
+
+/// Make a blocked thread runnable again. Waking a thread that isn't blocked makes its next
+/// `block()` return immediately.
+pub fn wake(id: ThreadId) {
+    with_threads(|t| {
+        if let Some(thread) = t.threads.get_mut(&id) {
+            match thread.state {
+                ThreadState::Blocked => t.make_ready(id),
+                ThreadState::Ready | ThreadState::Running | ThreadState::Sleeping(_) => {
+                    thread.wakeup_pending = true;
+                }
+                ThreadState::Exited => (),
+            }
+        }
+    });
+}
+
+/// Called from the timer interrupt, after EOI: wake sleepers, and preempt the running thread if
+/// its time slice is up (and it hasn't turned preemption off).
+pub(crate) fn timer_tick() {
+    let now = time::ticks();
+    let cur_id = current();
+    let preempt = match THREADS.lock().as_mut() {
+        Some(t) => {
+            let woken: Vec<ThreadId> = t
+                .threads
+                .values()
+                .filter(|th| match th.state {
+                    ThreadState::Sleeping(until) => until <= now,
+                    _ => false,
+                })
+                .map(|th| th.id)
+                .collect();
+            for id in woken {
+                t.make_ready(id);
+            }
+
+            t.account_current();
+            let rq = t.run_queues[percpu::cpu_id()].as_mut().unwrap();
+            if cur_id == rq.idle {
+                /* Maybe something's ready here, or on another CPU for it to take: let schedule()
+                 * look. */
+                true
+            } else {
+                rq.policy.tick(&t.threads[&cur_id].sched)
+            }
+        }
+        None => false, // threads not up yet
+    };
+    let preempt = preempt && percpu::preemptible();
+
+    if preempt {
+        schedule();
+    }
+}
+
+/// Called from the reschedule IPI, after EOI: another CPU queued a thread here.
+pub(crate) fn reschedule() {
+    if percpu::preemptible() {
+        schedule();
+    }
+}
+
+/// Switch to the next thread to run. The current thread is requeued if it's still runnable.
+/// Interrupts must be off.
+fn schedule() {
+    let switch = match THREADS.lock().as_mut() {
+        Some(t) => t.pick_switch(),
+        None => None,
+    };
+
+    if let Some((old_rsp, new_rsp)) = switch {
+        lockdep::assert_no_locks_held();
+        let irq_depth = irq::irq_depth();
+        let user_gs = percpu::user_gs_base();
+        unsafe { context::mtos_switch_context(old_rsp, new_rsp) };
+        percpu::set_user_gs_base(user_gs);
+        irq::set_irq_depth(irq_depth);
+        finish_switch();
+    }
+}
+
+impl Threads {
+    /// Choose the next thread and update states; returns where to save the current thread's stack
+    /// pointer and the one to switch to, or None to carry on with the current thread.
+    fn pick_switch(&mut self) -> Option<(*mut u64, u64)> {
+        let cur_id = current();
+        let idle = self.this_rq().idle;
+        self.account_current();
+
+        /* Requeue the current thread before picking, so the policy can choose to keep it (eg it's
+         * the highest priority). Idle is never queued; it's what runs when nothing else can. */
+        let cur_running = self.threads[&cur_id].state == ThreadState::Running;
+        if cur_running && cur_id != idle {
+            self.make_ready(cur_id);
+        }
+        let next_id = match self.this_rq().policy.pick_next() {
+            Some(id) => id,
+            None => self.steal().unwrap_or(idle),
+        };
+        if next_id == cur_id {
+            self.threads.get_mut(&cur_id).unwrap().state = ThreadState::Running;
+            return None;
+        }
+
+        let cur = self.threads.get_mut(&cur_id).unwrap();
+        if cur.state == ThreadState::Running {
+            cur.state = ThreadState::Ready; // only idle gets here
+        }
+        /* The Box's contents don't move when it's moved into `dead`, so this stays valid for the
+         * switch, and the next thread frees it afterwards. */
+        let old_rsp = &mut cur.rsp as *mut u64;
+        if cur.state == ThreadState::Exited {
+            let dead = self.threads.remove(&cur_id).unwrap();
+            self.this_rq().dead.push(dead);
+        } else {
+            self.this_rq().prev = Some(cur_id);
+        }
+
+        let next = self.threads.get_mut(&next_id).unwrap();
+        next.state = ThreadState::Running;
+        next.started = true;
+        next.on_cpu = true;
+        next.accounted_tsc = time::rdtsc();
+        if let Some(stack) = &next.stack {
+            gdt::set_kernel_stack(stack.top());
+        }
+        match &next.address_space {
+            Some(space) => space.activate(),
+            None => address_space::activate_kernel(),
+        }
+        percpu!(current_thread).store(next_id.0, Ordering::Relaxed);
+
+        Some((old_rsp, next.rsp))
+    }
+
+    /// Take a ready thread from another CPU's run queue for this one, which has nothing else to do.
+    fn steal(&mut self) -> Option<ThreadId> {
+        let this_cpu = percpu::cpu_id();
+        let Threads { threads, run_queues, .. } = self;
+        /* Not one that's been queued by a CPU that's still switching away from it: its saved rsp
+         * is stale until that CPU's next thread clears on_cpu. */
+        let movable = |id: ThreadId| {
+            threads
+                .get(&id)
+                .map_or(false, |th| !th.pinned && th.started && !th.on_cpu)
+        };
+        let (cpu, id) = run_queues
+            .iter_mut()
+            .enumerate()
+            .filter(|&(cpu, _)| cpu != this_cpu)
+            .filter_map(|(cpu, rq)| Some((cpu, rq.as_mut()?.policy.steal(&movable)?)))
+            .next()?;
+        let thread = threads.get_mut(&id).unwrap();
+        log::trace!("CPU {} took thread {} from CPU {}", this_cpu, id, cpu);
+        thread.cpu = this_cpu;
+        Some(id)
+    }
+
+    fn this_rq(&mut self) -> &mut RunQueue {
+        self.run_queues[percpu::cpu_id()].as_mut().expect("CPU hasn't joined the scheduler")
+    }
+
+    /// Queue a thread on its CPU, and prod that CPU if it isn't this one.
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.threads.get_mut(&id).unwrap();
+        thread.state = ThreadState::Ready;
+        let rq = self.run_queues[thread.cpu].as_mut().unwrap();
+        rq.policy.enqueue(id, &mut thread.sched);
+        if thread.cpu != percpu::cpu_id() {
+            ipi::send(ipi::Target::Cpu(thread.cpu), ipi::Message::Reschedule);
+        }
+    }
+
+    /// Charge this CPU's running thread for the CPU time since it was last charged.
+    fn account_current(&mut self) {
+        let now = time::rdtsc();
+        let cur = self.threads.get_mut(&current()).unwrap();
+        let ns = time::tsc_to_ns(now.wrapping_sub(cur.accounted_tsc));
+        cur.accounted_tsc = now;
+        cur.cpu_ns += ns;
+        let rq = self.run_queues[cur.cpu].as_mut().unwrap();
+        rq.policy.charge(&mut cur.sched, ns);
+    }
+
+    /// Change a thread's scheduling parameters, requeueing it if it's waiting to run.
+    fn update_params<F: FnOnce(&mut SchedParams)>(&mut self, id: ThreadId, f: F) -> bool {
+        let (queued, cpu) = match self.threads.get(&id) {
+            Some(th) => (th.state == ThreadState::Ready, th.cpu),
+            None => return false,
+        };
+        if queued {
+            self.run_queues[cpu].as_mut().unwrap().policy.remove(id);
+        }
+        f(&mut self.threads.get_mut(&id).unwrap().sched);
+        if queued {
+            self.make_ready(id);
+        }
+        true
+    }
+}
+
+/// Set a thread's priority, for the `prio` policy. Returns false if there's no such thread.
+pub fn set_priority(id: ThreadId, priority: u8) -> bool {
+    let priority = priority.min(scheduler::MAX_PRIORITY);
+    with_threads(|t| t.update_params(id, |p| p.priority = priority))
+}
+
+/// Set a thread's nice value, for the `fair` policy. Returns false if there's no such thread.
+pub fn set_nice(id: ThreadId, nice: i8) -> bool {
+    let nice = nice.max(scheduler::MIN_NICE).min(scheduler::MAX_NICE);
+    with_threads(|t| t.update_params(id, |p| p.nice = nice))
+}
+
+/// Switch scheduling policy at runtime, on every CPU; the ready threads are moved over.
+pub fn set_policy(name: &str) -> bool {
+    let name = match scheduler::from_name(name) {
+        Some(policy) => policy.name(),
+        None => return false,
+    };
+    with_threads(|t| {
+        t.policy = name;
+        let Threads { threads, run_queues, .. } = t;
+        for (cpu, rq) in run_queues.iter_mut().enumerate() {
+            let rq = match rq {
+                Some(rq) => rq,
+                None => continue,
+            };
+            let mut policy = scheduler::from_name(name).unwrap();
+            for th in threads.values_mut() {
+                if th.cpu == cpu && th.state == ThreadState::Ready && th.id != rq.idle {
+                    rq.policy.remove(th.id);
+                    policy.enqueue(th.id, &mut th.sched);
+                }
+            }
+            rq.policy = policy;
+        }
+    });
+    true
+}
+
+pub fn policy_name() -> &'static str {
+    with_threads(|t| t.policy)
+}
+
+/// Runs on the new thread straight after every switch: mark the thread switched away from as off
+/// the CPU, now its stack pointer's saved, and free threads that exited.
+fn finish_switch() {
+    let dead = interrupts::without_interrupts(|| match THREADS.lock().as_mut() {
+        Some(t) => {
+            if let Some(prev) = t.this_rq().prev.take() {
+                t.threads.get_mut(&prev).unwrap().on_cpu = false;
+            }
+            core::mem::replace(&mut t.this_rq().dead, Vec::new())
+        }
+        None => Vec::new(),
+    });
+    drop(dead); // outside the lock: dropping the stacks and address spaces takes the memory locks
+}
+
+pub fn list() -> Vec<ThreadInfo> {
+    with_threads(|t| {
+        t.threads
+            .values()
+            .map(|th| ThreadInfo {
+                id: th.id,
+                name: th.name.clone(),
+                state: th.state,
+                stack: th.stack.as_ref().map(|s| (s.bottom(), s.top())),
+                cpu: th.cpu,
+                priority: th.sched.priority,
+                nice: th.sched.nice,
+                cpu_time: Duration::from_nanos(th.cpu_ns),
+            })
+            .collect()
+    })
+}
+
+/// Print the thread table.
+pub fn dump() {
+    println!("policy {}", policy_name());
+    println!(
+        "{:>4} {:<12} {:<9} {:>3} {:>4} {:>4} {:>10} {}",
+        "ID", "NAME", "STATE", "CPU", "PRIO", "NICE", "CPU(ms)", "STACK"
+    );
+    for th in list() {
+        let state = match th.state {
+            ThreadState::Sleeping(_) => "Sleeping",
+            ThreadState::Ready => "Ready",
+            ThreadState::Running => "Running",
+            ThreadState::Blocked => "Blocked",
+            ThreadState::Exited => "Exited",
+        };
+        print!(
+            "{:>4} {:<12} {:<9} {:>3} {:>4} {:>4} {:>10} ",
+            th.id,
+            th.name,
+            state,
+            th.cpu,
+            th.priority,
+            th.nice,
+            th.cpu_time.as_millis()
+        );
+        match th.stack {
+            Some((bottom, top)) => println!("{:?}-{:?}", bottom, top),
+            None => println!("boot"),
+        }
+    }
+}
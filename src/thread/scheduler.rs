@@ -0,0 +1,393 @@
+use super::ThreadId;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Ticks a thread may run before it's preempted in favour of another ready one.
+pub const TIME_SLICE_TICKS: u32 = 5;
+
+pub const MAX_PRIORITY: u8 = 31;
+pub const DEFAULT_PRIORITY: u8 = 16;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// Per-thread scheduling parameters & state. Each policy uses the parts it cares about.
+#[derive(Debug, Clone, Copy)]
+pub struct SchedParams {
+    /// For FixedPriority: higher runs first.
+    pub priority: u8,
+    /// For Fair: lower gets a bigger share of the CPU.
+    pub nice: i8,
+    /// For Fair: CPU time received, in ns, scaled by the inverse of the nice weight.
+    pub vruntime: u64,
+}
+
+impl Default for SchedParams {
+    fn default() -> Self {
+        SchedParams {
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
+            vruntime: 0,
+        }
+    }
+}
+
+/// A scheduling policy: which ready thread runs next, and when the running one is preempted. The
+/// policy only sees ready threads; the running thread is handed back via `enqueue()` when it
+/// stops running but is still runnable.
+pub trait Policy: Send {
+    fn name(&self) -> &'static str;
+
+    fn enqueue(&mut self, id: ThreadId, params: &mut SchedParams);
+
+    fn remove(&mut self, id: ThreadId);
+
+    /// The thread to run next, if any are ready. It's no longer queued.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    fn has_ready(&self) -> bool;
+
+    /// Give up the ready thread that would run soonest of those `movable` allows, for another CPU
+    /// to run. It's no longer queued.
+    fn steal(&mut self, movable: &dyn Fn(ThreadId) -> bool) -> Option<ThreadId>;
+
+    /// Account `ns` of CPU time to the running thread.
+    fn charge(&mut self, _params: &mut SchedParams, _ns: u64) {}
+
+    /// A timer tick while a thread with `params` runs; true if it should now be preempted.
+    fn tick(&mut self, params: &SchedParams) -> bool;
+}
+
+/// Look a policy up by the name it's given on the command line with `sched=`.
+pub fn from_name(name: &str) -> Option<Box<dyn Policy>> {
+    match name {
+        "rr" => Some(Box::new(RoundRobin::new())),
+        "prio" => Some(Box::new(FixedPriority::new())),
+        "fair" => Some(Box::new(Fair::new())),
+        _ => None,
+    }
+}
+
+/// Round-robin over the ready threads, each getting a fixed time slice.
+pub struct RoundRobin {
+    queue: VecDeque<ThreadId>,
+    slice_left: u32,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            queue: VecDeque::new(),
+            slice_left: TIME_SLICE_TICKS,
+        }
+    }
+}
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn enqueue(&mut self, id: ThreadId, _params: &mut SchedParams) {
+        self.queue.push_back(id);
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.queue.retain(|&t| t != id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.slice_left = TIME_SLICE_TICKS;
+        self.queue.pop_front()
+    }
+
+    fn has_ready(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    fn steal(&mut self, movable: &dyn Fn(ThreadId) -> bool) -> Option<ThreadId> {
+        let index = self.queue.iter().position(|&id| movable(id))?;
+        self.queue.remove(index)
+    }
+
+    fn tick(&mut self, _params: &SchedParams) -> bool {
+        self.slice_left = self.slice_left.saturating_sub(1);
+        self.slice_left == 0 && self.has_ready()
+    }
+}
+
+/// Strict priorities, round-robin within each. A ready thread of higher priority preempts the
+/// running one at the next tick; lower priorities only run when nothing higher is ready.
+pub struct FixedPriority {
+    queues: BTreeMap<u8, VecDeque<ThreadId>>,
+    slice_left: u32,
+}
+
+impl FixedPriority {
+    pub fn new() -> Self {
+        FixedPriority {
+            queues: BTreeMap::new(),
+            slice_left: TIME_SLICE_TICKS,
+        }
+    }
+
+    fn highest_ready(&self) -> Option<u8> {
+        self.queues.iter().rev().find(|(_, q)| !q.is_empty()).map(|(&p, _)| p)
+    }
+}
+
+impl Policy for FixedPriority {
+    fn name(&self) -> &'static str {
+        "prio"
+    }
+
+    fn enqueue(&mut self, id: ThreadId, params: &mut SchedParams) {
+        self.queues.entry(params.priority).or_insert_with(VecDeque::new).push_back(id);
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        for q in self.queues.values_mut() {
+            q.retain(|&t| t != id);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.slice_left = TIME_SLICE_TICKS;
+        let prio = self.highest_ready()?;
+        self.queues.get_mut(&prio)?.pop_front()
+    }
+
+    fn has_ready(&self) -> bool {
+        self.highest_ready().is_some()
+    }
+
+    fn steal(&mut self, movable: &dyn Fn(ThreadId) -> bool) -> Option<ThreadId> {
+        for q in self.queues.values_mut().rev() {
+            if let Some(index) = q.iter().position(|&id| movable(id)) {
+                return q.remove(index);
+            }
+        }
+        None
+    }
+
+    fn tick(&mut self, params: &SchedParams) -> bool {
+        self.slice_left = self.slice_left.saturating_sub(1);
+        match self.highest_ready() {
+            Some(p) if p > params.priority => true,
+            Some(p) if p == params.priority => self.slice_left == 0,
+            _ => false,
+        }
+    }
+}
+
+/* Linux's nice-to-weight table: each step of nice is ~10% CPU relative to a neighbour. */
+const NICE_0_WEIGHT: u64 = 1024;
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// How far ahead of the most-starved ready thread the running one may get before it's preempted.
+const FAIR_GRANULARITY_NS: u64 = 4_000_000;
+
+fn nice_weight(nice: i8) -> u64 {
+    NICE_WEIGHTS[(nice.max(MIN_NICE).min(MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// CFS-style fair share: always run the ready thread that has had the least (weighted) CPU time.
+pub struct Fair {
+    queue: BTreeMap<(u64, ThreadId), ()>,
+    /// The smallest vruntime seen, so that threads waking from a long sleep don't come back owed
+    /// a huge burst of CPU.
+    min_vruntime: u64,
+}
+
+impl Fair {
+    pub fn new() -> Self {
+        Fair {
+            queue: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Policy for Fair {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn enqueue(&mut self, id: ThreadId, params: &mut SchedParams) {
+        params.vruntime = params.vruntime.max(self.min_vruntime);
+        self.queue.insert((params.vruntime, id), ());
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        let key = self.queue.keys().find(|(_, t)| *t == id).copied();
+        if let Some(key) = key {
+            self.queue.remove(&key);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let key = *self.queue.keys().next()?;
+        self.queue.remove(&key);
+        self.min_vruntime = self.min_vruntime.max(key.0);
+        Some(key.1)
+    }
+
+    fn has_ready(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /* Its vruntime is from this queue, but the other's enqueue() catches it up if it's behind. */
+    fn steal(&mut self, movable: &dyn Fn(ThreadId) -> bool) -> Option<ThreadId> {
+        let key = *self.queue.keys().find(|&&(_, id)| movable(id))?;
+        self.queue.remove(&key);
+        Some(key.1)
+    }
+
+    fn charge(&mut self, params: &mut SchedParams, ns: u64) {
+        params.vruntime += ns * NICE_0_WEIGHT / nice_weight(params.nice);
+    }
+
+    fn tick(&mut self, params: &SchedParams) -> bool {
+        match self.queue.keys().next() {
+            Some(&(leftmost, _)) => params.vruntime > leftmost + FAIR_GRANULARITY_NS,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u64) -> ThreadId {
+        ThreadId::from_u64(n)
+    }
+
+    fn prio(priority: u8) -> SchedParams {
+        SchedParams { priority, ..SchedParams::default() }
+    }
+
+    #[test]
+    fn names() {
+        for &name in &["rr", "prio", "fair"] {
+            assert_eq!(from_name(name).unwrap().name(), name);
+        }
+        assert!(from_name("lottery").is_none());
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut rr = RoundRobin::new();
+        let mut params = SchedParams::default();
+        for n in 1..=3 {
+            rr.enqueue(id(n), &mut params);
+        }
+        rr.remove(id(2));
+        assert_eq!(rr.pick_next(), Some(id(1)));
+        /* 3 is waiting, so 1 gets its slice and no more. */
+        for _ in 1..TIME_SLICE_TICKS {
+            assert!(!rr.tick(&params));
+        }
+        assert!(rr.tick(&params));
+        rr.enqueue(id(1), &mut params);
+        assert_eq!(rr.pick_next(), Some(id(3)));
+        assert_eq!(rr.pick_next(), Some(id(1)));
+        assert_eq!(rr.pick_next(), None);
+        /* With nothing else ready, the running thread carries on. */
+        for _ in 0..TIME_SLICE_TICKS * 2 {
+            assert!(!rr.tick(&params));
+        }
+    }
+
+    #[test]
+    fn fixed_priority_runs_the_highest_first() {
+        let mut fp = FixedPriority::new();
+        fp.enqueue(id(1), &mut prio(10));
+        fp.enqueue(id(2), &mut prio(20));
+        fp.enqueue(id(3), &mut prio(20));
+        assert_eq!(fp.pick_next(), Some(id(2)));
+        /* 3 is as high, so it gets a turn when 2's slice is up. */
+        for _ in 1..TIME_SLICE_TICKS {
+            assert!(!fp.tick(&prio(20)));
+        }
+        assert!(fp.tick(&prio(20)));
+        assert_eq!(fp.pick_next(), Some(id(3)));
+        /* Only 1 is left, which is lower, so 3 runs as long as it likes... */
+        for _ in 0..TIME_SLICE_TICKS * 2 {
+            assert!(!fp.tick(&prio(20)));
+        }
+        /* ...and while 1 runs, something higher waking up preempts it at once. */
+        assert_eq!(fp.pick_next(), Some(id(1)));
+        fp.enqueue(id(4), &mut prio(11));
+        assert!(fp.tick(&prio(10)));
+        assert_eq!(fp.pick_next(), Some(id(4)));
+        assert_eq!(fp.pick_next(), None);
+    }
+
+    #[test]
+    fn fair_runs_the_least_run() {
+        let mut fair = Fair::new();
+        let mut a = SchedParams::default();
+        let mut b = SchedParams::default();
+        fair.charge(&mut a, 3_000_000);
+        fair.enqueue(id(1), &mut a);
+        fair.enqueue(id(2), &mut b);
+        assert_eq!(fair.pick_next(), Some(id(2)));
+        /* b can get the granularity ahead of a before it's preempted. */
+        fair.charge(&mut b, 3_000_000 + FAIR_GRANULARITY_NS);
+        assert!(!fair.tick(&b));
+        fair.charge(&mut b, 1);
+        assert!(fair.tick(&b));
+        fair.enqueue(id(2), &mut b);
+        assert_eq!(fair.pick_next(), Some(id(1)));
+        assert_eq!(fair.pick_next(), Some(id(2)));
+        assert!(!fair.tick(&b));
+    }
+
+    #[test]
+    fn fair_weights_by_nice() {
+        let mut fair = Fair::new();
+        let mut nice = SchedParams { nice: MIN_NICE, ..SchedParams::default() };
+        let mut normal = SchedParams::default();
+        fair.charge(&mut nice, 1_000_000);
+        fair.charge(&mut normal, 1_000_000);
+        assert_eq!(normal.vruntime, 1_000_000);
+        assert_eq!(nice.vruntime, 1_000_000 * NICE_0_WEIGHT / nice_weight(MIN_NICE));
+        /* Out of range is clamped. */
+        assert_eq!(nice_weight(-100), nice_weight(MIN_NICE));
+        assert_eq!(nice_weight(100), nice_weight(MAX_NICE));
+    }
+
+    #[test]
+    fn fair_catches_sleepers_up() {
+        let mut fair = Fair::new();
+        let mut running = SchedParams { vruntime: 50_000_000, ..SchedParams::default() };
+        fair.enqueue(id(1), &mut running);
+        assert_eq!(fair.pick_next(), Some(id(1)));
+        /* Having slept through all that, it doesn't get to catch up all at once. */
+        let mut sleeper = SchedParams::default();
+        fair.enqueue(id(2), &mut sleeper);
+        assert_eq!(sleeper.vruntime, 50_000_000);
+    }
+
+    #[test]
+    fn steal_takes_only_movable_threads() {
+        let policies: Vec<Box<dyn Policy>> =
+            vec![Box::new(RoundRobin::new()), Box::new(FixedPriority::new()), Box::new(Fair::new())];
+        for mut policy in policies {
+            for n in 1..=3 {
+                policy.enqueue(id(n), &mut prio(n as u8));
+            }
+            let odd = |t: ThreadId| t.as_u64() % 2 == 1;
+            let first = policy.steal(&odd).unwrap();
+            let second = policy.steal(&odd).unwrap();
+            assert!(odd(first) && odd(second) && first != second, "{}", policy.name());
+            assert_eq!(policy.steal(&odd), None, "{}", policy.name());
+            assert_eq!(policy.pick_next(), Some(id(2)), "{}", policy.name());
+            assert!(!policy.has_ready(), "{}", policy.name());
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub const TIMER_HZ: u32 = 100;
+
+const PIT_FREQ: u32 = 1_193_182;
+const PORT_PIT_CHANNEL_0: u16 = 0x40;
+const PORT_PIT_COMMAND: u16 = 0x43;
+const PIT_CMD_CHANNEL_0_RATE_GENERATOR: u8 = 0b00_11_010_0; // channel 0, lo/hi byte, mode 2, binary
+const PORT_PIT_CHANNEL_2: u16 = 0x42;
+const PIT_CMD_CHANNEL_2_ONE_SHOT: u8 = 0b10_11_000_0; // channel 2, lo/hi byte, mode 0, binary
+const PORT_SYSTEM_CONTROL: u16 = 0x61; // channel 2's gate and output, and the PC speaker
+const SYSCTL_PIT_2_GATE: u8 = 1 << 0;
+const SYSCTL_SPEAKER: u8 = 1 << 1;
+const SYSCTL_PIT_2_OUT: u8 = 1 << 5;
+const TSC_CALIBRATION_MS: u32 = 50;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /* Sleeping tasks, by (deadline, sequence number) so equal deadlines don't collide. */
+    static ref SLEEPERS: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+}
+static SLEEPER_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Program the PIT to interrupt at TIMER_HZ rather than the BIOS's 18.2Hz.
+pub fn init() {
+    let divisor = (PIT_FREQ / TIMER_HZ) as u16;
+    let mut cmd = Port::<u8>::new(PORT_PIT_COMMAND);
+    let mut data = Port::<u8>::new(PORT_PIT_CHANNEL_0);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        cmd.write(PIT_CMD_CHANNEL_0_RATE_GENERATOR);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    });
+
+    let hz = calibrate_tsc();
+    TSC_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Count TSC cycles across a PIT channel 2 one-shot. Polled, so it works with interrupts off.
+fn calibrate_tsc() -> u64 {
+    let count = (PIT_FREQ * TSC_CALIBRATION_MS / 1000) as u16;
+    let mut cmd = Port::<u8>::new(PORT_PIT_COMMAND);
+    let mut data = Port::<u8>::new(PORT_PIT_CHANNEL_2);
+    let mut sysctl = Port::<u8>::new(PORT_SYSTEM_CONTROL);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let ctl = sysctl.read();
+        sysctl.write((ctl & !SYSCTL_SPEAKER) | SYSCTL_PIT_2_GATE);
+
+        cmd.write(PIT_CMD_CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        while sysctl.read() & SYSCTL_PIT_2_OUT == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        let end = rdtsc();
+
+        sysctl.write(ctl);
+        (end - start) * 1000 / u64::from(TSC_CALIBRATION_MS)
+    })
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// TSC frequency, as measured at boot.
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+pub fn tsc_to_ns(cycles: u64) -> u64 {
+    match tsc_hz() {
+        0 => 0,
+        hz => (u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64,
+    }
+}
+
+/// Busy-wait for `d`, eg for hardware that needs a pause between commands. Works with interrupts
+/// off; needs the TSC calibrated by `init()`.
+pub fn spin_delay(d: Duration) {
+    let cycles = (u128::from(tsc_hz()) * d.as_nanos() / 1_000_000_000) as u64;
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < cycles {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Called from the timer interrupt.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    let t = ticks();
+    let hz = u64::from(TIMER_HZ);
+    Duration::new(t / hz, ((t % hz) * (1_000_000_000 / hz)) as u32)
+}
+
+/// How many ticks `d` is, rounded up so we never sleep short. Saturates, so a huge `d` (eg from
+/// user mode) just means forever.
+pub fn ticks_for(d: Duration) -> u64 {
+    let ms = if d.as_millis() > u128::from(u64::MAX) { u64::MAX } else { d.as_millis() as u64 };
+    let hz = u64::from(TIMER_HZ);
+    ms.saturating_mul(hz).saturating_add(999) / 1000
+}
+
+/// Wake every sleeper whose deadline has passed. Called from the executor loop rather than the
+/// timer interrupt, so the heap work of dropping wakers stays out of interrupt context.
+pub fn run_timers() {
+    let now = ticks();
+    loop {
+        let waker = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            let first = *sleepers.keys().next()?;
+            if first.0 > now {
+                return None;
+            }
+            sleepers.remove(&first)
+        });
+        match waker {
+            Some(w) => w.wake(),
+            None => break,
+        }
+    }
+}
+
+/// Future that completes once the tick count reaches `deadline`.
+pub struct Sleep {
+    deadline: u64,
+    key: Option<(u64, u64)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline;
+        let key = *self
+            .key
+            .get_or_insert_with(|| (deadline, SLEEPER_SEQ.fetch_add(1, Ordering::Relaxed)));
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            SLEEPERS.lock().insert(key, cx.waker().clone());
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                SLEEPERS.lock().remove(&key);
+            });
+        }
+    }
+}
+
+/// Sleep for at least `d`, to tick granularity.
+pub fn sleep(d: Duration) -> Sleep {
+    Sleep {
+        deadline: ticks().saturating_add(ticks_for(d)),
+        key: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_round_up() {
+        let hz = u64::from(TIMER_HZ);
+        assert_eq!(ticks_for(Duration::from_millis(0)), 0);
+        assert_eq!(ticks_for(Duration::from_millis(1)), 1);
+        assert_eq!(ticks_for(Duration::from_secs(1)), hz);
+    }
+
+    #[test]
+    fn huge_durations_saturate() {
+        assert_eq!(ticks_for(Duration::from_millis(u64::MAX)), u64::MAX / 1000);
+        assert_eq!(ticks_for(Duration::from_secs(u64::MAX)), u64::MAX / 1000);
+    }
+}
@@ -0,0 +1,100 @@
+//! TLB management. The kernel's mappings are shared by every address space, so when one is
+//! removed or made more restrictive, other CPUs may still have the old entry cached and must drop
+//! it too. Adding a mapping needs nothing: x86 doesn't cache not-present entries.
+//!
+//! With PCIDs, TLB entries are tagged with the address space they came from, so they survive
+//! switching between address spaces. That means a kernel shootdown has to flush every PCID's
+//! entries, not just the current one's.
+
+use crate::{ipi, smp};
+use core::sync::atomic::{AtomicBool, Ordering};
+use raw_cpuid::CpuId;
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+/* Past this many pages, flushing everything is cheaper than one invlpg each. */
+const FLUSH_ALL_PAGES: u64 = 32;
+
+const CR4_PCIDE: u64 = 1 << 17;
+
+/* INVPCID types. */
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_CONTEXT: u64 = 1;
+const INVPCID_ALL: u64 = 2;
+
+static PCID: AtomicBool = AtomicBool::new(false);
+
+/// Turn on PCIDs on this CPU, if it has them and the INVPCID instruction, which flushing other
+/// address spaces' entries needs. Before it loads any CR3 but the kernel's; every CPU.
+pub fn init() {
+    let cpuid = CpuId::new();
+    let pcid = cpuid.get_feature_info().map_or(false, |f| f.has_pcid());
+    let invpcid = cpuid.get_extended_feature_info().map_or(false, |f| f.has_invpcid());
+    if pcid && invpcid {
+        unsafe {
+            let cr4: u64;
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+            asm!("mov cr4, {}", in(reg) cr4 | CR4_PCIDE, options(nostack));
+        }
+        PCID.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether address spaces get PCIDs.
+pub fn pcid_enabled() -> bool {
+    PCID.load(Ordering::Relaxed)
+}
+
+unsafe fn invpcid(kind: u64, pcid: u16, addr: u64) {
+    let descriptor = [u64::from(pcid), addr];
+    asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor, options(nostack));
+}
+
+/// Drop this CPU's entries for `pages` pages from `start`, in the current address space.
+pub(crate) fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FLUSH_ALL_PAGES {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(start + i * 4096);
+        }
+    }
+}
+
+/// Drop this CPU's entries for `pages` pages from `start` tagged with `pcid`, whether or not it's
+/// the current one.
+pub(crate) fn flush_pcid(pcid: u16, start: VirtAddr, pages: u64) {
+    unsafe {
+        if pages > FLUSH_ALL_PAGES {
+            invpcid(INVPCID_CONTEXT, pcid, 0);
+        } else {
+            for i in 0..pages {
+                invpcid(INVPCID_ADDRESS, pcid, start.as_u64() + i * 4096);
+            }
+        }
+    }
+}
+
+/// Drop all of this CPU's entries tagged with `pcid`, before it's given to another address space.
+pub(crate) fn flush_context(pcid: u16) {
+    unsafe { invpcid(INVPCID_CONTEXT, pcid, 0) };
+}
+
+fn flush_kernel(start: VirtAddr, pages: u64) {
+    if pcid_enabled() {
+        unsafe { invpcid(INVPCID_ALL, 0, 0) };
+    } else {
+        flush_local(start, pages);
+    }
+}
+
+/// Invalidate `pages` pages of kernel mappings from `start` on every CPU, once their page table
+/// entries have been changed. Any frames they mapped mustn't be reused until this returns. Like
+/// `ipi::call()`, not with a spinlock held, so it can't be done under `memory::with_mapper()`.
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    if smp::online_count() > 1 {
+        ipi::call(ipi::Target::All, || flush_kernel(start, pages));
+    } else {
+        flush_kernel(start, pages);
+    }
+}
@@ -0,0 +1,102 @@
+//! Running code in ring 3, in the user range of an `AddressSpace`.
+
+use crate::{gdt, percpu};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+/// User mappings go in L4 slots 32 up to 128, well clear of what the bootloader and kernel use.
+/// The rest of every address space is the kernel's.
+pub const USER_START: u64 = 0x_1000_0000_0000;
+pub const USER_END: u64 = 0x_4000_0000_0000;
+
+/* IF, and bit 1, which is always set. */
+pub(crate) const USER_RFLAGS: u64 = 0x202;
+/* CF, PF, AF, ZF, SF, DF and OF. */
+const USER_FLAGS_MASK: u64 = 0xcd5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Not (entirely) between `USER_START` and `USER_END`.
+    OutOfRange,
+    OutOfMemory,
+    AlreadyMapped,
+}
+
+/// A user thread's general purpose registers, instruction & stack pointers and flags.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+/// Drop to ring 3 at `entry`, with `stack` as the stack pointer, `arg` in rdi and the other
+/// registers zeroed, so no kernel values leak. See `resume()`.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr, arg: u64) -> ! {
+    percpu::set_user_gs_base(0);
+    let regs = Registers {
+        rip: entry.as_u64(),
+        rsp: stack.as_u64(),
+        rdi: arg,
+        ..Registers::default()
+    };
+    resume(&regs)
+}
+
+/// Go (back) to ring 3 with `regs`; user mode only gets to choose the arithmetic flags. Only from
+/// a thread with its own kernel stack: interrupts from user mode arrive at the top of it, over
+/// whatever's there now, so this never comes back.
+pub unsafe fn resume(regs: &Registers) -> ! {
+    interrupts::disable();
+    let regs = Registers {
+        rflags: regs.rflags & USER_FLAGS_MASK | USER_RFLAGS,
+        ..*regs
+    };
+    /* The iret frame goes on the stack below `regs`, then rax, which points at it, goes last. */
+    asm!(
+        "mov ds, {ds:x}",
+        "mov es, {ds:x}",
+        "push {ds}",
+        "push qword ptr [rax + 128]",
+        "push qword ptr [rax + 136]",
+        "push {cs}",
+        "push qword ptr [rax + 120]",
+        "mov rbx, [rax + 8]",
+        "mov rcx, [rax + 16]",
+        "mov rdx, [rax + 24]",
+        "mov rsi, [rax + 32]",
+        "mov rdi, [rax + 40]",
+        "mov rbp, [rax + 48]",
+        "mov r8, [rax + 56]",
+        "mov r9, [rax + 64]",
+        "mov r10, [rax + 72]",
+        "mov r11, [rax + 80]",
+        "mov r12, [rax + 88]",
+        "mov r13, [rax + 96]",
+        "mov r14, [rax + 104]",
+        "mov r15, [rax + 112]",
+        "mov rax, [rax]",
+        "swapgs",
+        "iretq",
+        ds = in(reg) u64::from(gdt::USER_DATA_SELECTOR.0),
+        cs = in(reg) u64::from(gdt::USER_CODE_SELECTOR.0),
+        in("rax") &regs,
+        options(noreturn)
+    );
+}
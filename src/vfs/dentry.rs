@@ -0,0 +1,209 @@
+//! Dentries: the names the VFS has looked up, each tying an inode to its name and its parent.
+//! A directory only keeps weak references to its children, so a dentry lasts as long as something
+//! has it (an open file, a path being walked through it, a child of its own) and no longer, and
+//! the heap isn't filled with names nobody's using. While it lasts, however a file is reached it's
+//! the same dentry. Mount points are kept for good, as that's where what's mounted is recorded.
+
+use super::{FileType, Inode, Stat, MAX_SYMLINKS, NAME_MAX};
+use crate::sync::IrqSpinLock;
+use crate::syscall::Error;
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+pub(super) struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    dev: u64,
+    /* None for the root of everything. The root of a filesystem mounted somewhere has the mount
+     * point's parent, so ".." leads out of it. */
+    parent: Option<Arc<Dentry>>,
+    /* The children looked up that are still around, by name. An entry whose dentry has gone is
+     * taken out by its drop, or replaced by the next lookup. */
+    children: IrqSpinLock<BTreeMap<String, Weak<Dentry>>>,
+    /* The root of the filesystem mounted here, if there is one. */
+    mounted: IrqSpinLock<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(name: &str, inode: Arc<dyn Inode>, dev: u64, parent: Option<Arc<Dentry>>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name: String::from(name),
+            inode,
+            dev,
+            parent,
+            children: IrqSpinLock::new(BTreeMap::new()),
+            mounted: IrqSpinLock::new(None),
+        })
+    }
+
+    /// The root of everything, for the filesystem whose root is `inode`.
+    pub fn root(inode: Arc<dyn Inode>, dev: u64) -> Arc<Dentry> {
+        Dentry::new("/", inode, dev, None)
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn stat(&self) -> Stat {
+        Stat { dev: self.dev, ..self.inode.stat() }
+    }
+
+    /// Mount the filesystem whose root is `root` here.
+    pub fn mount(self: &Arc<Self>, root: Arc<dyn Inode>, dev: u64) -> Result<(), Error> {
+        if self.stat().kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+        let mut mounted = self.mounted.lock();
+        if mounted.is_some() {
+            return Err(Error::Busy);
+        }
+        *mounted = Some(Dentry::new(&self.name, root, dev, self.parent.clone()));
+        MOUNT_POINTS.lock().push(self.clone());
+        Ok(())
+    }
+
+    /* What's seen here: the root of what's mounted on it (and on that...), or itself. */
+    fn top(self: Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self;
+        loop {
+            let mounted = dentry.mounted.lock().clone();
+            match mounted {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// The entry called `name` in this directory, as seen through mounts.
+    pub fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, Error> {
+        match name {
+            "." => Ok(self.clone()),
+            ".." => Ok(self.parent.clone().unwrap_or_else(|| self.clone())),
+            _ => Ok(self.lookup(name)?.top()),
+        }
+    }
+
+    /* The entry called `name` in this directory itself, looking it up if it's not cached. */
+    fn lookup(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, Error> {
+        if name.len() > NAME_MAX {
+            return Err(Error::NameTooLong);
+        }
+        let cached = self.children.lock().get(name).and_then(Weak::upgrade);
+        if let Some(child) = cached {
+            return Ok(child);
+        }
+        let inode = self.inode.lookup(name)?;
+        Ok(self.add(name, inode))
+    }
+
+    fn add(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        let mut children = self.children.lock();
+        if let Some(child) = children.get(name).and_then(Weak::upgrade) {
+            return child;
+        }
+        let child = Dentry::new(name, inode, self.dev, Some(self.clone()));
+        children.insert(String::from(name), Arc::downgrade(&child));
+        child
+    }
+
+    /// Make a file or directory called `name` here.
+    pub fn create(self: &Arc<Self>, name: &str, kind: FileType, mode: u32) -> Result<Arc<Dentry>, Error> {
+        self.check_new(name)?;
+        let inode = self.inode.create(name, kind, mode)?;
+        Ok(self.add(name, inode))
+    }
+
+    /// Make a symlink called `name` here, to `target`.
+    pub fn symlink(self: &Arc<Self>, name: &str, target: &str) -> Result<Arc<Dentry>, Error> {
+        self.check_new(name)?;
+        let inode = self.inode.symlink(name, target)?;
+        Ok(self.add(name, inode))
+    }
+
+    /* Check there's nothing called `name` here. */
+    fn check_new(self: &Arc<Self>, name: &str) -> Result<(), Error> {
+        match self.lookup(name) {
+            Ok(_) => Err(Error::Exists),
+            Err(Error::NoEntry) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the entry called `name` from this directory: a directory if `directory`, and
+    /// anything else if not.
+    pub fn remove(self: &Arc<Self>, name: &str, directory: bool) -> Result<(), Error> {
+        let child = self.lookup(name)?;
+        if child.mounted.lock().is_some() {
+            return Err(Error::Busy);
+        }
+        match child.stat().kind {
+            FileType::Directory if !directory => return Err(Error::IsDirectory),
+            FileType::Regular | FileType::Symlink if directory => return Err(Error::NotDirectory),
+            _ => (),
+        }
+        self.inode.unlink(name)?;
+        self.children.lock().remove(name);
+        Ok(())
+    }
+}
+
+/* Take this dentry's entry out of its parent's children, unless a new dentry has the name by now. */
+impl Drop for Dentry {
+    fn drop(&mut self) {
+        if let Some(parent) = &self.parent {
+            let mut children = parent.children.lock();
+            if children.get(&self.name).map_or(false, |child| child.strong_count() == 0) {
+                children.remove(&self.name);
+            }
+        }
+    }
+}
+
+/* Every dentry something's mounted on. There's no unmounting, so they're kept for good. */
+static MOUNT_POINTS: IrqSpinLock<Vec<Arc<Dentry>>> = IrqSpinLock::new(Vec::new());
+
+/// Resolve `path` from `start` (or from the root, if it starts with "/"). A symlink at the end is
+/// followed if `follow`; any before are.
+pub(super) fn walk(start: Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>, Error> {
+    let mut links = 0;
+    walk_from(start, path, follow, &mut links)
+}
+
+fn walk_from(start: Arc<Dentry>, path: &str, follow: bool, links: &mut usize) -> Result<Arc<Dentry>, Error> {
+    let mut dir = if path.starts_with('/') { super::root()? } else { start }.top();
+    let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+    while let Some(name) = names.next() {
+        let next = dir.child(name)?;
+        let last = names.peek().is_none();
+        dir = if next.stat().kind == FileType::Symlink && (follow || !last) {
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(Error::Loop);
+            }
+            let target = next.inode.readlink()?;
+            walk_from(dir, &target, true, links)?
+        } else {
+            next
+        };
+    }
+    Ok(dir)
+}
+
+/// Resolve all but the last name in `path`, returning that directory and the name.
+pub(super) fn walk_parent(start: Arc<Dentry>, path: &str) -> Result<(Arc<Dentry>, &str), Error> {
+    let path = path.trim_end_matches('/');
+    let split = path.rfind('/').map_or(0, |i| i + 1);
+    let name = &path[split..];
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::Invalid);
+    }
+    if name.len() > NAME_MAX {
+        return Err(Error::NameTooLong);
+    }
+    Ok((walk(start, &path[..split], true)?, name))
+}
@@ -0,0 +1,152 @@
+//! Open files: a dentry, how it was opened, and where in it reads and writes are up to.
+
+use super::dentry::Dentry;
+use super::{FileType, Stat, O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY, SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::process::fd::FileOps;
+use crate::sync::Mutex;
+use crate::syscall::Error;
+use alloc::sync::Arc;
+use core::convert::TryFrom;
+
+/* The fixed part of a `linux_dirent64`: d_ino, d_off, d_reclen and d_type; the name follows. */
+const DIRENT_HEADER: usize = 19;
+
+pub struct OpenFile {
+    dentry: Arc<Dentry>,
+    flags: u64,
+    /* In bytes, or for a directory, in entries. Held through each read or write, so they
+     * don't overlap. */
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub(super) fn new(dentry: Arc<Dentry>, flags: u64) -> OpenFile {
+        OpenFile { dentry, flags, offset: Mutex::new(0) }
+    }
+
+    pub fn stat(&self) -> Stat {
+        self.dentry.stat()
+    }
+
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+}
+
+impl FileOps for OpenFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if !self.readable() {
+            return Err(Error::BadFd);
+        }
+        let mut offset = self.offset.lock();
+        let len = self.dentry.inode().read_at(*offset, buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Write at the offset, or with `O_APPEND`, at the end.
+    fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        if !self.writable() {
+            return Err(Error::BadFd);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags & O_APPEND != 0 {
+            *offset = self.dentry.stat().size;
+        }
+        let len = self.dentry.inode().write_at(*offset, buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), Error> {
+        if !self.writable() {
+            return Err(Error::Invalid);
+        }
+        self.dentry.inode().truncate(len)
+    }
+
+    fn seek(&self, offset: i64, whence: u64) -> Result<u64, Error> {
+        let mut current = self.offset.lock();
+        let from = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *current,
+            SEEK_END => self.dentry.stat().size,
+            _ => return Err(Error::Invalid),
+        };
+        let to = i64::try_from(from).ok().and_then(|from| from.checked_add(offset)).ok_or(Error::Invalid)?;
+        *current = u64::try_from(to).map_err(|_| Error::Invalid)?;
+        Ok(*current)
+    }
+
+    fn getdents(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.dentry.stat().kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+        let mut index = self.offset.lock();
+        let mut len = 0;
+        while let Some(entry) = self.dentry.inode().readdir(*index as usize)? {
+            match encode_dirent(&mut buf[len..], entry.ino, *index + 1, entry.kind.dirent_type(), &entry.name) {
+                Some(size) => len += size,
+                None if len == 0 => return Err(Error::Invalid),
+                None => break,
+            }
+            *index += 1;
+        }
+        Ok(len)
+    }
+}
+
+/* Write a `linux_dirent64` to the start of `buf`, returning its size, or None if it won't fit.
+ * `next` is what `d_off` says: where to carry on reading after it. */
+fn encode_dirent(buf: &mut [u8], ino: u64, next: u64, kind: u8, name: &str) -> Option<usize> {
+    let size = (DIRENT_HEADER + name.len() + 1 + 7) & !7;
+    if size > buf.len() {
+        return None;
+    }
+    let entry = &mut buf[..size];
+    entry[0..8].copy_from_slice(&ino.to_ne_bytes());
+    entry[8..16].copy_from_slice(&next.to_ne_bytes());
+    entry[16..18].copy_from_slice(&(size as u16).to_ne_bytes());
+    entry[18] = kind;
+    entry[DIRENT_HEADER..DIRENT_HEADER + name.len()].copy_from_slice(name.as_bytes());
+    for byte in &mut entry[DIRENT_HEADER + name.len()..] {
+        *byte = 0;
+    }
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirent_layout() {
+        let mut buf = [0xffu8; 64];
+        assert_eq!(encode_dirent(&mut buf, 7, 3, 8, "hello"), Some(32));
+        assert_eq!(&buf[0..8], &7u64.to_ne_bytes());
+        assert_eq!(&buf[8..16], &3u64.to_ne_bytes());
+        assert_eq!(&buf[16..18], &32u16.to_ne_bytes());
+        assert_eq!(buf[18], 8);
+        assert_eq!(&buf[19..24], b"hello");
+        assert!(buf[24..32].iter().all(|&b| b == 0));
+        assert_eq!(buf[32], 0xff);
+    }
+
+    #[test]
+    fn dirent_sizes_are_8_byte_multiples() {
+        let mut buf = [0u8; 64];
+        assert_eq!(encode_dirent(&mut buf, 1, 1, 4, ""), Some(24));
+        assert_eq!(encode_dirent(&mut buf, 1, 1, 4, "abcd"), Some(24));
+        assert_eq!(encode_dirent(&mut buf, 1, 1, 4, "abcde"), Some(32));
+    }
+
+    #[test]
+    fn dirent_that_does_not_fit() {
+        let mut buf = [0u8; 31];
+        assert_eq!(encode_dirent(&mut buf, 1, 1, 8, "hello"), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! The virtual filesystem: one tree of names that filesystems are mounted into, and that `open()`
+//! and friends resolve paths in.
+//!
+//! A filesystem hands out inodes, the files, directories and symlinks in it. The VFS keeps the
+//! names in use as dentries, which tie an inode to its name and parent, and record what's mounted
+//! where: a path that reaches a mount point carries on in the root of the filesystem mounted
+//! there. The first filesystem mounted is the root, at "/".
+//!
+//! There's no current directory yet, so every path is taken from the root. Symlinks are followed,
+//! `MAX_SYMLINKS` deep.
+
+use crate::sync::IrqSpinLock;
+use crate::syscall::Error;
+use alloc::{string::String, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+mod dentry;
+mod file;
+pub mod ramfs;
+
+use dentry::Dentry;
+pub use file::OpenFile;
+
+/// `open()` flags, as Linux has them.
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_DIRECTORY: u64 = 0o200000;
+
+/// `lseek()`'s whence.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// The longest name a directory entry can have.
+pub const NAME_MAX: usize = 255;
+
+/// How many symlinks resolving one path can go through.
+pub const MAX_SYMLINKS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+}
+
+impl FileType {
+    /// The type's bits in a mode, as in `S_IFREG` etc.
+    pub fn mode_bits(self) -> u32 {
+        match self {
+            FileType::Regular => 0o100000,
+            FileType::Directory => 0o040000,
+            FileType::Symlink => 0o120000,
+        }
+    }
+
+    /// The type as `getdents()` gives it, as in `DT_REG` etc.
+    pub fn dirent_type(self) -> u8 {
+        match self {
+            FileType::Regular => 8,
+            FileType::Directory => 4,
+            FileType::Symlink => 10,
+        }
+    }
+}
+
+/// What `stat()` says about a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    /// Which mounted filesystem it's in; filled in by the VFS.
+    pub dev: u64,
+    pub ino: u64,
+    pub kind: FileType,
+    /// Permission bits. They're kept, but not checked.
+    pub mode: u32,
+    pub nlink: u32,
+    pub size: u64,
+}
+
+impl Stat {
+    pub const SIZE: usize = 32;
+
+    /// As user mode sees it: dev, ino, mode (with the type bits), nlink and size.
+    pub fn to_bytes(&self) -> [u8; Stat::SIZE] {
+        let mut bytes = [0; Stat::SIZE];
+        bytes[0..8].copy_from_slice(&self.dev.to_ne_bytes());
+        bytes[8..16].copy_from_slice(&self.ino.to_ne_bytes());
+        bytes[16..20].copy_from_slice(&(self.kind.mode_bits() | self.mode).to_ne_bytes());
+        bytes[20..24].copy_from_slice(&self.nlink.to_ne_bytes());
+        bytes[24..32].copy_from_slice(&self.size.to_ne_bytes());
+        bytes
+    }
+}
+
+/// An entry in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub kind: FileType,
+}
+
+/// A mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    /// What kind of filesystem it is, for listing mounts.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// A file, directory or symlink in a filesystem. The defaults are for an inode that can't do
+/// that, so a filesystem only implements what applies. Calls may block.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Stat;
+
+    /// Read from `offset` into `buf`, returning how many bytes were read; 0 past the end.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::IsDirectory)
+    }
+
+    /// Write `buf` at `offset`, growing the file if that's past the end.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsDirectory)
+    }
+
+    /// Make the file `len` bytes long.
+    fn truncate(&self, _len: u64) -> Result<(), Error> {
+        Err(Error::IsDirectory)
+    }
+
+    /// A symlink's target.
+    fn readlink(&self) -> Result<String, Error> {
+        Err(Error::Invalid)
+    }
+
+    /// The entry called `name` in a directory. `Error::NoEntry` if there isn't one.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// A directory's `index`th entry, or None past the last. "." and ".." aren't included.
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Make an empty file or directory called `name` in a directory.
+    fn create(&self, _name: &str, _kind: FileType, _mode: u32) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Make a symlink called `name` in a directory, to `target`.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Remove the entry called `name` from a directory. A directory has to be empty.
+    fn unlink(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::NotDirectory)
+    }
+}
+
+/* The root filesystem's root, once there is one. */
+static ROOT: IrqSpinLock<Option<Arc<Dentry>>> = IrqSpinLock::new(None);
+
+fn root() -> Result<Arc<Dentry>, Error> {
+    ROOT.lock().clone().ok_or(Error::NoEntry)
+}
+
+/// Mount `fs` at `path`, which has to be a directory with nothing else mounted on it. The first
+/// filesystem mounted has to go at "/".
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Error> {
+    static NEXT_DEV: AtomicU64 = AtomicU64::new(1);
+    let dev = NEXT_DEV.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut root = ROOT.lock();
+        if root.is_none() {
+            if path != "/" {
+                return Err(Error::NoEntry);
+            }
+            *root = Some(Dentry::root(fs.root(), dev));
+            log::info!("Mounted {} at /", fs.name());
+            return Ok(());
+        }
+    }
+    let at = dentry::walk(root()?, path, true)?;
+    at.mount(fs.root(), dev)?;
+    log::info!("Mounted {} at {}", fs.name(), path);
+    Ok(())
+}
+
+/// Open the file at `path` with `flags` (`O_RDONLY` etc); with `O_CREAT`, a regular file with
+/// permissions `mode` is made if there's nothing there.
+pub fn open(path: &str, flags: u64, mode: u32) -> Result<OpenFile, Error> {
+    let found = if flags & O_CREAT != 0 {
+        let (dir, name) = dentry::walk_parent(root()?, path)?;
+        match dir.child(name) {
+            Ok(_) if flags & O_EXCL != 0 => return Err(Error::Exists),
+            Ok(_) => dentry::walk(root()?, path, true)?,
+            Err(Error::NoEntry) => dir.create(name, FileType::Regular, mode)?,
+            Err(e) => return Err(e),
+        }
+    } else {
+        dentry::walk(root()?, path, true)?
+    };
+    let kind = found.stat().kind;
+    if flags & O_DIRECTORY != 0 && kind != FileType::Directory {
+        return Err(Error::NotDirectory);
+    }
+    let writing = flags & O_ACCMODE != O_RDONLY;
+    if kind == FileType::Directory && writing {
+        return Err(Error::IsDirectory);
+    }
+    if flags & O_TRUNC != 0 && writing {
+        found.inode().truncate(0)?;
+    }
+    Ok(OpenFile::new(found, flags))
+}
+
+/// What's at `path`, following symlinks.
+pub fn stat(path: &str) -> Result<Stat, Error> {
+    Ok(dentry::walk(root()?, path, true)?.stat())
+}
+
+/// Make a directory at `path`.
+pub fn mkdir(path: &str, mode: u32) -> Result<(), Error> {
+    let (dir, name) = dentry::walk_parent(root()?, path)?;
+    dir.create(name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+/// Make a symlink at `path`, to `target`.
+pub fn symlink(target: &str, path: &str) -> Result<(), Error> {
+    let (dir, name) = dentry::walk_parent(root()?, path)?;
+    dir.symlink(name, target)?;
+    Ok(())
+}
+
+/// Remove the file or symlink at `path`.
+pub fn unlink(path: &str) -> Result<(), Error> {
+    let (dir, name) = dentry::walk_parent(root()?, path)?;
+    dir.remove(name, false)
+}
+
+/// Remove the empty directory at `path`.
+pub fn rmdir(path: &str) -> Result<(), Error> {
+    let (dir, name) = dentry::walk_parent(root()?, path)?;
+    dir.remove(name, true)
+}
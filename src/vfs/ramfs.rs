@@ -0,0 +1,343 @@
+//! A filesystem that's all in memory, there until it's unmounted (which it never is) and gone at
+//! reboot; good for a root before there's a disk to have one on.
+//!
+//! A file's contents are whole pages, in frames of their own rather than on the heap, and only the
+//! pages that have been written to are there: the rest read as zeroes. Directories, names and
+//! symlink targets do go on the heap, which is small, so each ramfs has limits on both: how many
+//! pages its files can have between them, and roughly how much of the heap everything else can
+//! take. Going over either is `Error::NoSpace`.
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat};
+use crate::memory;
+use crate::sync::{IrqSpinLock, Mutex};
+use crate::syscall::Error;
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use core::cmp;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::PhysFrame;
+
+const PAGE_SIZE: u64 = 4096;
+
+/* Roughly what the heap pays for an inode, besides its name (or its target): the inode itself, the
+ * Arc around it, and its share of the directory's map. And for each page of a file, in its map. */
+const INODE_COST: usize = mem::size_of::<RamInode>() + 64;
+const PAGE_COST: usize = 32;
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+    space: Arc<Space>,
+}
+
+/// How much a ramfs has, and can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Pages of file contents.
+    pub pages: u64,
+    pub max_pages: u64,
+    /// Bytes of heap, for everything else.
+    pub heap: usize,
+    pub max_heap: usize,
+}
+
+/* What all of a ramfs's inodes share. */
+struct Space {
+    usage: IrqSpinLock<Usage>,
+    next_ino: AtomicU64,
+}
+
+impl Space {
+    fn charge(&self, pages: u64, heap: usize) -> Result<(), Error> {
+        let mut usage = self.usage.lock();
+        if usage.pages + pages > usage.max_pages || usage.heap + heap > usage.max_heap {
+            return Err(Error::NoSpace);
+        }
+        usage.pages += pages;
+        usage.heap += heap;
+        Ok(())
+    }
+
+    fn release(&self, pages: u64, heap: usize) {
+        let mut usage = self.usage.lock();
+        usage.pages -= pages;
+        usage.heap -= heap;
+    }
+}
+
+struct RamInode {
+    ino: u64,
+    kind: FileType,
+    mode: u32,
+    space: Arc<Space>,
+    contents: Mutex<Contents>,
+}
+
+enum Contents {
+    /* Pages past the end, or past `size` in the last, are zeroes. */
+    File { size: u64, pages: BTreeMap<u64, PhysFrame> },
+    Dir(BTreeMap<String, Arc<RamInode>>),
+    Link(String),
+}
+
+impl RamFs {
+    /// An empty ramfs, whose files can have `max_pages` pages between them, and that can take
+    /// about `max_heap` bytes of the heap for the rest.
+    pub fn new(max_pages: u64, max_heap: usize) -> Result<Arc<RamFs>, Error> {
+        let space = Arc::new(Space {
+            usage: IrqSpinLock::new(Usage { pages: 0, max_pages, heap: 0, max_heap }),
+            next_ino: AtomicU64::new(1),
+        });
+        let root = RamInode::new(&space, FileType::Directory, 0o755, Contents::Dir(BTreeMap::new()))?;
+        Ok(Arc::new(RamFs { root, space }))
+    }
+
+    pub fn usage(&self) -> Usage {
+        *self.space.usage.lock()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl RamInode {
+    fn new(space: &Arc<Space>, kind: FileType, mode: u32, contents: Contents) -> Result<Arc<RamInode>, Error> {
+        space.charge(0, INODE_COST + contents.heap())?;
+        Ok(Arc::new(RamInode {
+            ino: space.next_ino.fetch_add(1, Ordering::Relaxed),
+            kind,
+            mode,
+            space: space.clone(),
+            contents: Mutex::new(contents),
+        }))
+    }
+
+    /* A zeroed page for a file, if there's room for one. */
+    fn new_page(&self) -> Result<PhysFrame, Error> {
+        self.space.charge(1, PAGE_COST)?;
+        match memory::allocate_frame() {
+            Some(frame) => {
+                let frame = *frame;
+                page(frame).iter_mut().for_each(|byte| *byte = 0);
+                Ok(frame)
+            }
+            None => {
+                self.space.release(1, PAGE_COST);
+                Err(Error::NoMemory)
+            }
+        }
+    }
+
+    /* Make an entry called `name` in this directory. */
+    fn add(&self, name: &str, kind: FileType, mode: u32, contents: Contents) -> Result<Arc<dyn Inode>, Error> {
+        let mut dir = self.contents.lock();
+        let entries = match *dir {
+            Contents::Dir(ref mut entries) => entries,
+            _ => return Err(Error::NotDirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(Error::Exists);
+        }
+        let inode = RamInode::new(&self.space, kind, mode, contents)?;
+        self.space.charge(0, name.len())?;
+        entries.insert(String::from(name), inode.clone());
+        Ok(inode)
+    }
+}
+
+impl Contents {
+    /* What it has on the heap that isn't accounted for as it changes. */
+    fn heap(&self) -> usize {
+        match *self {
+            Contents::Link(ref target) => target.len(),
+            _ => 0,
+        }
+    }
+
+    fn not_file(&self) -> Error {
+        match *self {
+            Contents::Dir(_) => Error::IsDirectory,
+            _ => Error::Invalid,
+        }
+    }
+}
+
+/* The page in `frame`, through the physical memory mapping. Only for a page of a file whose
+ * contents are locked. */
+fn page(frame: PhysFrame) -> &'static mut [u8] {
+    let virt = memory::phys_to_virt(frame.start_address()).unwrap();
+    unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), PAGE_SIZE as usize) }
+}
+
+impl Inode for RamInode {
+    fn stat(&self) -> Stat {
+        let (nlink, size) = match *self.contents.lock() {
+            Contents::File { size, .. } => (1, size),
+            Contents::Dir(ref entries) => {
+                let subdirs = entries.values().filter(|inode| inode.kind == FileType::Directory).count();
+                (2 + subdirs as u32, entries.len() as u64)
+            }
+            Contents::Link(ref target) => (1, target.len() as u64),
+        };
+        Stat { dev: 0, ino: self.ino, kind: self.kind, mode: self.mode, nlink, size }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let contents = self.contents.lock();
+        let (size, pages) = match *contents {
+            Contents::File { size, ref pages } => (size, pages),
+            ref other => return Err(other.not_file()),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let in_page = (at % PAGE_SIZE) as usize;
+            let chunk = cmp::min(len - done, PAGE_SIZE as usize - in_page);
+            let dest = &mut buf[done..done + chunk];
+            match pages.get(&(at / PAGE_SIZE)) {
+                Some(&frame) => dest.copy_from_slice(&page(frame)[in_page..in_page + chunk]),
+                None => dest.iter_mut().for_each(|byte| *byte = 0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Write as much of `buf` as there's room for; `Error::NoSpace` only if that's none of it.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+        let mut contents = self.contents.lock();
+        let (size, pages) = match *contents {
+            Contents::File { ref mut size, ref mut pages } => (size, pages),
+            ref other => return Err(other.not_file()),
+        };
+        offset.checked_add(buf.len() as u64).ok_or(Error::Invalid)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let in_page = (at % PAGE_SIZE) as usize;
+            let chunk = cmp::min(buf.len() - done, PAGE_SIZE as usize - in_page);
+            let frame = match pages.get(&(at / PAGE_SIZE)) {
+                Some(&frame) => frame,
+                None => match self.new_page() {
+                    Ok(frame) => *pages.entry(at / PAGE_SIZE).or_insert(frame),
+                    Err(e) if done == 0 => return Err(e),
+                    Err(_) => break,
+                },
+            };
+            page(frame)[in_page..in_page + chunk].copy_from_slice(&buf[done..done + chunk]);
+            done += chunk;
+        }
+        if done > 0 {
+            *size = cmp::max(*size, offset + done as u64);
+        }
+        Ok(done)
+    }
+
+    /// Shrink, freeing the pages past the end, or grow, with pages that aren't there yet.
+    fn truncate(&self, len: u64) -> Result<(), Error> {
+        let mut contents = self.contents.lock();
+        let (size, pages) = match *contents {
+            Contents::File { ref mut size, ref mut pages } => (size, pages),
+            ref other => return Err(other.not_file()),
+        };
+        if len < *size {
+            let kept = len / PAGE_SIZE + if len % PAGE_SIZE == 0 { 0 } else { 1 };
+            let freed = pages.split_off(&kept);
+            for &frame in freed.values() {
+                memory::free_frame(frame);
+            }
+            self.space.release(freed.len() as u64, freed.len() * PAGE_COST);
+            if let Some(&frame) = pages.get(&(len / PAGE_SIZE)) {
+                page(frame)[(len % PAGE_SIZE) as usize..].iter_mut().for_each(|byte| *byte = 0);
+            }
+        }
+        *size = len;
+        Ok(())
+    }
+
+    fn readlink(&self) -> Result<String, Error> {
+        match *self.contents.lock() {
+            Contents::Link(ref target) => Ok(target.clone()),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        match *self.contents.lock() {
+            Contents::Dir(ref entries) => match entries.get(name) {
+                Some(inode) => Ok(inode.clone()),
+                None => Err(Error::NoEntry),
+            },
+            _ => Err(Error::NotDirectory),
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        match *self.contents.lock() {
+            Contents::Dir(ref entries) => Ok(entries.iter().nth(index).map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                ino: inode.ino,
+                kind: inode.kind,
+            })),
+            _ => Err(Error::NotDirectory),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, Error> {
+        let contents = match kind {
+            FileType::Regular => Contents::File { size: 0, pages: BTreeMap::new() },
+            FileType::Directory => Contents::Dir(BTreeMap::new()),
+            FileType::Symlink => return Err(Error::Invalid),
+        };
+        self.add(name, kind, mode, contents)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.add(name, FileType::Symlink, 0o777, Contents::Link(String::from(target)))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Error> {
+        let mut dir = self.contents.lock();
+        let entries = match *dir {
+            Contents::Dir(ref mut entries) => entries,
+            _ => return Err(Error::NotDirectory),
+        };
+        let inode = entries.get(name).ok_or(Error::NoEntry)?;
+        if let Contents::Dir(ref children) = *inode.contents.lock() {
+            if !children.is_empty() {
+                return Err(Error::NotEmpty);
+            }
+        }
+        entries.remove(name);
+        self.space.release(0, name.len());
+        Ok(())
+    }
+}
+
+/* Once nothing has it, not a directory nor an open file, give back what it had. */
+impl Drop for RamInode {
+    fn drop(&mut self) {
+        let contents = self.contents.lock();
+        let (pages, heap) = match *contents {
+            Contents::File { ref pages, .. } => {
+                for &frame in pages.values() {
+                    memory::free_frame(frame);
+                }
+                (pages.len() as u64, pages.len() * PAGE_COST)
+            }
+            Contents::Dir(ref entries) => (0, entries.keys().map(|name| name.len()).sum()),
+            Contents::Link(_) => (0, 0),
+        };
+        self.space.release(pages, INODE_COST + contents.heap() + heap);
+    }
+}
@@ -1,6 +1,6 @@
 use core::fmt;
+use crate::sync::IrqSpinLock;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 // The VGA buffer is memory-mapped to physical 0xb8000.
@@ -9,7 +9,8 @@ use volatile::Volatile;
 const VGA_BUFFER: u64 = 0x1000;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    /// Interrupt handlers may want to print, so this is held with interrupts off.
+    pub static ref WRITER: IrqSpinLock<Writer> = IrqSpinLock::new(Writer {
         col: 0,
         color: ColorCode::new(Color::LightGray, Color::Blue),
         buf: unsafe { &mut *(VGA_BUFFER as *mut Buffer) },
@@ -92,7 +93,12 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for b in s.bytes() {
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// Write text that may not be UTF-8, eg from user mode. Anything unprintable shows as a block.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
             match b {
                 // 3 dots is inclusive range
                 0x20..=0x7e | b'\n' => self.write_byte(b),
@@ -141,12 +147,13 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+pub fn write_bytes(bytes: &[u8]) {
+    WRITER.lock().write_bytes(bytes);
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        /* Interrupt handlers may want to print, which would cause them to deadlock with this code. */
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }